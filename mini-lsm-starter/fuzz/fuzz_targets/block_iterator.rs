@@ -0,0 +1,29 @@
+#![no_main]
+
+use std::sync::Arc;
+
+use libfuzzer_sys::fuzz_target;
+use mini_lsm_starter::block::{Block, BlockIterator};
+
+// Any data, restart points and offsets, consistent or not, must leave the iterator invalid
+// rather than panic.
+fuzz_target!(|input: (Vec<u8>, Vec<u16>, Vec<u16>, Vec<u8>)| {
+    let (data, restarts, offsets, key) = input;
+    let entries = offsets.len();
+    let block = Arc::new(Block::from_raw_parts(data, restarts, offsets));
+    let _ = block.last();
+
+    let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+    for _ in 0..entries {
+        if !iter.is_valid() {
+            break;
+        }
+        iter.next();
+    }
+
+    let mut iter = BlockIterator::create_and_seek_to_key(block, &key);
+    if iter.is_valid() {
+        assert!(iter.key().as_ref() >= key.as_slice());
+        iter.next();
+    }
+});
@@ -0,0 +1,142 @@
+//! Tracks which user keys are read often, for compaction to keep them in the shallower levels.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// Rows of the sketch, each hashing keys with its own seed.
+const ROWS: usize = 4;
+
+/// How reads are sampled and when a key counts as hot, see
+/// [`LsmStorageOptions::tiering`](crate::lsm_storage::LsmStorageOptions::tiering).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TieringOptions {
+    /// Record one point read out of this many.
+    pub sample_every: u64,
+    /// Number of counters per row of the sketch. More counters, fewer cold keys taken for hot
+    /// ones that hash alike.
+    pub width: usize,
+    /// A key is hot once this many of its reads were sampled, recently.
+    pub hot_threshold: u32,
+    /// Halve every count after this many samples, so that keys nobody reads anymore cool down.
+    pub decay_every: u64,
+}
+
+impl Default for TieringOptions {
+    fn default() -> Self {
+        Self {
+            sample_every: 1,
+            width: 4096,
+            hot_threshold: 8,
+            decay_every: 1 << 16,
+        }
+    }
+}
+
+/// A count-min sketch of the sampled reads. Counts only ever overestimate, so a hot key is
+/// never taken for a cold one; it costs a fixed amount of memory whatever the number of keys.
+#[derive(Debug)]
+pub struct AccessTracker {
+    options: TieringOptions,
+    counts: Vec<AtomicU32>,
+    reads: AtomicU64,
+    samples: AtomicU64,
+}
+
+impl AccessTracker {
+    pub fn new(options: TieringOptions) -> Self {
+        let width = options.width.max(1);
+        Self {
+            options: TieringOptions { width, ..options },
+            counts: (0..ROWS * width).map(|_| AtomicU32::new(0)).collect(),
+            reads: AtomicU64::new(0),
+            samples: AtomicU64::new(0),
+        }
+    }
+
+    fn slots(&self, key: &[u8]) -> impl Iterator<Item = usize> + '_ {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        (0..ROWS).map(move |row| {
+            // double hashing, as for the Bloom filters
+            let hash = hash.wrapping_add((row as u64).wrapping_mul(hash.rotate_left(32) | 1));
+            row * self.options.width + (hash % self.options.width as u64) as usize
+        })
+    }
+
+    /// Record a read of `key`, if it is sampled.
+    pub fn record(&self, key: &[u8]) {
+        let reads = self.reads.fetch_add(1, Ordering::Relaxed);
+        if reads % self.options.sample_every.max(1) != 0 {
+            return;
+        }
+        for slot in self.slots(key) {
+            self.counts[slot].fetch_add(1, Ordering::Relaxed);
+        }
+        let samples = self.samples.fetch_add(1, Ordering::Relaxed) + 1;
+        if samples % self.options.decay_every.max(1) == 0 {
+            for count in &self.counts {
+                // a racing increment may be halved away, which only makes the key look cooler
+                let _ = count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| Some(n / 2));
+            }
+        }
+    }
+
+    /// How many sampled reads of `key` are recorded, at least.
+    pub fn estimate(&self, key: &[u8]) -> u32 {
+        self.slots(key)
+            .map(|slot| self.counts[slot].load(Ordering::Relaxed))
+            .min()
+            .unwrap_or(0)
+    }
+
+    pub fn is_hot(&self, key: &[u8]) -> bool {
+        self.estimate(key) >= self.options.hot_threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skewed_reads() {
+        let tracker = AccessTracker::new(TieringOptions {
+            hot_threshold: 10,
+            ..Default::default()
+        });
+        for round in 0..100 {
+            tracker.record(format!("hot_{}", round % 5).as_bytes());
+            tracker.record(format!("cold_{}", round).as_bytes());
+        }
+        for key in 0..5 {
+            assert!(tracker.is_hot(format!("hot_{}", key).as_bytes()));
+        }
+        for key in 0..100 {
+            assert!(!tracker.is_hot(format!("cold_{}", key).as_bytes()));
+        }
+        assert_eq!(tracker.estimate(b"never read"), 0);
+    }
+
+    #[test]
+    fn test_sampling_and_decay() {
+        let tracker = AccessTracker::new(TieringOptions {
+            sample_every: 2,
+            hot_threshold: 4,
+            decay_every: 8,
+            ..Default::default()
+        });
+        for _ in 0..8 {
+            tracker.record(b"key");
+        }
+        assert_eq!(tracker.estimate(b"key"), 4);
+        assert!(tracker.is_hot(b"key"));
+        // the eighth sample halves every count
+        for _ in 0..8 {
+            tracker.record(b"other");
+        }
+        assert_eq!(tracker.estimate(b"key"), 2);
+        assert!(!tracker.is_hot(b"key"));
+    }
+}
@@ -1,118 +1,347 @@
 mod builder;
 mod iterator;
+mod scratch;
 
-pub use builder::BlockBuilder;
+use anyhow::{bail, ensure, Result};
+pub use builder::{BlockBuilder, DEFAULT_RESTART_INTERVAL};
 /// You may want to check `bytes::BufMut` out when manipulating continuous chunks of memory
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::compression::CompressionType;
+use crate::error::LsmError;
+use crate::format::{BLOCK_ENTRY, BLOCK_TRAILER};
 pub use iterator::BlockIterator;
+pub use scratch::EncodeScratch;
 
 /// A block is the smallest unit of read and caching in LSM tree.
 /// It is a collection of sorted key-value pairs.
 /// The `actual` storage format is as below (After `Block::encode`):
 ///
-/// --------------------------------------------------------------------------------------------------------------------------
-/// |             Data Section             | Padding |              Offset Section             |      Extra      |  CheckSum |
-/// --------------------------------------------------------------------------------------------------------------------------
-/// | Entry #1 | Entry #2 | ... | Entry #N | 00...00 | Offset #1 | Offset #2 | ... | Offset #N | num_of_elements |  crc32    |
-/// --------------------------------------------------------------------------------------------------------------------------
+/// -----------------------------------------------------------------------------------------------------------------------------------------------
+/// |             Data Section             | Padding |            Restart Section           |         Offset Section        |      Extra      |  CheckSum |
+/// -----------------------------------------------------------------------------------------------------------------------------------------------
+/// | Entry #1 | Entry #2 | ... | Entry #N | 00...00 | Restart #1 | ... | Restart #R | R: u16 | Offset #1 | ... | Offset #N | num_of_elements |  crc32    |
+/// -----------------------------------------------------------------------------------------------------------------------------------------------
+///
+/// An entry stores its key as the length of the prefix it shares with the key before it and the
+/// bytes that follow:
+///
+/// ---------------------------------------------------------------------------------------
+/// | shared_len: u16 | unshared_len: u16 | value_len: u16 | unshared_key_bytes | value |
+/// ---------------------------------------------------------------------------------------
+///
+/// The entries at the restart points, whose offsets the restart section lists, share nothing,
+/// so a key can be rebuilt from the restart point before it. The sizes of the fixed fields are
+/// the ones of [`BLOCK_ENTRY`] and [`BLOCK_TRAILER`].
 pub struct Block {
     data: Vec<u8>,
     padding: u16,
+    /// Offsets of the entries that store their whole key, in order.
+    restarts: Vec<u16>,
     offsets: Vec<u16>,
+    /// Every value ends with the [`entry_checksum`] of its entry. Not part of the encoding, the
+    /// table a block belongs to records it.
+    entry_checksums: bool,
     #[cfg(feature = "checksum")]
     sum: u32,
 }
 
 #[cfg(feature = "checksum")]
-const ABC: u16 = checksum_size();
+pub const CHECKSUM_SIZE: usize = BLOCK_TRAILER.checksum_size;
 #[cfg(not(feature = "checksum"))]
 pub const CHECKSUM_SIZE: usize = 0;
-pub const COUNT_SIZE: usize = std::mem::size_of::<u16>();
+pub const COUNT_SIZE: usize = BLOCK_TRAILER.count_size;
+/// Length of an offset in the restart and the offset sections.
+const OFFSET_SIZE: usize = BLOCK_TRAILER.offset_size;
+/// Length of the lengths that start an entry.
+const ENTRY_HEADER_SIZE: usize = BLOCK_ENTRY.len;
+/// Length of the entry checksum that ends a value when entry checksums are on.
+pub const ENTRY_CHECKSUM_SIZE: usize = std::mem::size_of::<u32>();
+
+/// The CRC that ends a block: of everything before it but the padding.
+#[cfg(feature = "checksum")]
+fn block_checksum(data: &[u8], restarts: &[u16], offsets: &[u16]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(data);
+    for section in [restarts, offsets] {
+        section
+            .iter()
+            .for_each(|offset| hasher.update(&offset.to_le_bytes()));
+        hasher.update(&(section.len() as u16).to_le_bytes());
+    }
+    hasher.finalize()
+}
+
+/// The CRC of an entry, kept after its value so that corruption anywhere between the encoder
+/// and the user is caught.
+pub fn entry_checksum(key: &[u8], value: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize()
+}
+
+/// Split the entry checksum off `stored` and check it, returning the value the user wrote.
+pub fn strip_entry_checksum<'a>(key: &[u8], stored: &'a [u8]) -> Result<&'a [u8], LsmError> {
+    let mismatch = || LsmError::EntryChecksumMismatch {
+        key: Bytes::copy_from_slice(key),
+    };
+    let split = stored
+        .len()
+        .checked_sub(ENTRY_CHECKSUM_SIZE)
+        .ok_or_else(mismatch)?;
+    let (value, sum) = stored.split_at(split);
+    if entry_checksum(key, value) != u32::from_le_bytes(sum.try_into().unwrap()) {
+        return Err(mismatch());
+    }
+    Ok(value)
+}
 
 impl Block {
     /// Encode the internal data to the data layout illustrated in the tutorial
     /// Note: You may want to recheck if any of the expected field is missing from your output
     pub fn encode(&self) -> Bytes {
-        let mut bytes = BytesMut::from(self.data.as_slice());
-        bytes.put_bytes(0, self.padding.into());
-        self.offsets
-            .iter()
-            .for_each(|offset| bytes.put_u16_le(*offset));
-        bytes.put_u16_le(self.offsets.len() as _);
-        #[cfg(feature = "checksum")]
-        bytes.put_u32_le(self.sum);
+        let mut bytes = BytesMut::with_capacity(self.encoded_len());
+        self.encode_into(&mut bytes);
         bytes.freeze()
     }
 
-    /// Decode from the data layout, transform the input `data` to a single `Block`
-    pub fn decode(data: &[u8]) -> Self {
+    /// Append the encoded block to `buf`, which lets callers recycle one buffer across blocks.
+    pub fn encode_into(&self, buf: &mut BytesMut) {
+        buf.reserve(self.encoded_len());
+        buf.extend_from_slice(&self.data);
+        buf.put_bytes(0, self.padding.into());
+        for section in [&self.restarts, &self.offsets] {
+            section
+                .iter()
+                .for_each(|offset| buf.put_uint_le(*offset as u64, OFFSET_SIZE));
+            buf.put_uint_le(section.len() as u64, COUNT_SIZE);
+        }
         #[cfg(feature = "checksum")]
-        let mut hasher = crc32fast::Hasher::new();
+        buf.put_uint_le(self.sum as u64, CHECKSUM_SIZE);
+    }
 
+    /// Decode from the data layout, transform the input `data` to a single `Block`. Fails on
+    /// data that is not a whole block, such as a truncated or corrupted one.
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let read_count = |end: usize| (&data[end - COUNT_SIZE..end]).get_uint_le(COUNT_SIZE);
+        let read_section = |end: usize, count: usize| {
+            data[end - count * OFFSET_SIZE..end]
+                .chunks(OFFSET_SIZE)
+                .map(|mut chk| chk.get_uint_le(OFFSET_SIZE) as u16)
+                .collect::<Vec<u16>>()
+        };
+        ensure!(
+            data.len() >= CHECKSUM_SIZE + 2 * COUNT_SIZE,
+            "corrupt block: {} bytes are too few for a block",
+            data.len()
+        );
         #[cfg(feature = "checksum")]
-        let sum = u32::from_le_bytes(data[data.len() - 4..data.len()].try_into().unwrap());
-        let count = u16::from_le_bytes(
-            data[data.len() - CHECKSUM_SIZE - COUNT_SIZE..data.len() - CHECKSUM_SIZE]
-                .try_into()
-                .unwrap(),
+        let sum = (&data[data.len() - CHECKSUM_SIZE..]).get_uint_le(CHECKSUM_SIZE) as u32;
+        let offsets_end = data.len() - CHECKSUM_SIZE - COUNT_SIZE;
+        let count = read_count(data.len() - CHECKSUM_SIZE) as usize;
+        ensure!(
+            offsets_end >= count * OFFSET_SIZE + COUNT_SIZE,
+            "corrupt block: {} entries do not fit in {} bytes",
+            count,
+            data.len()
         );
+        let offsets = read_section(offsets_end, count);
+        let restarts_end = offsets_end - count * OFFSET_SIZE - COUNT_SIZE;
+        let num_restarts = read_count(restarts_end + COUNT_SIZE) as usize;
+        ensure!(
+            restarts_end >= num_restarts * OFFSET_SIZE,
+            "corrupt block: {} restart points do not fit in {} bytes",
+            num_restarts,
+            data.len()
+        );
+        let restarts = read_section(restarts_end, num_restarts);
+        let sections_start = restarts_end - num_restarts * OFFSET_SIZE;
 
-        let mut raw = vec![];
-        let mut buf = data;
-        for _ in 0..count {
-            let key_len = buf.get_u16_le();
-            raw.put_u16_le(key_len);
-            raw.extend_from_slice(&buf.copy_to_bytes(key_len as _));
-
-            let val_len = buf.get_u16_le();
-            raw.put_u16_le(val_len);
-            raw.extend_from_slice(&buf.copy_to_bytes(val_len as _));
+        // the entries run up to the padding
+        let mut buf = &data[..sections_start];
+        for idx in 0..count {
+            let lens = buf.get(..ENTRY_HEADER_SIZE);
+            let len = lens.map(|lens| {
+                ENTRY_HEADER_SIZE
+                    + BLOCK_ENTRY.rest_of_key_len.get(lens) as usize
+                    + BLOCK_ENTRY.value_len.get(lens) as usize
+            });
+            match len {
+                Some(len) if len <= buf.len() => buf.advance(len),
+                _ => bail!("corrupt block: entry {} runs past the data section", idx),
+            }
+        }
+        let raw = data[..sections_start - buf.len()].to_vec();
+        if let Some(offset) = offsets
+            .iter()
+            .chain(&restarts)
+            .find(|&&offset| offset as usize >= raw.len())
+        {
+            bail!(
+                "corrupt block: offset {} points past the data section of {} bytes",
+                offset,
+                raw.len()
+            );
         }
-
-        // NOTE: don't use Vec::<_>::from_raw_parts because of alignment 1 -> 2
-        let off = &data[data.len() - CHECKSUM_SIZE - COUNT_SIZE - count as usize * 2
-            ..data.len() - CHECKSUM_SIZE - COUNT_SIZE];
-        let offsets = off
-            .chunks(2)
-            .map(|chk| u16::from_le_bytes(chk.try_into().unwrap()))
-            .collect::<Vec<u16>>();
-        // let offsets =
-        //     unsafe { std::slice::from_raw_parts(off.as_ptr() as *const u16, count as _).to_vec() };
 
         #[cfg(feature = "checksum")]
         {
-            hasher.update(&raw);
-            hasher.update(off);
-            hasher.update(&count.to_le_bytes());
-
-            // TODO: return a Result on corruption
-            debug_assert!(sum == hasher.finalize());
+            let actual = block_checksum(&raw, &restarts, &offsets);
+            if sum != actual {
+                return Err(LsmError::ChecksumMismatch {
+                    what: "corrupt block".to_string(),
+                    offset: 0,
+                    expected: sum,
+                    actual,
+                }
+                .into());
+            }
         }
 
-        let padding = (data.len() - raw.len() - off.len() - COUNT_SIZE - CHECKSUM_SIZE) as u16;
+        let padding = (sections_start - raw.len()) as u16;
 
-        Block {
+        Ok(Block {
             data: raw,
             padding,
+            restarts,
             offsets,
+            entry_checksums: false,
             #[cfg(feature = "checksum")]
             sum,
+        })
+    }
+
+    /// Decode a block [`BlockBuilder::build_compressed`] built: decompress it with the codec
+    /// its tag names, then decode it.
+    pub fn decode_compressed(data: &[u8]) -> Result<Self> {
+        Self::decode(&CompressionType::decompress(data)?)
+    }
+
+    /// Build a block out of raw parts, trusting nothing about them.
+    #[cfg(feature = "fuzzing")]
+    pub fn from_raw_parts(data: Vec<u8>, restarts: Vec<u16>, offsets: Vec<u16>) -> Self {
+        Block {
+            data,
+            padding: 0,
+            restarts,
+            offsets,
+            entry_checksums: false,
+            #[cfg(feature = "checksum")]
+            sum: 0,
+        }
+    }
+
+    /// Mark the values of a decoded block as ending with their entry checksum.
+    pub(crate) fn with_entry_checksums(self, entry_checksums: bool) -> Self {
+        Self {
+            entry_checksums,
+            ..self
+        }
+    }
+
+    pub fn has_entry_checksums(&self) -> bool {
+        self.entry_checksums
+    }
+
+    /// A copy of the block with a bit of the value of entry `idx` flipped, as a bad cache or a
+    /// bug in between would.
+    #[cfg(test)]
+    pub(crate) fn with_corrupted_value(&self, idx: usize) -> Self {
+        let pos = self.offsets[idx] as usize;
+        let (_, unshared, value) = self.entry_at(pos).unwrap();
+        assert!(!value.is_empty());
+        let mut data = self.data.clone();
+        data[pos + ENTRY_HEADER_SIZE + unshared.len()] ^= 1;
+        Self {
+            data,
+            padding: self.padding,
+            restarts: self.restarts.clone(),
+            offsets: self.offsets.clone(),
+            entry_checksums: self.entry_checksums,
+            #[cfg(feature = "checksum")]
+            sum: self.sum,
         }
     }
 
+    /// The key bytes the entry at `pos` stores: the whole key at a restart point, such as the
+    /// first entry.
     pub fn slice_at(&self, pos: usize) -> &[u8] {
-        let key_len = u16::from_le_bytes(self.data[pos..pos + 2].try_into().unwrap());
-        &self.data[pos + 2..pos + 2 + key_len as usize]
+        self.entry_at(pos).unwrap().1
     }
 
-    pub fn last(&self) -> Option<&[u8]> {
-        self.offsets.last().map(|&idx| self.slice_at(idx as _))
+    /// The length of the prefix the entry at `pos` shares with the key before it, the rest of
+    /// its key and its value, or `None` if its lengths run past the data.
+    pub fn entry_at(&self, pos: usize) -> Option<(usize, &[u8], &[u8])> {
+        let header = self.data.get(pos..pos + ENTRY_HEADER_SIZE)?;
+        let key_start = pos + ENTRY_HEADER_SIZE;
+        let value_start = key_start + BLOCK_ENTRY.rest_of_key_len.get(header) as usize;
+        let unshared = self.data.get(key_start..value_start)?;
+        let value_len = BLOCK_ENTRY.value_len.get(header) as usize;
+        let value = self.data.get(value_start..value_start + value_len)?;
+        Some((
+            BLOCK_ENTRY.shared_key_len.get(header) as usize,
+            unshared,
+            value,
+        ))
     }
 
-    pub fn len(&self) -> usize {
+    /// The key and the value of the entry at index `idx`, if it lies within the data. The key is
+    /// rebuilt from the restart point before the entry.
+    pub fn entry(&self, idx: usize) -> Option<(Vec<u8>, &[u8])> {
+        let pos = *self.offsets.get(idx)?;
+        let restart = self.restarts.partition_point(|&restart| restart <= pos);
+        let first = match restart.checked_sub(1) {
+            Some(restart) => self.restart_entry(restart)?,
+            None => 0,
+        };
+        let mut key = vec![];
+        for entry_idx in first..=idx {
+            let (shared, unshared, value) = self.entry_at(*self.offsets.get(entry_idx)? as usize)?;
+            if shared > key.len() {
+                return None;
+            }
+            key.truncate(shared);
+            key.extend_from_slice(unshared);
+            if entry_idx == idx {
+                return Some((key, value));
+            }
+        }
+        None
+    }
+
+    /// The whole key stored at restart point `restart`, if it lies within the data.
+    pub(crate) fn restart_key(&self, restart: usize) -> Option<&[u8]> {
+        let (shared, key, _) = self.entry_at(*self.restarts.get(restart)? as usize)?;
+        (shared == 0).then_some(key)
+    }
+
+    /// The index of the entry at restart point `restart`.
+    pub(crate) fn restart_entry(&self, restart: usize) -> Option<usize> {
+        let pos = self.restarts.get(restart)?;
+        self.offsets.binary_search(pos).ok()
+    }
+
+    pub fn num_restarts(&self) -> usize {
+        self.restarts.len()
+    }
+
+    pub fn num_entries(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn last(&self) -> Option<Vec<u8>> {
+        let idx = self.offsets.len().checked_sub(1)?;
+        self.entry(idx).map(|(key, _)| key)
+    }
+
+    /// Length of the output of `encode`.
+    pub fn encoded_len(&self) -> usize {
         self.data.len()
             + self.padding as usize
-            + self.offsets.len() * 2
+            + self.restarts.len() * OFFSET_SIZE
+            + COUNT_SIZE
+            + self.offsets.len() * OFFSET_SIZE
             + COUNT_SIZE
             + CHECKSUM_SIZE
     }
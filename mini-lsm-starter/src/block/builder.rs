@@ -1,19 +1,26 @@
-use bytes::BufMut;
+use bytes::{BufMut, Bytes};
 
-use super::Block;
-use super::{CHECKSUM_SIZE, COUNT_SIZE};
 #[cfg(feature = "checksum")]
-use crc32fast;
+use super::block_checksum;
+use super::{entry_checksum, Block};
+use super::{CHECKSUM_SIZE, COUNT_SIZE, ENTRY_CHECKSUM_SIZE, ENTRY_HEADER_SIZE, OFFSET_SIZE};
+use crate::compression::CompressionType;
+use crate::format::BLOCK_ENTRY;
+
+/// Every how many entries a block stores a whole key, unless told otherwise.
+pub const DEFAULT_RESTART_INTERVAL: usize = 16;
 
 /// Builds a block.
 pub struct BlockBuilder {
     cap: usize,
     data: Vec<u8>,
     offsets: Vec<u16>,
-    #[cfg(feature = "checksum")]
-    padding: u16,
-    #[cfg(feature = "checksum")]
-    hasher: crc32fast::Hasher,
+    /// Offsets of the entries that store their whole key.
+    restarts: Vec<u16>,
+    restart_interval: usize,
+    /// The key of the latest entry, which the next one shares its prefix with.
+    last_key: Vec<u8>,
+    entry_checksums: bool,
 }
 
 impl BlockBuilder {
@@ -26,46 +33,110 @@ impl BlockBuilder {
             cap: block_size,
             data: vec![],
             offsets: vec![],
-            #[cfg(feature = "checksum")]
-            padding: 0,
-            #[cfg(feature = "checksum")]
-            hasher: crc32fast::Hasher::new(),
+            restarts: vec![],
+            restart_interval: DEFAULT_RESTART_INTERVAL,
+            last_key: vec![],
+            entry_checksums: false,
         }
     }
 
+    /// Store a whole key every `interval` entries, the others as what follows the prefix they
+    /// share with the key before them. A shorter interval makes seeks cheaper and the block
+    /// larger. Call it before adding any entry.
+    pub fn with_restart_interval(mut self, interval: usize) -> Self {
+        assert!(interval > 0, "the restart interval must be at least 1");
+        self.restart_interval = interval;
+        self
+    }
+
+    /// End every value with the [`entry_checksum`] of its entry. Call it before adding any entry.
+    pub fn with_entry_checksums(mut self) -> Self {
+        self.entry_checksums = true;
+        self
+    }
+
     // fn extend(&mut self, bytes: &[u8]) {
     //     #[cfg(feature = "checksum")]
     //     self.hasher.update(bytes);
     // }
 
+    /// Bytes the entries take once encoded, padding left out.
+    fn used(&self) -> usize {
+        self.data.len()
+            + self.restarts.len() * OFFSET_SIZE
+            + COUNT_SIZE
+            + self.offsets.len() * OFFSET_SIZE
+            + COUNT_SIZE
+            + CHECKSUM_SIZE
+    }
+
+    /// Negative once a single entry overflows the block.
     fn remaining(&self) -> isize {
-        let meta_len = COUNT_SIZE + CHECKSUM_SIZE;
-        let used = self.data.len() + self.offsets.len() * 2 + meta_len;
+        self.cap as isize - self.used() as isize
+    }
+
+    /// The bytes of key `key` shares with the latest key, whether it stores the whole key, and
+    /// the bytes its entry, its offset and its restart point take, for a value of `value_len`.
+    fn entry_len(&self, key: &[u8], value_len: usize) -> (usize, bool, usize) {
+        let value_len = if self.entry_checksums {
+            value_len + ENTRY_CHECKSUM_SIZE
+        } else {
+            value_len
+        };
+        let restart = self.offsets.len() % self.restart_interval == 0;
+        let shared = match restart {
+            true => 0,
+            false => key
+                .iter()
+                .zip(&self.last_key)
+                .take_while(|(a, b)| a == b)
+                .count(),
+        };
+        // an offset in the offset section, and one in the restart section at a restart point
+        let offsets = (1 + restart as usize) * OFFSET_SIZE;
+        let len = ENTRY_HEADER_SIZE + key.len() - shared + value_len + offsets;
+        (shared, restart, len)
+    }
 
-        self.cap as isize - used as isize
+    /// Whether `add` would take the entry rather than report the block full.
+    pub fn fits(&self, key: &[u8], value: &[u8]) -> bool {
+        // a block takes at least one entry, however large
+        self.is_empty() || self.entry_len(key, value.len()).2 as isize <= self.remaining()
     }
 
     /// Adds a key-value pair to the block. Returns false when the block is full.
     #[must_use]
     pub fn add(&mut self, key: &[u8], value: &[u8]) -> bool {
-        let len = 2 + key.len() + 2 + value.len();
-        let meta_len = COUNT_SIZE + CHECKSUM_SIZE;
-
-        debug_assert!(self.remaining() >= 0);
-
-        // TODO: better tests
-        // assert!(2 + key.len() + 2 + value.len() + 2 + COUNT_SIZE + CHECKSUM_SIZE <= self.cap);
-
-        if self.data.len() + len + meta_len > self.cap {
+        let (shared, restart, len) = self.entry_len(key, value.len());
+        if !self.is_empty() && len as isize > self.remaining() {
             // encoded size
             return false;
         }
-
+        let unshared = &key[shared..];
+        let value_len = if self.entry_checksums {
+            value.len() + ENTRY_CHECKSUM_SIZE
+        } else {
+            value.len()
+        };
+
+        if restart {
+            self.restarts.push(self.data.len() as u16);
+        }
         self.offsets.push(self.data.len() as u16);
-        self.data.put_u16_le(key.len() as u16);
-        self.data.put_slice(key);
-        self.data.put_u16_le(value.len() as u16);
+        let mut header = [0; ENTRY_HEADER_SIZE];
+        BLOCK_ENTRY.shared_key_len.put(&mut header, shared as u64);
+        BLOCK_ENTRY
+            .rest_of_key_len
+            .put(&mut header, unshared.len() as u64);
+        BLOCK_ENTRY.value_len.put(&mut header, value_len as u64);
+        self.data.put_slice(&header);
+        self.data.put_slice(unshared);
         self.data.put_slice(value);
+        if self.entry_checksums {
+            self.data.put_u32_le(entry_checksum(key, value));
+        }
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
 
         true
     }
@@ -77,28 +148,43 @@ impl BlockBuilder {
 
     /// Finalize the block.
     pub fn build(self) -> Block {
-        let padding = self.remaining() as _;
-
-        #[cfg(feature = "checksum")]
-        {
-            self.block
-                .offsets
-                .iter()
-                .for_each(|off| self.hasher.update(&off.to_le_bytes()));
-
-            self.hasher
-                .update(&(self.block.offsets.len() as u16).to_le_bytes());
-            self.block.sum = self.hasher.finalize();
-        }
-
+        // a block overflowed by its single entry goes without
+        let padding = self.remaining().max(0) as u16;
         Block {
+            #[cfg(feature = "checksum")]
+            sum: block_checksum(&self.data, &self.restarts, &self.offsets),
             data: self.data,
+            restarts: self.restarts,
             offsets: self.offsets,
             padding,
+            entry_checksums: self.entry_checksums,
+        }
+    }
+
+    /// Finalize the block and compress its encoding with `ty`, behind the tag of the codec.
+    pub fn build_compressed(self, ty: CompressionType) -> Bytes {
+        Bytes::from(ty.compress(&self.build().encode()))
+    }
+
+    /// The key of the first entry, which stores it whole. Empty for an empty block.
+    pub fn first_key(&self) -> &[u8] {
+        match self.data.get(..ENTRY_HEADER_SIZE) {
+            Some(header) => {
+                let len = BLOCK_ENTRY.rest_of_key_len.get(header) as usize;
+                &self.data[ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + len]
+            }
+            None => &[],
         }
     }
 
-    pub fn size(&self) -> usize {
-        self.cap - self.remaining() as usize
+    /// The key of the latest entry. Empty for an empty block.
+    pub fn last_key(&self) -> &[u8] {
+        &self.last_key
+    }
+
+    /// Length of the block `build` returns, once encoded: the block size, unless a single entry
+    /// overflows it.
+    pub fn encoded_len(&self) -> usize {
+        self.used().max(self.cap)
     }
 }
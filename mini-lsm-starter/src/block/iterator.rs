@@ -1,8 +1,10 @@
 use std::sync::Arc;
 
+use anyhow::Result;
 use bytes::Bytes;
 
-use super::Block;
+use super::{strip_entry_checksum, Block};
+use crate::error::LsmError;
 
 /// Iterates on a block.
 pub struct BlockIterator {
@@ -14,6 +16,8 @@ pub struct BlockIterator {
     value: Bytes,
     /// Current index of the key-value pair, should be in range of [0, num_of_elements)
     idx: usize,
+    /// The entry checksum mismatch that left the iterator invalid, if any.
+    corruption: Option<LsmError>,
 }
 
 type Entry = (Bytes, Bytes);
@@ -25,6 +29,7 @@ impl BlockIterator {
             key: Bytes::new(),
             value: Bytes::new(),
             idx: 0,
+            corruption: None,
         }
     }
 
@@ -58,6 +63,25 @@ impl BlockIterator {
         !self.key.is_empty()
     }
 
+    /// Fail with [`LsmError::EntryChecksumMismatch`] if the iterator stopped on an entry whose
+    /// checksum does not match.
+    pub fn check(&self) -> Result<()> {
+        match &self.corruption {
+            Some(err) => Err(err.clone().into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the index of the current entry in the block.
+    pub fn entry_idx(&self) -> usize {
+        self.idx
+    }
+
+    /// Returns true if the iterator is on the last entry of the block.
+    pub fn is_last(&self) -> bool {
+        self.is_valid() && self.idx + 1 == self.block.offsets.len()
+    }
+
     /// Seeks to the first key in the block.
     pub fn seek_to_first(&mut self) {
         // TODO: self.block.offsets.first() > Some(0)?
@@ -69,6 +93,17 @@ impl BlockIterator {
         if self.block.offsets.len() == self.idx + 1 {
             self.key.clear();
             self.idx = 0;
+        } else if self.is_valid() {
+            // the next key shares its prefix with this one
+            self.idx += 1;
+            let block = self.block.clone();
+            let entry = block.offsets[self.idx] as usize;
+            let entry = block.entry_at(entry).and_then(|(shared, unshared, value)| {
+                let mut key = self.key.get(..shared)?.to_vec();
+                key.extend_from_slice(unshared);
+                Some((key, value))
+            });
+            self.load(entry);
         } else {
             self.seek_to(self.idx + 1);
         }
@@ -76,39 +111,86 @@ impl BlockIterator {
         (self.key.clone(), self.value.clone())
     }
 
+    /// Moves to the entry at `idx`, rebuilding its key from the restart point before it.
     fn seek_to(&mut self, idx: usize) {
         self.idx = idx;
-        let pos = self.block.offsets[self.idx] as usize;
-        self.key = Bytes::copy_from_slice(self.block.slice_at(pos));
-        self.value = Bytes::copy_from_slice(self.block.slice_at(pos + 2 + self.key.len()));
+        let block = self.block.clone();
+        self.load(block.entry(idx));
+    }
+
+    /// Makes `entry` the current one. The iterator becomes invalid if there is no such entry, it
+    /// does not fit in the block or its checksum does not match, which only happens to a
+    /// corrupted block.
+    fn load(&mut self, entry: Option<(Vec<u8>, &[u8])>) {
+        let entry = entry.map(|(key, value)| {
+            if self.block.has_entry_checksums() {
+                strip_entry_checksum(&key, value).map(|value| (key, value))
+            } else {
+                Ok((key, value))
+            }
+        });
+        match entry {
+            Some(Ok((key, value))) => {
+                self.key = Bytes::from(key);
+                self.value = Bytes::copy_from_slice(value);
+            }
+            Some(Err(err)) => {
+                self.corruption = Some(err);
+                self.key.clear();
+                self.value.clear();
+            }
+            None => {
+                self.key.clear();
+                self.value.clear();
+            }
+        }
     }
 
     /// Seek to the first key that >= `key`.
     /// Note: You should assume the key-value pairs in the block are sorted when being added by callers.
     /// similar to std::lower_bound
     pub fn seek_to_key(&mut self, key: &[u8]) {
-        let mut lo: isize = 0;
-        let mut hi = self.block.offsets.len() as isize - 1;
+        // the first restart point at or past `key`
+        let mut lo = 0;
+        let mut hi = self.block.num_restarts();
         while lo < hi {
             let mid = lo + (hi - lo) / 2;
-
-            let curr = self.block.slice_at(self.block.offsets[mid as usize] as _);
-            match curr.cmp(key) {
-                std::cmp::Ordering::Less => {
-                    lo = mid + 1;
-                }
-                std::cmp::Ordering::Equal => return self.seek_to(mid as _),
-                std::cmp::Ordering::Greater => {
-                    self.idx = mid as _;
-                    hi = mid;
-                }
+            match self.block.restart_key(mid) {
+                Some(curr) if curr < key => lo = mid + 1,
+                Some(_) => hi = mid,
+                // a corrupted entry, give up rather than guess
+                None => return self.seek_to(self.block.offsets.len()),
             }
         }
 
-        if self.block.slice_at(self.block.offsets[lo as usize] as _) >= key {
-            self.seek_to(lo as _)
-        } else {
-            self.key.clear()
+        // the key lies after the restart point before it, if any
+        let first = match lo.checked_sub(1) {
+            Some(restart) => match self.block.restart_entry(restart) {
+                Some(idx) => idx,
+                None => return self.seek_to(self.block.offsets.len()),
+            },
+            None => 0,
+        };
+        // walk the keys from there, checking only the entry the seek stops at
+        let block = self.block.clone();
+        let mut curr = vec![];
+        for idx in first.. {
+            let entry = block
+                .offsets
+                .get(idx)
+                .and_then(|&pos| block.entry_at(pos as usize));
+            match entry {
+                Some((shared, unshared, value)) if shared <= curr.len() => {
+                    curr.truncate(shared);
+                    curr.extend_from_slice(unshared);
+                    if &curr[..] >= key {
+                        self.idx = idx;
+                        return self.load(Some((curr, value)));
+                    }
+                }
+                // past the last entry when every key is smaller, or on a corrupted one
+                _ => return self.seek_to(block.offsets.len()),
+            }
         }
     }
 }
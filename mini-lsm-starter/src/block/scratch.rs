@@ -0,0 +1,52 @@
+use bytes::BytesMut;
+
+use super::Block;
+
+/// A buffer that blocks are encoded into one at a time, so that writing an SST does not
+/// allocate once per block.
+///
+/// The buffer keeps the capacity of the largest block it has seen. Call `shrink` once a file
+/// is done, so that one oversized block does not pin its capacity forever.
+#[derive(Default)]
+pub struct EncodeScratch {
+    buf: BytesMut,
+    /// The largest block encoded since the last `shrink`.
+    high_water: usize,
+    /// How many times the buffer had to grow.
+    allocations: usize,
+}
+
+impl EncodeScratch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encode `block` into the buffer, replacing whatever was there.
+    pub fn encode(&mut self, block: &Block) -> &[u8] {
+        let len = block.encoded_len();
+        self.buf.clear();
+        if self.buf.capacity() < len {
+            self.allocations += 1;
+        }
+        block.encode_into(&mut self.buf);
+        self.high_water = self.high_water.max(len);
+        &self.buf
+    }
+
+    /// Give back memory beyond twice the largest block encoded since the last call.
+    pub fn shrink(&mut self) {
+        if self.high_water > 0 && self.buf.capacity() > 2 * self.high_water {
+            self.buf = BytesMut::with_capacity(self.high_water);
+            self.allocations += 1;
+        }
+        self.high_water = 0;
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+}
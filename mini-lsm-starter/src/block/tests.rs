@@ -52,15 +52,91 @@ fn test_block_encode() {
     block.encode();
 }
 
+#[test]
+fn test_block_encode_into() {
+    let block = generate_block();
+    let mut buf = bytes::BytesMut::new();
+    block.encode_into(&mut buf);
+    assert_eq!(&buf[..], &block.encode()[..]);
+
+    // appends rather than overwrites
+    block.encode_into(&mut buf);
+    assert_eq!(buf.len(), 2 * block.encoded_len());
+    assert_eq!(&buf[block.encoded_len()..], &block.encode()[..]);
+}
+
+#[test]
+fn test_block_encoded_len() {
+    let check = |builder: BlockBuilder| {
+        let expected = builder.encoded_len();
+        let block = builder.build();
+        assert_eq!(block.encoded_len(), expected);
+        assert_eq!(block.encode().len(), expected);
+        assert_eq!(
+            Block::decode(&block.encode()).unwrap().encoded_len(),
+            expected
+        );
+        expected
+    };
+
+    // padded to the block size, whatever it holds
+    let mut builder = BlockBuilder::new(128);
+    assert!(builder.add(b"k", b"v"));
+    assert_eq!(check(builder), 128);
+    let mut builder = BlockBuilder::new(128).with_entry_checksums();
+    assert!(builder.add(b"k", b"v"));
+    assert_eq!(check(builder), 128);
+    let mut builder = BlockBuilder::new(64);
+    let mut added = 0;
+    while builder.add(format!("key_{:03}", added).as_bytes(), b"value") {
+        added += 1;
+    }
+    assert!(added > 1);
+    assert_eq!(check(builder), 64);
+
+    // a single entry larger than the block is not padded
+    let mut builder = BlockBuilder::new(64);
+    assert!(builder.add(b"key", &[7; 100]));
+    assert!(!builder.add(b"key2", b"value"));
+    // the entry, its restart point and its offset
+    let len = ENTRY_HEADER_SIZE + 3 + 100 + 2 + COUNT_SIZE + 2 + COUNT_SIZE + CHECKSUM_SIZE;
+    assert_eq!(check(builder), len);
+}
+
 #[test]
 fn test_block_decode() {
     let block = generate_block();
     let encoded = block.encode();
-    let decoded_block = Block::decode(&encoded);
+    let decoded_block = Block::decode(&encoded).unwrap();
     assert_eq!(block.offsets, decoded_block.offsets);
+    assert_eq!(block.restarts, decoded_block.restarts);
     assert_eq!(block.data, decoded_block.data);
 }
 
+#[test]
+fn test_block_decode_corrupt() {
+    let block = generate_block();
+    let encoded = block.encode().to_vec();
+    // an entry count past the end of the block
+    let mut corrupt = encoded.clone();
+    corrupt[encoded.len() - CHECKSUM_SIZE - 1] ^= 0x80;
+    assert!(Block::decode(&corrupt).is_err());
+    #[cfg(feature = "checksum")]
+    {
+        // the padding is all the checksum leaves out
+        let padding = block.data.len()..block.data.len() + block.padding as usize;
+        for pos in (0..encoded.len()).filter(|pos| !padding.contains(pos)) {
+            let mut corrupt = encoded.clone();
+            corrupt[pos] ^= 1;
+            assert!(Block::decode(&corrupt).is_err(), "flipped byte {}", pos);
+        }
+    }
+    // never a panic, whatever is left of the block
+    for len in 0..encoded.len() {
+        let _ = Block::decode(&encoded[..len]);
+    }
+}
+
 fn as_bytes(x: &[u8]) -> Bytes {
     Bytes::copy_from_slice(x)
 }
@@ -121,3 +197,146 @@ fn test_block_seek_key() {
         iter.seek_to_key(b"k");
     }
 }
+
+/// A block straight from `data` and `offsets`, every entry a restart point, the way a
+/// corrupted file could decode.
+fn raw_block(data: &[u8], offsets: &[u16]) -> Arc<Block> {
+    Arc::new(Block {
+        data: data.to_vec(),
+        padding: 0,
+        restarts: offsets.to_vec(),
+        offsets: offsets.to_vec(),
+        entry_checksums: false,
+        #[cfg(feature = "checksum")]
+        sum: 0,
+    })
+}
+
+#[test]
+fn test_block_iterator_on_malformed_blocks() {
+    // one well-formed entry: key "a", value "b"
+    let entry = [0, 0, 1, 0, 1, 0, b'a', b'b'];
+
+    // offset past the end
+    let block = raw_block(&entry, &[0, 200]);
+    let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+    assert_eq!(&iter.key()[..], b"a");
+    iter.next();
+    assert!(!iter.is_valid());
+    assert!(iter.value().is_empty());
+    assert!(!BlockIterator::create_and_seek_to_key(block.clone(), b"b").is_valid());
+    assert_eq!(block.last(), None);
+
+    // key length past the end
+    let block = raw_block(&[0, 0, 0xff, 0, 0, 0, b'a'], &[0]);
+    assert!(!BlockIterator::create_and_seek_to_first(block.clone()).is_valid());
+    assert!(!BlockIterator::create_and_seek_to_key(block, b"a").is_valid());
+
+    // value length past the end
+    let block = raw_block(&[0, 0, 1, 0, 0xff, 0xff, b'a', b'b'], &[0]);
+    assert!(!BlockIterator::create_and_seek_to_first(block.clone()).is_valid());
+    assert_eq!(block.last(), None);
+
+    // length prefix cut short
+    let block = raw_block(&[1], &[0]);
+    assert!(!BlockIterator::create_and_seek_to_first(block).is_valid());
+
+    // overlapping entries decode to whatever the bytes say, within the block
+    let block = raw_block(&entry, &[0, 3, 1]);
+    let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+    for _ in 0..3 {
+        iter.next();
+    }
+    let _ = BlockIterator::create_and_seek_to_key(block, b"a");
+
+    // no entries at all
+    let block = raw_block(&[], &[]);
+    let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+    assert!(!iter.is_valid());
+    iter.next();
+    assert!(!iter.is_valid());
+    assert!(!BlockIterator::create_and_seek_to_key(block.clone(), b"a").is_valid());
+    assert_eq!(block.last(), None);
+}
+
+#[test]
+fn test_block_prefix_delta() {
+    // keys sharing a 20-byte prefix
+    let key_of = |idx: usize| format!("user/0000012345/item/{:05}", idx).into_bytes();
+    let fill = |builder: &mut BlockBuilder| {
+        let mut added = 0;
+        while builder.add(&key_of(added), b"value") {
+            added += 1;
+        }
+        added
+    };
+    let delta = fill(&mut BlockBuilder::new(4096));
+    let whole = fill(&mut BlockBuilder::new(4096).with_restart_interval(1));
+    // every key of the full-key block takes 20 bytes more, but for the restart points
+    assert!(delta * 2 > whole * 3, "{} vs {} entries", delta, whole);
+
+    for interval in [1, 2, 16, 1000] {
+        let mut builder = BlockBuilder::new(8192).with_restart_interval(interval);
+        for idx in 0..100 {
+            assert!(builder.add(&key_of(idx * 2), &value_of(idx)));
+        }
+        let block = Arc::new(Block::decode(&builder.build().encode()).unwrap());
+        assert_eq!(block.num_restarts(), (100 + interval - 1) / interval);
+        assert_eq!(block.last(), Some(key_of(198)));
+
+        let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+        for idx in 0..100 {
+            assert_eq!(&iter.key()[..], &key_of(idx * 2)[..]);
+            assert_eq!(&iter.value()[..], &value_of(idx)[..]);
+            iter.next();
+        }
+        assert!(!iter.is_valid());
+
+        // keys on and between the entries, from the back
+        for idx in (0..100).rev() {
+            let iter = BlockIterator::create_and_seek_to_key(block.clone(), &key_of(idx * 2));
+            assert_eq!(iter.entry_idx(), idx);
+            assert_eq!(&iter.key()[..], &key_of(idx * 2)[..]);
+            let iter = BlockIterator::create_and_seek_to_key(block.clone(), &key_of(idx * 2 + 1));
+            if idx == 99 {
+                assert!(!iter.is_valid());
+            } else {
+                assert_eq!(&iter.key()[..], &key_of(idx * 2 + 2)[..]);
+            }
+        }
+        let iter = BlockIterator::create_and_seek_to_key(block.clone(), b"a");
+        assert_eq!(&iter.key()[..], &key_of(0)[..]);
+    }
+}
+
+#[test]
+fn test_block_lz4_round_trip() {
+    let build = || {
+        let mut builder = BlockBuilder::new(4096);
+        for idx in 0..num_of_keys() {
+            assert!(builder.add(&key_of(idx), &value_of(idx)));
+        }
+        builder
+    };
+    let encoded = build().build().encode();
+    let compressed = build().build_compressed(CompressionType::Lz4);
+    assert_eq!(compressed[0], CompressionType::Lz4.tag());
+    assert!(compressed.len() < encoded.len());
+
+    let block = Arc::new(Block::decode_compressed(&compressed).unwrap());
+    assert_eq!(block.encode(), encoded);
+    let mut iter = BlockIterator::create_and_seek_to_first(block);
+    for idx in 0..num_of_keys() {
+        assert_eq!(&iter.key()[..], &key_of(idx)[..]);
+        assert_eq!(&iter.value()[..], &value_of(idx)[..]);
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+
+    // a block stored as is still carries its tag
+    let stored = build().build_compressed(CompressionType::None);
+    assert_eq!(&stored[1..], &encoded[..]);
+    assert_eq!(Block::decode_compressed(&stored).unwrap().encode(), encoded);
+    assert!(Block::decode_compressed(&[]).is_err());
+    assert!(Block::decode_compressed(&[9, 0, 0]).is_err());
+}
@@ -0,0 +1,95 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use parking_lot::{Condvar, Mutex};
+
+use crate::error::LsmError;
+
+#[derive(Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    lock: Mutex<()>,
+    notify: Condvar,
+}
+
+/// Asks a long-running scan or compaction to give up. The work checks the token as it goes and
+/// fails with [`LsmError::Cancelled`] once it is cancelled; clones share the same state.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    state: Arc<CancelState>,
+}
+
+impl std::fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel the work holding the token, waking up whoever waits for it.
+    pub fn cancel(&self) {
+        let _guard = self.state.lock.lock();
+        self.state.cancelled.store(true, Ordering::Release);
+        self.state.notify.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Fail with [`LsmError::Cancelled`] once the token is cancelled.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(LsmError::Cancelled.into());
+        }
+        Ok(())
+    }
+
+    /// Wait until the token is cancelled. Returns whether it was before `timeout`.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut guard = self.state.lock.lock();
+        while !self.is_cancelled() {
+            if self
+                .state
+                .notify
+                .wait_until(&mut guard, deadline)
+                .timed_out()
+            {
+                return self.is_cancelled();
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_wakes_up_waiters() {
+        let token = CancellationToken::new();
+        assert!(token.check().is_ok());
+        assert!(!token.wait_timeout(Duration::from_millis(10)));
+
+        let waiter = {
+            let token = token.clone();
+            std::thread::spawn(move || token.wait_timeout(Duration::from_secs(10)))
+        };
+        token.cancel();
+        assert!(waiter.join().unwrap());
+        assert_eq!(
+            token.check().unwrap_err().downcast_ref::<LsmError>(),
+            Some(&LsmError::Cancelled)
+        );
+    }
+}
@@ -0,0 +1,962 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+use crate::error::LsmError;
+use crate::iterators::concat_iterator::SstConcatIterator;
+use crate::iterators::merge_iterator::MergeIterator;
+use crate::sequence::WriteToken;
+use crate::storage::Level;
+use crate::table::SsTable;
+
+/// Levels of the tree, L0 to L6. The levels below L0 exist from the start, and the last one is
+/// never compacted further down.
+pub const NUM_LEVELS: usize = 7;
+
+/// An SST a compaction would read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactionInput {
+    pub sst_id: usize,
+    /// 0 for L0, `n` for `levels[n - 1]`.
+    pub level: usize,
+    pub bytes: u64,
+}
+
+/// What a compaction did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompactionSummary {
+    /// The first level of the inputs.
+    pub level: usize,
+    /// The level the output went to, `level + 1` unless a [`CompactionPicker`] asked otherwise.
+    pub output_level: usize,
+    /// Size of the input tables.
+    pub bytes_read: u64,
+    /// Size of the output tables.
+    pub bytes_written: u64,
+    /// Size of the input tables of `level`, the others being counted towards `output_level`.
+    pub level_bytes_read: u64,
+    /// Size of the output tables kept at `level` for being read often, the others going to
+    /// `output_level`.
+    pub level_bytes_written: u64,
+}
+
+/// What a compaction would do, worked out without running it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionPlan {
+    /// The level being compacted into `level + 1`.
+    pub level: usize,
+    /// The inputs, newest first.
+    pub inputs: Vec<CompactionInput>,
+    pub input_bytes: u64,
+    /// The input bytes minus the ones assumed to be shadowed by newer inputs.
+    pub estimated_output_bytes: u64,
+    /// Bytes written per byte moved out of `level`.
+    pub estimated_write_amplification: f64,
+}
+
+impl CompactionPlan {
+    /// Whether there is nothing to compact.
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+}
+
+/// A compaction for the storage to run: merge the input tables into a table of `output_level`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompactionTask {
+    /// The ids of the input tables, by level.
+    pub inputs: BTreeMap<usize, Vec<usize>>,
+    pub output_level: usize,
+}
+
+impl CompactionTask {
+    /// The task running `plan`, into the level below the one it compacts.
+    pub fn from_plan(plan: &CompactionPlan) -> Self {
+        let mut inputs = BTreeMap::<usize, Vec<usize>>::new();
+        for input in &plan.inputs {
+            inputs.entry(input.level).or_default().push(input.sst_id);
+        }
+        Self {
+            inputs,
+            output_level: plan.level + 1,
+        }
+    }
+
+    /// The task merging `upper`, tables of `level`, and `lower`, tables of the next level, into
+    /// the next level.
+    pub(crate) fn next_level(level: usize, upper: &[Arc<SsTable>], lower: &[Arc<SsTable>]) -> Self {
+        let ids = |tables: &[Arc<SsTable>]| tables.iter().map(|sst| sst.sst_id()).collect();
+        Self {
+            inputs: BTreeMap::from([(level, ids(upper)), (level + 1, ids(lower))]),
+            output_level: level + 1,
+        }
+    }
+
+    /// Whether the task has no input.
+    pub fn is_empty(&self) -> bool {
+        self.inputs.values().all(Vec::is_empty)
+    }
+
+    /// The ids of every input table.
+    pub fn input_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.inputs.values().flatten().copied()
+    }
+}
+
+/// The tables of a storage, as a [`CompactionPicker`] sees them.
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct LsmStateDescription {
+    /// The L0 tables, oldest first.
+    pub l0_sstables: Vec<Arc<SsTable>>,
+    /// The tables of the levels below L0, each sorted by key: `levels[n - 1]` holds level `n`.
+    pub levels: Vec<Vec<Arc<SsTable>>>,
+    /// Ids of the tables the running compactions read, which a task has to leave alone.
+    pub compacting: HashSet<usize>,
+    /// The current [`LsmStorageOptions::l0_compaction_trigger`].
+    ///
+    /// [`LsmStorageOptions::l0_compaction_trigger`]: crate::lsm_storage::LsmStorageOptions::l0_compaction_trigger
+    pub l0_compaction_trigger: usize,
+    /// How much more urgent compactions are for the number of live files, see
+    /// [`file_count_boost`].
+    pub file_count_boost: f64,
+}
+
+/// Picks the compactions the compaction worker runs, see [`CompactionConfig`].
+pub trait CompactionPicker: Send + Sync {
+    /// The next compaction to run, or `None` to leave the tables as they are. The storage checks
+    /// the task before running it, see
+    /// [`LsmStorage::compact_task`](crate::lsm_storage::LsmStorage::compact_task).
+    fn pick(&self, state: &LsmStateDescription) -> Result<Option<CompactionTask>>;
+}
+
+/// The built-in picker: the plan of [`pick_compaction`] for L0, and below L0 a single table of
+/// the level it picks, see [`LsmStorage::compact_level_to_next`], so that the rest of the level
+/// stays put. Unless a running compaction reads one of the inputs, in which case it waits for
+/// the next round.
+///
+/// [`LsmStorage::compact_level_to_next`]: crate::lsm_storage::LsmStorage::compact_level_to_next
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LeveledPicker;
+
+impl CompactionPicker for LeveledPicker {
+    fn pick(&self, state: &LsmStateDescription) -> Result<Option<CompactionTask>> {
+        let plan = pick_compaction(
+            &state.l0_sstables,
+            &state.levels,
+            state.l0_compaction_trigger,
+            state.file_count_boost,
+        )?;
+        let task = match plan.level {
+            _ if plan.is_empty() => return Ok(None),
+            0 => CompactionTask::from_plan(&plan),
+            level => {
+                let (upper, lower) = select_file_inputs(&state.l0_sstables, &state.levels, level)?;
+                CompactionTask::next_level(level, &upper, &lower)
+            }
+        };
+        let busy = task.input_ids().any(|id| state.compacting.contains(&id));
+        Ok((!task.is_empty() && !busy).then_some(task))
+    }
+}
+
+/// How the compaction worker picks its compactions.
+#[derive(Clone, Default)]
+pub enum CompactionConfig {
+    /// The [`LeveledPicker`].
+    #[default]
+    Leveled,
+    Custom(Arc<dyn CompactionPicker>),
+}
+
+impl CompactionConfig {
+    pub fn picker(&self) -> &dyn CompactionPicker {
+        match self {
+            CompactionConfig::Leveled => &LeveledPicker,
+            CompactionConfig::Custom(picker) => picker.as_ref(),
+        }
+    }
+}
+
+impl fmt::Debug for CompactionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompactionConfig::Leveled => write!(f, "Leveled"),
+            CompactionConfig::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+/// Plan the compaction of `level` into the next level, or of the first level holding at least
+/// `min_files` tables when `level` is `None`, the last of the [`NUM_LEVELS`] aside.
+///
+/// All tables of the level are picked, along with the tables of the next level overlapping
+/// them. Blocks of an older input that start within the key range of a newer input are assumed
+/// to be overwritten, which overestimates the savings when the newer input is sparse.
+pub fn plan_compaction(
+    l0_sstables: &[Arc<SsTable>],
+    levels: &[Vec<Arc<SsTable>>],
+    level: Option<usize>,
+    min_files: usize,
+) -> Result<CompactionPlan> {
+    let tables_of = |level: usize| tables_of(l0_sstables, levels, level);
+    let level = match level {
+        Some(level) if tables_of(level).is_none() => {
+            bail!("level {} does not exist", level)
+        }
+        Some(level) => level,
+        None => {
+            match (0..NUM_LEVELS - 1).find(|&n| tables_of(n).map_or(0, <[_]>::len) >= min_files) {
+                Some(level) => level,
+                None => {
+                    return Ok(CompactionPlan {
+                        level: 0,
+                        inputs: vec![],
+                        input_bytes: 0,
+                        estimated_output_bytes: 0,
+                        estimated_write_amplification: 0.0,
+                    })
+                }
+            }
+        }
+    };
+
+    let (upper, lower) = select_inputs(l0_sstables, levels, level)?;
+
+    let mut inputs = vec![];
+    let mut newer_ranges: Vec<(Bytes, Bytes)> = vec![];
+    let mut shadowed_bytes = 0;
+    for (sst, sst_level) in upper
+        .iter()
+        .map(|sst| (sst, level))
+        .chain(lower.iter().map(|sst| (sst, level + 1)))
+    {
+        shadowed_bytes += sst.data_bytes_within(&newer_ranges)?;
+        newer_ranges.extend(sst.key_range()?);
+        inputs.push(CompactionInput {
+            sst_id: sst.sst_id(),
+            level: sst_level,
+            bytes: sst.file_size(),
+        });
+    }
+
+    let input_bytes = inputs.iter().map(|input| input.bytes).sum::<u64>();
+    let upper_bytes = upper.iter().map(|sst| sst.file_size()).sum::<u64>();
+    let estimated_output_bytes = input_bytes.saturating_sub(shadowed_bytes);
+    Ok(CompactionPlan {
+        level,
+        inputs,
+        input_bytes,
+        estimated_output_bytes,
+        estimated_write_amplification: if upper_bytes == 0 {
+            0.0
+        } else {
+            estimated_output_bytes as f64 / upper_bytes as f64
+        },
+    })
+}
+
+fn tables_of<'a>(
+    l0_sstables: &'a [Arc<SsTable>],
+    levels: &'a [Vec<Arc<SsTable>>],
+    level: usize,
+) -> Option<&'a [Arc<SsTable>]> {
+    match level {
+        0 => Some(l0_sstables),
+        n => levels.get(n - 1).map(|tables| tables.as_slice()),
+    }
+}
+
+/// The tables compacting `level` reads: all of the level, newest first, and the tables of the
+/// next level overlapping the key range they span, by key, since the output spans all of it.
+pub(crate) fn select_inputs(
+    l0_sstables: &[Arc<SsTable>],
+    levels: &[Vec<Arc<SsTable>>],
+    level: usize,
+) -> Result<(Level, Level)> {
+    let upper = match tables_of(l0_sstables, levels, level) {
+        Some(tables) => tables,
+        None => bail!("level {} does not exist", level),
+    };
+    // newest first: L0 is ordered from the oldest table, the other levels by key
+    let mut upper = upper.to_vec();
+    if level == 0 {
+        upper.reverse();
+    }
+    let lower = match span(&upper)? {
+        Some((lo, hi)) => tables_of(l0_sstables, levels, level + 1)
+            .unwrap_or_default()
+            .iter()
+            .filter(|sst| sst.overlaps(Bound::Included(&lo[..]), Bound::Included(&hi[..])))
+            .cloned()
+            .collect(),
+        None => vec![],
+    };
+    Ok((upper, lower))
+}
+
+/// The tables compacting a single table of `level` into the next level reads: the table
+/// overlapping the most tables of the next level, the first one on a tie, and every table of
+/// either level within the key range the inputs span, until none is left out, see
+/// [`resolve_task`]. Ordered like [`select_inputs`]; both are empty if no table of `level` has
+/// a key.
+pub(crate) fn select_file_inputs(
+    l0_sstables: &[Arc<SsTable>],
+    levels: &[Vec<Arc<SsTable>>],
+    level: usize,
+) -> Result<(Level, Level)> {
+    let upper = match tables_of(l0_sstables, levels, level) {
+        Some(tables) => tables,
+        None => bail!("level {} does not exist", level),
+    };
+    let lower = tables_of(l0_sstables, levels, level + 1).unwrap_or_default();
+    let within = |tables: &[Arc<SsTable>], lo: &[u8], hi: &[u8]| {
+        tables
+            .iter()
+            .filter(|sst| sst.overlaps(Bound::Included(lo), Bound::Included(hi)))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+
+    let mut seed: Option<(usize, (Bytes, Bytes))> = None;
+    for sst in upper {
+        if let Some((first, last)) = sst.key_range()? {
+            let overlaps = within(lower, &first, &last).len();
+            let more = match &seed {
+                Some((most, _)) => overlaps > *most,
+                None => true,
+            };
+            if more {
+                seed = Some((overlaps, (first, last)));
+            }
+        }
+    }
+    let (mut lo, mut hi) = match seed {
+        Some((_, range)) => range,
+        None => return Ok((vec![], vec![])),
+    };
+    loop {
+        let mut picked_upper = within(upper, &lo, &hi);
+        let picked_lower = within(lower, &lo, &hi);
+        // the picked tables have keys within the range, so their span is never empty
+        let widened = span(picked_upper.iter().chain(&picked_lower))?.unwrap();
+        if widened == (lo.clone(), hi.clone()) {
+            if level == 0 {
+                picked_upper.reverse();
+            }
+            return Ok((picked_upper, picked_lower));
+        }
+        (lo, hi) = widened;
+    }
+}
+
+/// The key range `tables` span together, unless none of them has a key.
+fn span<'a>(tables: impl IntoIterator<Item = &'a Arc<SsTable>>) -> Result<Option<(Bytes, Bytes)>> {
+    let mut span: Option<(Bytes, Bytes)> = None;
+    for sst in tables {
+        if let Some((first, last)) = sst.key_range()? {
+            span = Some(match span {
+                Some((lo, hi)) => (lo.min(first), hi.max(last)),
+                None => (first, last),
+            });
+        }
+    }
+    Ok(span)
+}
+
+/// The input tables of `task` by level, in the order the merge takes them: L0 newest first, and
+/// the other levels by key.
+///
+/// The task is checked against the tables of the storage first: every input is in the level the
+/// task says it is, and none is being compacted already. The output goes to a level below L0 and
+/// not above any input. Since the output spans the key range of all the inputs, every table
+/// within that range down to the output level has to be an input too, or it would end up
+/// holding older data shadowing newer data, or overlapping the output in its level; at L0, that
+/// goes for the tables older than the newest input only.
+pub(crate) fn resolve_task(
+    task: &CompactionTask,
+    l0_sstables: &[Arc<SsTable>],
+    levels: &[Vec<Arc<SsTable>>],
+    compacting: &HashSet<usize>,
+) -> Result<Vec<(usize, Level)>> {
+    let invalid = |msg: String| anyhow::Error::from(LsmError::InvalidArgument(msg));
+    let input_levels = task
+        .inputs
+        .iter()
+        .filter(|(_, ids)| !ids.is_empty())
+        .map(|(&level, _)| level)
+        .collect::<Vec<_>>();
+    let (top, bottom) = match (input_levels.first(), input_levels.last()) {
+        (Some(&top), Some(&bottom)) => (top, bottom),
+        _ => return Err(invalid("the compaction task has no input".to_string())),
+    };
+    let deepest = (levels.len() + 1).min(NUM_LEVELS - 1);
+    if task.output_level == 0 || task.output_level < bottom || task.output_level > deepest {
+        return Err(invalid(format!(
+            "output level {} is not within {}..={}",
+            task.output_level,
+            bottom.max(1),
+            deepest
+        )));
+    }
+
+    let mut seen = HashSet::new();
+    let mut resolved = vec![];
+    for level in input_levels {
+        let tables = tables_of(l0_sstables, levels, level)
+            .ok_or_else(|| invalid(format!("level {} does not exist", level)))?;
+        for &id in &task.inputs[&level] {
+            if !seen.insert(id) {
+                return Err(invalid(format!("table {} is an input twice", id)));
+            }
+            if compacting.contains(&id) {
+                bail!("table {} is being compacted already", id);
+            }
+            if !tables.iter().any(|sst| sst.sst_id() == id) {
+                return Err(invalid(format!("table {} is not in level {}", id, level)));
+            }
+        }
+        let mut picked = tables
+            .iter()
+            .filter(|sst| seen.contains(&sst.sst_id()))
+            .cloned()
+            .collect::<Vec<_>>();
+        if level == 0 {
+            picked.reverse();
+        }
+        resolved.push((level, picked));
+    }
+
+    let (lo, hi) = match span(resolved.iter().flat_map(|(_, tables)| tables))? {
+        Some(span) => span,
+        None => return Ok(resolved),
+    };
+    for level in top..=task.output_level {
+        let mut tables = tables_of(l0_sstables, levels, level).unwrap_or_default();
+        if level == 0 {
+            // the L0 tables newer than every input are read before the output anyway
+            let newest = tables.iter().rposition(|sst| seen.contains(&sst.sst_id()));
+            tables = &tables[..newest.map_or(0, |idx| idx + 1)];
+        }
+        let left_out = tables.iter().find(|sst| {
+            !seen.contains(&sst.sst_id())
+                && sst.overlaps(Bound::Included(&lo[..]), Bound::Included(&hi[..]))
+        });
+        if let Some(sst) = left_out {
+            return Err(invalid(format!(
+                "table {} of level {} overlaps the inputs but is not one of them",
+                sst.sst_id(),
+                level
+            )));
+        }
+    }
+    Ok(resolved)
+}
+
+/// The first L0 table newer than every L0 input of `inputs`, as [`resolve_task`] returns them,
+/// that overlaps the key range of the inputs. Such a table is read before the inputs, but not
+/// before a table that takes a fresh id back into L0.
+pub(crate) fn newer_l0_overlapping(
+    l0_sstables: &[Arc<SsTable>],
+    inputs: &[(usize, Level)],
+) -> Result<Option<usize>> {
+    let (lo, hi) = match span(inputs.iter().flat_map(|(_, tables)| tables))? {
+        Some(span) => span,
+        None => return Ok(None),
+    };
+    let ids = inputs
+        .iter()
+        .filter(|(level, _)| *level == 0)
+        .flat_map(|(_, tables)| tables.iter().map(|sst| sst.sst_id()))
+        .collect::<HashSet<_>>();
+    let newest = l0_sstables
+        .iter()
+        .rposition(|sst| ids.contains(&sst.sst_id()));
+    let newer = &l0_sstables[newest.map_or(0, |idx| idx + 1)..];
+    Ok(newer
+        .iter()
+        .find(|sst| sst.overlaps(Bound::Included(&lo[..]), Bound::Included(&hi[..])))
+        .map(|sst| sst.sst_id()))
+}
+
+/// Group L0 tables, newest first, into sorted runs: each run takes the tables that follow it
+/// as long as they overlap none of its tables, and is sorted by key. A run only holds tables
+/// of consecutive ages, so the runs stay ordered newest first for the merge to pick the newest
+/// version of a key.
+pub(crate) fn sorted_runs(newest_first: &[Arc<SsTable>]) -> Result<Vec<Level>> {
+    let mut runs: Vec<Vec<(Bytes, Bytes, Arc<SsTable>)>> = vec![];
+    for sst in newest_first {
+        // a table without blocks holds nothing to merge
+        let (first, last) = match sst.key_range()? {
+            Some(range) => range,
+            None => continue,
+        };
+        let fits = runs.last().map_or(false, |run| {
+            run.iter().all(|(lo, hi, _)| last < *lo || *hi < first)
+        });
+        if !fits {
+            runs.push(vec![]);
+        }
+        runs.last_mut().unwrap().push((first, last, sst.clone()));
+    }
+    Ok(runs
+        .into_iter()
+        .map(|mut run| {
+            run.sort_by(|a, b| a.0.cmp(&b.0));
+            run.into_iter().map(|(_, _, sst)| sst).collect()
+        })
+        .collect())
+}
+
+/// Merge sorted runs, newest first, with one concat iterator per run, so that the merge is as
+/// wide as the number of runs whatever the number of tables.
+pub(crate) fn merge_runs(runs: Vec<Level>) -> Result<MergeIterator<SstConcatIterator>> {
+    let iters = runs
+        .into_iter()
+        .map(|run| SstConcatIterator::create_and_seek_to_first(run).map(Box::new))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(MergeIterator::create(iters))
+}
+
+/// How much more urgent compactions get with `live_files` SSTs against a soft cap of
+/// `soft_max_files`: 1 up to the cap, growing in proportion to the overage past it.
+pub fn file_count_boost(live_files: usize, soft_max_files: Option<usize>) -> f64 {
+    match soft_max_files {
+        Some(max) if live_files > max => 1.0 + (live_files - max) as f64 / max.max(1) as f64,
+        _ => 1.0,
+    }
+}
+
+/// Pick the next compaction. A level scores its number of tables over `min_files`, times
+/// `boost`, and is eligible from a score of 1.
+///
+/// Unboosted, the first eligible level is picked. A boost, see [`file_count_boost`], makes
+/// levels with fewer tables eligible too and picks the plan reading the most tables, which
+/// removes the most files; a plan of a single table removes none and is never picked then.
+pub fn pick_compaction(
+    l0_sstables: &[Arc<SsTable>],
+    levels: &[Vec<Arc<SsTable>>],
+    min_files: usize,
+    boost: f64,
+) -> Result<CompactionPlan> {
+    if boost <= 1.0 {
+        return plan_compaction(l0_sstables, levels, None, min_files);
+    }
+    let mut picked: Option<CompactionPlan> = None;
+    for (level, tables) in std::iter::once(l0_sstables)
+        .chain(levels.iter().map(|tables| tables.as_slice()))
+        .take(NUM_LEVELS - 1)
+        .enumerate()
+    {
+        let score = tables.len() as f64 / min_files as f64 * boost;
+        if tables.is_empty() || score < 1.0 {
+            continue;
+        }
+        let plan = plan_compaction(l0_sstables, levels, Some(level), min_files)?;
+        let better = match &picked {
+            Some(best) => plan.inputs.len() > best.inputs.len(),
+            None => plan.inputs.len() > 1,
+        };
+        if better {
+            picked = Some(plan);
+        }
+    }
+    match picked {
+        Some(plan) => Ok(plan),
+        None => plan_compaction(l0_sstables, levels, None, min_files),
+    }
+}
+
+/// Whether a compaction of `inputs` into `output_level` may drop tombstones, which it only does
+/// when no table of a level below the output overlaps the key range of the inputs, where a
+/// dropped tombstone would bring back the value it deleted, and every write of every input
+/// table is older than the gc horizon.
+pub(crate) fn may_drop_tombstones<'a>(
+    inputs: impl IntoIterator<Item = &'a Arc<SsTable>> + Clone,
+    levels: &[Level],
+    output_level: usize,
+    gc_horizon: WriteToken,
+) -> Result<bool> {
+    if !inputs
+        .clone()
+        .into_iter()
+        .all(|sst| sst.max_seq() < gc_horizon.0)
+    {
+        return Ok(false);
+    }
+    let (lo, hi) = match span(inputs)? {
+        Some(span) => span,
+        None => return Ok(true),
+    };
+    // `levels` starts at L1, so the levels below the output start at its index
+    let mut below = levels.iter().skip(output_level).flatten();
+    Ok(!below.any(|sst| sst.overlaps(Bound::Included(&lo[..]), Bound::Included(&hi[..]))))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::iterators::StorageIterator;
+    use crate::table::SsTableBuilder;
+
+    fn build(dir: &std::path::Path, id: usize, keys: std::ops::Range<usize>) -> Arc<SsTable> {
+        let mut builder = SsTableBuilder::new(128);
+        for key in keys {
+            builder.add(
+                format!("key_{:03}", key).as_bytes(),
+                format!("value_{:010}", key).as_bytes(),
+            );
+        }
+        let path = dir.join(format!("{}.sst", id));
+        Arc::new(builder.export(id, None, path).unwrap())
+    }
+
+    #[test]
+    fn test_plan_two_level_overlap() {
+        let dir = tempdir().unwrap();
+        let l0 = vec![
+            build(dir.path(), 4, 100..200),
+            build(dir.path(), 5, 150..250),
+        ];
+        let l1 = vec![
+            build(dir.path(), 1, 0..50),
+            build(dir.path(), 2, 180..300),
+            build(dir.path(), 3, 400..500),
+        ];
+        let levels = vec![l1];
+
+        let plan = plan_compaction(&l0, &levels, Some(0), 2).unwrap();
+        assert_eq!(plan.level, 0);
+        let inputs = plan
+            .inputs
+            .iter()
+            .map(|input| (input.sst_id, input.level))
+            .collect::<Vec<_>>();
+        assert_eq!(inputs, vec![(5, 0), (4, 0), (2, 1)]);
+        assert_eq!(
+            plan.input_bytes,
+            [&l0[0], &l0[1], &levels[0][1]]
+                .iter()
+                .map(|sst| sst.file_size())
+                .sum::<u64>()
+        );
+        // the overwritten keys do not make it into the output, the other ones do
+        let largest = plan.inputs.iter().map(|input| input.bytes).max().unwrap();
+        assert!(plan.estimated_output_bytes < plan.input_bytes);
+        assert!(plan.estimated_output_bytes >= largest);
+        assert!(plan.estimated_write_amplification > 1.0);
+
+        // the picker goes for the first level holding enough tables
+        let picked = plan_compaction(&l0, &levels, None, 2).unwrap();
+        assert_eq!(picked, plan);
+        assert!(plan_compaction(&l0, &levels, None, 4).unwrap().is_empty());
+        assert!(plan_compaction(&l0, &levels, Some(2), 2).is_err());
+    }
+
+    #[test]
+    fn test_boost_picks_the_most_inputs() {
+        let dir = tempdir().unwrap();
+        let l0 = vec![build(dir.path(), 7, 0..10)];
+        let l1 = vec![
+            build(dir.path(), 1, 0..10),
+            build(dir.path(), 2, 20..30),
+            build(dir.path(), 3, 40..50),
+        ];
+        let l2 = vec![
+            build(dir.path(), 4, 0..30),
+            build(dir.path(), 5, 40..50),
+            build(dir.path(), 6, 60..70),
+        ];
+        let levels = vec![l1, l2];
+        let inputs = |plan: &CompactionPlan| {
+            plan.inputs
+                .iter()
+                .map(|input| input.sst_id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(file_count_boost(7, None), 1.0);
+        assert_eq!(file_count_boost(7, Some(8)), 1.0);
+        assert_eq!(file_count_boost(12, Some(8)), 1.5);
+        assert_eq!(file_count_boost(24, Some(8)), 3.0);
+
+        // unboosted, the first level holding enough tables
+        let plan = pick_compaction(&l0, &levels, 3, 1.0).unwrap();
+        assert_eq!(plan.level, 1);
+        assert_eq!(inputs(&plan), vec![1, 2, 3, 4, 5]);
+        assert_eq!(plan, plan_compaction(&l0, &levels, None, 3).unwrap());
+        assert!(pick_compaction(&l0, &levels, 4, 1.0).unwrap().is_empty());
+
+        // boosted, L0 becomes eligible, but L1 still merges the most files
+        let plan = pick_compaction(&l0, &levels, 3, 3.0).unwrap();
+        assert_eq!(plan.level, 1);
+        let plan = pick_compaction(&l0, &levels, 4, 1.5).unwrap();
+        assert_eq!(inputs(&plan), vec![1, 2, 3, 4, 5]);
+        let single = vec![vec![levels[0][0].clone()]];
+        assert!(pick_compaction(&l0, &single, 2, 1.0).unwrap().is_empty());
+        let plan = pick_compaction(&l0, &single, 2, 2.0).unwrap();
+        assert_eq!(plan.level, 0);
+        assert_eq!(inputs(&plan), vec![7, 1]);
+        // a table with nothing to merge with is left alone
+        assert!(pick_compaction(&l0, &[], 2, 10.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_merge_width_is_the_number_of_runs() -> Result<()> {
+        let dir = tempdir().unwrap();
+        // 64 tiny L0 tables clear of each other, the newest last in L0 order
+        let l0 = (0..64)
+            .map(|idx| build(dir.path(), 100 + idx, idx * 10..idx * 10 + 3))
+            .collect::<Vec<_>>();
+        let l1 = vec![build(dir.path(), 1, 0..300), build(dir.path(), 2, 300..640)];
+        let (upper, lower) = select_inputs(&l0, &[l1], 0)?;
+        assert_eq!(upper.len(), 64);
+        assert_eq!(lower.len(), 2);
+
+        let mut runs = sorted_runs(&upper)?;
+        assert_eq!(runs.len(), 1);
+        runs.push(lower);
+        let mut iter = merge_runs(runs)?;
+        let mut keys = vec![];
+        while iter.is_valid() {
+            assert!(iter.num_active_iterators() <= 2);
+            keys.push(iter.key().clone());
+            iter.next()?;
+        }
+        // the keys both levels hold come out once
+        let expected = (0..640)
+            .map(|key| Bytes::from(format!("key_{:03}", key)))
+            .collect::<Vec<_>>();
+        assert_eq!(keys, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sorted_runs_keep_ages_apart() -> Result<()> {
+        let dir = tempdir().unwrap();
+        let ids = |runs: &[Vec<Arc<SsTable>>]| {
+            runs.iter()
+                .map(|run| run.iter().map(|sst| sst.sst_id()).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        // newest first: 4 and 3 are clear of each other, 2 overlaps 3, and 1 is clear of 2
+        // but has to stay behind it, since it is older than 2
+        let newest_first = vec![
+            build(dir.path(), 4, 20..30),
+            build(dir.path(), 3, 0..10),
+            build(dir.path(), 2, 5..15),
+            build(dir.path(), 1, 40..50),
+        ];
+        assert_eq!(
+            ids(&sorted_runs(&newest_first)?),
+            vec![vec![3, 4], vec![2, 1]]
+        );
+        assert!(sorted_runs(&[])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_task_checks() {
+        let dir = tempdir().unwrap();
+        // oldest first
+        let l0 = vec![
+            build(dir.path(), 5, 0..50),
+            build(dir.path(), 6, 40..60),
+            build(dir.path(), 7, 100..110),
+        ];
+        let l1 = vec![build(dir.path(), 1, 0..30), build(dir.path(), 2, 60..90)];
+        let l2 = vec![build(dir.path(), 3, 0..200)];
+        let levels = vec![l1, l2];
+        let task = |inputs: &[(usize, &[usize])], output_level| CompactionTask {
+            inputs: inputs
+                .iter()
+                .map(|(level, ids)| (*level, ids.to_vec()))
+                .collect(),
+            output_level,
+        };
+        let resolve = |task: &CompactionTask, compacting: &[usize]| {
+            resolve_task(task, &l0, &levels, &compacting.iter().copied().collect())
+        };
+        let invalid = |task: &CompactionTask| {
+            let err = resolve(task, &[]).err().unwrap();
+            assert!(
+                matches!(err.downcast_ref(), Some(LsmError::InvalidArgument(_))),
+                "{}",
+                err
+            );
+        };
+
+        // L0 newest first, then by level
+        let ids = |resolved: Vec<(usize, Level)>| {
+            resolved
+                .into_iter()
+                .map(|(level, tables)| (level, tables.iter().map(|sst| sst.sst_id()).collect()))
+                .collect::<Vec<(usize, Vec<usize>)>>()
+        };
+        let resolved = resolve(&task(&[(0, &[5, 6]), (1, &[1])], 1), &[]).unwrap();
+        assert_eq!(ids(resolved), vec![(0, vec![6, 5]), (1, vec![1])]);
+        // the newer L0 tables may stay behind, the older ones may not
+        assert!(resolve(&task(&[(0, &[5]), (1, &[1])], 1), &[]).is_ok());
+        invalid(&task(&[(0, &[6]), (1, &[1])], 1));
+        // the output spans the keys of table 1 of L1, and of table 3 of L2 below it
+        invalid(&task(&[(0, &[5, 6])], 1));
+        invalid(&task(&[(0, &[5, 6]), (1, &[1])], 2));
+        // with table 3, the output spans the keys of table 2 of L1 too
+        invalid(&task(&[(0, &[5, 6]), (1, &[1]), (2, &[3])], 2));
+        assert!(resolve(&task(&[(0, &[5, 6]), (1, &[1, 2]), (2, &[3])], 2), &[]).is_ok());
+        invalid(&task(&[], 1));
+        invalid(&task(&[(0, &[5, 5]), (1, &[1])], 1));
+        invalid(&task(&[(1, &[5])], 2));
+        invalid(&task(&[(3, &[1])], 4));
+        invalid(&task(&[(0, &[5]), (1, &[1])], 0));
+        invalid(&task(&[(2, &[3])], 1));
+        invalid(&task(&[(2, &[3])], 4));
+        assert!(resolve(&task(&[(2, &[3])], 3), &[]).is_ok());
+        // a table a running compaction reads is no input
+        let err = resolve(&task(&[(0, &[5]), (1, &[1])], 1), &[1])
+            .err()
+            .unwrap();
+        assert!(err.downcast_ref::<LsmError>().is_none());
+    }
+
+    #[test]
+    fn test_leveled_picker_runs_the_plan() {
+        let dir = tempdir().unwrap();
+        let l0 = vec![
+            build(dir.path(), 4, 100..200),
+            build(dir.path(), 5, 150..250),
+        ];
+        let levels = vec![vec![
+            build(dir.path(), 1, 0..50),
+            build(dir.path(), 2, 180..300),
+        ]];
+        let mut state = LsmStateDescription {
+            l0_sstables: l0.clone(),
+            levels: levels.clone(),
+            compacting: HashSet::new(),
+            l0_compaction_trigger: 2,
+            file_count_boost: 1.0,
+        };
+        let plan = pick_compaction(&l0, &levels, 2, 1.0).unwrap();
+        let task = LeveledPicker.pick(&state).unwrap().unwrap();
+        assert_eq!(task, CompactionTask::from_plan(&plan));
+        assert_eq!(task.inputs, BTreeMap::from([(0, vec![5, 4]), (1, vec![2])]));
+        assert_eq!(task.output_level, 1);
+        assert!(resolve_task(&task, &l0, &levels, &state.compacting).is_ok());
+
+        state.compacting.insert(2);
+        assert!(LeveledPicker.pick(&state).unwrap().is_none());
+        state.compacting.clear();
+        state.l0_compaction_trigger = 3;
+        assert!(LeveledPicker.pick(&state).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_select_file_inputs() {
+        let dir = tempdir().unwrap();
+        let l1 = vec![
+            build(dir.path(), 1, 0..50),
+            build(dir.path(), 2, 100..150),
+            build(dir.path(), 3, 200..300),
+        ];
+        let l2 = vec![
+            build(dir.path(), 4, 0..20),
+            build(dir.path(), 5, 110..120),
+            build(dir.path(), 6, 140..210),
+            build(dir.path(), 7, 230..240),
+            build(dir.path(), 8, 250..260),
+        ];
+        let levels = vec![l1, l2];
+        let ids = |tables: &Level| tables.iter().map(|sst| sst.sst_id()).collect::<Vec<_>>();
+
+        // table 3 overlaps the most tables of L2, and table 6 draws table 2 in, then table 5
+        let (upper, lower) = select_file_inputs(&[], &levels, 1).unwrap();
+        assert_eq!((ids(&upper), ids(&lower)), (vec![2, 3], vec![5, 6, 7, 8]));
+        let task = CompactionTask::next_level(1, &upper, &lower);
+        assert!(resolve_task(&task, &[], &levels, &HashSet::new()).is_ok());
+
+        // at L0, the other tables within the range come along, newest first
+        let l0 = vec![
+            build(dir.path(), 9, 0..10),
+            build(dir.path(), 10, 5..60),
+            build(dir.path(), 11, 400..500),
+        ];
+        let (upper, lower) = select_file_inputs(&l0, &levels, 0).unwrap();
+        assert_eq!((ids(&upper), ids(&lower)), (vec![10, 9], vec![1]));
+        let task = CompactionTask::next_level(0, &upper, &lower);
+        assert!(resolve_task(&task, &l0, &levels, &HashSet::new()).is_ok());
+
+        // the last level has no next one to take its tables
+        let (upper, lower) = select_file_inputs(&[], &levels, 2).unwrap();
+        assert_eq!((ids(&upper), ids(&lower)), (vec![4], vec![]));
+        let task = CompactionTask::next_level(2, &upper, &lower);
+        assert!(resolve_task(&task, &[], &levels, &HashSet::new()).is_ok());
+        let bottom = vec![vec![]; NUM_LEVELS - 1];
+        let task = CompactionTask {
+            inputs: BTreeMap::from([(NUM_LEVELS - 1, vec![1])]),
+            output_level: NUM_LEVELS,
+        };
+        assert!(resolve_task(&task, &[], &bottom, &HashSet::new()).is_err());
+
+        let (upper, lower) = select_file_inputs(&[], &[], 0).unwrap();
+        assert!(upper.is_empty() && lower.is_empty());
+        assert!(select_file_inputs(&[], &[], 1).is_err());
+
+        // below L0, the picker moves a single table
+        let state = LsmStateDescription {
+            l0_sstables: vec![],
+            levels: levels.clone(),
+            compacting: HashSet::new(),
+            l0_compaction_trigger: 2,
+            file_count_boost: 1.0,
+        };
+        let task = LeveledPicker.pick(&state).unwrap().unwrap();
+        assert_eq!(
+            task.inputs,
+            BTreeMap::from([(1, vec![2, 3]), (2, vec![5, 6, 7, 8])])
+        );
+    }
+
+    #[test]
+    fn test_tombstones_kept_until_horizon_and_bottom() {
+        let dir = tempdir().unwrap();
+        // the older table has the larger id, as a compaction output can
+        let inputs = [(7, 0..10, 20), (2, 5..15, 30)]
+            .into_iter()
+            .map(|(id, keys, max_seq)| {
+                let mut builder = SsTableBuilder::new(128).with_max_seq(max_seq);
+                for key in keys {
+                    builder.add(format!("key_{:03}", key).as_bytes(), b"value");
+                }
+                let path = dir.path().join(format!("{}.sst", id));
+                Arc::new(builder.export(id, None, path).unwrap())
+            })
+            .collect::<Vec<_>>();
+
+        let drops = |inputs: &[Arc<SsTable>], horizon| {
+            may_drop_tombstones(inputs, &[], 1, WriteToken(horizon)).unwrap()
+        };
+        assert!(drops(&inputs, u64::MAX));
+        assert!(!drops(&inputs, 0));
+        assert!(!drops(&inputs, 8));
+        assert!(!drops(&inputs, 30));
+        assert!(drops(&inputs, 31));
+        assert!(drops(&inputs[..1], 21));
+        assert!(drops(&[], 0));
+
+        // past the horizon, a table below the output holding the keys still keeps them
+        let below = vec![vec![], vec![build(dir.path(), 9, 12..20)]];
+        let bottom = vec![vec![], vec![build(dir.path(), 10, 20..30)]];
+        let horizon = WriteToken(u64::MAX);
+        assert!(!may_drop_tombstones(&inputs, &below, 1, horizon).unwrap());
+        assert!(may_drop_tombstones(&inputs, &below, 2, horizon).unwrap());
+        assert!(may_drop_tombstones(&inputs, &bottom, 1, horizon).unwrap());
+        assert!(!may_drop_tombstones(&inputs, &bottom, 1, WriteToken(30)).unwrap());
+    }
+}
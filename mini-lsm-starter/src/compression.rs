@@ -0,0 +1,81 @@
+//! Compression of the SST blocks. A compressed block starts with a one-byte tag naming its
+//! codec, followed by the codec's output for the encoded block.
+
+use anyhow::{bail, Result};
+
+/// The codec of a block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CompressionType {
+    /// Stored as encoded.
+    #[default]
+    None,
+    /// LZ4, with the uncompressed length in front.
+    Lz4,
+    /// Reserved: this build has no Snappy codec, and stores these blocks as `None`.
+    Snappy,
+    /// Reserved: this build has no Zstd codec, and stores these blocks as `None`.
+    Zstd,
+}
+
+impl CompressionType {
+    /// The tag a block compressed with `self` starts with.
+    pub fn tag(self) -> u8 {
+        match self {
+            CompressionType::None => 0,
+            CompressionType::Lz4 => 1,
+            CompressionType::Snappy => 2,
+            CompressionType::Zstd => 3,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Result<Self> {
+        Ok(match tag {
+            0 => CompressionType::None,
+            1 => CompressionType::Lz4,
+            2 => CompressionType::Snappy,
+            3 => CompressionType::Zstd,
+            _ => bail!("unknown block compression tag {}", tag),
+        })
+    }
+
+    /// Whether this build can compress and decompress blocks with `self`.
+    pub fn is_supported(self) -> bool {
+        matches!(self, CompressionType::None | CompressionType::Lz4)
+    }
+
+    /// `data` compressed with `self`, behind its tag. A codec this build does not have falls
+    /// back to `None`.
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            CompressionType::Lz4 => {
+                let mut vec = vec![self.tag()];
+                vec.extend_from_slice(&lz4_flex::compress_prepend_size(data));
+                vec
+            }
+            _ => {
+                let mut vec = Vec::with_capacity(data.len() + 1);
+                vec.push(CompressionType::None.tag());
+                vec.extend_from_slice(data);
+                vec
+            }
+        }
+    }
+
+    /// The data `compress` was given, back from its output.
+    pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+        let (&tag, rest) = match data.split_first() {
+            Some(split) => split,
+            None => bail!("compressed block is empty"),
+        };
+        match Self::from_tag(tag)? {
+            CompressionType::None => Ok(rest.to_vec()),
+            CompressionType::Lz4 => lz4_flex::decompress_size_prepended(rest)
+                .map_err(|err| anyhow::anyhow!("cannot decompress LZ4 block: {}", err)),
+            ty => bail!(
+                "block compressed with {:?}, which this build cannot decompress",
+                ty
+            ),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use bytes::Bytes;
+
+/// Errors callers may want to tell apart, carried inside `anyhow::Error`. Use
+/// `err.downcast_ref::<LsmError>()` to match on them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LsmError {
+    /// A read ran past its `ReadOptions::deadline`. Iterators stay where they were, so calling
+    /// `next` again with a later deadline resumes the scan.
+    DeadlineExceeded,
+    /// A read of `requested = (offset, len)` runs past the end of `file`, which is `size` bytes
+    /// long. The metadata the extent came from is corrupted.
+    Corruption {
+        file: PathBuf,
+        requested: (u64, u64),
+        size: u64,
+    },
+    /// The checksum stored for `what`, a section starting at `offset` in its file, is
+    /// `expected`, but its content sums to `actual`.
+    ChecksumMismatch {
+        what: String,
+        offset: u64,
+        expected: u32,
+        actual: u32,
+    },
+    /// An argument that cannot describe any valid request, such as a scan whose lower bound
+    /// lies past its upper bound.
+    InvalidArgument(String),
+    /// The write of `token` was not visible yet when the wait for it timed out, with every write
+    /// up to `applied` visible.
+    VisibilityTimeout { token: u64, applied: u64 },
+    /// A scan fell further behind the storage than its `ReadOptions::max_staleness` allows.
+    SnapshotTooOld {
+        sequences_behind: u64,
+        compactions_since: u64,
+    },
+    /// The scan or compaction was cancelled through its `CancellationToken`.
+    Cancelled,
+    /// The entry checksum of `key` does not match its value, which was corrupted somewhere
+    /// after it was written.
+    EntryChecksumMismatch { key: Bytes },
+    /// A write batch of `size` bytes is larger than `LsmStorageOptions::max_batch_bytes`. None
+    /// of it was written.
+    BatchTooLarge { size: usize, limit: usize },
+    /// The option cannot change on a running storage; reopen it with the new value instead.
+    ImmutableOption(String),
+    /// The directory holds `tables` SSTs but no manifest, as written by the versions before the
+    /// manifest, and `LsmStorageOptions::adopt_legacy_files` is off. Adopt them with
+    /// `LsmStorage::adopt_legacy` first.
+    LegacyDirectory { tables: usize },
+    /// A call to `next` skipped `tombstones_skipped` deleted keys in a row, the
+    /// `ReadOptions::max_tombstones_per_next` of the scan, without reaching a live key. The
+    /// iterator stays on the last of them, so calling `next` again carries on.
+    Yielded { tombstones_skipped: usize },
+    /// The storage was closed with `LsmStorage::close`, and takes no more writes.
+    Closed,
+}
+
+impl fmt::Display for LsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LsmError::DeadlineExceeded => write!(f, "read deadline exceeded"),
+            LsmError::Corruption {
+                file,
+                requested: (offset, len),
+                size,
+            } => write!(
+                f,
+                "{}: read of {} bytes at offset {} runs past the end of the file ({} bytes)",
+                file.display(),
+                len,
+                offset,
+                size
+            ),
+            LsmError::ChecksumMismatch {
+                what,
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} at offset {}: checksum {:#010x}, expected {:#010x}",
+                what, offset, actual, expected
+            ),
+            LsmError::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            LsmError::VisibilityTimeout { token, applied } => write!(
+                f,
+                "write {} did not become visible in time, writes up to {} are",
+                token, applied
+            ),
+            LsmError::SnapshotTooOld {
+                sequences_behind,
+                compactions_since,
+            } => write!(
+                f,
+                "snapshot too old: {} writes and {} flushes or compactions behind",
+                sequences_behind, compactions_since
+            ),
+            LsmError::Cancelled => write!(f, "cancelled"),
+            LsmError::EntryChecksumMismatch { key } => {
+                write!(f, "entry checksum mismatch for key {:?}", key)
+            }
+            LsmError::BatchTooLarge { size, limit } => write!(
+                f,
+                "write batch of {} bytes exceeds the limit of {} bytes",
+                size, limit
+            ),
+            LsmError::ImmutableOption(name) => {
+                write!(f, "option {} cannot change on a running storage", name)
+            }
+            LsmError::LegacyDirectory { tables } => write!(
+                f,
+                "found {} SSTs but no manifest, run LsmStorage::adopt_legacy on the directory or \
+                 open it with adopt_legacy_files",
+                tables
+            ),
+            LsmError::Yielded { tombstones_skipped } => write!(
+                f,
+                "skipped {} deleted keys without reaching a live one, call next again to carry on",
+                tombstones_skipped
+            ),
+            LsmError::Closed => write!(f, "the storage is closed"),
+        }
+    }
+}
+
+impl std::error::Error for LsmError {}
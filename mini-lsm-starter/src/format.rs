@@ -0,0 +1,163 @@
+//! The layouts of the fixed-size parts of the files on disk: the footer of an SST in each of its
+//! versions, the header of a block entry, the trailer of a block, a block meta and a record of
+//! the write-ahead log. The encoders and the decoders take their offsets and sizes from here,
+//! and the golden files under `testdata/format` pin the bytes they produce, so that a change
+//! to any of them fails the tests rather than the tables and logs already written.
+//!
+//! Every integer is little endian.
+
+use bytes::{Buf, BufMut};
+
+/// A field of a fixed layout: where it starts, and how many bytes it takes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub offset: usize,
+    pub size: usize,
+}
+
+impl Field {
+    const fn new(offset: usize, size: usize) -> Self {
+        Self { offset, size }
+    }
+
+    /// Where the field ends, i.e. the offset of the next one.
+    pub const fn end(&self) -> usize {
+        self.offset + self.size
+    }
+
+    /// The field of the layout `buf` starts with. Panics if `buf` is too short for it.
+    pub fn get(&self, buf: &[u8]) -> u64 {
+        (&buf[self.offset..self.end()]).get_uint_le(self.size)
+    }
+
+    /// Set the field of the layout `buf` starts with to `value`, cut to the size of the field.
+    pub fn put(&self, buf: &mut [u8], value: u64) {
+        (&mut buf[self.offset..self.end()]).put_uint_le(value, self.size);
+    }
+}
+
+/// The footer that ends every SST, in one of its versions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FooterLayout {
+    pub version: u16,
+    /// Where the meta section starts in the table.
+    pub meta_offset: Field,
+    /// The CRC32 of the meta section, from version 2 on.
+    pub meta_checksum: Option<Field>,
+    pub format_version: Field,
+    pub magic: Field,
+    /// The low half of the CRC32 of the fields before it.
+    pub checksum: Field,
+    pub len: usize,
+}
+
+/// `| Meta Block Offset (u32) | Format Version (u16) | Magic (u32) | Footer Checksum (u16) |`
+pub const FOOTER_V1: FooterLayout = FooterLayout {
+    version: 1,
+    meta_offset: Field::new(0, 4),
+    meta_checksum: None,
+    format_version: Field::new(4, 2),
+    magic: Field::new(6, 4),
+    checksum: Field::new(10, 2),
+    len: 12,
+};
+
+/// `| Meta Block Offset (u32) | Meta Checksum (u32) | Format Version (u16) | Magic (u32) |
+/// Footer Checksum (u16) |`. The fields from the format version on end the footer of every
+/// version, so that the version can be read before the layout is known.
+pub const FOOTER_V2: FooterLayout = FooterLayout {
+    version: 2,
+    meta_offset: Field::new(0, 4),
+    meta_checksum: Some(Field::new(4, 4)),
+    format_version: Field::new(8, 2),
+    magic: Field::new(10, 4),
+    checksum: Field::new(14, 2),
+    len: 16,
+};
+
+impl FooterLayout {
+    /// The layout of the footers of format `version`, if this build reads it.
+    pub fn of_version(version: u16) -> Option<&'static Self> {
+        [&FOOTER_V1, &FOOTER_V2]
+            .into_iter()
+            .find(|layout| layout.version == version)
+    }
+}
+
+/// The header of an entry of a block, followed by the rest of the key and the value:
+/// `| Shared Key Len (u16) | Rest Of Key Len (u16) | Value Len (u16) |`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntryLayout {
+    /// Bytes the key shares with the key of the entry before it, 0 at a restart point.
+    pub shared_key_len: Field,
+    pub rest_of_key_len: Field,
+    /// Bytes of the value, its entry checksum included.
+    pub value_len: Field,
+    pub len: usize,
+}
+
+pub const BLOCK_ENTRY: EntryLayout = EntryLayout {
+    shared_key_len: Field::new(0, 2),
+    rest_of_key_len: Field::new(2, 2),
+    value_len: Field::new(4, 2),
+    len: 6,
+};
+
+/// The sections ending a block, after the entries and the padding: `| Restart (u16) ... |
+/// Num Restarts (u16) | Offset (u16) ... | Num Entries (u16) |`, then a CRC32 (u32) with the
+/// `checksum` feature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockTrailerLayout {
+    /// Bytes of an offset into the entries, of a restart point or an entry.
+    pub offset_size: usize,
+    /// Bytes of the count ending each section.
+    pub count_size: usize,
+    pub checksum_size: usize,
+}
+
+pub const BLOCK_TRAILER: BlockTrailerLayout = BlockTrailerLayout {
+    offset_size: 2,
+    count_size: 2,
+    checksum_size: 4,
+};
+
+/// A meta of a data block in the meta section of an SST: `| Offset (u32) | First Key Len (u16)
+/// | First Key | Last Key Len (u16) | Last Key |`, the last key only in the tables whose footer
+/// says they hold it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockMetaLayout {
+    /// Bytes of the offset of the block in the table.
+    pub offset_size: usize,
+    /// Bytes of the length before each key.
+    pub key_len_size: usize,
+}
+
+pub const BLOCK_META: BlockMetaLayout = BlockMetaLayout {
+    offset_size: 4,
+    key_len_size: 2,
+};
+
+/// A record of the write-ahead log, padded with zeros to a multiple of `alignment`:
+/// `| Key Len (u16) | Value Len (u16) | Key | Value |`. The value ends with its entry checksum
+/// (u32) in a log with entry checksums. A batch starts with a marker record with an empty key,
+/// whose value counts the records of the batch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WalRecordLayout {
+    pub key_len: Field,
+    pub value_len: Field,
+    pub header_len: usize,
+    pub alignment: usize,
+    /// Bytes of the value of a batch marker.
+    pub batch_count_size: usize,
+}
+
+pub const WAL_RECORD: WalRecordLayout = WalRecordLayout {
+    key_len: Field::new(0, 2),
+    value_len: Field::new(2, 2),
+    header_len: 4,
+    alignment: 4096,
+    batch_count_size: 4,
+};
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,218 @@
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use super::*;
+use crate::wal::Wal;
+
+/// Set to write the golden files from the encoders rather than compare them, after a deliberate
+/// change to the format. `v1.sst` is left as it is, since nothing writes version 1 any more.
+const BLESS: &str = "MINI_LSM_BLESS";
+
+fn golden(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("testdata/format")
+        .join(name)
+}
+
+/// Check that `actual` is the golden file `name`, byte for byte.
+fn check_golden(name: &str, actual: &[u8]) {
+    let path = golden(name);
+    if std::env::var_os(BLESS).is_some() {
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = std::fs::read(&path).unwrap();
+    let diff = expected
+        .iter()
+        .zip(actual)
+        .position(|(expected, actual)| expected != actual)
+        .unwrap_or(expected.len().min(actual.len()));
+    assert!(
+        expected == actual,
+        "{} changed: {} bytes, {} expected, first differing at offset {}; if the format is \
+         meant to change, run the tests with {} set to rewrite it",
+        name,
+        actual.len(),
+        expected.len(),
+        diff,
+        BLESS
+    );
+}
+
+#[test]
+fn test_layouts_tile_their_records() {
+    for layout in [&FOOTER_V1, &FOOTER_V2] {
+        let fields = std::iter::once(layout.meta_offset)
+            .chain(layout.meta_checksum)
+            .chain([layout.format_version, layout.magic, layout.checksum]);
+        let end = fields.fold(0, |offset, field| {
+            assert_eq!(field.offset, offset, "version {}", layout.version);
+            field.end()
+        });
+        assert_eq!(end, layout.len);
+        assert_eq!(FooterLayout::of_version(layout.version), Some(layout));
+        // the fields read before the version is known
+        for (v1, field) in [
+            (FOOTER_V1.format_version, layout.format_version),
+            (FOOTER_V1.magic, layout.magic),
+        ] {
+            assert_eq!(FOOTER_V1.len - v1.offset, layout.len - field.offset);
+        }
+    }
+    assert_eq!(FooterLayout::of_version(3), None);
+
+    let entry = [
+        BLOCK_ENTRY.shared_key_len,
+        BLOCK_ENTRY.rest_of_key_len,
+        BLOCK_ENTRY.value_len,
+    ];
+    assert_eq!(
+        entry.iter().fold(0, |offset, field| offset + field.size),
+        BLOCK_ENTRY.len
+    );
+    assert_eq!(WAL_RECORD.value_len.end(), WAL_RECORD.header_len);
+}
+
+/// Write the golden log: a record on its own, then a batch of two.
+fn write_wal(path: &Path, entry_checksums: bool) {
+    let mut wal = Wal::create(path).unwrap();
+    if entry_checksums {
+        wal = wal.with_entry_checksums();
+    }
+    wal.append(&Bytes::from("key"), &Bytes::from("value"))
+        .unwrap();
+    wal.append_batch(&[
+        (Bytes::from("a"), Bytes::from("1")),
+        (Bytes::from("b"), Bytes::new()),
+    ])
+    .unwrap();
+    wal.sync().unwrap();
+}
+
+#[test]
+fn test_wal_encoding_matches_golden_files() {
+    let dir = tempdir().unwrap();
+    for (name, entry_checksums) in [("plain.wal", false), ("entry_checksums.wal", true)] {
+        let path = dir.path().join(name);
+        write_wal(&path, entry_checksums);
+        check_golden(name, &std::fs::read(&path).unwrap());
+    }
+}
+
+#[test]
+fn test_wal_golden_files_decode() {
+    for (name, entry_checksums) in [("plain.wal", false), ("entry_checksums.wal", true)] {
+        let mut wal = Wal::from(golden(name)).unwrap();
+        if entry_checksums {
+            wal = wal.with_entry_checksums();
+        }
+        let mut batches = vec![];
+        let stats = wal
+            .replay_batches(&mut |records| {
+                batches.push(
+                    records
+                        .into_iter()
+                        .map(|record| (record.key, record.value))
+                        .collect::<Vec<_>>(),
+                );
+                Ok(())
+            })
+            .unwrap();
+        let entry = |key: &'static str, value: &'static str| (Bytes::from(key), Bytes::from(value));
+        assert_eq!(
+            batches,
+            vec![
+                vec![entry("key", "value")],
+                vec![entry("a", "1"), entry("b", "")]
+            ],
+            "{}",
+            name
+        );
+        assert_eq!(stats.records, 3);
+        // a record each, the batch marker included, each padded to the alignment
+        assert_eq!(stats.bytes, 4 * WAL_RECORD.alignment as u64);
+    }
+}
+
+/// The golden SSTs, which hold no block checksums, so the `checksum` feature leaves them out.
+#[cfg(not(feature = "checksum"))]
+mod sst {
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use tempfile::tempdir;
+
+    use super::{check_golden, golden};
+    use crate::iterators::StorageIterator;
+    use crate::table::{FileObject, SsTable, SsTableBuilder, SsTableIterator, TableProperties};
+
+    /// The entries of the golden SSTs, a deletion included.
+    fn sst_entries() -> Vec<(Bytes, Bytes)> {
+        [
+            ("apple", "red"),
+            ("apricot", "orange"),
+            ("banana", "yellow"),
+            ("blueberry", "blue"),
+            ("cherry", "dark red"),
+            ("date", ""),
+            ("fig", "purple"),
+            ("grape", "green"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (Bytes::from(key), Bytes::from(value)))
+        .collect()
+    }
+
+    fn read_sst(path: &Path) -> (Arc<SsTable>, Vec<(Bytes, Bytes)>) {
+        let table = Arc::new(SsTable::open(0, None, FileObject::open(path).unwrap()).unwrap());
+        let mut iter = SsTableIterator::create_and_seek_to_first(table.clone()).unwrap();
+        let mut entries = vec![];
+        while iter.is_valid() {
+            entries.push((iter.key().clone(), iter.value().clone()));
+            iter.next().unwrap();
+        }
+        (table, entries)
+    }
+
+    #[test]
+    fn test_sst_encoding_matches_golden_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("1.sst");
+        let mut builder = SsTableBuilder::new(64);
+        for (key, value) in sst_entries() {
+            builder.add(&key, &value);
+        }
+        builder.build_for_test(&path).unwrap();
+        check_golden("v2.sst", &std::fs::read(&path).unwrap());
+    }
+
+    #[test]
+    fn test_sst_golden_files_decode() {
+        let first_and_last_keys = [("apple", "banana"), ("blueberry", "date"), ("fig", "grape")];
+        // the version 1 table is the version 2 one with the footer of version 1, which has no meta
+        // checksum; the trailers before the footer do not depend on the version
+        for name in ["v1.sst", "v2.sst"] {
+            let (table, entries) = read_sst(&golden(name));
+            assert_eq!(entries, sst_entries(), "{}", name);
+            let properties = TableProperties {
+                num_entries: 8,
+                num_tombstones: 1,
+            };
+            assert_eq!(table.properties(), Some(properties), "{}", name);
+            let metas = table.block_metas().unwrap();
+            let keys: Vec<_> = metas
+                .iter()
+                .map(|meta| (&meta.first_key[..], &meta.last_key[..]))
+                .collect();
+            let expected: Vec<_> = first_and_last_keys
+                .iter()
+                .map(|(first, last)| (first.as_bytes(), last.as_bytes()))
+                .collect();
+            assert_eq!(keys, expected, "{}", name);
+            assert!(table.may_contain(b"fig") && table.may_contain(b"date"));
+        }
+    }
+}
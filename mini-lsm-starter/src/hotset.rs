@@ -0,0 +1,99 @@
+//! The blocks the cache found most often, kept across restarts so that an open can read them
+//! back in before the gets ask for them. A block is recorded by its first and its last key
+//! rather than by its table and index, which compactions renumber.
+
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Result;
+use bytes::{Buf, BufMut, Bytes};
+
+/// Name of the hot set file in the database directory.
+pub const HOTSET: &str = "HOTSET";
+
+const HOTSET_MAGIC: u32 = 0x484f_5453;
+
+/// Write `ranges`, the first and the last key of each hot block, hottest first, to the hot set
+/// of `dir`, replacing it as a whole.
+pub fn write(dir: &Path, ranges: &[(Bytes, Bytes)]) -> Result<()> {
+    let mut buf = vec![];
+    buf.put_u32_le(HOTSET_MAGIC);
+    buf.put_u32_le(ranges.len() as u32);
+    for (first, last) in ranges {
+        for key in [first, last] {
+            buf.put_u32_le(key.len() as u32);
+            buf.put_slice(key);
+        }
+    }
+    buf.put_u32_le(crc32fast::hash(&buf));
+
+    let tmp = dir.join(format!("{}.tmp", HOTSET));
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, dir.join(HOTSET))?;
+    Ok(())
+}
+
+/// The ranges of the hot set of `dir`, hottest first. A missing, torn or corrupt hot set is
+/// only a cold start, and reads as empty.
+pub fn read(dir: &Path) -> Vec<(Bytes, Bytes)> {
+    std::fs::read(dir.join(HOTSET))
+        .ok()
+        .and_then(|data| decode(&data))
+        .unwrap_or_default()
+}
+
+fn decode(data: &[u8]) -> Option<Vec<(Bytes, Bytes)>> {
+    let body_len = data.len().checked_sub(4)?;
+    let (body, mut checksum) = data.split_at(body_len);
+    if checksum.get_u32_le() != crc32fast::hash(body) {
+        return None;
+    }
+    let mut buf = body;
+    if buf.remaining() < 8 || buf.get_u32_le() != HOTSET_MAGIC {
+        return None;
+    }
+    let count = buf.get_u32_le() as usize;
+    let mut key = || {
+        if buf.remaining() < 4 {
+            return None;
+        }
+        let len = buf.get_u32_le() as usize;
+        if buf.remaining() < len {
+            return None;
+        }
+        Some(buf.copy_to_bytes(len))
+    };
+    let mut ranges = Vec::with_capacity(count.min(body_len));
+    for _ in 0..count {
+        ranges.push((key()?, key()?));
+    }
+    Some(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hotset_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(read(dir.path()).is_empty());
+        let ranges = vec![
+            (Bytes::from_static(b"a"), Bytes::from_static(b"c")),
+            (Bytes::from_static(b""), Bytes::from_static(b"zz")),
+        ];
+        write(dir.path(), &ranges).unwrap();
+        assert_eq!(read(dir.path()), ranges);
+
+        // a flipped byte makes it a cold start, not an error
+        let path = dir.path().join(HOTSET);
+        let mut data = std::fs::read(&path).unwrap();
+        data[9] ^= 1;
+        std::fs::write(&path, &data).unwrap();
+        assert!(read(dir.path()).is_empty());
+        std::fs::write(&path, &data[..3]).unwrap();
+        assert!(read(dir.path()).is_empty());
+    }
+}
@@ -1,3 +1,4 @@
+pub mod concat_iterator;
 pub mod merge_iterator;
 pub mod two_merge_iterator;
 
@@ -17,5 +18,35 @@ pub trait StorageIterator {
     fn next(&mut self) -> anyhow::Result<()>;
 }
 
+/// An iterator that has nothing to yield.
+#[derive(Default)]
+pub struct EmptyIterator {
+    empty: Bytes,
+}
+
+impl EmptyIterator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageIterator for EmptyIterator {
+    fn value(&self) -> &Bytes {
+        &self.empty
+    }
+
+    fn key(&self) -> &Bytes {
+        &self.empty
+    }
+
+    fn is_valid(&self) -> bool {
+        false
+    }
+
+    fn next(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,99 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use super::StorageIterator;
+use crate::table::{SsTable, SsTableIterator};
+
+/// Creates the iterator of a table once the run reaches it, or `None` to leave the table out.
+pub type OpenTable = Box<dyn FnMut(Arc<SsTable>) -> Result<Option<SsTableIterator>> + Send>;
+
+/// Iterate a sorted run, tables sorted by key that do not overlap, one table at a time. Only
+/// the current table has an iterator, so a run of any length pins a single block.
+pub struct SstConcatIterator {
+    tables: Vec<Arc<SsTable>>,
+    /// The table to open once `current` runs out.
+    next_idx: usize,
+    current: Option<SsTableIterator>,
+    open: OpenTable,
+}
+
+impl SstConcatIterator {
+    pub fn create_and_seek_to_first(tables: Vec<Arc<SsTable>>) -> Result<Self> {
+        Self::with_opener(
+            tables,
+            Box::new(|table| SsTableIterator::create_and_seek_to_first(table).map(Some)),
+        )
+    }
+
+    /// Iterate the run from the first key `>= key` on. The tables before the one that may hold it
+    /// are found by their first key, without opening them.
+    pub fn create_and_seek_to_key(tables: Vec<Arc<SsTable>>, key: &[u8]) -> Result<Self> {
+        let start = tables
+            .partition_point(|table| match table.first_key() {
+                Some(first) => first <= key,
+                None => true,
+            })
+            .saturating_sub(1);
+        let key = Bytes::copy_from_slice(key);
+        // the tables after the first one start past `key`, so seeking lands on their first entry
+        let open: OpenTable =
+            Box::new(move |table| SsTableIterator::create_and_seek_to_key(table, &key).map(Some));
+        Self::open_from(tables, start, open)
+    }
+
+    /// Iterate `tables`, each one from where `open` positions its iterator, e.g. to scan a range
+    /// of the run.
+    pub fn with_opener(tables: Vec<Arc<SsTable>>, open: OpenTable) -> Result<Self> {
+        Self::open_from(tables, 0, open)
+    }
+
+    fn open_from(tables: Vec<Arc<SsTable>>, next_idx: usize, open: OpenTable) -> Result<Self> {
+        let mut iter = Self {
+            tables,
+            next_idx,
+            current: None,
+            open,
+        };
+        iter.open_next()?;
+        Ok(iter)
+    }
+
+    /// Move on to the next table holding anything, once the current one runs out.
+    fn open_next(&mut self) -> Result<()> {
+        while !self.current.as_ref().map_or(false, |iter| iter.is_valid()) {
+            self.current = None;
+            let table = match self.tables.get(self.next_idx) {
+                Some(table) => table.clone(),
+                None => return Ok(()),
+            };
+            self.next_idx += 1;
+            if table.num_of_blocks() > 0 {
+                self.current = (self.open)(table)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageIterator for SstConcatIterator {
+    fn value(&self) -> &Bytes {
+        self.current.as_ref().unwrap().value()
+    }
+
+    fn key(&self) -> &Bytes {
+        self.current.as_ref().unwrap().key()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.current.as_ref().map_or(false, |iter| iter.is_valid())
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if let Some(current) = &mut self.current {
+            current.next()?;
+        }
+        self.open_next()
+    }
+}
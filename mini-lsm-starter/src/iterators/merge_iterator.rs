@@ -59,6 +59,11 @@ impl<I: StorageIterator> MergeIterator<I> {
             current,
         }
     }
+
+    /// Number of child iterators with entries left, which is the width of the heap.
+    pub fn num_active_iterators(&self) -> usize {
+        self.iters.len() + usize::from(self.current.is_some())
+    }
 }
 
 impl<I: StorageIterator> StorageIterator for MergeIterator<I> {
@@ -75,16 +80,20 @@ impl<I: StorageIterator> StorageIterator for MergeIterator<I> {
     }
 
     fn next(&mut self) -> Result<()> {
+        // move the older iterators past the current key before the current one, so that none of
+        // them yields it again when the current one runs out at it
         while self.is_valid()
             && self.iters.peek().map(|x| x.inner_iter.key())
                 == self.current.as_ref().map(|x| x.inner_iter.key())
         {
             // NOTE: Avoid calling PeekMut::drop
             let mut opt = self.iters.pop().unwrap();
-            opt.inner_iter.next()?;
-            if opt.inner_iter.is_valid() {
+            // keep a failed iterator in the heap, so that calling `next` again picks up from here
+            let res = opt.inner_iter.next();
+            if res.is_err() || opt.inner_iter.is_valid() {
                 self.iters.push(opt);
             }
+            res?;
         }
 
         self.current.as_mut().unwrap().inner_iter.next()?;
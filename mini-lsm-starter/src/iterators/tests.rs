@@ -3,6 +3,7 @@ use bytes::Bytes;
 
 use super::StorageIterator;
 
+pub mod concat_iterator_test;
 pub mod merge_iterator_test;
 pub mod two_merge_iterator_test;
 
@@ -0,0 +1,63 @@
+use std::sync::Arc;
+
+use tempfile::tempdir;
+
+use super::*;
+use crate::iterators::concat_iterator::SstConcatIterator;
+use crate::table::{SsTable, SsTableBuilder};
+
+fn key_of(idx: usize) -> Bytes {
+    Bytes::from(format!("key_{:03}", idx))
+}
+
+fn keys_of(mut iter: impl StorageIterator) -> Vec<Bytes> {
+    let mut keys = vec![];
+    while iter.is_valid() {
+        keys.push(iter.key().clone());
+        iter.next().unwrap();
+    }
+    keys
+}
+
+#[test]
+fn test_concat_seeks_to_key_across_tables() {
+    let dir = tempdir().unwrap();
+    // three tables of several blocks each, with gaps between them
+    let runs = [0..10, 20..30, 40..50];
+    let tables: Vec<Arc<SsTable>> = runs
+        .iter()
+        .enumerate()
+        .map(|(id, run)| {
+            let mut builder = SsTableBuilder::new(32);
+            for idx in run.clone() {
+                builder.add(&key_of(idx), b"value");
+            }
+            Arc::new(
+                builder
+                    .build_for_test(dir.path().join(format!("{}.sst", id)))
+                    .unwrap(),
+            )
+        })
+        .collect();
+    let all: Vec<_> = runs.iter().cloned().flatten().collect();
+    let from = |idx: usize| -> Vec<Bytes> {
+        all.iter()
+            .filter(|&&key| key >= idx)
+            .map(|&key| key_of(key))
+            .collect()
+    };
+
+    let seek = |key: &[u8]| {
+        keys_of(SstConcatIterator::create_and_seek_to_key(tables.clone(), key).unwrap())
+    };
+    assert_eq!(seek(b"a"), from(0));
+    for idx in [0, 5, 9, 10, 15, 20, 29, 35, 40, 49] {
+        assert_eq!(seek(&key_of(idx)), from(idx), "{:?}", key_of(idx));
+    }
+    assert!(seek(&key_of(50)).is_empty());
+    assert_eq!(
+        keys_of(SstConcatIterator::create_and_seek_to_first(tables.clone()).unwrap()),
+        from(0)
+    );
+    assert!(keys_of(SstConcatIterator::create_and_seek_to_key(vec![], b"a").unwrap()).is_empty());
+}
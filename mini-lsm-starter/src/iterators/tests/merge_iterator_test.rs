@@ -136,3 +136,38 @@ fn test_merge_empty() {
     let iter = MergeIterator::<MockIterator>::create(vec![]);
     check_iter_result(iter, vec![]);
 }
+
+#[test]
+fn test_merge_iterators_ending_at_the_same_key() {
+    let i1 = MockIterator::new(vec![
+        (Bytes::from("a"), Bytes::from("1.1")),
+        (Bytes::from("c"), Bytes::from("3.1")),
+    ]);
+    let i2 = MockIterator::new(vec![
+        (Bytes::from("b"), Bytes::from("2.2")),
+        (Bytes::from("c"), Bytes::from("3.2")),
+    ]);
+    let i3 = MockIterator::new(vec![(Bytes::from("c"), Bytes::from("3.3"))]);
+
+    // the current iterator runs out at the key it shares with the others, which are moved past
+    // it before the next one takes over
+    let iter = MergeIterator::create(vec![Box::new(i1.clone()), Box::new(i2.clone())]);
+    check_iter_result(
+        iter,
+        vec![
+            (Bytes::from("a"), Bytes::from("1.1")),
+            (Bytes::from("b"), Bytes::from("2.2")),
+            (Bytes::from("c"), Bytes::from("3.1")),
+        ],
+    );
+
+    let iter = MergeIterator::create(vec![Box::new(i3), Box::new(i2), Box::new(i1)]);
+    check_iter_result(
+        iter,
+        vec![
+            (Bytes::from("a"), Bytes::from("1.1")),
+            (Bytes::from("b"), Bytes::from("2.2")),
+            (Bytes::from("c"), Bytes::from("3.3")),
+        ],
+    );
+}
@@ -27,20 +27,10 @@ impl<A: StorageIterator, B: StorageIterator> TwoMergeIterator<A, B> {
 
         Ok(this)
     }
+}
 
-    fn copy_from_a(&mut self) {
-        if self.a.is_valid() {
-            self.key = self.a.key().clone();
-            self.value = self.a.value().clone();
-        }
-    }
-
-    fn copy_from_b(&mut self) {
-        if self.b.is_valid() {
-            self.key = self.b.key().clone();
-            self.value = self.b.value().clone();
-        }
-    }
+fn entry<I: StorageIterator>(iter: &I) -> (Bytes, Bytes) {
+    (iter.key().clone(), iter.value().clone())
 }
 
 impl<A: StorageIterator, B: StorageIterator> StorageIterator for TwoMergeIterator<A, B> {
@@ -56,25 +46,36 @@ impl<A: StorageIterator, B: StorageIterator> StorageIterator for TwoMergeIterato
         !self.key.is_empty()
     }
 
+    /// Nothing is copied unless the children move, so a failed call can be retried.
     fn next(&mut self) -> Result<()> {
         if self.a.is_valid() && self.b.is_valid() {
             match self.a.key().cmp(&self.b.key()) {
-                std::cmp::Ordering::Less | std::cmp::Ordering::Equal => {
-                    self.copy_from_a();
+                std::cmp::Ordering::Less => {
+                    let entry = entry(&self.a);
                     self.a.next()?;
+                    (self.key, self.value) = entry;
+                }
+                std::cmp::Ordering::Equal => {
+                    // `b` moves first: if `a` then fails, it still holds the key and wins again
+                    let entry = entry(&self.a);
                     self.b.next()?;
+                    self.a.next()?;
+                    (self.key, self.value) = entry;
                 }
                 _ => {
-                    self.copy_from_b();
+                    let entry = entry(&self.b);
                     self.b.next()?;
+                    (self.key, self.value) = entry;
                 }
             }
         } else if self.a.is_valid() {
-            self.copy_from_a();
+            let entry = entry(&self.a);
             self.a.next()?;
+            (self.key, self.value) = entry;
         } else if self.b.is_valid() {
-            self.copy_from_b();
+            let entry = entry(&self.b);
             self.b.next()?;
+            (self.key, self.value) = entry;
         } else {
             self.key = Bytes::new();
         }
@@ -0,0 +1,279 @@
+//! The internal key codec: a user key tagged with the sequence of the write and the kind of
+//! value it carries.
+//!
+//! | user key | seq (u64) | kind (u8) |
+//!
+//! Internal keys order by user key ascending, then by sequence descending, so that the newest
+//! version of a key comes first. The encoding does not sort bytewise, compare encoded keys with
+//! [`compare`].
+//!
+//! The memtables, the tables and the log still store user keys, a delete as an empty value:
+//! [`ValueKind::of_value`] tells the kinds apart where they are read back, and raw scans hand out
+//! their entries as [`RawEntry::internal_key`](crate::lsm_iterator::RawEntry::internal_key).
+
+use std::cmp::Ordering;
+
+use anyhow::Result;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// Length of the sequence and kind that follow the user key.
+pub const TRAILER_LEN: usize = 9;
+
+/// What an internal key holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u8)]
+pub enum ValueKind {
+    Delete = 0,
+    Put = 1,
+}
+
+impl ValueKind {
+    /// The kind that sorts first among the versions of a key with the same sequence.
+    pub const MAX: ValueKind = ValueKind::Put;
+
+    /// The kind of a value as the memtables, the tables and the log store it, where a delete
+    /// is an empty value.
+    pub fn of_value(value: &[u8]) -> Self {
+        match value.is_empty() {
+            true => Self::Delete,
+            false => Self::Put,
+        }
+    }
+
+    fn from_u8(tag: u8) -> Result<Self> {
+        match tag {
+            0 => Ok(Self::Delete),
+            1 => Ok(Self::Put),
+            tag => anyhow::bail!("unknown value kind {}", tag),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct InternalKey {
+    pub user_key: Bytes,
+    pub seq: u64,
+    pub kind: ValueKind,
+}
+
+/// An internal key borrowing its user key from the buffer it was decoded from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct InternalKeyRef<'a> {
+    pub user_key: &'a [u8],
+    pub seq: u64,
+    pub kind: ValueKind,
+}
+
+impl InternalKey {
+    pub fn new(user_key: Bytes, seq: u64, kind: ValueKind) -> Self {
+        Self {
+            user_key,
+            seq,
+            kind,
+        }
+    }
+
+    pub fn as_ref(&self) -> InternalKeyRef<'_> {
+        InternalKeyRef {
+            user_key: &self.user_key,
+            seq: self.seq,
+            kind: self.kind,
+        }
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        self.user_key.len() + TRAILER_LEN
+    }
+
+    pub fn encode_into(&self, buf: &mut BytesMut) {
+        self.as_ref().encode_into(buf)
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
+        self.encode_into(&mut buf);
+        buf.freeze()
+    }
+
+    /// Decode a key encoded by [`InternalKey::encode_into`].
+    pub fn decode(buf: &[u8]) -> Result<InternalKeyRef<'_>> {
+        anyhow::ensure!(
+            buf.len() >= TRAILER_LEN,
+            "internal key of {} bytes is shorter than its trailer",
+            buf.len()
+        );
+        let (user_key, mut trailer) = buf.split_at(buf.len() - TRAILER_LEN);
+        let seq = trailer.get_u64();
+        let kind = ValueKind::from_u8(trailer.get_u8())?;
+        Ok(InternalKeyRef {
+            user_key,
+            seq,
+            kind,
+        })
+    }
+}
+
+impl<'a> InternalKeyRef<'a> {
+    pub fn encode_into(&self, buf: &mut BytesMut) {
+        buf.reserve(self.user_key.len() + TRAILER_LEN);
+        buf.put_slice(self.user_key);
+        buf.put_u64(self.seq);
+        buf.put_u8(self.kind as u8);
+    }
+
+    pub fn to_owned(&self) -> InternalKey {
+        InternalKey {
+            user_key: Bytes::copy_from_slice(self.user_key),
+            seq: self.seq,
+            kind: self.kind,
+        }
+    }
+}
+
+impl<'a> Ord for InternalKeyRef<'a> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.user_key
+            .cmp(other.user_key)
+            .then_with(|| other.seq.cmp(&self.seq))
+            .then_with(|| other.kind.cmp(&self.kind))
+    }
+}
+
+impl<'a> PartialOrd for InternalKeyRef<'a> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InternalKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_ref().cmp(&other.as_ref())
+    }
+}
+
+impl PartialOrd for InternalKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Compare two encoded internal keys in internal key order.
+pub fn compare(a: &[u8], b: &[u8]) -> Result<Ordering> {
+    Ok(InternalKey::decode(a)?.cmp(&InternalKey::decode(b)?))
+}
+
+/// The user key of an encoded internal key.
+pub fn extract_user_key(buf: &[u8]) -> Result<&[u8]> {
+    Ok(InternalKey::decode(buf)?.user_key)
+}
+
+/// The internal key that sorts before every version of `user_key`, to seek to its newest one.
+pub fn max_seq_key(user_key: Bytes) -> InternalKey {
+    InternalKey::new(user_key, u64::MAX, ValueKind::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keys over a small alphabet, so that random keys often share prefixes or are equal.
+    fn random_keys(count: usize) -> Vec<InternalKey> {
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        (0..count)
+            .map(|_| {
+                let len = (next() % 4) as usize;
+                let user_key: Vec<u8> =
+                    (0..len).map(|_| b"ab\0\xff"[next() as usize % 4]).collect();
+                let seq = match next() % 4 {
+                    0 => 0,
+                    1 => u64::MAX,
+                    _ => next() % 8,
+                };
+                let kind = if next() % 2 == 0 {
+                    ValueKind::Put
+                } else {
+                    ValueKind::Delete
+                };
+                InternalKey::new(Bytes::from(user_key), seq, kind)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        for key in random_keys(500) {
+            let encoded = key.encode();
+            assert_eq!(encoded.len(), key.encoded_len());
+            assert_eq!(InternalKey::decode(&encoded)?, key.as_ref());
+            assert_eq!(InternalKey::decode(&encoded)?.to_owned(), key);
+            assert_eq!(extract_user_key(&encoded)?, &key.user_key[..]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_boundary_seqs() -> Result<()> {
+        for seq in [0, 1, u64::MAX - 1, u64::MAX] {
+            let key = InternalKey::new(Bytes::from_static(b"key"), seq, ValueKind::Delete);
+            assert_eq!(InternalKey::decode(&key.encode())?.seq, seq);
+        }
+        let empty = InternalKey::new(Bytes::new(), u64::MAX, ValueKind::Put);
+        assert_eq!(InternalKey::decode(&empty.encode())?, empty.as_ref());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed() {
+        assert!(InternalKey::decode(b"").is_err());
+        assert!(InternalKey::decode(&[0; TRAILER_LEN - 1]).is_err());
+        let mut buf = BytesMut::new();
+        InternalKey::new(Bytes::from_static(b"key"), 7, ValueKind::Put).encode_into(&mut buf);
+        *buf.last_mut().unwrap() = 2;
+        assert!(InternalKey::decode(&buf).is_err());
+    }
+
+    #[test]
+    fn test_ordering() -> Result<()> {
+        let keys = random_keys(300);
+        for a in &keys {
+            for b in &keys {
+                let order = a.cmp(b);
+                assert_eq!(order, b.cmp(a).reverse());
+                assert_eq!(compare(&a.encode(), &b.encode())?, order);
+                if a.user_key != b.user_key {
+                    assert_eq!(order, a.user_key.cmp(&b.user_key));
+                } else if a.seq != b.seq {
+                    // the newest version first
+                    assert_eq!(order, b.seq.cmp(&a.seq));
+                }
+                assert_eq!(order == Ordering::Equal, a == b);
+            }
+        }
+
+        let mut sorted = keys;
+        sorted.sort();
+        for pair in sorted.windows(2) {
+            assert!(pair[0] <= pair[1]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_seq_key_seeks_to_newest() {
+        let user_key = Bytes::from_static(b"key");
+        let seek = max_seq_key(user_key.clone());
+        for seq in [0, 5, u64::MAX] {
+            for kind in [ValueKind::Put, ValueKind::Delete] {
+                assert!(seek <= InternalKey::new(user_key.clone(), seq, kind));
+            }
+        }
+        assert!(seek > InternalKey::new(Bytes::from_static(b"kex"), 0, ValueKind::Delete));
+        assert!(seek > InternalKey::new(Bytes::from_static(b"ke"), 0, ValueKind::Delete));
+    }
+}
@@ -0,0 +1,318 @@
+//! A range of user keys, as the scans take it: owned bounds, built once from a std range or a
+//! pair of bounds, which the layers below read rather than check again.
+
+use std::ops::{Bound, Range, RangeFrom, RangeFull, RangeInclusive, RangeTo, RangeToInclusive};
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use crate::error::LsmError;
+
+/// The keys between a lower and an upper bound. Any of the std ranges over keys converts into
+/// one, `..` taking in every key, and so does a pair of bounds.
+///
+/// The conversions take the bounds as they are, an inverted range included, which a scan then
+/// rejects with [`LsmError::InvalidArgument`], unless its
+/// [`ReadOptions::empty_on_inverted_bounds`](crate::lsm_storage::ReadOptions::empty_on_inverted_bounds)
+/// says to scan nothing. [`KeyRange::new`] rejects it right away.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct KeyRange {
+    lower: Bound<Bytes>,
+    upper: Bound<Bytes>,
+}
+
+impl KeyRange {
+    /// Every key.
+    pub fn all() -> Self {
+        Self::from_bounds(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// The keys within `lower` and `upper`. Fails with [`LsmError::InvalidArgument`] if the
+    /// lower bound lies past the upper one.
+    pub fn new(lower: Bound<Bytes>, upper: Bound<Bytes>) -> Result<Self> {
+        let range = Self::from_bounds(lower, upper);
+        range.check()?;
+        Ok(range)
+    }
+
+    pub(crate) fn from_bounds(lower: Bound<Bytes>, upper: Bound<Bytes>) -> Self {
+        Self { lower, upper }
+    }
+
+    pub fn lower(&self) -> Bound<&[u8]> {
+        self.lower.as_ref().map(|key| key.as_ref())
+    }
+
+    pub fn upper(&self) -> Bound<&[u8]> {
+        self.upper.as_ref().map(|key| key.as_ref())
+    }
+
+    /// The bounds, sharing the keys of the range.
+    pub fn bounds(&self) -> (&Bound<Bytes>, &Bound<Bytes>) {
+        (&self.lower, &self.upper)
+    }
+
+    pub fn into_bounds(self) -> (Bound<Bytes>, Bound<Bytes>) {
+        (self.lower, self.upper)
+    }
+
+    /// Whether the lower bound lies past the upper one.
+    pub fn is_inverted(&self) -> bool {
+        match (&self.lower, &self.upper) {
+            (
+                Bound::Included(lo) | Bound::Excluded(lo),
+                Bound::Included(hi) | Bound::Excluded(hi),
+            ) => lo > hi,
+            _ => false,
+        }
+    }
+
+    /// Fail with [`LsmError::InvalidArgument`] if the range is inverted.
+    pub fn check(&self) -> Result<()> {
+        if self.is_inverted() {
+            return Err(LsmError::InvalidArgument(format!(
+                "lower bound {:?} is past upper bound {:?}",
+                self.lower, self.upper
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Whether no key lies within the range: it is inverted, or both bounds are on the same key
+    /// and one of them leaves it out.
+    pub fn is_empty(&self) -> bool {
+        match (&self.lower, &self.upper) {
+            (Bound::Included(lo), Bound::Included(hi)) => lo > hi,
+            (
+                Bound::Included(lo) | Bound::Excluded(lo),
+                Bound::Included(hi) | Bound::Excluded(hi),
+            ) => lo >= hi,
+            _ => false,
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        let above_lower = match self.lower() {
+            Bound::Included(lo) => key >= lo,
+            Bound::Excluded(lo) => key > lo,
+            Bound::Unbounded => true,
+        };
+        let below_upper = match self.upper() {
+            Bound::Included(hi) => key <= hi,
+            Bound::Excluded(hi) => key < hi,
+            Bound::Unbounded => true,
+        };
+        above_lower && below_upper
+    }
+
+    /// The keys within both ranges, which may be empty.
+    pub fn intersect(&self, other: &KeyRange) -> KeyRange {
+        // of two bounds on the same key, the excluded one is the tighter
+        let tighter =
+            |a: &Bound<Bytes>, b: &Bound<Bytes>, past: fn(&Bytes, &Bytes) -> bool| match (a, b) {
+                (Bound::Unbounded, bound) | (bound, Bound::Unbounded) => bound.clone(),
+                (
+                    Bound::Included(ka) | Bound::Excluded(ka),
+                    Bound::Included(kb) | Bound::Excluded(kb),
+                ) if ka == kb => match a {
+                    Bound::Excluded(_) => a.clone(),
+                    _ => b.clone(),
+                },
+                (
+                    Bound::Included(ka) | Bound::Excluded(ka),
+                    Bound::Included(kb) | Bound::Excluded(kb),
+                ) => match past(ka, kb) {
+                    true => a.clone(),
+                    false => b.clone(),
+                },
+            };
+        KeyRange {
+            lower: tighter(&self.lower, &other.lower, |a, b| a > b),
+            upper: tighter(&self.upper, &other.upper, |a, b| a < b),
+        }
+    }
+}
+
+impl Default for KeyRange {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+fn owned(key: impl AsRef<[u8]>) -> Bytes {
+    Bytes::copy_from_slice(key.as_ref())
+}
+
+impl<K: AsRef<[u8]>> From<Range<K>> for KeyRange {
+    fn from(range: Range<K>) -> Self {
+        Self::from_bounds(
+            Bound::Included(owned(range.start)),
+            Bound::Excluded(owned(range.end)),
+        )
+    }
+}
+
+impl<K: AsRef<[u8]>> From<RangeInclusive<K>> for KeyRange {
+    fn from(range: RangeInclusive<K>) -> Self {
+        let (start, end) = range.into_inner();
+        Self::from_bounds(Bound::Included(owned(start)), Bound::Included(owned(end)))
+    }
+}
+
+impl<K: AsRef<[u8]>> From<RangeFrom<K>> for KeyRange {
+    fn from(range: RangeFrom<K>) -> Self {
+        Self::from_bounds(Bound::Included(owned(range.start)), Bound::Unbounded)
+    }
+}
+
+impl<K: AsRef<[u8]>> From<RangeTo<K>> for KeyRange {
+    fn from(range: RangeTo<K>) -> Self {
+        Self::from_bounds(Bound::Unbounded, Bound::Excluded(owned(range.end)))
+    }
+}
+
+impl<K: AsRef<[u8]>> From<RangeToInclusive<K>> for KeyRange {
+    fn from(range: RangeToInclusive<K>) -> Self {
+        Self::from_bounds(Bound::Unbounded, Bound::Included(owned(range.end)))
+    }
+}
+
+impl From<RangeFull> for KeyRange {
+    fn from(_: RangeFull) -> Self {
+        Self::all()
+    }
+}
+
+impl From<(Bound<&[u8]>, Bound<&[u8]>)> for KeyRange {
+    fn from((lower, upper): (Bound<&[u8]>, Bound<&[u8]>)) -> Self {
+        Self::from_bounds(lower.map(owned), upper.map(owned))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(lower: Bound<&str>, upper: Bound<&str>) -> KeyRange {
+        KeyRange::from_bounds(lower.map(owned), upper.map(owned))
+    }
+
+    #[test]
+    fn test_key_range_from_std_ranges() {
+        use Bound::*;
+
+        assert_eq!(
+            KeyRange::from("b".."d"),
+            range(Included("b"), Excluded("d"))
+        );
+        assert_eq!(
+            KeyRange::from("b"..="d"),
+            range(Included("b"), Included("d"))
+        );
+        assert_eq!(KeyRange::from("b"..), range(Included("b"), Unbounded));
+        assert_eq!(KeyRange::from(.."d"), range(Unbounded, Excluded("d")));
+        assert_eq!(KeyRange::from(..="d"), range(Unbounded, Included("d")));
+        assert_eq!(KeyRange::from(..), KeyRange::all());
+        assert_eq!(KeyRange::default(), KeyRange::all());
+        let (b, d) = (Bytes::from("b"), Bytes::from("d"));
+        assert_eq!(
+            KeyRange::from(b.clone()..d.clone()),
+            KeyRange::from("b".."d")
+        );
+        assert_eq!(KeyRange::from(&b[..]..&d[..]), KeyRange::from("b".."d"));
+        assert_eq!(
+            KeyRange::from((Excluded(&b[..]), Included(&d[..]))),
+            range(Excluded("b"), Included("d"))
+        );
+
+        let keys = ["a", "b", "c", "d", "e"];
+        let within = |range: KeyRange| -> Vec<&str> {
+            keys.into_iter()
+                .filter(|key| range.contains(key.as_bytes()))
+                .collect()
+        };
+        assert_eq!(within(("b".."d").into()), vec!["b", "c"]);
+        assert_eq!(within(("b"..="d").into()), vec!["b", "c", "d"]);
+        assert_eq!(within(("d"..).into()), vec!["d", "e"]);
+        assert_eq!(within((.."b").into()), vec!["a"]);
+        assert_eq!(within((..="b").into()), vec!["a", "b"]);
+        assert_eq!(within((..).into()), keys.to_vec());
+    }
+
+    #[test]
+    fn test_key_range_empty_and_inverted() {
+        use Bound::*;
+
+        assert!(!KeyRange::all().is_empty());
+        assert!(!KeyRange::from("b"..="b").is_empty());
+        for empty in [
+            range(Included("b"), Excluded("b")),
+            range(Excluded("b"), Included("b")),
+            range(Excluded("b"), Excluded("b")),
+        ] {
+            assert!(empty.is_empty() && !empty.is_inverted(), "{:?}", empty);
+            empty.check().unwrap();
+        }
+
+        #[allow(clippy::reversed_empty_ranges)]
+        let inverted = KeyRange::from("d".."b");
+        assert!(inverted.is_empty() && inverted.is_inverted());
+        let err = inverted.check().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(LsmError::InvalidArgument(_))
+        ));
+        let (lower, upper) = inverted.into_bounds();
+        assert!(KeyRange::new(lower, upper).is_err());
+        assert!(KeyRange::new(Included(Bytes::from("b")), Unbounded).is_ok());
+    }
+
+    #[test]
+    fn test_key_range_intersect() {
+        use Bound::*;
+
+        let cases = [
+            // the tighter bound on each side
+            (
+                ("a".."f").into(),
+                ("c"..="h").into(),
+                range(Included("c"), Excluded("f")),
+            ),
+            (
+                (.."f").into(),
+                ("c"..).into(),
+                range(Included("c"), Excluded("f")),
+            ),
+            (KeyRange::all(), ("c"..="d").into(), ("c"..="d").into()),
+            // on the same key, the excluded bound wins
+            (
+                range(Excluded("c"), Included("f")),
+                range(Included("c"), Excluded("f")),
+                range(Excluded("c"), Excluded("f")),
+            ),
+            // ranges meeting at a key only share it if both take it in
+            (("a"..="c").into(), ("c"..="e").into(), ("c"..="c").into()),
+            (
+                ("a".."c").into(),
+                ("c"..="e").into(),
+                range(Included("c"), Excluded("c")),
+            ),
+            // disjoint ranges give an inverted one
+            (
+                ("a".."b").into(),
+                ("d"..).into(),
+                range(Included("d"), Excluded("b")),
+            ),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(a.intersect(&b), expected, "{:?} and {:?}", a, b);
+            assert_eq!(b.intersect(&a), expected, "{:?} and {:?}", b, a);
+        }
+        let disjoint = KeyRange::from("a".."b").intersect(&("d"..).into());
+        assert!(disjoint.is_empty() && disjoint.is_inverted());
+        assert!(KeyRange::from("a".."c")
+            .intersect(&("c"..).into())
+            .is_empty());
+    }
+}
@@ -1,12 +1,32 @@
 #![feature(bound_map)]
 #![feature(write_all_vectored)]
 
+pub mod access;
 pub mod block;
+pub mod cancel;
+pub mod compaction;
+pub mod compression;
+pub mod error;
+pub mod format;
+pub mod hotset;
 pub mod iterators;
+pub mod key;
+pub mod key_range;
 pub mod lsm_iterator;
 pub mod lsm_storage;
+pub mod manifest;
 pub mod mem_table;
+pub mod metrics;
+pub mod negative_cache;
+pub mod prelude;
+pub mod quarantine;
+pub mod retention;
+pub mod sequence;
+mod storage;
 pub mod table;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod value_handle;
 pub mod wal;
 
 #[cfg(test)]
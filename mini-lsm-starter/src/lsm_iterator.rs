@@ -1,24 +1,345 @@
-use anyhow::Result;
-use bytes::Bytes;
+use std::ops::Bound;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Weak};
+use std::time::Instant;
+
+use anyhow::{bail, ensure, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use parking_lot::Mutex;
 
 use crate::{
+    cancel::CancellationToken,
+    error::LsmError,
     iterators::{
-        merge_iterator::MergeIterator, two_merge_iterator::TwoMergeIterator, StorageIterator,
+        concat_iterator::SstConcatIterator, merge_iterator::MergeIterator,
+        two_merge_iterator::TwoMergeIterator, EmptyIterator, StorageIterator,
     },
+    key::{InternalKey, ValueKind},
+    key_range::KeyRange,
+    manifest::DbId,
     mem_table::MemTableIterator,
-    table::SsTableIterator,
+    metrics::Metrics,
+    sequence::CommitSequence,
+    table::{SharedCacheFill, SharedDeadline, SharedReadaheadPeak, SsTableIterator},
 };
 
-type LsmIteratorInner =
-    TwoMergeIterator<MergeIterator<MemTableIterator>, MergeIterator<SsTableIterator>>;
+/// The memtables, then the L0 tables, then a run per level below L0, each preferred over what
+/// comes after it.
+type LsmIteratorInner = TwoMergeIterator<
+    MergeIterator<MemTableIterator>,
+    TwoMergeIterator<MergeIterator<SsTableIterator>, MergeIterator<SstConcatIterator>>,
+>;
+
+/// The ids of the SSTs a scan skipped, which grows as the scan reaches the tables of the levels
+/// below L0.
+pub(crate) type SharedSkipped = Arc<Mutex<Vec<usize>>>;
+
+/// How far the storage has moved on since an iterator took its snapshot.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SnapshotAge {
+    /// Writes committed since.
+    pub sequences_behind: u64,
+    /// Flushes and compactions completed since, each of which rewrote some of the data.
+    pub compactions_since: u64,
+}
+
+impl SnapshotAge {
+    /// Whether either count is past the one of `limit`.
+    pub fn exceeds(&self, limit: &SnapshotAge) -> bool {
+        self.sequences_behind > limit.sequences_behind
+            || self.compactions_since > limit.compactions_since
+    }
+}
+
+/// The counters of a storage at the time a snapshot was taken, and weak handles to read their
+/// current values without keeping the storage alive.
+pub(crate) struct SnapshotClock {
+    sequence: Weak<CommitSequence>,
+    metrics: Weak<Metrics>,
+    taken_at: (u64, u64),
+}
+
+impl SnapshotClock {
+    fn counters(sequence: &CommitSequence, metrics: &Metrics) -> (u64, u64) {
+        (
+            sequence.applied().0,
+            metrics.flushes() + metrics.compactions(),
+        )
+    }
+
+    pub fn start(sequence: &Arc<CommitSequence>, metrics: &Arc<Metrics>) -> Self {
+        Self::since(sequence, metrics, Self::counters(sequence, metrics))
+    }
+
+    /// A clock of a snapshot taken earlier, when the counters were `taken_at`.
+    pub fn since(
+        sequence: &Arc<CommitSequence>,
+        metrics: &Arc<Metrics>,
+        taken_at: (u64, u64),
+    ) -> Self {
+        Self {
+            sequence: Arc::downgrade(sequence),
+            metrics: Arc::downgrade(metrics),
+            taken_at,
+        }
+    }
+
+    pub fn taken_at(&self) -> (u64, u64) {
+        self.taken_at
+    }
+
+    /// The age of the snapshot, or zero once the storage is gone.
+    pub fn age(&self) -> SnapshotAge {
+        match (self.sequence.upgrade(), self.metrics.upgrade()) {
+            (Some(sequence), Some(metrics)) => {
+                let (sequences, compactions) = Self::counters(&sequence, &metrics);
+                SnapshotAge {
+                    sequences_behind: sequences.saturating_sub(self.taken_at.0),
+                    compactions_since: compactions.saturating_sub(self.taken_at.1),
+                }
+            }
+            _ => SnapshotAge::default(),
+        }
+    }
+}
+
+/// What a scan left out.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScanStats {
+    /// Ids of the SSTs skipped under [`ReadOptions::skip_unreadable`] because their iterator
+    /// could not be created. The tables below L0 are only opened as the scan reaches them, so
+    /// they show up then.
+    ///
+    /// [`ReadOptions::skip_unreadable`]: crate::lsm_storage::ReadOptions::skip_unreadable
+    pub skipped_tables: Vec<usize>,
+    /// Whether the scan read more than
+    /// [`LsmStorageOptions::scan_fill_cache_limit`] from disk, and stopped adding the blocks
+    /// to the block cache.
+    ///
+    /// [`LsmStorageOptions::scan_fill_cache_limit`]: crate::lsm_storage::LsmStorageOptions::scan_fill_cache_limit
+    pub cache_fill_stopped: bool,
+    /// Deleted keys the scan moved over without yielding them.
+    pub tombstones_skipped: u64,
+    /// The most blocks the scan read from an SST at once, see
+    /// [`ReadOptions::max_readahead_blocks`]. 0 until it reads a block.
+    ///
+    /// [`ReadOptions::max_readahead_blocks`]: crate::lsm_storage::ReadOptions::max_readahead_blocks
+    pub max_readahead_blocks: usize,
+}
+
+/// The skipping of deleted keys checks the deadline and the cancellation every this many keys.
+const SKIP_CHECK_ENTRIES: usize = 256;
+
+#[cfg(test)]
+thread_local! {
+    /// Deleted keys skipped in a row by the last scan call on the current thread, be it the
+    /// creation of the scan or `LsmIterator::next`.
+    pub(crate) static LAST_NEXT_SKIPPED: std::cell::Cell<usize> = Default::default();
+}
 
 pub struct LsmIterator {
     iter: LsmIteratorInner,
+    stats: ScanStats,
+    /// The read deadline of the table iterators.
+    deadline: SharedDeadline,
+    /// The block cache insertions of the table iterators.
+    cache_fill: Option<SharedCacheFill>,
+    readahead_peak: Option<SharedReadaheadPeak>,
+    skipped: Option<SharedSkipped>,
+    clock: Option<SnapshotClock>,
+    max_staleness: Option<SnapshotAge>,
+    cancel: Option<CancellationToken>,
+    /// Yield the deletions too, as empty values, rather than skip them.
+    keep_tombstones: bool,
+    /// Deleted keys a call to `next` skips at most before failing with [`LsmError::Yielded`].
+    tombstone_budget: Option<usize>,
 }
 
 impl LsmIterator {
-    pub fn new(iter: LsmIteratorInner) -> Self {
-        Self { iter }
+    pub fn new(iter: LsmIteratorInner, deadline: SharedDeadline) -> Self {
+        Self {
+            iter,
+            stats: ScanStats::default(),
+            deadline,
+            cache_fill: None,
+            readahead_peak: None,
+            skipped: None,
+            clock: None,
+            max_staleness: None,
+            cancel: None,
+            keep_tombstones: false,
+            tombstone_budget: None,
+        }
+    }
+
+    /// Fail `next` with [`LsmError::Yielded`] once it skipped `budget` deleted keys in a row,
+    /// see [`ReadOptions::max_tombstones_per_next`].
+    ///
+    /// [`ReadOptions::max_tombstones_per_next`]: crate::lsm_storage::ReadOptions::max_tombstones_per_next
+    pub(crate) fn with_tombstone_budget(self, tombstone_budget: Option<usize>) -> Self {
+        assert!(
+            tombstone_budget != Some(0),
+            "a call to next must be allowed to skip at least one deleted key"
+        );
+        Self {
+            tombstone_budget,
+            ..self
+        }
+    }
+
+    /// Move past the deleted keys the scan starts on. There is no call to `next` to give back
+    /// control to yet, so it skips them all, checking only the deadline and the cancellation.
+    pub(crate) fn skip_leading_tombstones(mut self) -> Result<Self> {
+        self.skip_tombstones(None)?;
+        Ok(self)
+    }
+
+    /// Yield the newest version of every key, deletions included, see
+    /// [`LsmStorage::scan_raw`](crate::lsm_storage::LsmStorage::scan_raw).
+    pub(crate) fn with_tombstones(self, keep_tombstones: bool) -> Self {
+        Self {
+            keep_tombstones,
+            ..self
+        }
+    }
+
+    pub(crate) fn with_skipped(mut self, skipped: SharedSkipped) -> Self {
+        self.skipped = Some(skipped);
+        self.sync_skipped();
+        self
+    }
+
+    /// Take in the tables skipped since the last call, counting them in the metrics once the
+    /// iterator tracks the storage they belong to.
+    fn sync_skipped(&mut self) {
+        let skipped = match &self.skipped {
+            Some(skipped) => skipped.lock(),
+            None => return,
+        };
+        let new = &skipped[self.stats.skipped_tables.len()..];
+        if new.is_empty() {
+            return;
+        }
+        if let Some(metrics) = self
+            .clock
+            .as_ref()
+            .and_then(|clock| clock.metrics.upgrade())
+        {
+            metrics.record_unreadable_tables_skipped(new.len() as u64);
+        }
+        self.stats.skipped_tables.extend_from_slice(new);
+    }
+
+    pub(crate) fn with_cache_fill(mut self, cache_fill: Option<SharedCacheFill>) -> Self {
+        self.cache_fill = cache_fill;
+        self.sync_cache_fill();
+        self
+    }
+
+    fn sync_cache_fill(&mut self) {
+        if let Some(fill) = &self.cache_fill {
+            self.stats.cache_fill_stopped = fill.stopped();
+        }
+    }
+
+    pub(crate) fn with_readahead_peak(mut self, peak: SharedReadaheadPeak) -> Self {
+        self.readahead_peak = Some(peak);
+        self.sync_readahead_peak();
+        self
+    }
+
+    fn sync_readahead_peak(&mut self) {
+        if let Some(peak) = &self.readahead_peak {
+            self.stats.max_readahead_blocks = peak.load(Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> &ScanStats {
+        &self.stats
+    }
+
+    /// Fail `next` with [`LsmError::Cancelled`] once `cancel` is cancelled.
+    pub(crate) fn with_cancel(self, cancel: Option<CancellationToken>) -> Self {
+        Self { cancel, ..self }
+    }
+
+    /// Track the age of the snapshot with `clock`, failing `next` with
+    /// [`LsmError::SnapshotTooOld`] once it exceeds `max_staleness`.
+    pub(crate) fn set_clock(&mut self, clock: SnapshotClock, max_staleness: Option<SnapshotAge>) {
+        self.clock = Some(clock);
+        self.max_staleness = max_staleness;
+    }
+
+    /// How far the storage has moved on since the iterator was created. Zero for an iterator
+    /// that does not track it.
+    pub fn snapshot_age(&self) -> SnapshotAge {
+        self.clock
+            .as_ref()
+            .map_or_else(SnapshotAge::default, SnapshotClock::age)
+    }
+
+    /// Move the read deadline, e.g. to resume a scan that failed with
+    /// [`LsmError::DeadlineExceeded`](crate::error::LsmError::DeadlineExceeded).
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        *self.deadline.lock() = deadline;
+    }
+}
+
+impl LsmIterator {
+    fn advance(&mut self) -> Result<()> {
+        if self.keep_tombstones {
+            return self.iter.next();
+        }
+        // a call that failed while skipping deletions is left on one, and resumes the skipping
+        if !self.iter.value().is_empty() {
+            self.iter.next()?;
+        }
+        self.skip_tombstones(self.tombstone_budget)
+    }
+
+    /// Skip deleted keys up to the next live one, or fail with [`LsmError::Yielded`] after
+    /// `budget` of them, staying on the deleted key the next call carries on from.
+    fn skip_tombstones(&mut self, budget: Option<usize>) -> Result<()> {
+        if self.keep_tombstones {
+            return Ok(());
+        }
+        let mut skipped = 0;
+        let result = loop {
+            if !self.iter.is_valid() || !self.iter.value().is_empty() {
+                break Ok(());
+            }
+            if budget == Some(skipped) {
+                break Err(LsmError::Yielded {
+                    tombstones_skipped: skipped,
+                }
+                .into());
+            }
+            if skipped > 0 && skipped % SKIP_CHECK_ENTRIES == 0 {
+                if let Err(err) = self.check_interrupted() {
+                    break Err(err);
+                }
+            }
+            if let Err(err) = self.iter.next() {
+                break Err(err);
+            }
+            skipped += 1;
+        };
+        self.stats.tombstones_skipped += skipped as u64;
+        #[cfg(test)]
+        LAST_NEXT_SKIPPED.with(|last| last.set(skipped));
+        result
+    }
+
+    /// Whether the scan was cancelled or ran past its deadline, which the table iterators only
+    /// check before reading a block, and so never while the deletions come from the memtables.
+    fn check_interrupted(&self) -> Result<()> {
+        if let Some(cancel) = &self.cancel {
+            cancel.check()?;
+        }
+        match *self.deadline.lock() {
+            Some(deadline) if Instant::now() >= deadline => Err(LsmError::DeadlineExceeded.into()),
+            _ => Ok(()),
+        }
     }
 }
 
@@ -36,11 +357,24 @@ impl StorageIterator for LsmIterator {
     }
 
     fn next(&mut self) -> Result<()> {
-        self.iter.next()?;
-        while self.iter.is_valid() && self.iter.value().is_empty() {
-            self.iter.next()?;
+        if let Some(cancel) = &self.cancel {
+            cancel.check()?;
         }
-        Ok(())
+        if let Some(limit) = &self.max_staleness {
+            let age = self.snapshot_age();
+            if age.exceeds(limit) {
+                return Err(LsmError::SnapshotTooOld {
+                    sequences_behind: age.sequences_behind,
+                    compactions_since: age.compactions_since,
+                }
+                .into());
+            }
+        }
+        let moved = self.advance();
+        self.sync_cache_fill();
+        self.sync_readahead_peak();
+        self.sync_skipped();
+        moved
     }
 }
 
@@ -56,6 +390,18 @@ impl<I: StorageIterator> FusedIterator<I> {
     }
 }
 
+impl FusedIterator<LsmIterator> {
+    /// See [`LsmIterator::snapshot_age`].
+    pub fn snapshot_age(&self) -> SnapshotAge {
+        self.iter.snapshot_age()
+    }
+
+    /// See [`LsmIterator::stats`].
+    pub fn stats(&self) -> &ScanStats {
+        self.iter.stats()
+    }
+}
+
 impl<I: StorageIterator> StorageIterator for FusedIterator<I> {
     fn is_valid(&self) -> bool {
         self.iter.is_valid()
@@ -73,3 +419,308 @@ impl<I: StorageIterator> StorageIterator for FusedIterator<I> {
         self.iter.next()
     }
 }
+
+/// What an entry of a raw scan does to its key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntryOp {
+    Put(Bytes),
+    Delete,
+}
+
+/// The newest version of a key, as [`RawScanIter`] yields it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawEntry {
+    pub key: Bytes,
+    pub op: EntryOp,
+    /// Entries carry no sequence number yet, so this is the commit sequence the scan started
+    /// from: the write of the entry has a sequence no later than it.
+    pub seq: u64,
+}
+
+impl RawEntry {
+    /// The entry as an internal key, tagged with its sequence and kind.
+    pub fn internal_key(&self) -> InternalKey {
+        let kind = match self.op {
+            EntryOp::Put(_) => ValueKind::Put,
+            EntryOp::Delete => ValueKind::Delete,
+        };
+        InternalKey::new(self.key.clone(), self.seq, kind)
+    }
+}
+
+/// The iterator returned by [`LsmStorage::scan_raw`](crate::lsm_storage::LsmStorage::scan_raw):
+/// a scan that reports the keys deleted in its range rather than skip them. Stops after the
+/// first error.
+pub struct RawScanIter {
+    iter: ScanIter,
+    seq: u64,
+    /// Whether the scan moved past the current entry, which it does on the next call.
+    started: bool,
+    failed: bool,
+}
+
+impl RawScanIter {
+    pub(crate) fn new(iter: ScanIter) -> Self {
+        let seq = iter.token.taken_at.0;
+        Self {
+            iter,
+            seq,
+            started: false,
+            failed: false,
+        }
+    }
+
+    /// See [`LsmIterator::stats`].
+    pub fn stats(&self) -> &ScanStats {
+        self.iter.stats()
+    }
+}
+
+impl Iterator for RawScanIter {
+    type Item = Result<RawEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        if std::mem::replace(&mut self.started, true) {
+            if let Err(err) = self.iter.next() {
+                self.failed = true;
+                return Some(Err(err));
+            }
+        }
+        if !self.iter.is_valid() {
+            return None;
+        }
+        let value = self.iter.value();
+        let op = match ValueKind::of_value(value) {
+            ValueKind::Delete => EntryOp::Delete,
+            ValueKind::Put => EntryOp::Put(value.clone()),
+        };
+        Some(Ok(RawEntry {
+            key: self.iter.key().clone(),
+            op,
+            seq: self.seq,
+        }))
+    }
+}
+
+/// Where a scan stopped, to carry on with
+/// [`LsmStorage::scan_resume`](crate::lsm_storage::LsmStorage::scan_resume) without holding
+/// the iterator in between, e.g. across the requests of a paginated API.
+///
+/// It holds the bounds of the scan, the last key the scan moved past and the counters of the
+/// snapshot the scan started from. Send it around as [`ResumeToken::encode`]d bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResumeToken {
+    /// Tells the scans of this open of the storage apart from the ones of earlier opens.
+    pub(crate) session: DbId,
+    pub(crate) taken_at: (u64, u64),
+    pub(crate) range: KeyRange,
+    pub(crate) last_key: Option<Bytes>,
+}
+
+const RESUME_TOKEN_VERSION: u8 = 1;
+
+impl ResumeToken {
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_u8(RESUME_TOKEN_VERSION);
+        buf.put_slice(self.session.as_bytes());
+        buf.put_u64(self.taken_at.0);
+        buf.put_u64(self.taken_at.1);
+        let (lower, upper) = self.range.bounds();
+        for bound in [lower, upper] {
+            match bound {
+                Bound::Unbounded => buf.put_u8(0),
+                Bound::Included(key) => put_key(&mut buf, 1, key),
+                Bound::Excluded(key) => put_key(&mut buf, 2, key),
+            }
+        }
+        match &self.last_key {
+            None => buf.put_u8(0),
+            Some(key) => put_key(&mut buf, 1, key),
+        }
+        buf.freeze()
+    }
+
+    pub fn decode(mut buf: &[u8]) -> Result<Self> {
+        ensure!(
+            buf.len() >= 33,
+            "resume token of {} bytes is too short",
+            buf.len()
+        );
+        let version = buf.get_u8();
+        ensure!(
+            version == RESUME_TOKEN_VERSION,
+            "unknown resume token version {}",
+            version
+        );
+        let mut session = [0; 16];
+        buf.copy_to_slice(&mut session);
+        let taken_at = (buf.get_u64(), buf.get_u64());
+        let mut bounds = [Bound::Unbounded, Bound::Unbounded];
+        for bound in &mut bounds {
+            *bound = match get_key(&mut buf)? {
+                (0, _) => Bound::Unbounded,
+                (1, key) => Bound::Included(key),
+                (2, key) => Bound::Excluded(key),
+                (tag, _) => bail!("malformed resume token bound {}", tag),
+            };
+        }
+        let last_key = match get_key(&mut buf)? {
+            (0, _) => None,
+            (1, key) => Some(key),
+            (tag, _) => bail!("malformed resume token key {}", tag),
+        };
+        ensure!(
+            buf.is_empty(),
+            "{} trailing bytes in resume token",
+            buf.len()
+        );
+        let [lower, upper] = bounds;
+        Ok(Self {
+            session: DbId::from_bytes(session),
+            taken_at,
+            range: KeyRange::from_bounds(lower, upper),
+            last_key,
+        })
+    }
+
+    /// The last key the scan moved past, `None` if it never moved.
+    pub fn last_key(&self) -> Option<&Bytes> {
+        self.last_key.as_ref()
+    }
+}
+
+fn put_key(buf: &mut BytesMut, tag: u8, key: &[u8]) {
+    buf.put_u8(tag);
+    buf.put_u32(key.len() as u32);
+    buf.put_slice(key);
+}
+
+/// A tag, and the key following it unless the tag is 0.
+fn get_key(buf: &mut &[u8]) -> Result<(u8, Bytes)> {
+    ensure!(buf.has_remaining(), "truncated resume token");
+    let tag = buf.get_u8();
+    if tag == 0 {
+        return Ok((tag, Bytes::new()));
+    }
+    ensure!(buf.remaining() >= 4, "truncated resume token");
+    let len = buf.get_u32() as usize;
+    ensure!(buf.remaining() >= len, "truncated resume token");
+    let key = Bytes::copy_from_slice(&buf[..len]);
+    buf.advance(len);
+    Ok((tag, key))
+}
+
+/// The iterator returned by the scans of an [`LsmStorage`](crate::lsm_storage::LsmStorage),
+/// hiding the iterators it is built of.
+pub struct ScanIter {
+    source: ScanSource,
+    /// Moves along with the scan, see [`ScanIter::resume_token`].
+    token: ResumeToken,
+}
+
+enum ScanSource {
+    Lsm(Box<FusedIterator<LsmIterator>>),
+    /// A range that cannot hold any key, which is not worth building the iterators for.
+    Empty(EmptyIterator),
+}
+
+impl ScanIter {
+    pub(crate) fn new(iter: FusedIterator<LsmIterator>, token: ResumeToken) -> Self {
+        Self {
+            source: ScanSource::Lsm(Box::new(iter)),
+            token,
+        }
+    }
+
+    pub(crate) fn empty(token: ResumeToken) -> Self {
+        Self {
+            source: ScanSource::Empty(EmptyIterator::new()),
+            token,
+        }
+    }
+
+    pub(crate) fn set_clock(&mut self, clock: SnapshotClock, max_staleness: Option<SnapshotAge>) {
+        if let ScanSource::Lsm(iter) = &mut self.source {
+            iter.iter.set_clock(clock, max_staleness)
+        }
+    }
+
+    /// Where the scan is: resuming from the token yields the keys after the last one `next`
+    /// moved past, so a page ends with the key read before the last call to `next`.
+    pub fn resume_token(&self) -> ResumeToken {
+        self.token.clone()
+    }
+
+    /// See [`LsmIterator::snapshot_age`].
+    pub fn snapshot_age(&self) -> SnapshotAge {
+        match &self.source {
+            ScanSource::Lsm(iter) => iter.snapshot_age(),
+            ScanSource::Empty(_) => SnapshotAge::default(),
+        }
+    }
+
+    /// See [`LsmIterator::stats`].
+    pub fn stats(&self) -> &ScanStats {
+        static NOTHING_SKIPPED: ScanStats = ScanStats {
+            skipped_tables: Vec::new(),
+            cache_fill_stopped: false,
+            tombstones_skipped: 0,
+            max_readahead_blocks: 0,
+        };
+        match &self.source {
+            ScanSource::Lsm(iter) => iter.stats(),
+            ScanSource::Empty(_) => &NOTHING_SKIPPED,
+        }
+    }
+
+    /// See [`LsmIterator::set_deadline`].
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        if let ScanSource::Lsm(iter) = &mut self.source {
+            iter.iter.set_deadline(deadline)
+        }
+    }
+}
+
+impl StorageIterator for ScanIter {
+    fn is_valid(&self) -> bool {
+        match &self.source {
+            ScanSource::Lsm(iter) => iter.is_valid(),
+            ScanSource::Empty(iter) => iter.is_valid(),
+        }
+    }
+
+    fn key(&self) -> &Bytes {
+        match &self.source {
+            ScanSource::Lsm(iter) => iter.key(),
+            ScanSource::Empty(iter) => iter.key(),
+        }
+    }
+
+    fn value(&self) -> &Bytes {
+        match &self.source {
+            ScanSource::Lsm(iter) => iter.value(),
+            ScanSource::Empty(iter) => iter.value(),
+        }
+    }
+
+    fn next(&mut self) -> Result<()> {
+        let key = self.is_valid().then(|| self.key().clone());
+        let moved = match &mut self.source {
+            ScanSource::Lsm(iter) => iter.next(),
+            ScanSource::Empty(iter) => iter.next(),
+        };
+        // a call that yields did move past the key, onto the deletions after it
+        let yielded = matches!(
+            moved.as_ref().map_err(|err| err.downcast_ref::<LsmError>()),
+            Err(Some(LsmError::Yielded { .. }))
+        );
+        if key.is_some() && (moved.is_ok() || yielded) {
+            self.token.last_key = key;
+        }
+        moved
+    }
+}
@@ -0,0 +1,654 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::lsm_storage::LsmStorageOptions;
+
+/// Name of the manifest file in the database directory.
+pub const MANIFEST: &str = "MANIFEST";
+
+/// Version of the on-disk layout written by this build.
+pub const FORMAT_VERSION: u32 = 2;
+
+const HEADER_MAGIC: &str = "mini-lsm manifest";
+
+/// Identifies a database across restarts: a random (version 4) UUID, generated when the
+/// database is created and kept in the manifest header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DbId([u8; 16]);
+
+impl DbId {
+    pub fn generate() -> Self {
+        use std::collections::hash_map::RandomState;
+        use std::hash::{BuildHasher, Hash, Hasher};
+
+        // every RandomState is seeded afresh from the OS, so there is no need for a rand crate
+        let mut bytes = [0; 16];
+        for (idx, half) in bytes.chunks_mut(8).enumerate() {
+            let mut hasher = RandomState::new().build_hasher();
+            (idx, std::process::id(), std::time::SystemTime::now()).hash(&mut hasher);
+            half.copy_from_slice(&hasher.finish().to_le_bytes());
+        }
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+
+    /// The first 8 hex digits, enough to tell the databases of a process apart in thread names.
+    pub fn short(&self) -> String {
+        self.to_string()[..8].to_string()
+    }
+}
+
+impl std::fmt::Display for DbId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (idx, byte) in self.0.iter().enumerate() {
+            if matches!(idx, 4 | 6 | 8 | 10) {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for DbId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let hex = s.replace('-', "");
+        if hex.len() != 32 || s.len() != 36 {
+            bail!("malformed database id {:?}", s);
+        }
+        let mut bytes = [0; 16];
+        for (idx, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16)
+                .with_context(|| format!("malformed database id {:?}", s))?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+/// The options a database was written with, recorded in the manifest header so that a reopen
+/// with incompatible options fails instead of misreading the data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OptionsFingerprint {
+    pub comparator: String,
+    pub checksum: String,
+    pub format_version: u32,
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+    pub block_cache_capacity: u64,
+    /// Missing from the manifests written before entry checksums, which had none.
+    pub entry_checksums: bool,
+}
+
+impl OptionsFingerprint {
+    pub fn of(options: &LsmStorageOptions) -> Self {
+        Self {
+            comparator: options.comparator.clone(),
+            checksum: checksum_type().to_string(),
+            format_version: FORMAT_VERSION,
+            max_key_size: options.max_key_size,
+            max_value_size: options.max_value_size,
+            block_cache_capacity: options.block_cache_capacity,
+            entry_checksums: options.entry_checksums,
+        }
+    }
+
+    /// Encode the fingerprint as the manifest header: a magic line, `key=value` lines and a
+    /// blank line that ends the header.
+    pub fn encode(&self) -> String {
+        format!("{}\n{}\n", HEADER_MAGIC, self.encode_fields())
+    }
+
+    fn encode_fields(&self) -> String {
+        format!(
+            "comparator={}\nchecksum={}\nformat_version={}\nmax_key_size={}\nmax_value_size={}\nblock_cache_capacity={}\nentry_checksums={}\n",
+            self.comparator,
+            self.checksum,
+            self.format_version,
+            self.max_key_size,
+            self.max_value_size,
+            self.block_cache_capacity,
+            self.entry_checksums,
+        )
+    }
+
+    pub fn decode(header: &str) -> Result<Self> {
+        let fields = header_fields(header)?;
+        let field = |key: &str| {
+            fields
+                .get(key)
+                .copied()
+                .ok_or_else(|| anyhow!("manifest header is missing {}", key))
+        };
+
+        Ok(Self {
+            comparator: field("comparator")?.to_string(),
+            checksum: field("checksum")?.to_string(),
+            format_version: field("format_version")?.parse()?,
+            max_key_size: field("max_key_size")?.parse()?,
+            max_value_size: field("max_value_size")?.parse()?,
+            block_cache_capacity: field("block_cache_capacity")?.parse()?,
+            entry_checksums: match fields.get("entry_checksums") {
+                Some(value) => value.parse()?,
+                None => false,
+            },
+        })
+    }
+
+    /// Check that a database written with `self` can be opened with `new`. Incompatible changes
+    /// are errors, format changes are errors unless `allow_format_change` is set, and the
+    /// benign changes that remain are returned as warnings.
+    pub fn check(&self, new: &Self, allow_format_change: bool) -> Result<Vec<String>> {
+        if self.comparator != new.comparator {
+            bail!(
+                "comparator mismatch: the database was created with {:?} but is opened with {:?}",
+                self.comparator,
+                new.comparator
+            );
+        }
+
+        let mut format_changes = vec![];
+        if self.checksum != new.checksum {
+            format_changes.push(format!(
+                "checksum type changed from {:?} to {:?}",
+                self.checksum, new.checksum
+            ));
+        }
+        if self.entry_checksums != new.entry_checksums {
+            format_changes.push(format!(
+                "entry checksums turned {}",
+                if new.entry_checksums { "on" } else { "off" }
+            ));
+        }
+        if self.format_version != new.format_version {
+            format_changes.push(format!(
+                "format version changed from {} to {}",
+                self.format_version, new.format_version
+            ));
+        }
+        if new.max_key_size < self.max_key_size {
+            format_changes.push(format!(
+                "max_key_size shrank from {} to {}",
+                self.max_key_size, new.max_key_size
+            ));
+        }
+        if new.max_value_size < self.max_value_size {
+            format_changes.push(format!(
+                "max_value_size shrank from {} to {}",
+                self.max_value_size, new.max_value_size
+            ));
+        }
+        if !format_changes.is_empty() && !allow_format_change {
+            bail!(
+                "{}; set allow_format_change to migrate the database",
+                format_changes.join(", ")
+            );
+        }
+
+        let mut warnings = format_changes;
+        if new.max_key_size > self.max_key_size {
+            warnings.push(format!(
+                "max_key_size grew from {} to {}",
+                self.max_key_size, new.max_key_size
+            ));
+        }
+        if new.max_value_size > self.max_value_size {
+            warnings.push(format!(
+                "max_value_size grew from {} to {}",
+                self.max_value_size, new.max_value_size
+            ));
+        }
+        if self.block_cache_capacity != new.block_cache_capacity {
+            warnings.push(format!(
+                "block cache capacity changed from {} to {}",
+                self.block_cache_capacity, new.block_cache_capacity
+            ));
+        }
+
+        Ok(warnings)
+    }
+}
+
+impl std::fmt::Display for OptionsFingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let fields = self.encode_fields();
+        write!(f, "{}", fields.trim_end().replace('\n', " "))
+    }
+}
+
+/// The `key=value` lines of a manifest header.
+fn header_fields(header: &str) -> Result<HashMap<&str, &str>> {
+    let mut lines = header.lines();
+    if lines.next() != Some(HEADER_MAGIC) {
+        bail!("not a manifest");
+    }
+
+    let mut fields = HashMap::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("malformed manifest header line {:?}", line))?;
+        fields.insert(key, value);
+    }
+    Ok(fields)
+}
+
+fn checksum_type() -> &'static str {
+    if cfg!(feature = "checksum") {
+        "crc32"
+    } else {
+        "none"
+    }
+}
+
+/// Read the options fingerprint from the manifest in `dir`, if there is one.
+pub fn read_header(dir: &Path) -> Result<Option<OptionsFingerprint>> {
+    let path = dir.join(MANIFEST);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = std::fs::read_to_string(&path)?;
+    OptionsFingerprint::decode(&content)
+        .with_context(|| format!("failed to read {}", path.display()))
+        .map(Some)
+}
+
+/// Read the database id from the manifest in `dir`. `None` if there is no manifest, or if it
+/// was written before databases had ids.
+pub fn read_db_id(dir: &Path) -> Result<Option<DbId>> {
+    let path = dir.join(MANIFEST);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = std::fs::read_to_string(&path)?;
+    let fields =
+        header_fields(&content).with_context(|| format!("failed to read {}", path.display()))?;
+    fields.get("db_id").map(|id| id.parse()).transpose()
+}
+
+/// Replace the manifest header in `dir` with `db_id` and `fingerprint`, keeping whatever
+/// follows it.
+pub fn write_header(dir: &Path, db_id: &DbId, fingerprint: &OptionsFingerprint) -> Result<()> {
+    let body = match std::fs::read_to_string(dir.join(MANIFEST)) {
+        Ok(content) => content
+            .split_once("\n\n")
+            .map(|(_, body)| body.to_string())
+            .unwrap_or_default(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.into()),
+    };
+    replace(dir, db_id, fingerprint, &body)
+}
+
+/// Replace the manifest in `dir` with a header of `db_id` and `fingerprint`, and a body
+/// recording that the tables `(level, id)` are live, in one go: a crash leaves either the old
+/// manifest or the new one.
+pub fn write_with_tables(
+    dir: &Path,
+    db_id: &DbId,
+    fingerprint: &OptionsFingerprint,
+    tables: impl IntoIterator<Item = (usize, usize)>,
+) -> Result<()> {
+    replace(dir, db_id, fingerprint, &table_records(tables))
+}
+
+fn table_records(tables: impl IntoIterator<Item = (usize, usize)>) -> String {
+    let records = tables
+        .into_iter()
+        .map(|(level, id)| ManifestRecord::AddTable { level, id });
+    encode_records(records)
+}
+
+fn encode_records(records: impl IntoIterator<Item = ManifestRecord>) -> String {
+    let mut encoded = String::new();
+    for record in records {
+        encoded.push_str(&record.to_string());
+        encoded.push('\n');
+    }
+    encoded
+}
+
+/// Write the manifest of `dir` to a temporary file, then rename it over the manifest.
+fn replace(dir: &Path, db_id: &DbId, fingerprint: &OptionsFingerprint, body: &str) -> Result<()> {
+    let path = dir.join(MANIFEST);
+    let tmp = dir.join(format!("{}.tmp", MANIFEST));
+    let mut file = std::fs::File::create(&tmp)?;
+    let header = format!(
+        "{}\ndb_id={}\n{}\n",
+        HEADER_MAGIC,
+        db_id,
+        fingerprint.encode_fields()
+    );
+    file.write_all(header.as_bytes())?;
+    file.write_all(body.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+/// A change to the tables of the database, one line of the manifest body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ManifestRecord {
+    /// Table `id` went live at `level`, written by a flush, a compaction or an adoption.
+    AddTable { level: usize, id: usize },
+    /// Table `id` was compacted or merged away.
+    RemoveTable { id: usize },
+    /// The storage was closed with `close`, leaving `wal_bytes` of write-ahead log. Only
+    /// meaningful as the last record: the next open drops it, so that a crash after that open
+    /// is told apart from a clean shutdown.
+    CleanShutdown { wal_bytes: u64 },
+}
+
+impl std::fmt::Display for ManifestRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AddTable { level, id } => write!(f, "sst level={} id={}", level, id),
+            Self::RemoveTable { id } => write!(f, "remove id={}", id),
+            Self::CleanShutdown { wal_bytes } => {
+                write!(f, "clean shutdown wal_bytes={}", wal_bytes)
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for ManifestRecord {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let add = s
+            .strip_prefix("sst level=")
+            .and_then(|rest| rest.split_once(" id="))
+            .and_then(|(level, id)| {
+                Some(Self::AddTable {
+                    level: level.parse().ok()?,
+                    id: id.parse().ok()?,
+                })
+            });
+        let remove = || {
+            s.strip_prefix("remove id=").and_then(|id| {
+                Some(Self::RemoveTable {
+                    id: id.parse().ok()?,
+                })
+            })
+        };
+        let clean_shutdown = || {
+            s.strip_prefix("clean shutdown wal_bytes=")
+                .and_then(|wal_bytes| {
+                    Some(Self::CleanShutdown {
+                        wal_bytes: wal_bytes.parse().ok()?,
+                    })
+                })
+        };
+        add.or_else(remove)
+            .or_else(clean_shutdown)
+            .ok_or_else(|| anyhow!("malformed manifest record {:?}", s))
+    }
+}
+
+/// Record in the manifest body of `dir` that the tables `(level, id)` are live, one
+/// `sst level={} id={}` line each. The header must have been written.
+pub fn append_tables(dir: &Path, tables: impl IntoIterator<Item = (usize, usize)>) -> Result<()> {
+    append(dir, &table_records(tables))
+}
+
+/// Append `records` to the manifest body of `dir`, and sync it before returning. The header
+/// must have been written.
+pub fn append_records(dir: &Path, records: impl IntoIterator<Item = ManifestRecord>) -> Result<()> {
+    append(dir, &encode_records(records))
+}
+
+fn append(dir: &Path, records: &str) -> Result<()> {
+    if records.is_empty() {
+        return Ok(());
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(dir.join(MANIFEST))?;
+    file.write_all(records.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// The records of the manifest body of `dir`, in the order they were appended. A last line
+/// without its newline is a record a crash cut short, and is left out.
+pub fn read_records(dir: &Path) -> Result<Vec<ManifestRecord>> {
+    let path = dir.join(MANIFEST);
+    let content = std::fs::read_to_string(&path)?;
+    let body = content.split_once("\n\n").map_or("", |(_, body)| body);
+    let complete = body.rfind('\n').map_or("", |end| &body[..end]);
+    complete
+        .lines()
+        .map(|line| {
+            line.parse()
+                .with_context(|| format!("failed to read {}", path.display()))
+        })
+        .collect()
+}
+
+/// The tables the manifest of `dir` records, once its records are replayed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecordedTables {
+    /// The level of each table recorded as live.
+    pub live: BTreeMap<usize, usize>,
+    /// The tables recorded as removed, whose files may outlive the record.
+    pub removed: BTreeSet<usize>,
+}
+
+impl RecordedTables {
+    pub fn replay(records: impl IntoIterator<Item = ManifestRecord>) -> Self {
+        let mut tables = Self::default();
+        for record in records {
+            match record {
+                ManifestRecord::AddTable { level, id } => {
+                    tables.live.insert(id, level);
+                }
+                ManifestRecord::RemoveTable { id } => {
+                    tables.live.remove(&id);
+                    tables.removed.insert(id);
+                }
+                ManifestRecord::CleanShutdown { .. } => {}
+            }
+        }
+        tables
+    }
+
+    /// The highest table id recorded, live or removed.
+    pub fn max_id(&self) -> Option<usize> {
+        let live = self.live.keys().next_back();
+        live.max(self.removed.iter().next_back()).copied()
+    }
+}
+
+/// Replay the manifest of `dir`. A directory without one records no table.
+pub fn read_recorded(dir: &Path) -> Result<RecordedTables> {
+    if !dir.join(MANIFEST).exists() {
+        return Ok(RecordedTables::default());
+    }
+    Ok(RecordedTables::replay(read_records(dir)?))
+}
+
+/// The tables recorded as live in the manifest body of `dir`, as `(level, id)` in the order they
+/// were recorded.
+pub fn read_tables(dir: &Path) -> Result<Vec<(usize, usize)>> {
+    let records = read_records(dir)?;
+    let removed = RecordedTables::replay(records.iter().copied()).removed;
+    Ok(records
+        .into_iter()
+        .filter_map(|record| match record {
+            ManifestRecord::AddTable { level, id } if !removed.contains(&id) => Some((level, id)),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Cut the record a crash left torn off the end of the manifest of `dir`, so that the records
+/// appended next start on a line of their own. Returns whether there was one.
+pub fn trim_torn_record(dir: &Path) -> Result<bool> {
+    let path = dir.join(MANIFEST);
+    let content = std::fs::read(&path)?;
+    let len = content
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .map_or(0, |end| end + 1);
+    if len == content.len() {
+        return Ok(false);
+    }
+    let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+    file.set_len(len as u64)?;
+    file.sync_all()?;
+    Ok(true)
+}
+
+/// If the last record of the manifest of `dir` is a [`ManifestRecord::CleanShutdown`], cut it
+/// off and return the bytes of write-ahead log it recorded.
+pub fn take_clean_shutdown(dir: &Path) -> Result<Option<u64>> {
+    let path = dir.join(MANIFEST);
+    let content = std::fs::read_to_string(&path)?;
+    let body = match content.strip_suffix('\n') {
+        Some(body) => body,
+        None => return Ok(None),
+    };
+    let (len, last) = match body.rfind('\n') {
+        Some(end) => (end + 1, &body[end + 1..]),
+        None => return Ok(None),
+    };
+    let wal_bytes = match last.parse() {
+        Ok(ManifestRecord::CleanShutdown { wal_bytes }) => wal_bytes,
+        _ => return Ok(None),
+    };
+    let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+    file.set_len(len as u64)?;
+    file.sync_all()?;
+    Ok(Some(wal_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint() -> OptionsFingerprint {
+        OptionsFingerprint::of(&LsmStorageOptions::default())
+    }
+
+    #[test]
+    fn test_fingerprint_roundtrip() {
+        let fingerprint = fingerprint();
+        assert_eq!(
+            OptionsFingerprint::decode(&fingerprint.encode()).unwrap(),
+            fingerprint
+        );
+        assert!(OptionsFingerprint::decode("garbage\n").is_err());
+    }
+
+    #[test]
+    fn test_db_id() -> Result<()> {
+        let id = DbId::generate();
+        assert_ne!(id, DbId::generate());
+        let text = id.to_string();
+        assert_eq!(text.len(), 36);
+        assert_eq!(&text[14..15], "4");
+        assert_eq!(text.parse::<DbId>()?, id);
+        assert!(text.starts_with(&id.short()));
+        assert!("not-a-uuid".parse::<DbId>().is_err());
+        assert!(text.replace('-', "").parse::<DbId>().is_err());
+
+        let dir = tempfile::tempdir()?;
+        assert_eq!(read_db_id(dir.path())?, None);
+        write_header(dir.path(), &id, &fingerprint())?;
+        assert_eq!(read_db_id(dir.path())?, Some(id));
+        // the fingerprint reads past the id
+        assert_eq!(read_header(dir.path())?, Some(fingerprint()));
+
+        // the tables recorded after the header are kept when it is written again
+        append_tables(dir.path(), [(0, 1), (0, 3)])?;
+        append_tables(dir.path(), [(1, 2)])?;
+        write_header(dir.path(), &id, &fingerprint())?;
+        assert_eq!(read_tables(dir.path())?, vec![(0, 1), (0, 3), (1, 2)]);
+        assert_eq!(read_header(dir.path())?, Some(fingerprint()));
+        // and replaced by the ones written along with it
+        write_with_tables(dir.path(), &id, &fingerprint(), [(0, 4)])?;
+        assert_eq!(read_tables(dir.path())?, vec![(0, 4)]);
+        assert_eq!(read_db_id(dir.path())?, Some(id));
+        Ok(())
+    }
+
+    #[test]
+    fn test_records_replay() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        write_header(dir.path(), &DbId::generate(), &fingerprint())?;
+        assert_eq!(read_recorded(dir.path())?, RecordedTables::default());
+        append_tables(dir.path(), [(0, 1), (0, 2)])?;
+        append_records(
+            dir.path(),
+            [
+                ManifestRecord::AddTable { level: 1, id: 3 },
+                ManifestRecord::RemoveTable { id: 1 },
+                ManifestRecord::RemoveTable { id: 2 },
+            ],
+        )?;
+        assert_eq!(read_tables(dir.path())?, vec![(1, 3)]);
+        let recorded = read_recorded(dir.path())?;
+        assert_eq!(recorded.live, BTreeMap::from([(3, 1)]));
+        assert_eq!(recorded.removed, BTreeSet::from([1, 2]));
+        assert_eq!(recorded.max_id(), Some(3));
+
+        // a torn record is left out until it is trimmed off
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(MANIFEST))?;
+        file.write_all(b"remove id=")?;
+        assert_eq!(read_tables(dir.path())?, vec![(1, 3)]);
+        assert!(trim_torn_record(dir.path())?);
+        assert!(!trim_torn_record(dir.path())?);
+        append_tables(dir.path(), [(0, 4)])?;
+        assert_eq!(read_tables(dir.path())?, vec![(1, 3), (0, 4)]);
+
+        // the clean shutdown marker only counts as the last record, and is taken once
+        append_records(dir.path(), [ManifestRecord::CleanShutdown { wal_bytes: 8 }])?;
+        assert_eq!(read_tables(dir.path())?, vec![(1, 3), (0, 4)]);
+        assert_eq!(take_clean_shutdown(dir.path())?, Some(8));
+        assert_eq!(take_clean_shutdown(dir.path())?, None);
+        append_records(dir.path(), [ManifestRecord::CleanShutdown { wal_bytes: 0 }])?;
+        append_tables(dir.path(), [(0, 5)])?;
+        assert_eq!(take_clean_shutdown(dir.path())?, None);
+        assert_eq!(read_tables(dir.path())?, vec![(1, 3), (0, 4), (0, 5)]);
+
+        assert!("sst level=x id=1".parse::<ManifestRecord>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint_check() {
+        let old = fingerprint();
+        assert!(old.check(&old, false).unwrap().is_empty());
+
+        let new = OptionsFingerprint {
+            format_version: FORMAT_VERSION + 1,
+            ..old.clone()
+        };
+        let err = old.check(&new, false).unwrap_err().to_string();
+        assert!(err.contains("allow_format_change"), "{}", err);
+        assert_eq!(old.check(&new, true).unwrap().len(), 1);
+
+        let new = OptionsFingerprint {
+            max_key_size: old.max_key_size / 2,
+            ..old.clone()
+        };
+        assert!(old.check(&new, false).is_err());
+        assert_eq!(new.check(&old, false).unwrap().len(), 1);
+    }
+}
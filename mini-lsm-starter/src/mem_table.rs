@@ -2,6 +2,7 @@ use std::ops::Bound;
 use std::sync::Arc;
 
 use anyhow::Result;
+use arc_swap::ArcSwap;
 use bytes::Bytes;
 use crossbeam_skiplist::SkipMap;
 use ouroboros::self_referencing;
@@ -9,25 +10,34 @@ use ouroboros::self_referencing;
 use crate::iterators::StorageIterator;
 use crate::table::SsTableBuilder;
 
+/// The entries of a mem-table. A value is swapped in place when its key is written again, as
+/// replacing the entry would leave the key missing for a moment.
+type Map = SkipMap<Bytes, ArcSwap<Bytes>>;
+
 /// A basic mem-table based on crossbeam-skiplist
 pub struct MemTable {
     // needs interior mutability
-    map: Arc<SkipMap<Bytes, Bytes>>,
+    map: Arc<Map>,
     size: std::sync::atomic::AtomicUsize,
+    /// The commit sequence of the latest write put, 0 if none.
+    max_seq: std::sync::atomic::AtomicU64,
 }
 
 impl MemTable {
     /// Create a new mem-table.
     pub fn create() -> Self {
         Self {
-            map: Arc::new(SkipMap::<Bytes, Bytes>::new()),
+            map: Arc::new(Map::new()),
             size: std::sync::atomic::AtomicUsize::new(0),
+            max_seq: std::sync::atomic::AtomicU64::new(0),
         }
     }
 
     /// Get a value by key.
     pub fn get(&self, key: &[u8]) -> Option<Bytes> {
-        self.map.get(key).map(|entry| entry.value().clone())
+        self.map
+            .get(key)
+            .map(|entry| entry.value().load().as_ref().clone())
     }
 
     pub fn size(&self) -> usize {
@@ -38,45 +48,179 @@ impl MemTable {
         self.map.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Record that the writes up to commit sequence `seq` were put, see `max_seq`.
+    pub fn note_seq(&self, seq: u64) {
+        self.max_seq
+            .fetch_max(seq, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// The commit sequence of the latest write put, which the table flushed from the
+    /// mem-table records.
+    pub fn max_seq(&self) -> u64 {
+        self.max_seq.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
     /// Put a key-value pair into the mem-table.
     pub fn put(&self, key: Bytes, value: Bytes) {
-        self.size
-            .fetch_add(key.len() + value.len(), std::sync::atomic::Ordering::SeqCst);
-        self.map.insert(key, value);
+        // account for the length prefixes as well, so that tombstones are not free
+        self.size.fetch_add(
+            2 + key.len() + 2 + value.len(),
+            std::sync::atomic::Ordering::SeqCst,
+        );
+        match self.map.get(&key) {
+            Some(entry) => entry.value().store(Arc::new(value)),
+            None => {
+                let entry = self
+                    .map
+                    .get_or_insert(key, ArcSwap::from_pointee(value.clone()));
+                // a writer racing to insert the key got there first
+                entry.value().store(Arc::new(value));
+            }
+        }
     }
 
     /// Get an iterator over a range of keys.
     pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> MemTableIterator {
-        let lower = lower.map(Bytes::copy_from_slice);
-        let upper = upper.map(Bytes::copy_from_slice);
+        self.scan_bytes(
+            lower.map(Bytes::copy_from_slice),
+            upper.map(Bytes::copy_from_slice),
+        )
+    }
 
-        let mut iter = MemTableIteratorBuilder {
+    /// Get an iterator over a range of keys, taking the bounds without copying them.
+    pub fn scan_bytes(&self, lower: Bound<Bytes>, upper: Bound<Bytes>) -> MemTableIterator {
+        scan_map(&self.map, lower, upper)
+    }
+
+    /// Flush the mem-table to SSTable.
+    pub fn to_sst(&self, block_size: usize) -> SsTableBuilder {
+        self.to_sst_with(SsTableBuilder::new(block_size))
+    }
+
+    /// Like `to_sst`, adding the entries to a builder the caller set up.
+    pub fn to_sst_with(&self, builder: SsTableBuilder) -> SsTableBuilder {
+        map_to_sst(&self.map, builder)
+    }
+
+    /// Turn the mem-table into a read-only handle, once it has been swapped out of the active
+    /// slot. Writers that still hold the old `Arc<MemTable>` keep writing into the same map, so
+    /// only freeze a mem-table nobody can reach for writing anymore.
+    pub fn freeze(self: Arc<Self>) -> Arc<FrozenMemTable> {
+        Arc::new(FrozenMemTable {
             map: self.map.clone(),
-            iter_builder: |map| map.range((lower, upper)),
-            curr: None,
+            size: self.size(),
+            max_seq: self.max_seq(),
+        })
+    }
+
+    /// Copy the entries into a read-only mem-table of their own, which writes after the call
+    /// do not reach. Cheaper than freezing for a small mem-table, as the copy leaves the active
+    /// one in place.
+    pub fn copy_frozen(&self) -> Arc<FrozenMemTable> {
+        let map = Map::new();
+        for entry in self.map.iter() {
+            let value = entry.value().load_full();
+            map.insert(entry.key().clone(), ArcSwap::new(value));
         }
-        .build();
-        let _ = iter.next(); // XXX: This is anti-pattern
-        iter
+        Arc::new(FrozenMemTable {
+            map: Arc::new(map),
+            size: self.size(),
+            max_seq: self.max_seq(),
+        })
+    }
+}
+
+/// An immutable mem-table waiting to be flushed. It can be read and flushed, but not written.
+pub struct FrozenMemTable {
+    map: Arc<Map>,
+    size: usize,
+    max_seq: u64,
+}
+
+impl FrozenMemTable {
+    /// Get a value by key.
+    pub fn get(&self, key: &[u8]) -> Option<Bytes> {
+        self.map
+            .get(key)
+            .map(|entry| entry.value().load().as_ref().clone())
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn max_seq(&self) -> u64 {
+        self.max_seq
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Get an iterator over a range of keys.
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> MemTableIterator {
+        self.scan_bytes(
+            lower.map(Bytes::copy_from_slice),
+            upper.map(Bytes::copy_from_slice),
+        )
+    }
+
+    /// Get an iterator over a range of keys, taking the bounds without copying them.
+    pub fn scan_bytes(&self, lower: Bound<Bytes>, upper: Bound<Bytes>) -> MemTableIterator {
+        scan_map(&self.map, lower, upper)
     }
 
     /// Flush the mem-table to SSTable.
     pub fn to_sst(&self, block_size: usize) -> SsTableBuilder {
-        let mut builder = SsTableBuilder::new(block_size);
-        self.map
-            .iter()
-            .for_each(|entry| builder.add(entry.key(), entry.value()));
-        builder
+        self.to_sst_with(SsTableBuilder::new(block_size))
+    }
+
+    /// Like `to_sst`, adding the entries to a builder the caller set up.
+    pub fn to_sst_with(&self, builder: SsTableBuilder) -> SsTableBuilder {
+        map_to_sst(&self.map, builder)
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The memtable iterators the current thread created.
+    pub(crate) static MEMTABLE_SCANS: std::cell::Cell<usize> = Default::default();
+}
+
+fn scan_map(map: &Arc<Map>, lower: Bound<Bytes>, upper: Bound<Bytes>) -> MemTableIterator {
+    #[cfg(test)]
+    MEMTABLE_SCANS.with(|scans| scans.set(scans.get() + 1));
+    let mut iter = MemTableIteratorBuilder {
+        map: map.clone(),
+        iter_builder: |map| map.range((lower, upper)),
+        curr: None,
     }
+    .build();
+    let _ = iter.next(); // XXX: This is anti-pattern
+    iter
+}
+
+fn map_to_sst(map: &Map, mut builder: SsTableBuilder) -> SsTableBuilder {
+    map.iter()
+        .for_each(|entry| builder.add(entry.key(), &entry.value().load()));
+    builder
 }
 
 type SkipMapRangeIter<'a> =
-    crossbeam_skiplist::map::Range<'a, Bytes, (Bound<Bytes>, Bound<Bytes>), Bytes, Bytes>;
+    crossbeam_skiplist::map::Range<'a, Bytes, (Bound<Bytes>, Bound<Bytes>), Bytes, ArcSwap<Bytes>>;
 
 /// An iterator over a range of `SkipMap`.
 #[self_referencing]
 pub struct MemTableIterator {
-    map: Arc<SkipMap<Bytes, Bytes>>,
+    map: Arc<Map>,
     #[borrows(map)]
     #[not_covariant]
     iter: SkipMapRangeIter<'this>,
@@ -103,7 +247,7 @@ impl StorageIterator for MemTableIterator {
             *fields.curr = fields
                 .iter
                 .next()
-                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .map(|entry| (entry.key().clone(), entry.value().load().as_ref().clone()))
         });
 
         Ok(())
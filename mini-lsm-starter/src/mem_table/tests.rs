@@ -97,3 +97,59 @@ fn test_memtable_iter() {
         assert!(!iter.is_valid());
     }
 }
+
+#[test]
+fn test_memtable_scan_bytes() {
+    use std::ops::Bound;
+    let memtable = MemTable::create();
+    memtable.put(__(b"key1"), __(b"value1"));
+    memtable.put(__(b"key2"), __(b"value2"));
+    memtable.put(__(b"key3"), __(b"value3"));
+
+    let mut by_slice = memtable.scan(Bound::Excluded(b"key1"), Bound::Included(b"key3"));
+    let mut by_bytes =
+        memtable.scan_bytes(Bound::Excluded(__(b"key1")), Bound::Included(__(b"key3")));
+    while by_slice.is_valid() {
+        assert!(by_bytes.is_valid());
+        assert_eq!(by_slice.key(), by_bytes.key());
+        assert_eq!(by_slice.value(), by_bytes.value());
+        by_slice.next().unwrap();
+        by_bytes.next().unwrap();
+    }
+    assert!(!by_bytes.is_valid());
+}
+
+#[test]
+fn test_memtable_freeze() {
+    use std::ops::Bound;
+    use std::sync::Arc;
+
+    let mut active = Arc::new(MemTable::create());
+    active.put(__(b"key1"), __(b"value1"));
+    active.put(__(b"key2"), __(b""));
+    let size = active.size();
+
+    let frozen = std::mem::replace(&mut active, Arc::new(MemTable::create())).freeze();
+    active.put(__(b"key3"), __(b"value3"));
+
+    assert_eq!(frozen.len(), 2);
+    assert_eq!(frozen.size(), size);
+    assert_eq!(&frozen.get(b"key1").unwrap()[..], b"value1");
+    assert_eq!(&frozen.get(b"key2").unwrap()[..], b"");
+    assert!(frozen.get(b"key3").is_none());
+
+    let mut iter = frozen.scan(Bound::Unbounded, Bound::Unbounded);
+    assert_eq!(iter.key(), &__(b"key1"));
+    iter.next().unwrap();
+    assert_eq!(iter.key(), &__(b"key2"));
+    iter.next().unwrap();
+    assert!(!iter.is_valid());
+
+    let dir = tempdir().unwrap();
+    let sst = frozen
+        .to_sst(128)
+        .build_for_test(dir.path().join("1.sst"))
+        .unwrap();
+    let iter = SsTableIterator::create_and_seek_to_first(sst.into()).unwrap();
+    assert_eq!(iter.key(), &__(b"key1"));
+}
@@ -0,0 +1,346 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use parking_lot::Mutex;
+
+use crate::compaction::CompactionSummary;
+use crate::wal::ReplayStats;
+
+/// Levels [`Metrics::level_io`] keeps apart: L0 to L6, and one for every level past them.
+pub const LEVEL_IO_SLOTS: usize = 8;
+
+/// What reads the blocks of a table, for [`Metrics::level_io`] to tell apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadKind {
+    Get,
+    Scan,
+}
+
+/// Bytes moved in and out of one level, see [`Metrics::level_io`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LevelIo {
+    /// Bytes of the blocks gets went through, whether the block cache held them or not.
+    pub get_bytes_read: u64,
+    /// Bytes of the blocks scans went through, likewise.
+    pub scan_bytes_read: u64,
+    /// Size of the tables compactions took as input.
+    pub compaction_bytes_read: u64,
+    /// Size of the tables flushes and compactions wrote into the level.
+    pub bytes_written: u64,
+}
+
+#[derive(Debug, Default)]
+struct LevelCounters {
+    get_bytes_read: AtomicU64,
+    scan_bytes_read: AtomicU64,
+    compaction_bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+/// Counters kept by an [`LsmStorage`](crate::lsm_storage::LsmStorage) since it was opened.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    small_sst_merges: AtomicU64,
+    wal_records_replayed: AtomicU64,
+    wal_bytes_replayed: AtomicU64,
+    bytes_ingested: AtomicU64,
+    flush_bytes_written: AtomicU64,
+    compaction_bytes_read: AtomicU64,
+    compaction_bytes_written: AtomicU64,
+    flushes: AtomicU64,
+    compactions: AtomicU64,
+    unreadable_tables_skipped: AtomicU64,
+    background_retries: AtomicU64,
+    live_file_count: AtomicU64,
+    hot_entries_kept: AtomicU64,
+    cold_entries_sunk: AtomicU64,
+    /// Bits of the `f64` boost, 0 until the first update.
+    file_count_boost: AtomicU64,
+    last_compaction: Mutex<Option<CompactionSummary>>,
+    level_io: [LevelCounters; LEVEL_IO_SLOTS],
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of runs of small L0 tables merged into one table.
+    pub fn small_sst_merges(&self) -> u64 {
+        self.small_sst_merges.load(Ordering::Relaxed)
+    }
+
+    /// Number of write-ahead log records replayed at open.
+    pub fn wal_records_replayed(&self) -> u64 {
+        self.wal_records_replayed.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of write-ahead log replayed at open.
+    pub fn wal_bytes_replayed(&self) -> u64 {
+        self.wal_bytes_replayed.load(Ordering::Relaxed)
+    }
+
+    /// Key and value bytes taken in by writes.
+    pub fn bytes_ingested(&self) -> u64 {
+        self.bytes_ingested.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of SST written by flushes.
+    pub fn flush_bytes_written(&self) -> u64 {
+        self.flush_bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of SST read by compactions.
+    pub fn compaction_bytes_read(&self) -> u64 {
+        self.compaction_bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of SST written by compactions.
+    pub fn compaction_bytes_written(&self) -> u64 {
+        self.compaction_bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Number of memtables flushed.
+    pub fn flushes(&self) -> u64 {
+        self.flushes.load(Ordering::Relaxed)
+    }
+
+    /// Number of compactions run.
+    pub fn compactions(&self) -> u64 {
+        self.compactions.load(Ordering::Relaxed)
+    }
+
+    /// Number of SSTs left out of scans because they could not be read, see
+    /// [`ReadOptions::skip_unreadable`](crate::lsm_storage::ReadOptions::skip_unreadable).
+    pub fn unreadable_tables_skipped(&self) -> u64 {
+        self.unreadable_tables_skipped.load(Ordering::Relaxed)
+    }
+
+    /// Number of times a background job was run again after failing with a transient IO error,
+    /// see [`RetryPolicy`](crate::lsm_storage::RetryPolicy).
+    pub fn background_retries(&self) -> u64 {
+        self.background_retries.load(Ordering::Relaxed)
+    }
+
+    /// Number of entries compactions kept at the compacted level for being read often, see
+    /// [`LsmStorageOptions::tiering`](crate::lsm_storage::LsmStorageOptions::tiering).
+    pub fn hot_entries_kept(&self) -> u64 {
+        self.hot_entries_kept.load(Ordering::Relaxed)
+    }
+
+    /// Number of entries compactions with tiering on moved down for not being read often.
+    pub fn cold_entries_sunk(&self) -> u64 {
+        self.cold_entries_sunk.load(Ordering::Relaxed)
+    }
+
+    /// Number of SSTs in the storage, as of the latest flush or compaction pick.
+    pub fn live_file_count(&self) -> u64 {
+        self.live_file_count.load(Ordering::Relaxed)
+    }
+
+    /// How much the compaction scores are boosted for having more files than
+    /// [`LsmStorageOptions::soft_max_files`](crate::lsm_storage::LsmStorageOptions::soft_max_files),
+    /// 1 when they are not.
+    pub fn file_count_boost(&self) -> f64 {
+        match self.file_count_boost.load(Ordering::Relaxed) {
+            0 => 1.0,
+            bits => f64::from_bits(bits),
+        }
+    }
+
+    /// Bytes written by flushes and compactions per byte ingested, or 0 before any write.
+    pub fn write_amplification(&self) -> f64 {
+        match self.bytes_ingested() {
+            0 => 0.0,
+            ingested => {
+                (self.flush_bytes_written() + self.compaction_bytes_written()) as f64
+                    / ingested as f64
+            }
+        }
+    }
+
+    /// The numbers of the latest compaction.
+    pub fn last_compaction(&self) -> Option<CompactionSummary> {
+        self.last_compaction.lock().clone()
+    }
+
+    /// The bytes read from and written into `level`, 0 for L0. The levels from
+    /// [`LEVEL_IO_SLOTS`] - 1 down share their counters.
+    pub fn level_io(&self, level: usize) -> LevelIo {
+        let counters = self.level_counters(level);
+        LevelIo {
+            get_bytes_read: counters.get_bytes_read.load(Ordering::Relaxed),
+            scan_bytes_read: counters.scan_bytes_read.load(Ordering::Relaxed),
+            compaction_bytes_read: counters.compaction_bytes_read.load(Ordering::Relaxed),
+            bytes_written: counters.bytes_written.load(Ordering::Relaxed),
+        }
+    }
+
+    fn level_counters(&self, level: usize) -> &LevelCounters {
+        &self.level_io[level.min(LEVEL_IO_SLOTS - 1)]
+    }
+
+    pub(crate) fn record_read(&self, kind: ReadKind, level: usize, bytes: u64) {
+        let counters = self.level_counters(level);
+        let counter = match kind {
+            ReadKind::Get => &counters.get_bytes_read,
+            ReadKind::Scan => &counters.scan_bytes_read,
+        };
+        counter.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_ingest(&self, bytes: u64) {
+        self.bytes_ingested.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_flush(&self, bytes_written: u64) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        self.flush_bytes_written
+            .fetch_add(bytes_written, Ordering::Relaxed);
+        self.level_counters(0)
+            .bytes_written
+            .fetch_add(bytes_written, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_compaction(&self, summary: CompactionSummary) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        self.compaction_bytes_read
+            .fetch_add(summary.bytes_read, Ordering::Relaxed);
+        self.compaction_bytes_written
+            .fetch_add(summary.bytes_written, Ordering::Relaxed);
+        let (upper, lower) = (
+            self.level_counters(summary.level),
+            self.level_counters(summary.output_level),
+        );
+        upper
+            .compaction_bytes_read
+            .fetch_add(summary.level_bytes_read, Ordering::Relaxed);
+        lower.compaction_bytes_read.fetch_add(
+            summary.bytes_read - summary.level_bytes_read,
+            Ordering::Relaxed,
+        );
+        upper
+            .bytes_written
+            .fetch_add(summary.level_bytes_written, Ordering::Relaxed);
+        lower.bytes_written.fetch_add(
+            summary.bytes_written - summary.level_bytes_written,
+            Ordering::Relaxed,
+        );
+        *self.last_compaction.lock() = Some(summary);
+    }
+
+    pub(crate) fn record_small_sst_merges(&self, merges: u64) {
+        self.small_sst_merges.fetch_add(merges, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_unreadable_tables_skipped(&self, tables: u64) {
+        self.unreadable_tables_skipped
+            .fetch_add(tables, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_background_retry(&self) {
+        self.background_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_tiering(&self, hot: u64, cold: u64) {
+        self.hot_entries_kept.fetch_add(hot, Ordering::Relaxed);
+        self.cold_entries_sunk.fetch_add(cold, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_live_files(&self, files: u64, boost: f64) {
+        self.live_file_count.store(files, Ordering::Relaxed);
+        self.file_count_boost
+            .store(boost.to_bits(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_wal_replay(&self, stats: &ReplayStats) {
+        self.wal_records_replayed
+            .fetch_add(stats.records, Ordering::Relaxed);
+        self.wal_bytes_replayed
+            .fetch_add(stats.bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_amplification() {
+        let metrics = Metrics::new();
+        assert_eq!(metrics.write_amplification(), 0.0);
+
+        metrics.record_ingest(1000);
+        metrics.record_flush(1200);
+        assert_eq!(metrics.write_amplification(), 1.2);
+
+        let summary = CompactionSummary {
+            level: 0,
+            output_level: 1,
+            bytes_read: 2400,
+            bytes_written: 1800,
+            level_bytes_read: 1200,
+            level_bytes_written: 0,
+        };
+        metrics.record_compaction(summary.clone());
+        assert_eq!(metrics.compaction_bytes_read(), 2400);
+        assert_eq!(metrics.write_amplification(), 3.0);
+        assert_eq!(metrics.last_compaction(), Some(summary));
+    }
+
+    #[test]
+    fn test_level_io() {
+        let metrics = Metrics::new();
+        metrics.record_flush(1000);
+        metrics.record_read(ReadKind::Get, 0, 10);
+        metrics.record_read(ReadKind::Scan, 1, 20);
+        metrics.record_compaction(CompactionSummary {
+            level: 1,
+            output_level: 2,
+            bytes_read: 3000,
+            bytes_written: 2500,
+            level_bytes_read: 1000,
+            level_bytes_written: 500,
+        });
+        assert_eq!(
+            metrics.level_io(0),
+            LevelIo {
+                get_bytes_read: 10,
+                bytes_written: 1000,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            metrics.level_io(1),
+            LevelIo {
+                scan_bytes_read: 20,
+                compaction_bytes_read: 1000,
+                bytes_written: 500,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            metrics.level_io(2),
+            LevelIo {
+                compaction_bytes_read: 2000,
+                bytes_written: 2000,
+                ..Default::default()
+            }
+        );
+
+        // the deepest slot takes every level past it
+        metrics.record_read(ReadKind::Get, LEVEL_IO_SLOTS + 3, 5);
+        assert_eq!(metrics.level_io(LEVEL_IO_SLOTS - 1).get_bytes_read, 5);
+        assert_eq!(metrics.level_io(LEVEL_IO_SLOTS).get_bytes_read, 5);
+    }
+
+    #[test]
+    fn test_live_files() {
+        let metrics = Metrics::new();
+        assert_eq!(metrics.live_file_count(), 0);
+        assert_eq!(metrics.file_count_boost(), 1.0);
+
+        metrics.record_live_files(12, 1.5);
+        assert_eq!(metrics.live_file_count(), 12);
+        assert_eq!(metrics.file_count_boost(), 1.5);
+    }
+}
@@ -0,0 +1,126 @@
+//! Remembers the keys `get` found no value for, so that probing them again skips the tables.
+
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::Bytes;
+use lru::LruCache;
+use parking_lot::Mutex;
+
+use crate::sequence::WriteToken;
+
+/// How the lookups of a [`NegativeCache`] went.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NegativeCacheStats {
+    /// Gets answered from the cache, without reading the memtables or the tables.
+    pub hits: u64,
+    /// Gets the cache had no entry for.
+    pub misses: u64,
+    /// Keys held, at most the capacity.
+    pub entries: u64,
+}
+
+struct Entries {
+    /// The missing keys, each with the applied sequence as of which it was missing.
+    keys: LruCache<Bytes, u64>,
+    /// The sequence of the latest write holding a put.
+    last_put: u64,
+}
+
+/// The keys recent gets found missing, least recently used out first. See
+/// [`LsmStorageOptions::negative_cache_capacity`](crate::lsm_storage::LsmStorageOptions::negative_cache_capacity).
+///
+/// A write forgets the keys it puts before it is applied, so an entry is never older than the
+/// latest put of its key. A get racing with a put may miss the put and still find the key
+/// missing, so a miss is only recorded if no put at all was applied since the get started: the
+/// entries go by the global sequence, not by key range. Deletes leave the entries alone, since
+/// the keys stay missing. The entries hold the whole key, so that keys hashing alike never
+/// answer for each other.
+pub struct NegativeCache {
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl NegativeCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            entries: Mutex::new(Entries {
+                keys: LruCache::new(capacity),
+                last_put: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether `key` is known to be missing.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        let hit = self.entries.lock().keys.get(key).is_some();
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        hit
+    }
+
+    /// Record that a get found `key` missing, reading as of `seen`, the sequence applied when it
+    /// started. Left out if a put was issued since, which may be of `key`.
+    pub fn insert(&self, key: &[u8], seen: WriteToken) {
+        let mut entries = self.entries.lock();
+        if entries.last_put <= seen.0 {
+            entries.keys.put(Bytes::copy_from_slice(key), seen.0);
+        }
+    }
+
+    /// Forget `keys`, which the write of `token` puts. Call it before the write is applied.
+    pub fn invalidate<'a>(&self, keys: impl IntoIterator<Item = &'a Bytes>, token: WriteToken) {
+        let mut entries = self.entries.lock();
+        entries.last_put = entries.last_put.max(token.0);
+        for key in keys {
+            entries.keys.pop(key);
+        }
+    }
+
+    pub fn stats(&self) -> NegativeCacheStats {
+        NegativeCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.entries.lock().keys.len() as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_puts_since_the_lookup_keep_misses_out() {
+        let cache = NegativeCache::new(NonZeroUsize::new(2).unwrap());
+        cache.insert(b"a", WriteToken(0));
+        assert!(cache.contains(b"a"));
+        assert!(!cache.contains(b"b"));
+
+        // a put of another key, issued while a get of `b` was reading as of sequence 0
+        cache.invalidate(&[Bytes::from_static(b"c")], WriteToken(1));
+        cache.insert(b"b", WriteToken(0));
+        assert!(!cache.contains(b"b"));
+        assert!(cache.contains(b"a"));
+        cache.insert(b"b", WriteToken(1));
+        assert!(cache.contains(b"b"));
+
+        cache.invalidate(&[Bytes::from_static(b"a")], WriteToken(2));
+        assert!(!cache.contains(b"a"));
+        cache.insert(b"c", WriteToken(2));
+        cache.insert(b"d", WriteToken(2));
+        // `b` was used least recently
+        assert!(!cache.contains(b"b"));
+        assert_eq!(
+            cache.stats(),
+            NegativeCacheStats {
+                hits: 3,
+                misses: 4,
+                entries: 2,
+            }
+        );
+    }
+}
@@ -0,0 +1,25 @@
+//! The types most users need, in one place: `use mini_lsm_starter::prelude::*;`.
+
+pub use crate::access::TieringOptions;
+pub use crate::cancel::CancellationToken;
+pub use crate::compaction::{
+    CompactionConfig, CompactionPicker, CompactionTask, LeveledPicker, LsmStateDescription,
+};
+pub use crate::error::LsmError;
+pub use crate::iterators::StorageIterator;
+pub use crate::key_range::KeyRange;
+pub use crate::lsm_iterator::{
+    EntryOp, RawEntry, RawScanIter, ResumeToken, ScanIter, ScanStats, SnapshotAge,
+};
+pub use crate::lsm_storage::{
+    AdoptionReport, CloseReport, LsmStorage, LsmStorageOptions, MutableOptions, OpenReport,
+    PrefixExtractor, ReadOptions, RecoveryMode, RetryPolicy, Snapshot, SstLayout, VerifyLevel,
+    WorkerStatus, WriteBatch, WriteOptions,
+};
+pub use crate::manifest::DbId;
+pub use crate::metrics::{LevelIo, Metrics};
+pub use crate::negative_cache::NegativeCacheStats;
+pub use crate::retention::{TrashOptions, TrashStats};
+pub use crate::sequence::WriteToken;
+pub use crate::table::compressed_cache::{BlockCacheStats, CacheAdmission};
+pub use crate::value_handle::{ValueHandle, ValueLocation};
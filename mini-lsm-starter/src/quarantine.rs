@@ -0,0 +1,163 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+
+use crate::error::LsmError;
+
+/// Corrupt files are moved into this subdirectory of the database.
+pub const QUARANTINE_DIR: &str = "corrupt";
+
+/// Describes what failed on a corrupt file. It is written next to the quarantined file as a
+/// `.json` sidecar.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CorruptionReport {
+    /// The corrupt file, as found in the database directory.
+    pub file: PathBuf,
+    /// The operation that tripped on the file, e.g. `open sst`.
+    pub operation: String,
+    /// The offset within the file where the corruption was found, if known.
+    pub offset: Option<u64>,
+    pub expected_checksum: Option<u32>,
+    pub actual_checksum: Option<u32>,
+    /// The error raised by the operation.
+    pub error: String,
+}
+
+impl CorruptionReport {
+    /// Describe `error`, taking the offset and the checksums from the [`LsmError`] it carries,
+    /// if any.
+    pub fn new(file: impl Into<PathBuf>, operation: &str, error: &anyhow::Error) -> Self {
+        let mut report = Self {
+            file: file.into(),
+            operation: operation.to_string(),
+            error: format!("{:#}", error),
+            ..Default::default()
+        };
+        match error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<LsmError>())
+        {
+            Some(LsmError::Corruption {
+                requested: (offset, _),
+                ..
+            }) => report.offset = Some(*offset),
+            Some(LsmError::ChecksumMismatch {
+                offset,
+                expected,
+                actual,
+                ..
+            }) => {
+                report.offset = Some(*offset);
+                report.expected_checksum = Some(*expected);
+                report.actual_checksum = Some(*actual);
+            }
+            _ => {}
+        }
+        report
+    }
+
+    /// Encode the report as a single JSON object.
+    pub fn to_json(&self, quarantined_at_ms: u128) -> String {
+        fn opt<T: std::fmt::Display>(value: Option<T>) -> String {
+            value.map_or_else(|| "null".to_string(), |v| v.to_string())
+        }
+
+        let file = self
+            .file
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        format!(
+            "{{\"file\":{},\"operation\":{},\"offset\":{},\"expected_checksum\":{},\"actual_checksum\":{},\"error\":{},\"quarantined_at_ms\":{}}}\n",
+            json_string(&file),
+            json_string(&self.operation),
+            opt(self.offset),
+            opt(self.expected_checksum),
+            opt(self.actual_checksum),
+            json_string(&self.error),
+            quarantined_at_ms,
+        )
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Move the file described by `report` into `<dir>/corrupt/`, suffixing its name with the
+/// current timestamp, and write the report next to it. Returns the new location of the file.
+pub fn quarantine(dir: &Path, report: &CorruptionReport) -> Result<PathBuf> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let name = report
+        .file
+        .file_name()
+        .ok_or_else(|| anyhow!("cannot quarantine {:?}", report.file))?
+        .to_string_lossy();
+
+    let target_dir = dir.join(QUARANTINE_DIR);
+    std::fs::create_dir_all(&target_dir)?;
+    let target = target_dir.join(format!("{}.{}", name, now));
+    std::fs::rename(&report.file, &target)?;
+    std::fs::write(
+        target_dir.join(format!("{}.{}.json", name, now)),
+        report.to_json(now),
+    )?;
+
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_json() {
+        let report = CorruptionReport {
+            file: PathBuf::from("/db/1.sst"),
+            operation: "open sst".to_string(),
+            offset: Some(42),
+            expected_checksum: Some(1),
+            actual_checksum: None,
+            error: "bad \"footer\"\n".to_string(),
+        };
+        assert_eq!(
+            report.to_json(7),
+            "{\"file\":\"1.sst\",\"operation\":\"open sst\",\"offset\":42,\"expected_checksum\":1,\"actual_checksum\":null,\"error\":\"bad \\\"footer\\\"\\n\",\"quarantined_at_ms\":7}\n"
+        );
+    }
+
+    #[test]
+    fn test_quarantine_moves_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("3.sst");
+        std::fs::write(&file, b"garbage")?;
+
+        let report = CorruptionReport::new(&file, "open sst", &anyhow!("bad footer"));
+        let target = quarantine(dir.path(), &report)?;
+
+        assert!(!file.exists());
+        assert_eq!(std::fs::read(&target)?, b"garbage");
+        assert_eq!(target.parent().unwrap(), dir.path().join(QUARANTINE_DIR));
+        let sidecar = std::fs::read_to_string(format!("{}.json", target.display()))?;
+        assert!(sidecar.contains("\"file\":\"3.sst\""));
+        assert!(sidecar.contains("\"error\":\"bad footer\""));
+
+        Ok(())
+    }
+}
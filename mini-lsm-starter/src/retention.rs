@@ -0,0 +1,380 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use parking_lot::Mutex;
+
+/// Obsolete files are moved into this subdirectory of the database when
+/// [`TrashOptions::subdir`] is set.
+pub const TRASH_DIR: &str = "trash";
+
+/// A data file in the database directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FileId {
+    Sst(usize),
+    Wal(usize),
+}
+
+impl FileId {
+    pub fn file_name(&self) -> String {
+        match self {
+            FileId::Sst(id) => format!("{}.sst", id),
+            FileId::Wal(id) => format!("{}.wal", id),
+        }
+    }
+
+    /// The inverse of `file_name`.
+    pub fn parse(name: &str) -> Option<Self> {
+        let (id, ext) = name.split_once('.')?;
+        let id = id.parse().ok()?;
+        match ext {
+            "sst" => Some(FileId::Sst(id)),
+            "wal" => Some(FileId::Wal(id)),
+            _ => None,
+        }
+    }
+}
+
+/// How obsolete files are deleted.
+#[derive(Clone, Debug, Default)]
+pub struct TrashOptions {
+    /// Keep obsolete files at least this long, e.g. to look at them while debugging.
+    pub grace_period: Duration,
+    /// Let the background janitor delete at most this many files per second, so that retiring
+    /// many files at once does not flood the disk. `None` deletes them as soon as they are due.
+    pub files_per_sec: Option<u32>,
+    /// Move obsolete files into [`TRASH_DIR`] rather than leaving them in place. Files found there
+    /// at open are queued for deletion again.
+    pub subdir: bool,
+}
+
+/// The obsolete files waiting to be deleted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrashStats {
+    pub pending_files: usize,
+    pub pending_bytes: u64,
+    /// The error of the latest failed deletion, if any.
+    pub last_error: Option<String>,
+}
+
+struct ObsoleteFile {
+    path: PathBuf,
+    bytes: u64,
+    /// When the file may be deleted.
+    due: Instant,
+}
+
+#[derive(Default)]
+struct RetentionState {
+    /// How many guards pin each file.
+    pins: BTreeMap<FileId, usize>,
+    /// Files the engine no longer references, waiting to be deleted.
+    obsolete: BTreeMap<FileId, ObsoleteFile>,
+    last_error: Option<String>,
+}
+
+/// Decides when an obsolete file can be deleted.
+///
+/// Snapshots, checkpoints and backups acquire a [`RetentionGuard`] over the files they read;
+/// obsolete files stay on disk until every guard pinning them is dropped.
+///
+/// Deleting them is deferred: a file waits for the grace period of [`TrashOptions`] before
+/// `purge` or the background janitor of [`LsmStorage`](crate::lsm_storage::LsmStorage) delete it.
+#[derive(Default)]
+pub struct FileRetention {
+    state: Mutex<RetentionState>,
+    trash: TrashOptions,
+}
+
+impl FileRetention {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_trash(trash: TrashOptions) -> Self {
+        Self {
+            trash,
+            ..Default::default()
+        }
+    }
+
+    pub fn trash_options(&self) -> &TrashOptions {
+        &self.trash
+    }
+
+    /// Pin `files` until the returned guard is dropped.
+    pub fn acquire(self: &Arc<Self>, files: impl IntoIterator<Item = FileId>) -> RetentionGuard {
+        let files = files.into_iter().collect::<Vec<_>>();
+        let mut state = self.state.lock();
+        for file in &files {
+            *state.pins.entry(*file).or_default() += 1;
+        }
+
+        RetentionGuard {
+            registry: self.clone(),
+            files,
+        }
+    }
+
+    /// The files pinned by at least one guard.
+    pub fn pinned(&self) -> BTreeSet<FileId> {
+        self.state.lock().pins.keys().copied().collect()
+    }
+
+    /// Hand over files in `dir` the engine no longer references, to be deleted once their grace
+    /// period is over. They are moved into the trash directory first if the options ask for it.
+    pub fn mark_obsolete(&self, dir: &Path, files: impl IntoIterator<Item = FileId>) -> Result<()> {
+        self.mark_obsolete_at(
+            dir,
+            files
+                .into_iter()
+                .map(|file| (file, dir.join(file.file_name()))),
+        )
+    }
+
+    /// Like `mark_obsolete`, for files that do not sit at the top of `dir` under their
+    /// [`FileId::file_name`], e.g. the SSTs of a layout that records their level. In the trash
+    /// they go by their file name.
+    pub fn mark_obsolete_at(
+        &self,
+        dir: &Path,
+        files: impl IntoIterator<Item = (FileId, PathBuf)>,
+    ) -> Result<()> {
+        let due = Instant::now() + self.trash.grace_period;
+        for (file, mut path) in files {
+            let bytes = match std::fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+                Err(err) => return Err(err.into()),
+            };
+            if self.trash.subdir && bytes > 0 {
+                let trash = dir.join(TRASH_DIR);
+                std::fs::create_dir_all(&trash)?;
+                std::fs::rename(&path, trash.join(file.file_name()))?;
+                path = trash.join(file.file_name());
+            }
+            let obsolete = ObsoleteFile { path, bytes, due };
+            self.state.lock().obsolete.insert(file, obsolete);
+        }
+        Ok(())
+    }
+
+    /// Queue the files left in the trash directory of `dir` by an earlier run for deletion.
+    pub fn recover_trash(&self, dir: &Path) -> Result<()> {
+        let trash = dir.join(TRASH_DIR);
+        if !trash.exists() {
+            return Ok(());
+        }
+        let due = Instant::now() + self.trash.grace_period;
+        let mut state = self.state.lock();
+        for entry in std::fs::read_dir(trash)? {
+            let entry = entry?;
+            let file = match entry.file_name().to_str().and_then(FileId::parse) {
+                Some(file) => file,
+                None => continue,
+            };
+            let obsolete = ObsoleteFile {
+                path: entry.path(),
+                bytes: entry.metadata()?.len(),
+                due,
+            };
+            state.obsolete.insert(file, obsolete);
+        }
+        Ok(())
+    }
+
+    /// Delete the obsolete files whose grace period is over and that no guard pins. Returns the
+    /// deleted files.
+    pub fn purge(&self) -> Result<Vec<FileId>> {
+        self.purge_due(Instant::now(), usize::MAX)
+    }
+
+    /// Like `purge`, without waiting for the grace period.
+    pub fn purge_now(&self) -> Result<Vec<FileId>> {
+        self.purge_due(Instant::now() + self.trash.grace_period, usize::MAX)
+    }
+
+    /// Delete at most `limit` of the files due by `now`.
+    pub(crate) fn purge_due(&self, now: Instant, limit: usize) -> Result<Vec<FileId>> {
+        let mut state = self.state.lock();
+        let reclaimable = state
+            .obsolete
+            .iter()
+            .filter(|(file, obsolete)| obsolete.due <= now && !state.pins.contains_key(file))
+            .map(|(file, _)| *file)
+            .take(limit)
+            .collect::<Vec<_>>();
+
+        for file in &reclaimable {
+            match std::fs::remove_file(&state.obsolete[file].path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => {
+                    state.last_error = Some(format!("{}: {}", file.file_name(), err));
+                    return Err(err.into());
+                }
+            }
+            state.obsolete.remove(file);
+        }
+
+        Ok(reclaimable)
+    }
+
+    pub fn trash_stats(&self) -> TrashStats {
+        let state = self.state.lock();
+        TrashStats {
+            pending_files: state.obsolete.len(),
+            pending_bytes: state.obsolete.values().map(|file| file.bytes).sum(),
+            last_error: state.last_error.clone(),
+        }
+    }
+}
+
+/// Keeps files from being deleted while it is alive.
+pub struct RetentionGuard {
+    registry: Arc<FileRetention>,
+    files: Vec<FileId>,
+}
+
+impl RetentionGuard {
+    pub fn files(&self) -> &[FileId] {
+        &self.files
+    }
+}
+
+impl Drop for RetentionGuard {
+    fn drop(&mut self) {
+        let mut state = self.registry.state.lock();
+        for file in &self.files {
+            if let Some(count) = state.pins.get_mut(file) {
+                *count -= 1;
+                if *count == 0 {
+                    state.pins.remove(file);
+                }
+            }
+        }
+    }
+}
+
+struct Retired {
+    retention: Arc<FileRetention>,
+    dir: PathBuf,
+    file: FileId,
+    path: PathBuf,
+}
+
+/// Hands a file over to a [`FileRetention`] as obsolete once dropped, if it was retired
+/// meanwhile. A table holds one, so that the file it was compacted out of stays on disk as long
+/// as a reader holds the table.
+#[derive(Default)]
+pub struct RetireOnDrop {
+    retired: Mutex<Option<Retired>>,
+}
+
+impl RetireOnDrop {
+    /// Mark `file` at `path`, in the database at `dir`, obsolete in `retention` on drop.
+    pub fn retire(&self, retention: Arc<FileRetention>, dir: &Path, file: FileId, path: PathBuf) {
+        *self.retired.lock() = Some(Retired {
+            retention,
+            dir: dir.to_path_buf(),
+            file,
+            path,
+        });
+    }
+}
+
+impl Drop for RetireOnDrop {
+    fn drop(&mut self) {
+        if let Some(retired) = self.retired.get_mut().take() {
+            let file = [(retired.file, retired.path)];
+            // the next open finds the file removed from the manifest and retires it again
+            if let Err(err) = retired.retention.mark_obsolete_at(&retired.dir, file) {
+                log::warn!(
+                    "{}: failed to retire {}: {:#}",
+                    retired.dir.display(),
+                    retired.file.file_name(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_purge_waits_for_guards() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for id in 1..=3 {
+            std::fs::write(dir.path().join(FileId::Sst(id).file_name()), b"sst")?;
+        }
+        let retention = Arc::new(FileRetention::new());
+
+        // a snapshot and a checkpoint pin their files from two threads
+        let (snapshot, checkpoint) = std::thread::scope(|s| {
+            let snapshot = s.spawn(|| retention.acquire([FileId::Sst(1), FileId::Sst(2)]));
+            let checkpoint = s.spawn(|| retention.acquire([FileId::Sst(2), FileId::Sst(3)]));
+            (snapshot.join().unwrap(), checkpoint.join().unwrap())
+        });
+        assert_eq!(
+            retention.pinned(),
+            BTreeSet::from([FileId::Sst(1), FileId::Sst(2), FileId::Sst(3)])
+        );
+
+        // compaction replaced all of them
+        retention.mark_obsolete(dir.path(), [FileId::Sst(1), FileId::Sst(2), FileId::Sst(3)])?;
+        assert!(retention.purge()?.is_empty());
+
+        drop(snapshot);
+        assert_eq!(retention.purge()?, vec![FileId::Sst(1)]);
+        assert!(!dir.path().join("1.sst").exists());
+        assert!(dir.path().join("2.sst").exists());
+
+        drop(checkpoint);
+        assert!(retention.pinned().is_empty());
+        assert_eq!(retention.purge()?, vec![FileId::Sst(2), FileId::Sst(3)]);
+        assert!(!dir.path().join("2.sst").exists());
+        assert!(!dir.path().join("3.sst").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_purge_waits_for_grace_period() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for id in 1..=3 {
+            std::fs::write(dir.path().join(FileId::Sst(id).file_name()), b"sst")?;
+        }
+        let retention = FileRetention::with_trash(TrashOptions {
+            grace_period: Duration::from_secs(60),
+            subdir: true,
+            ..Default::default()
+        });
+
+        retention.mark_obsolete(dir.path(), [FileId::Sst(1), FileId::Sst(2)])?;
+        assert!(!dir.path().join("1.sst").exists());
+        assert!(dir.path().join(TRASH_DIR).join("1.sst").exists());
+        let stats = retention.trash_stats();
+        assert_eq!((stats.pending_files, stats.pending_bytes), (2, 6));
+        assert!(retention.purge()?.is_empty());
+
+        // the janitor deletes a limited number of files per round once they are due
+        let later = Instant::now() + Duration::from_secs(61);
+        assert_eq!(retention.purge_due(later, 1)?, vec![FileId::Sst(1)]);
+        assert_eq!(retention.trash_stats().pending_files, 1);
+
+        // a new registry picks up what was left in the trash
+        let reopened = FileRetention::new();
+        reopened.recover_trash(dir.path())?;
+        assert_eq!(reopened.trash_stats().pending_files, 1);
+        assert_eq!(reopened.purge_now()?, vec![FileId::Sst(2)]);
+        assert!(!dir.path().join(TRASH_DIR).join("2.sst").exists());
+        assert!(dir.path().join("3.sst").exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+/// The commit sequence of a write. Hand it to another handle of the same storage, which can
+/// wait for the write to become visible with
+/// [`LsmStorage::wait_for_visibility`](crate::lsm_storage::LsmStorage::wait_for_visibility).
+///
+/// Tables record the highest sequence of the writes they hold, so sequences keep growing
+/// across reopens of the same directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WriteToken(pub u64);
+
+#[derive(Default)]
+struct SequenceState {
+    last_issued: u64,
+    /// Every write up to this sequence is visible to readers.
+    applied: u64,
+}
+
+/// Hands out commit sequences and tracks the applied watermark, waking up the readers waiting
+/// for it.
+#[derive(Default)]
+pub(crate) struct CommitSequence {
+    state: Mutex<SequenceState>,
+    advanced: Condvar,
+}
+
+impl CommitSequence {
+    /// Carry on from the writes up to `last`, issued and applied before, e.g. by an earlier open
+    /// of the storage.
+    pub fn starting_after(last: WriteToken) -> Self {
+        let sequence = Self::default();
+        *sequence.state.lock() = SequenceState {
+            last_issued: last.0,
+            applied: last.0,
+        };
+        sequence
+    }
+
+    /// The sequence of the next write. Callers issue and apply sequences in order, under the
+    /// lock that orders the writes.
+    pub fn issue(&self) -> WriteToken {
+        let mut state = self.state.lock();
+        state.last_issued += 1;
+        WriteToken(state.last_issued)
+    }
+
+    pub fn mark_applied(&self, token: WriteToken) {
+        let mut state = self.state.lock();
+        state.applied = state.applied.max(token.0);
+        self.advanced.notify_all();
+    }
+
+    pub fn applied(&self) -> WriteToken {
+        WriteToken(self.state.lock().applied)
+    }
+
+    /// Wait until the write of `token` is applied. Returns whether it was before `timeout`.
+    pub fn wait_applied(&self, token: WriteToken, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock();
+        while state.applied < token.0 {
+            if self.advanced.wait_until(&mut state, deadline).timed_out() {
+                return state.applied >= token.0;
+            }
+        }
+        true
+    }
+}
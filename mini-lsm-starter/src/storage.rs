@@ -1 +1,40 @@
-pub struct Storage {}
+//! The storage engine, split by concern:
+//!
+//! - `state`: the immutable [`LsmStorageInner`] a read works on, and its invariants.
+//! - `engine`: [`LsmStorage`], the user-facing API and the write path.
+//! - `batch`: the [`WriteBatch`] writes go through the write path as.
+//! - `snapshot`: the [`Snapshot`] consistent reads go through.
+//! - `background`: the compaction worker and the janitor.
+//! - `paths`: where the files live in the database directory.
+//! - `lifecycle`: what is logged when the storage opens and stops, through the `log` facade
+//!   at info level, so an application without a logger prints nothing.
+//! - `open`: the steps of open that change the directory, safe to run again after a crash.
+//! - `verify`: the checks of the SSTs at open.
+//!
+//! Downstream code uses it through [`crate::lsm_storage`].
+
+mod background;
+mod batch;
+mod engine;
+mod lifecycle;
+mod open;
+mod options;
+mod paths;
+mod snapshot;
+mod state;
+mod verify;
+
+pub use background::{is_transient, RetryPolicy, WorkerStatus};
+pub use batch::WriteBatch;
+pub use engine::LsmStorage;
+pub use lifecycle::{AdoptionReport, CloseReport, OpenReport};
+#[cfg(test)]
+pub(crate) use open::OPEN_FAULT;
+pub use options::{
+    LsmStorageOptions, MutableOptions, PrefixExtractor, ReadOptions, RecoveryMode, SstLayout,
+    VerifyLevel, WriteOptions, MAX_KEY_SIZE, MAX_VALUE_SIZE,
+};
+pub use snapshot::Snapshot;
+#[allow(deprecated)]
+pub use state::RawBlockCache;
+pub use state::{BlockCache, CacheStats, Level, LsmStorageInner};
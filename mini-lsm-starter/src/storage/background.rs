@@ -0,0 +1,510 @@
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bytes::Bytes;
+use parking_lot::{Mutex, RwLock};
+
+use super::options::LiveOptions;
+use super::state::BlockCache;
+use super::LsmStorage;
+use crate::hotset;
+use crate::manifest::{self, ManifestRecord};
+use crate::retention::FileRetention;
+
+pub(crate) static MIN_NUM_SST_FILES_TO_COMPACT: usize = 2;
+
+static COMPACTION_WORKER: &str = "mini-lsm-compaction";
+
+static JANITOR: &str = "mini-lsm-janitor";
+
+static HOTSET_WRITER: &str = "mini-lsm-hotset";
+
+static WARMER: &str = "mini-lsm-warm";
+
+static SMALL_SST_MERGER: &str = "mini-lsm-small-sst";
+
+/// Most blocks a hot set records, fewer if the block cache holds fewer.
+static MAX_HOTSET_BLOCKS: u64 = 1 << 12;
+
+/// How long `stop` waits for the compaction worker to acknowledge.
+static SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+
+/// How often an unthrottled janitor looks for files that are due.
+static JANITOR_INTERVAL: Duration = Duration::from_millis(100);
+
+#[cfg(test)]
+pub(crate) type JobHook = Box<dyn FnOnce() + Send>;
+
+type TransientFn = dyn Fn(&std::io::Error) -> bool + Send + Sync;
+
+/// How the compaction worker runs a job again after it fails with a transient IO error, such as
+/// a flush hitting a full queue. Other errors fail the job right away, and calls made by the
+/// user, such as `sync` or `compact`, are never retried.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// Attempts at a job, the first one included. 1 never retries.
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled before every next one.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Tells the transient IO errors apart, in place of [`is_transient`].
+    pub classify: Option<Arc<TransientFn>>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            classify: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("classify", &self.classify.is_some())
+            .finish()
+    }
+}
+
+impl RetryPolicy {
+    /// Whether a job failing with `err` is worth running again: an IO error somewhere in its
+    /// chain that the policy takes for transient.
+    fn retries(&self, err: &anyhow::Error) -> bool {
+        let io_err = err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<std::io::Error>());
+        match (io_err, &self.classify) {
+            (None, _) => false,
+            (Some(io_err), Some(classify)) => classify(io_err),
+            (Some(io_err), None) => is_transient(io_err),
+        }
+    }
+
+    /// The wait before retry `retry`, counted from 1.
+    fn backoff(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry - 1).unwrap_or(u32::MAX);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// Whether `err` is likely to go away if the operation is tried again a moment later: it was
+/// interrupted, would have blocked, or timed out.
+pub fn is_transient(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::Interrupted
+            | std::io::ErrorKind::WouldBlock
+            | std::io::ErrorKind::TimedOut
+    )
+}
+
+/// The health of a background worker thread.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WorkerStatus {
+    /// The name of the worker, e.g. `mini-lsm-compaction`. Its thread is named after it and the
+    /// short database id, e.g. `mini-lsm-compaction-1b4e28ba`.
+    pub name: String,
+    /// Whether the worker is still accepting jobs.
+    pub alive: bool,
+    /// Number of jobs the worker has run, including the failed ones.
+    pub jobs_completed: u64,
+    /// Number of jobs that returned an error or panicked.
+    pub jobs_failed: u64,
+    /// The error or panic message of the latest failed job.
+    pub last_error: Option<String>,
+}
+
+/// Delete the obsolete files in the background as they fall due, one at a time when throttled,
+/// and evict blocks from a cache shrunk by `set_options`, until `stop` receives a message or is
+/// disconnected.
+fn spawn_janitor(
+    (name, status): (String, Arc<Mutex<WorkerStatus>>),
+    retention: Arc<FileRetention>,
+    (cache, live): (Arc<BlockCache>, Arc<RwLock<LiveOptions>>),
+    stop: flume::Receiver<()>,
+) -> Result<()> {
+    let (interval, batch) = match retention.trash_options().files_per_sec {
+        Some(rate) => (Duration::from_secs(1) / rate.max(1), 1),
+        None => (JANITOR_INTERVAL, usize::MAX),
+    };
+    std::thread::Builder::new().name(name).spawn(move || {
+        while let Err(flume::RecvTimeoutError::Timeout) = stop.recv_timeout(interval) {
+            // a failed deletion shows up in the trash stats and is retried next round
+            run_job(&status, || {
+                retention.purge_due(Instant::now(), batch)?;
+                cache.resize(live.read().block_cache_capacity);
+                Ok(())
+            });
+        }
+        status.lock().alive = false;
+    })?;
+    Ok(())
+}
+
+/// Run one job of a background thread, recording how it went in `status`. A job that fails or
+/// panics does not bring the thread down.
+fn run_job(status: &Mutex<WorkerStatus>, job: impl FnOnce() -> Result<()>) {
+    let result = std::panic::catch_unwind(AssertUnwindSafe(job));
+    status.lock().record(result);
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        format!("panicked: {}", msg)
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        format!("panicked: {}", msg)
+    } else {
+        "panicked".to_string()
+    }
+}
+
+impl WorkerStatus {
+    /// The status of a compaction worker about to start.
+    pub(super) fn compaction() -> Self {
+        Self::starting(COMPACTION_WORKER)
+    }
+
+    /// The status of the worker `name` about to start.
+    fn starting(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            alive: true,
+            ..Default::default()
+        }
+    }
+
+    /// Count a job that returned or panicked with `result`.
+    fn record(&mut self, result: std::thread::Result<Result<()>>) {
+        self.jobs_completed += 1;
+        let error = match result {
+            Ok(Ok(())) => return,
+            Ok(Err(err)) => format!("{:#}", err),
+            Err(payload) => panic_message(payload.as_ref()),
+        };
+        self.jobs_failed += 1;
+        self.last_error = Some(error);
+    }
+}
+
+impl LsmStorage {
+    /// Start the compaction worker, unless the options turn it off, the janitor, which runs
+    /// until `janitor_rx` receives a message or is disconnected, and the hot set writer, the
+    /// warmer and the merger of small tables if the options ask for them.
+    pub(super) fn start_workers(&self, janitor_rx: flume::Receiver<()>) -> Result<()> {
+        let db_id = self.db_id().short();
+        if self.options.warm_on_open {
+            let mut this = self.clone();
+            this.handles = None;
+            let status = self.add_worker(WARMER);
+            let thread = std::thread::Builder::new()
+                .name(format!("{}-{}", WARMER, db_id))
+                .spawn(move || {
+                    run_job(&status, || {
+                        this.warm_cache();
+                        Ok(())
+                    });
+                    status.lock().alive = false;
+                })?;
+            *self.warm_thread.lock() = Some(thread);
+        }
+        if let Some(interval) = self.options.hotset_interval {
+            let mut this = self.clone();
+            this.handles = None;
+            let status = self.add_worker(HOTSET_WRITER);
+            std::thread::Builder::new()
+                .name(format!("{}-{}", HOTSET_WRITER, db_id))
+                .spawn(move || {
+                    while !this.cancel.wait_timeout(interval) {
+                        run_job(&status, || this.save_hotset_or_warn());
+                    }
+                    status.lock().alive = false;
+                })?;
+        }
+        if let (Some(threshold), Some(interval)) = (
+            self.options.small_sst_threshold,
+            self.options.small_sst_merge_interval,
+        ) {
+            let mut this = self.clone();
+            this.handles = None;
+            let status = self.add_worker(SMALL_SST_MERGER);
+            std::thread::Builder::new()
+                .name(format!("{}-{}", SMALL_SST_MERGER, db_id))
+                .spawn(move || {
+                    while !this.cancel.wait_timeout(interval) {
+                        run_job(&status, || this.merge_small_tables_or_warn(threshold));
+                    }
+                    status.lock().alive = false;
+                })?;
+        }
+        if self.options.background_compaction {
+            let mut this = self.clone();
+            this.handles = None;
+            let thread = std::thread::Builder::new()
+                .name(format!("{}-{}", COMPACTION_WORKER, db_id))
+                .spawn(move || this.loop_compaction())?;
+            *self.worker_thread.lock() = Some(thread);
+        } else {
+            self.worker.lock().alive = false;
+        }
+        spawn_janitor(
+            (format!("{}-{}", JANITOR, db_id), self.add_worker(JANITOR)),
+            self.retention.clone(),
+            (self.cache.clone(), self.live.clone()),
+            janitor_rx,
+        )
+    }
+
+    /// Record the blocks the block cache found most often, hottest first, in the
+    /// [`HOTSET`](hotset::HOTSET) file, for [`LsmStorageOptions::warm_on_open`] to read back at
+    /// the next open. Returns the number of blocks recorded.
+    ///
+    /// [`LsmStorageOptions::warm_on_open`]: crate::lsm_storage::LsmStorageOptions::warm_on_open
+    pub fn save_hotset(&self) -> Result<usize> {
+        let limit = self.live.read().block_cache_capacity.min(MAX_HOTSET_BLOCKS);
+        let ranges = self
+            .cache
+            .hottest(limit as usize)
+            .into_iter()
+            .filter_map(|block| Some((Bytes::from(block.entry(0)?.0), Bytes::from(block.last()?))))
+            .collect::<Vec<_>>();
+        hotset::write(&self.dir, &ranges)?;
+        Ok(ranges.len())
+    }
+
+    /// Add the status of a background thread about to start, which [`background_health`]
+    /// reports after the compaction worker.
+    ///
+    /// [`background_health`]: LsmStorage::background_health
+    fn add_worker(&self, name: &str) -> Arc<Mutex<WorkerStatus>> {
+        let status = Arc::new(Mutex::new(WorkerStatus::starting(name)));
+        self.other_workers.lock().push(status.clone());
+        status
+    }
+
+    /// Save the hot set, logging a failure before passing it on.
+    fn save_hotset_or_warn(&self) -> Result<()> {
+        self.save_hotset().map(drop).inspect_err(|err| {
+            log::warn!(
+                "{} ({}): failed to save the hot set: {:#}",
+                self.dir.display(),
+                self.db_id(),
+                err
+            );
+        })
+    }
+
+    /// Merge the small tables, logging a failure before passing it on.
+    fn merge_small_tables_or_warn(&self, threshold: u64) -> Result<()> {
+        self.merge_small_tables(threshold)
+            .map(drop)
+            .inspect_err(|err| {
+                log::warn!(
+                    "{} ({}): failed to merge the small tables: {:#}",
+                    self.dir.display(),
+                    self.db_id(),
+                    err
+                );
+            })
+    }
+
+    /// Read the blocks the hot set records into the block cache, hottest first, one range at a
+    /// time so that the reads of the user get their turn, until the cache would be full or the
+    /// storage stops.
+    fn warm_cache(&self) {
+        let capacity = self.live.read().block_cache_capacity as usize;
+        let mut visited = 0;
+        for (first, last) in hotset::read(&self.dir) {
+            if visited >= capacity || self.cancel.is_cancelled() {
+                break;
+            }
+            let inner = self.inner.read().clone();
+            visited += inner.warm_blocks(&first, &last);
+            std::thread::yield_now();
+        }
+    }
+
+    /// Wait for the warming of the block cache `warm_on_open` started, if any, to finish.
+    #[cfg(test)]
+    pub(crate) fn wait_for_warm(&self) {
+        let thread = self.warm_thread.lock().take();
+        if let Some(thread) = thread {
+            thread.join().unwrap();
+        }
+    }
+
+    /// Report the health of every background worker: the compaction worker first, then the
+    /// other threads in the order they started.
+    pub fn background_health(&self) -> Vec<WorkerStatus> {
+        let mut health = vec![self.worker.lock().clone()];
+        health.extend(
+            self.other_workers
+                .lock()
+                .iter()
+                .map(|status| status.lock().clone()),
+        );
+        health
+    }
+
+    /// Ask the compaction worker to flush and compact.
+    pub(crate) fn schedule_compaction(&self) -> Result<()> {
+        self.sync_tx.send(Some(()))?;
+        Ok(())
+    }
+
+    /// Runs jobs until stopped. A failing or panicking job is recorded in the worker status and
+    /// does not bring the worker down, so later jobs still get a chance to run.
+    fn loop_compaction(&self) {
+        for msg in self.sync_rx.iter() {
+            if msg.is_none() {
+                break;
+            }
+
+            run_job(&self.worker, || {
+                self.with_retries("compaction job", || self.compaction_job())
+            });
+        }
+
+        self.worker.lock().alive = false;
+        self.worker_exited.notify_all();
+    }
+
+    /// Run `job` until it succeeds, fails with an error the [`RetryPolicy`] does not retry, or
+    /// runs out of attempts. Stopping the storage ends the wait between attempts.
+    fn with_retries(&self, name: &str, mut job: impl FnMut() -> Result<()>) -> Result<()> {
+        let policy = &self.options.background_retry;
+        let mut attempt = 1;
+        loop {
+            let err = match job() {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            if attempt >= policy.max_attempts || !policy.retries(&err) {
+                return Err(err);
+            }
+            let backoff = policy.backoff(attempt);
+            log::warn!(
+                "{} ({}): retrying {} in {:?} after attempt {} failed: {:#}",
+                self.dir.display(),
+                self.db_id(),
+                name,
+                backoff,
+                attempt,
+                err
+            );
+            if self.cancel.wait_timeout(backoff) {
+                return Err(err);
+            }
+            self.metrics.record_background_retry();
+            attempt += 1;
+        }
+    }
+
+    fn compaction_job(&self) -> Result<()> {
+        #[cfg(test)]
+        {
+            let hook = self.job_hook.lock().take();
+            if let Some(hook) = hook {
+                hook();
+            }
+        }
+
+        self.sync()?;
+
+        let state = self.describe_state();
+        if let Some(task) = self.options.compaction.picker().pick(&state)? {
+            self.compact_task(&task)?;
+        }
+
+        Ok(())
+    }
+
+    /// Stop the background workers, cancelling the compactions in flight, and give the
+    /// compaction worker a moment to exit.
+    pub fn stop(&self) -> Result<()> {
+        if !self.closed.swap(true, Ordering::AcqRel) {
+            log::info!("{}", self.close_report());
+        }
+        self.cancel.cancel();
+        // the janitor may be gone already, the files it leaves are queued again at open
+        let _ = self.janitor_tx.send(());
+        self.sync_tx.send(None).map_err(|x| anyhow::anyhow!(x))?;
+
+        let deadline = Instant::now() + SHUTDOWN_GRACE;
+        let mut status = self.worker.lock();
+        while status.alive {
+            if self
+                .worker_exited
+                .wait_until(&mut status, deadline)
+                .timed_out()
+            {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Shut the storage down for a clean restart: reject new writes with
+    /// [`LsmError::Closed`](crate::error::LsmError::Closed), sync the write-ahead log, flush the
+    /// memtables if [`LsmStorageOptions::flush_on_close`] says so, `stop` the background workers,
+    /// join the compaction worker and wait for the compactions in flight to wind down, and last
+    /// record the clean shutdown in the manifest. The next open trusts the manifest and the log
+    /// as `close` left them, and skips the recovery work a crash needs. Reads keep working;
+    /// closing again does nothing. Dropping the last handle closes the storage, ignoring errors.
+    ///
+    /// [`LsmStorageOptions::flush_on_close`]: crate::lsm_storage::LsmStorageOptions::flush_on_close
+    pub fn close(&self) -> Result<()> {
+        {
+            let mut wal = self.wal.lock();
+            if self.closing.swap(true, Ordering::AcqRel) {
+                return Ok(());
+            }
+            wal.sync()?;
+        }
+        if let Some(group_commit) = &self.group_commit {
+            group_commit.shutdown();
+        }
+        if self.options.flush_on_close {
+            self.sync()?;
+        }
+        if self.options.hotset_interval.is_some() {
+            let _ = self.save_hotset_or_warn();
+        }
+        self.stop()?;
+        // the worker cannot wait for itself, when a job of its own closes the storage
+        let thread = self.worker_thread.lock().take();
+        if let Some(thread) = thread.filter(|t| t.thread().id() != std::thread::current().id()) {
+            thread
+                .join()
+                .map_err(|payload| anyhow::anyhow!(panic_message(payload.as_ref())))?;
+        }
+        // a compaction that cannot finish in time records its tables after the marker, which
+        // then no longer counts
+        let deadline = Instant::now() + SHUTDOWN_GRACE;
+        while !self.compacting.lock().is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let wal_bytes = self.wal.lock().synced_len();
+        manifest::append_records(&self.dir, [ManifestRecord::CleanShutdown { wal_bytes }])
+    }
+
+    /// Stop the workers and let go of the storage without `close`, the way a crash leaves it.
+    #[cfg(test)]
+    pub(crate) fn kill(mut self) {
+        // the last handle dropped finds the storage closing already, and leaves it be
+        self.closing.store(true, Ordering::Release);
+        self.handles = None;
+        let _ = self.stop();
+    }
+}
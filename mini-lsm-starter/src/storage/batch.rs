@@ -0,0 +1,102 @@
+use std::collections::HashSet;
+
+use bytes::Bytes;
+
+/// A single mutation on its way through the write path.
+pub(super) enum WriteOp {
+    Put(Bytes, Bytes),
+    /// Written as an empty value.
+    Delete(Bytes),
+}
+
+impl WriteOp {
+    pub(super) fn key(&self) -> &Bytes {
+        match self {
+            WriteOp::Put(key, _) | WriteOp::Delete(key) => key,
+        }
+    }
+
+    /// What the entry adds to the size of a memtable.
+    pub(super) fn size(&self) -> usize {
+        match self {
+            WriteOp::Put(key, value) => 2 + key.len() + 2 + value.len(),
+            WriteOp::Delete(key) => 2 + key.len() + 2,
+        }
+    }
+}
+
+/// Writes that [`LsmStorage::write`](crate::lsm_storage::LsmStorage::write) applies together:
+/// they go into the same memtable, and recovery brings back either all of them or none.
+///
+/// Of several writes of one key, the last one wins: the key goes into the memtable once, with
+/// its last value, so that no read sees the ones before it.
+#[derive(Default)]
+pub struct WriteBatch {
+    pub(super) ops: Vec<WriteOp>,
+    /// Sum of the sizes of `ops`.
+    size: usize,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: Bytes, value: Bytes) {
+        self.push(WriteOp::Put(key, value));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.push(WriteOp::Delete(Bytes::copy_from_slice(key)));
+    }
+
+    fn push(&mut self, op: WriteOp) {
+        self.size += op.size();
+        self.ops.push(op);
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Drop the writes, so that the batch can be filled again.
+    pub fn clear(&mut self) {
+        self.ops.clear();
+        self.size = 0;
+    }
+
+    /// Keep only the last write of every key, the one the batch leaves in effect, so that the
+    /// batch is smaller in the write-ahead log and against
+    /// [`LsmStorageOptions::max_batch_bytes`](crate::lsm_storage::LsmStorageOptions::max_batch_bytes).
+    /// The writes kept stay in order.
+    pub fn dedup(&mut self) {
+        self.ops = last_per_key(std::mem::take(&mut self.ops), WriteOp::key);
+        self.size = self.ops.iter().map(WriteOp::size).sum();
+    }
+
+    /// Bytes the batch adds to a memtable, which
+    /// [`LsmStorageOptions::max_batch_bytes`](crate::lsm_storage::LsmStorageOptions::max_batch_bytes)
+    /// limits.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// The last of the `items` of every key, in the order of the items.
+pub(super) fn last_per_key<T>(items: Vec<T>, key: impl Fn(&T) -> &Bytes) -> Vec<T> {
+    if items.len() < 2 {
+        return items;
+    }
+    let mut seen = HashSet::with_capacity(items.len());
+    let mut kept = items
+        .into_iter()
+        .rev()
+        .filter(|item| seen.insert(key(item).clone()))
+        .collect::<Vec<_>>();
+    kept.reverse();
+    kept
+}
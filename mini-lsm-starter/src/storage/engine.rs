@@ -0,0 +1,1747 @@
+use std::collections::{BTreeSet, HashSet};
+use std::num::NonZeroUsize;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Context, Result};
+use bytes::Bytes;
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
+
+#[cfg(test)]
+use super::background::JobHook;
+use super::background::WorkerStatus;
+use super::batch::{last_per_key, WriteBatch, WriteOp};
+use super::lifecycle::{AdoptionReport, CloseReport, OpenReport};
+use super::open::{record_adoption, remove_tmp_files, step_done};
+use super::options::LiveOptions;
+use super::paths::{
+    migrate_layout, path_of_sst, path_of_tmp_sst, path_of_wal, removed_sst_files, sst_files,
+    sync_parent_dirs,
+};
+use super::snapshot::Snapshot;
+use super::state::{insert_by_key, sst_builder, BlockCache, LsmStorageInner};
+use super::verify::{random_seed, TableVerifier};
+use super::{
+    LsmStorageOptions, MutableOptions, PrefixExtractor, ReadOptions, RecoveryMode, VerifyLevel,
+    WriteOptions, MAX_KEY_SIZE, MAX_VALUE_SIZE,
+};
+use crate::access::AccessTracker;
+use crate::block::{EncodeScratch, ENTRY_CHECKSUM_SIZE};
+use crate::cancel::CancellationToken;
+use crate::compaction::{
+    file_count_boost, may_drop_tombstones, merge_runs, newer_l0_overlapping, pick_compaction,
+    plan_compaction, resolve_task, select_file_inputs, select_inputs, sorted_runs, CompactionPlan,
+    CompactionSummary, CompactionTask, LsmStateDescription,
+};
+use crate::error::LsmError;
+use crate::iterators::StorageIterator;
+use crate::key::ValueKind;
+use crate::key_range::KeyRange;
+use crate::lsm_iterator::{RawScanIter, ResumeToken, ScanIter, SnapshotAge, SnapshotClock};
+use crate::manifest::{self, DbId, ManifestRecord, OptionsFingerprint};
+use crate::mem_table::MemTable;
+use crate::metrics::Metrics;
+use crate::negative_cache::{NegativeCache, NegativeCacheStats};
+use crate::quarantine::{quarantine, CorruptionReport};
+use crate::retention::{FileId, FileRetention, RetentionGuard, TrashStats};
+use crate::sequence::{CommitSequence, WriteToken};
+use crate::table::compressed_cache::{BlockCacheStats, CompressedBlockCache};
+use crate::table::{ScanCacheFill, SsTable, SsTableBuilder};
+use crate::value_handle::ValueHandle;
+use crate::wal::{ReplayStats, Wal, WalGroupCommit};
+
+/// How long `get_after` waits for the write of its token.
+static VISIBILITY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The smallest key greater than every key starting with `prefix`, or `None` if there is none.
+fn prefix_successor(prefix: &[u8]) -> Option<Bytes> {
+    let end = prefix.iter().rposition(|&byte| byte != u8::MAX)?;
+    let mut successor = prefix[..=end].to_vec();
+    successor[end] += 1;
+    Some(successor.into())
+}
+
+/// The prefix `extractor` maps every key within the bounds to, if they all share one.
+fn shared_prefix<'a>(extractor: &PrefixExtractor, range: &'a KeyRange) -> Option<&'a [u8]> {
+    let prefix = match range.lower() {
+        Bound::Included(key) | Bound::Excluded(key) => extractor.extract(key)?,
+        Bound::Unbounded => return None,
+    };
+    let within = match (range.upper(), prefix_successor(prefix)) {
+        (_, None) => true,
+        (Bound::Included(key), Some(successor)) => key < &successor[..],
+        (Bound::Excluded(key), Some(successor)) => key <= &successor[..],
+        (Bound::Unbounded, Some(_)) => false,
+    };
+    within.then_some(prefix)
+}
+
+/// Compactions check for cancellation every this many entries.
+const CANCEL_CHECK_ENTRIES: usize = 256;
+
+/// A snapshot copies a memtable smaller than this rather than freezing it, so that taking
+/// snapshots often does not leave a trail of tiny tables to flush.
+const SNAPSHOT_COPY_LIMIT: usize = 64 << 10;
+
+/// The input tables of a running compaction, released once it is done with them.
+struct Reserved<'a> {
+    compacting: &'a Mutex<HashSet<usize>>,
+    ids: Vec<usize>,
+}
+
+impl Drop for Reserved<'_> {
+    fn drop(&mut self) {
+        let mut compacting = self.compacting.lock();
+        for id in &self.ids {
+            compacting.remove(id);
+        }
+    }
+}
+
+/// The tables a compaction writes to one level, each streamed to disk as its blocks fill and
+/// ended at the first block boundary past the target size. The files of the tables not taken are deleted
+/// on drop, so that a failed compaction leaves none behind.
+struct OutputTables<'a> {
+    storage: &'a LsmStorage,
+    level: usize,
+    target_size: usize,
+    /// The id of the first table, if taken beforehand. The others take the next free one.
+    first_id: Option<usize>,
+    current: Option<(usize, PathBuf, SsTableBuilder)>,
+    tables: Vec<SsTable>,
+    /// Every file created, until the tables are taken.
+    paths: Vec<PathBuf>,
+    entries: u64,
+    /// The commit sequence the tables record as their latest write's, see `SsTable::max_seq`.
+    max_seq: u64,
+}
+
+impl<'a> OutputTables<'a> {
+    fn new(storage: &'a LsmStorage, level: usize, target_size: usize) -> Self {
+        Self {
+            storage,
+            level,
+            target_size,
+            first_id: None,
+            current: None,
+            tables: vec![],
+            paths: vec![],
+            entries: 0,
+            max_seq: 0,
+        }
+    }
+
+    fn with_first_id(mut self, id: usize) -> Self {
+        self.first_id = Some(id);
+        self
+    }
+
+    fn with_max_seq(mut self, seq: u64) -> Self {
+        self.max_seq = seq;
+        self
+    }
+
+    fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let builder = match &mut self.current {
+            Some((_, _, builder)) => builder,
+            None => {
+                let storage = self.storage;
+                let id = match self.first_id.take() {
+                    Some(id) => id,
+                    None => {
+                        let state_lock = storage.state_lock.lock();
+                        storage.update_state(&state_lock, |inner| {
+                            inner.next_sst_id += 1;
+                            inner.next_sst_id - 1
+                        })
+                    }
+                };
+                let path = storage.path_of_sst(self.level, id)?;
+                // written aside, so that a crash never leaves a torn table under its name
+                let tmp = path_of_tmp_sst(&storage.dir, id);
+                let builder = sst_builder(&storage.options)
+                    .with_max_seq(self.max_seq)
+                    .stream_to(&tmp);
+                self.paths.push(tmp);
+                &mut self.current.insert((id, path, builder?)).2
+            }
+        };
+        if !builder.add_within(key, value, self.target_size) {
+            // the entry starts a block past the target, and the next table with it
+            self.finish()?;
+            return self.add(key, value);
+        }
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// End the table being written, if any.
+    fn finish(&mut self) -> Result<()> {
+        if let Some((id, path, builder)) = self.current.take() {
+            let storage = self.storage;
+            let tmp = path_of_tmp_sst(&storage.dir, id);
+            let mut table = builder.export(id, Some(storage.cache.clone()), &tmp)?;
+            table.rename_file(&path)?;
+            self.paths.retain(|written| written != &tmp);
+            self.paths.push(path);
+            let table = table
+                .with_compressed_cache(storage.compressed_cache.clone())
+                .with_level_io(self.level, storage.metrics.clone());
+            self.tables.push(table);
+        }
+        Ok(())
+    }
+
+    fn file_size(&self) -> u64 {
+        self.tables.iter().map(|sst| sst.file_size()).sum()
+    }
+
+    /// Sync the directories of the tables written, so that the manifest may record them.
+    fn sync_dirs(&self) -> Result<()> {
+        sync_parent_dirs(self.paths.iter().map(PathBuf::as_path))
+    }
+
+    /// The tables written, whose files are kept from now on.
+    fn take(&mut self) -> Vec<SsTable> {
+        self.paths.clear();
+        std::mem::take(&mut self.tables)
+    }
+}
+
+impl Drop for OutputTables<'_> {
+    fn drop(&mut self) {
+        self.current = None;
+        for path in &self.paths {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// The storage interface of the LSM tree.
+#[derive(Clone)]
+pub struct LsmStorage {
+    /// The current state, replaced as a whole by the structural changes. Readers clone the
+    /// `Arc` out and let go of the lock.
+    pub(super) inner: Arc<RwLock<Arc<LsmStorageInner>>>,
+    /// Serializes the structural changes: freezing the memtable, installing flushed tables and
+    /// compacting. Taken after the WAL lock and before `inner`.
+    pub(super) state_lock: Arc<Mutex<()>>,
+    pub(super) dir: std::path::PathBuf,
+    pub(super) cache: Arc<BlockCache>,
+    pub(super) compressed_cache: Option<Arc<CompressedBlockCache>>,
+    pub(super) options: Arc<LsmStorageOptions>,
+    /// The options `set_options` changes; their fields in `options` are the values at open.
+    pub(super) live: Arc<RwLock<LiveOptions>>,
+    /// Writers hold the lock while they log and insert, so that the log and the memtable see
+    /// writes in the same order.
+    pub(super) wal: Arc<Mutex<Wal>>,
+    /// Logs the writes in place of `wal` with [`LsmStorageOptions::group_commit`] on. Writers
+    /// queue their records under the WAL lock, and wait for them to reach the disk after.
+    pub(super) group_commit: Option<Arc<WalGroupCommit>>,
+    /// Sequences the writes, under the WAL lock.
+    pub(super) sequence: Arc<CommitSequence>,
+    /// Files that snapshots, checkpoints and backups still read.
+    pub(super) retention: Arc<FileRetention>,
+    /// Encode buffer shared by flushes and compactions.
+    pub(super) scratch: Arc<Mutex<EncodeScratch>>,
+    pub(super) metrics: Arc<Metrics>,
+    /// Samples the point reads, with tiering on.
+    pub(super) access: Option<Arc<AccessTracker>>,
+    /// The keys recent gets found missing, when the options ask for it.
+    pub(super) negative_cache: Option<Arc<NegativeCache>>,
+    /// Compaction only drops the tombstones of tables holding only older writes.
+    pub(super) gc_horizon: Arc<AtomicU64>,
+    /// Ids of the tables the running compactions read.
+    pub(super) compacting: Arc<Mutex<HashSet<usize>>>,
+    pub(super) sync_tx: flume::Sender<Option<()>>,
+    pub(super) sync_rx: flume::Receiver<Option<()>>,
+    /// Stops the janitor deleting obsolete files.
+    pub(super) janitor_tx: flume::Sender<()>,
+    /// Status of the compaction worker, the background-error slot included.
+    pub(super) worker: Arc<Mutex<WorkerStatus>>,
+    /// Status of the other background threads, in the order they started.
+    pub(super) other_workers: Arc<Mutex<Vec<Arc<Mutex<WorkerStatus>>>>>,
+    /// Signalled, along with `worker`, when the compaction worker exits.
+    pub(super) worker_exited: Arc<Condvar>,
+    /// Cancels the compactions in flight once the storage stops.
+    pub(super) cancel: CancellationToken,
+    pub(super) open_report: Arc<OpenReport>,
+    /// Tells the resume tokens of this open apart from the ones of earlier opens.
+    pub(super) session: DbId,
+    pub(super) opened_at: Instant,
+    /// Set by the first `stop`, which logs the close report.
+    pub(super) closed: Arc<AtomicBool>,
+    /// Set by `close`, under the WAL lock, after which writes fail with [`LsmError::Closed`].
+    pub(super) closing: Arc<AtomicBool>,
+    /// Shared by the handles of the user, the last of which closes the storage as it is
+    /// dropped. `None` in the handles the background threads hold, and in the one it closes.
+    pub(super) handles: Option<Arc<CloseOnDrop>>,
+    /// The compaction worker, joined by `close`.
+    pub(crate) worker_thread: Arc<Mutex<Option<std::thread::JoinHandle<()>>>>,
+    /// Reads the hot set back into the block cache after open, with `warm_on_open`.
+    pub(crate) warm_thread: Arc<Mutex<Option<std::thread::JoinHandle<()>>>>,
+    /// Runs once at the start of the next background job.
+    #[cfg(test)]
+    pub(crate) job_hook: Arc<Mutex<Option<JobHook>>>,
+}
+
+/// What the handles of the user share: dropped along with the last of them, it closes the
+/// storage, exactly once.
+pub(super) struct CloseOnDrop(LsmStorage);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.close();
+    }
+}
+
+impl LsmStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_options(path, LsmStorageOptions::default())
+    }
+
+    pub fn open_with_options(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Self> {
+        let dir = path.as_ref();
+        ensure!(
+            options.max_key_size <= MAX_KEY_SIZE && options.max_value_size <= MAX_VALUE_SIZE,
+            "key and value size limits cannot exceed {} and {} bytes",
+            MAX_KEY_SIZE,
+            MAX_VALUE_SIZE
+        );
+        options.validate()?;
+        std::fs::create_dir_all(dir)?;
+
+        let fingerprint = OptionsFingerprint::of(&options);
+        // the manifests written before databases had ids get one now
+        let recorded_id = manifest::read_db_id(dir)?;
+        let db_id = recorded_id.unwrap_or_else(DbId::generate);
+        let recorded = manifest::read_header(dir)?;
+        // SSTs without a manifest were written by a version from before there was one
+        let legacy_tables = match recorded {
+            Some(_) => 0,
+            None => sst_files(dir)?.len(),
+        };
+        if legacy_tables > 0 && !options.adopt_legacy_files {
+            return Err(LsmError::LegacyDirectory {
+                tables: legacy_tables,
+            }
+            .into());
+        }
+        let managed = recorded.is_some();
+        let created = !managed && legacy_tables == 0;
+        // written once the tables are recovered, so that an open that fails leaves it as it was
+        let write_header = match recorded {
+            Some(recorded) if recorded == fingerprint && recorded_id.is_some() => false,
+            Some(recorded) => {
+                for warning in recorded.check(&fingerprint, options.allow_format_change)? {
+                    log::warn!("{} ({}): {}", dir.display(), db_id, warning);
+                }
+                let wal_len = std::fs::metadata(path_of_wal(dir)).map_or(0, |meta| meta.len());
+                ensure!(
+                    recorded.entry_checksums == fingerprint.entry_checksums || wal_len == 0,
+                    "the write-ahead log was written with entry checksums {}, open with the \
+                     same setting and sync before changing it",
+                    if recorded.entry_checksums {
+                        "on"
+                    } else {
+                        "off"
+                    }
+                );
+                true
+            }
+            // written along with the tables adopted
+            None => legacy_tables == 0,
+        };
+
+        // dropped from the manifest before anything else changes, so that a crash from here on
+        // is not taken for a clean shutdown
+        let clean_shutdown = match managed {
+            true => manifest::take_clean_shutdown(dir)?,
+            false => None,
+        };
+        // `close` leaves no temporary file, torn manifest record or torn log record behind
+        if clean_shutdown.is_none() {
+            remove_tmp_files(dir)?;
+        }
+        step_done("remove temporary files")?;
+        migrate_layout(dir, options.sst_layout)?;
+        step_done("migrate layout")?;
+        let entry_checksums = options.entry_checksums;
+        let open_wal = |wal: Wal| {
+            if entry_checksums {
+                wal.with_entry_checksums()
+            } else {
+                wal
+            }
+        };
+        let cache = Arc::new(BlockCache::new(options.block_cache_capacity));
+        let compressed_cache = options.compressed_block_cache_bytes.map(|bytes| {
+            Arc::new(CompressedBlockCache::new(
+                bytes,
+                options.block_cache_admission,
+            ))
+        });
+        let verify_seed = match options.verify_on_open {
+            VerifyLevel::SampleBlocks { .. } => {
+                Some(options.verify_seed.unwrap_or_else(random_seed))
+            }
+            _ => None,
+        };
+        let mut verifier = TableVerifier::new(options.verify_on_open, verify_seed.unwrap_or(0));
+        let mut unreadable = vec![];
+        let metrics = Arc::new(Metrics::new());
+        let (inner, unrecorded) = LsmStorageInner::recover(
+            dir,
+            &cache,
+            &compressed_cache,
+            &metrics,
+            &mut verifier,
+            |report| match options.recovery {
+                RecoveryMode::Strict => Err(anyhow::anyhow!(
+                    "{} failed on {}: {}",
+                    report.operation,
+                    report.file.display(),
+                    report.error
+                )),
+                RecoveryMode::BestEffort => {
+                    quarantine(dir, &report)?;
+                    unreadable.push(report);
+                    Ok(())
+                }
+            },
+        )
+        .with_context(|| format!("failed to recover database {}", db_id))?;
+        step_done("recover tables")?;
+        if !unrecorded.missing.is_empty() {
+            let missing = format!(
+                "the manifest records {} sst(s) which are missing: {:?}",
+                unrecorded.missing.len(),
+                unrecorded.missing
+            );
+            match options.recovery {
+                RecoveryMode::Strict => {
+                    anyhow::bail!("failed to recover database {}: {}", db_id, missing)
+                }
+                RecoveryMode::BestEffort => {
+                    log::warn!("{} ({}): {}", dir.display(), db_id, missing)
+                }
+            }
+        }
+        if managed && clean_shutdown.is_none() && manifest::trim_torn_record(dir)? {
+            log::warn!(
+                "{} ({}): dropped a manifest record cut short by a crash",
+                dir.display(),
+                db_id
+            );
+        }
+        if legacy_tables > 0 {
+            let report = record_adoption(dir, &db_id, &fingerprint, &inner, unreadable)?;
+            log::info!("{}: {}", dir.display(), report);
+        } else if write_header {
+            manifest::write_header(dir, &db_id, &fingerprint)?;
+        }
+        // recovery went on without the missing and the quarantined tables, so no later open
+        // looks for them
+        manifest::append_records(
+            dir,
+            unrecorded
+                .missing
+                .iter()
+                .chain(&unrecorded.dropped)
+                .map(|&id| ManifestRecord::RemoveTable { id }),
+        )?;
+        step_done("write manifest")?;
+
+        let retention = Arc::new(FileRetention::with_trash(options.trash.clone()));
+        retention.recover_trash(dir)?;
+        retention.mark_obsolete_at(
+            dir,
+            removed_sst_files(dir)?
+                .into_iter()
+                .chain(unrecorded.orphans)
+                .map(|file| (FileId::Sst(file.id), file.path)),
+        )?;
+        let scratch = EncodeScratch::new();
+        inner.validate()?;
+
+        let open_report = OpenReport {
+            db_id,
+            dir: dir.into(),
+            created,
+            recovered_tables: inner.num_sst_files(),
+            wal_records_replayed: 0,
+            clean_shutdown: clean_shutdown.is_some(),
+            fingerprint,
+            verify_seed,
+        };
+        let access = options
+            .tiering
+            .clone()
+            .map(|tiering| Arc::new(AccessTracker::new(tiering)));
+        let negative_cache = options
+            .negative_cache_capacity
+            .and_then(NonZeroUsize::new)
+            .map(|capacity| Arc::new(NegativeCache::new(capacity)));
+        let (tx, rx) = flume::unbounded();
+        let (janitor_tx, janitor_rx) = flume::unbounded();
+        let wal = Arc::new(Mutex::new(open_wal(Wal::create(path_of_wal(dir))?)));
+        let group_commit = match options.group_commit {
+            true => Some(Arc::new(WalGroupCommit::new(
+                wal.clone(),
+                options.group_commit_timeout,
+            )?)),
+            false => None,
+        };
+        // the writes the tables hold come before any this open issues
+        let sequence = Arc::new(CommitSequence::starting_after(WriteToken(
+            inner.max_sst_seq(),
+        )));
+        let mut lsm = Self {
+            inner: Arc::new(RwLock::new(Arc::new(inner))),
+            state_lock: Arc::new(Mutex::new(())),
+            dir: dir.into(),
+            cache,
+            compressed_cache,
+            live: Arc::new(RwLock::new(LiveOptions::new(&options))),
+            options: Arc::new(options),
+            wal,
+            group_commit,
+            sequence,
+            retention,
+            scratch: Arc::new(Mutex::new(scratch)),
+            metrics,
+            access,
+            negative_cache,
+            gc_horizon: Arc::new(AtomicU64::new(u64::MAX)),
+            compacting: Arc::new(Mutex::new(HashSet::new())),
+            sync_tx: tx,
+            sync_rx: rx,
+            janitor_tx,
+            worker: Arc::new(Mutex::new(WorkerStatus::compaction())),
+            other_workers: Arc::new(Mutex::new(Vec::new())),
+            worker_exited: Arc::new(Condvar::new()),
+            cancel: CancellationToken::new(),
+            open_report: Arc::new(open_report),
+            session: DbId::generate(),
+            opened_at: Instant::now(),
+            closed: Arc::new(AtomicBool::new(false)),
+            closing: Arc::new(AtomicBool::new(false)),
+            // a storage that fails to open is dropped without `close`
+            handles: None,
+            worker_thread: Arc::new(Mutex::new(None)),
+            warm_thread: Arc::new(Mutex::new(None)),
+            #[cfg(test)]
+            job_hook: Arc::new(Mutex::new(None)),
+        };
+
+        if let Some(threshold) = lsm.options.small_sst_threshold {
+            lsm.merge_small_tables(threshold)?;
+            step_done("merge small tables")?;
+            lsm.retention.purge()?;
+            step_done("purge")?;
+        }
+
+        let wal = path_of_wal(dir);
+        let wal_len = std::fs::metadata(&wal).map_or(0, |meta| meta.len());
+        // a log of the length `close` left holds whole records, and none if it flushed
+        let clean = clean_shutdown == Some(wal_len);
+        if !(clean && wal_len == 0) {
+            match lsm.replay_wal(&open_wal(Wal::from(&wal)?))? {
+                Ok(stats) => {
+                    Arc::make_mut(&mut lsm.open_report).wal_records_replayed = stats.records;
+                    // a batch cut short by a crash would take in the records appended after it;
+                    // the records before it are replayed again if the next open comes before a
+                    // flush
+                    if !clean {
+                        lsm.wal.lock().truncate_to(stats.bytes)?;
+                    }
+                }
+                Err(err) => match lsm.options.recovery {
+                    RecoveryMode::Strict => {
+                        return Err(err.context(format!(
+                            "failed to recover database {}: {} failed to replay",
+                            lsm.open_report.db_id,
+                            wal.display()
+                        )))
+                    }
+                    RecoveryMode::BestEffort => {
+                        let report = CorruptionReport::new(&wal, "replay wal", &err);
+                        quarantine(dir, &report)?;
+                        log::warn!(
+                            "{} ({}): quarantined {}: {}",
+                            dir.display(),
+                            lsm.open_report.db_id,
+                            wal.display(),
+                            report.error
+                        );
+                        // the records replayed before the corruption are flushed, as the log
+                        // they came from is gone
+                        *lsm.wal.lock() = open_wal(Wal::create(&wal)?);
+                        lsm.sync()?;
+                    }
+                },
+            }
+        }
+        step_done("trim write-ahead log")?;
+
+        log::info!("{}", lsm.open_report);
+        lsm.file_count_boost(&lsm.inner.read());
+        lsm.start_workers(janitor_rx)?;
+        lsm.handles = Some(Arc::new(CloseOnDrop(lsm.clone())));
+        Ok(lsm)
+    }
+
+    /// Write a manifest for the SSTs of a directory without one, as left by the versions from
+    /// before the manifest. Every table goes to the level its location records, L0 for
+    /// `{id}.sst`, and the files that fail to load are quarantined. The manifest records the
+    /// default options, as if the database had been created with them.
+    ///
+    /// [`LsmStorage::open`] does the same when [`LsmStorageOptions::adopt_legacy_files`] is on.
+    pub fn adopt_legacy(path: impl AsRef<Path>) -> Result<AdoptionReport> {
+        let dir = path.as_ref();
+        ensure!(
+            manifest::read_header(dir)?.is_none(),
+            "{} already has a manifest",
+            dir.display()
+        );
+        let cache = Arc::new(BlockCache::new(1 << 10));
+        let mut verifier = TableVerifier::new(VerifyLevel::None, 0);
+        let mut unreadable = vec![];
+        let metrics = Arc::new(Metrics::new());
+        let (inner, _) =
+            LsmStorageInner::recover(dir, &cache, &None, &metrics, &mut verifier, |report| {
+                quarantine(dir, &report)?;
+                unreadable.push(report);
+                Ok(())
+            })?;
+        let fingerprint = OptionsFingerprint::of(&LsmStorageOptions::default());
+        record_adoption(dir, &DbId::generate(), &fingerprint, &inner, unreadable)
+    }
+
+    /// List the files that recovery would quarantine, without touching anything on disk.
+    pub fn recover_dry_run(path: impl AsRef<Path>) -> Result<Vec<CorruptionReport>> {
+        let cache = Arc::new(BlockCache::new(1 << 10));
+        let mut candidates = vec![];
+        let mut verifier = TableVerifier::new(VerifyLevel::None, 0);
+        let metrics = Arc::new(Metrics::new());
+        LsmStorageInner::recover(
+            path.as_ref(),
+            &cache,
+            &None,
+            &metrics,
+            &mut verifier,
+            |report| {
+                candidates.push(report);
+                Ok(())
+            },
+        )?;
+        Ok(candidates)
+    }
+
+    /// Pin the SSTs the storage currently reads from, so that they outlive compaction until the
+    /// guard is dropped.
+    pub fn retain_live_files(&self) -> RetentionGuard {
+        let inner = self.inner.read().clone();
+        let files = inner
+            .l0_sstables
+            .iter()
+            .chain(inner.levels.iter().flatten())
+            .map(|sst| FileId::Sst(sst.sst_id()));
+        self.retention.acquire(files)
+    }
+
+    /// The files held on disk by a [`RetentionGuard`], for debugging.
+    pub fn pinned_files(&self) -> BTreeSet<FileId> {
+        self.retention.pinned()
+    }
+
+    /// Delete the obsolete files whose grace period is over and that no guard pins anymore,
+    /// without waiting for the janitor.
+    pub fn purge_obsolete_files(&self) -> Result<Vec<FileId>> {
+        self.retention.purge()
+    }
+
+    /// Delete the obsolete files that no guard pins anymore, grace period or not.
+    pub fn purge_trash_now(&self) -> Result<Vec<FileId>> {
+        self.retention.purge_now()
+    }
+
+    /// The obsolete files waiting to be deleted.
+    pub fn trash_stats(&self) -> TrashStats {
+        self.retention.trash_stats()
+    }
+
+    /// Keep the tombstones of the deletes with a commit sequence of at least `horizon` through
+    /// compactions, for consumers that still have to observe them. Entries carry no sequence of
+    /// their own, so a compaction keeps the tombstones of its inputs unless every one of them
+    /// holds only writes older than the horizon, see [`SsTable::max_seq`].
+    /// `WriteToken(u64::MAX)`, the default, retains nothing.
+    pub fn set_gc_horizon(&self, horizon: WriteToken) {
+        self.gc_horizon.store(horizon.0, Ordering::Release);
+    }
+
+    pub fn gc_horizon(&self) -> WriteToken {
+        WriteToken(self.gc_horizon.load(Ordering::Acquire))
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// The id of the database, the same across restarts.
+    pub fn db_id(&self) -> DbId {
+        self.open_report.db_id
+    }
+
+    /// What the storage found when it was opened.
+    pub fn open_report(&self) -> &OpenReport {
+        &self.open_report
+    }
+
+    /// The totals of the storage since it was opened, as logged by `stop`.
+    pub fn close_report(&self) -> CloseReport {
+        CloseReport {
+            db_id: self.db_id(),
+            uptime: self.opened_at.elapsed(),
+            flushes: self.metrics.flushes(),
+            flush_bytes_written: self.metrics.flush_bytes_written(),
+            compactions: self.metrics.compactions(),
+            bytes_ingested: self.metrics.bytes_ingested(),
+            live_files: self.metrics.live_file_count(),
+        }
+    }
+
+    /// An estimate of the number of keys, from the entry counts of the memtables and the
+    /// properties of the SSTs, without any IO. Overwritten and deleted keys are counted until
+    /// compaction merges their versions away, so it over-counts rather than under-counts.
+    pub fn approximate_len(&self) -> u64 {
+        self.inner.read().approximate_len()
+    }
+
+    /// Whether the storage looks empty, from the same counts as `approximate_len`. Keys deleted
+    /// in a newer table than the one holding them keep it `false` until compaction.
+    pub fn is_empty_estimate(&self) -> bool {
+        self.inner.read().is_empty_estimate()
+    }
+
+    /// Apply the changes of `delta` all at once, or none of them if any is invalid.
+    pub fn set_options(&self, delta: MutableOptions) -> Result<()> {
+        let live = {
+            let mut live = self.live.write();
+            *live = live.with(&delta)?;
+            *live
+        };
+        self.cache.resize(live.block_cache_capacity);
+        self.file_count_boost(&self.inner.read());
+        Ok(())
+    }
+
+    /// The options in effect, with the changes of `set_options`.
+    pub fn current_options(&self) -> LsmStorageOptions {
+        let live = *self.live.read();
+        LsmStorageOptions {
+            block_cache_capacity: live.block_cache_capacity,
+            l0_compaction_trigger: live.l0_compaction_trigger,
+            soft_max_files: live.soft_max_files,
+            max_batch_bytes: live.max_batch_bytes,
+            ..self.options.as_ref().clone()
+        }
+    }
+
+    /// Get a key from the storage. The SSTs whose key filter rules the key out are skipped
+    /// without reading a block, see [`SsTable::may_contain`].
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.get_opt(key, ReadOptions::default())
+    }
+
+    pub fn get_opt(&self, key: &[u8], options: ReadOptions) -> Result<Option<Bytes>> {
+        if let Some(access) = &self.access {
+            access.record(key);
+        }
+        // taken before reading, so that a put racing with the read keeps the miss out
+        let seen = self.sequence.applied();
+        if let Some(cache) = &self.negative_cache {
+            if cache.contains(key) {
+                return Ok(None);
+            }
+        }
+        let value = self.inner.read().get(key, &options)?;
+        let value = value.filter(|v| !v.is_empty());
+        if let (None, Some(cache)) = (&value, &self.negative_cache) {
+            cache.insert(key, seen);
+        }
+        Ok(value)
+    }
+
+    /// Like `get`, returning a handle that knows the length of the value and reads it, or a
+    /// part of it, on demand. The handle keeps the SST holding the value on disk until dropped.
+    pub fn get_handle(&self, key: &[u8]) -> Result<Option<ValueHandle>> {
+        self.get_handle_opt(key, ReadOptions::default())
+    }
+
+    pub fn get_handle_opt(&self, key: &[u8], options: ReadOptions) -> Result<Option<ValueHandle>> {
+        if let Some(access) = &self.access {
+            access.record(key);
+        }
+        let key = Bytes::copy_from_slice(key);
+        let (inner, guard) = {
+            let _wal = self.wal.lock();
+            let inner = self.inner.read().clone();
+            if let Some(value) = inner.get_from_memtables(&key) {
+                return Ok((!value.is_empty()).then(|| ValueHandle::in_memory(key, value)));
+            }
+            // pin the tables until the one holding the value is known
+            (inner, self.retain_live_files())
+        };
+        let (table, block_idx, entry_idx, value) = match inner.locate_in_sstables(&key, &options)? {
+            Some(found) if !found.3.is_empty() => found,
+            _ => return Ok(None),
+        };
+        let pin = self.retention.acquire([FileId::Sst(table.sst_id())]);
+        drop(guard);
+        Ok(Some(ValueHandle::in_sst(
+            key,
+            value.len(),
+            table,
+            (block_idx, entry_idx),
+            pin,
+            options,
+        )))
+    }
+
+    /// Wait until the write of `token`, possibly made through another handle, is visible to the
+    /// reads of this one. Fails with [`LsmError::VisibilityTimeout`] after `timeout`.
+    pub fn wait_for_visibility(&self, token: WriteToken, timeout: Duration) -> Result<()> {
+        if self.sequence.wait_applied(token, timeout) {
+            return Ok(());
+        }
+        Err(LsmError::VisibilityTimeout {
+            token: token.0,
+            applied: self.sequence.applied().0,
+        }
+        .into())
+    }
+
+    /// Like `get`, reading no earlier than the write of `token` became visible.
+    pub fn get_after(&self, key: &[u8], token: WriteToken) -> Result<Option<Bytes>> {
+        self.wait_for_visibility(token, VISIBILITY_TIMEOUT)?;
+        self.get(key)
+    }
+
+    /// Get several keys at once, as of a single point in time: a write racing with the call is
+    /// seen for all of the keys or for none of them. Values are returned in the order of `keys`.
+    pub fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Bytes>>> {
+        self.get_many_opt(keys, ReadOptions::default())
+    }
+
+    pub fn get_many_opt(&self, keys: &[&[u8]], options: ReadOptions) -> Result<Vec<Option<Bytes>>> {
+        if let Some(access) = &self.access {
+            keys.iter().for_each(|key| access.record(key));
+        }
+        let (inner, mut found) = {
+            // writers insert under the WAL lock, so the memtables hold still while it is held
+            let _wal = self.wal.lock();
+            let inner = self.inner.read().clone();
+            let found = keys
+                .iter()
+                .map(|key| inner.get_from_memtables(key))
+                .collect::<Vec<_>>();
+            (inner, found)
+        };
+        inner.get_many_from_sstables(keys, &mut found, &options)?;
+
+        Ok(found
+            .into_iter()
+            .map(|value| value.filter(|value| !value.is_empty()))
+            .collect())
+    }
+
+    /// Capture the storage as it is now, for reads that agree with each other, see [`Snapshot`].
+    /// A small memtable is copied and a larger one frozen, so that the writes after the call
+    /// go into one the snapshot does not see.
+    pub fn get_snapshot(&self) -> Snapshot {
+        // writers insert under the WAL lock, so the memtable holds still while it is held
+        let _wal = self.wal.lock();
+        let small = {
+            let inner = self.inner.read();
+            (inner.memtable.size() < SNAPSHOT_COPY_LIMIT).then(|| inner.as_ref().clone())
+        };
+        let mut inner = match small {
+            Some(mut inner) => {
+                if !inner.memtable.is_empty() {
+                    inner.imm_memtables.push(inner.memtable.copy_frozen());
+                }
+                inner
+            }
+            None => {
+                let state_lock = self.state_lock.lock();
+                self.update_state(&state_lock, |inner| {
+                    inner.archive_mem_table();
+                    inner.clone()
+                })
+            }
+        };
+        inner.memtable = Arc::new(MemTable::create());
+        Snapshot::new(Arc::new(inner))
+    }
+
+    /// Put a key-value pair into the storage by writing into the current memtable.
+    /// The returned token tells other handles when they can see it, see
+    /// [`LsmStorage::wait_for_visibility`].
+    pub fn put(&self, key: Bytes, value: Bytes) -> Result<WriteToken> {
+        self.put_opt(key, value, WriteOptions::default())
+    }
+
+    pub fn put_opt(&self, key: Bytes, value: Bytes, options: WriteOptions) -> Result<WriteToken> {
+        self.write_internal(vec![WriteOp::Put(key, value)], options)
+    }
+
+    /// Remove a key from the storage by writing an empty value.
+    pub fn delete(&self, key: &[u8]) -> Result<WriteToken> {
+        self.delete_opt(key, WriteOptions::default())
+    }
+
+    pub fn delete_opt(&self, key: &[u8], options: WriteOptions) -> Result<WriteToken> {
+        self.write_internal(vec![WriteOp::Delete(Bytes::copy_from_slice(key))], options)
+    }
+
+    /// Apply every write of `batch` or none of them, in a single memtable. A batch that does
+    /// not fit in what is left of the current memtable freezes it first.
+    pub fn write(&self, batch: WriteBatch) -> Result<WriteToken> {
+        self.write_opt(batch, WriteOptions::default())
+    }
+
+    pub fn write_opt(&self, batch: WriteBatch, options: WriteOptions) -> Result<WriteToken> {
+        let limit = self.live.read().max_batch_bytes;
+        if batch.size() > limit {
+            return Err(LsmError::BatchTooLarge {
+                size: batch.size(),
+                limit,
+            }
+            .into());
+        }
+        self.write_internal(batch.ops, options)
+    }
+
+    /// The single write path shared by every mutation: validates the entries, logs them,
+    /// inserts them into the current memtable and schedules a flush once a memtable is full.
+    fn write_internal(&self, ops: Vec<WriteOp>, options: WriteOptions) -> Result<WriteToken> {
+        let bytes: usize = ops
+            .iter()
+            .map(|op| match op {
+                WriteOp::Put(key, value) => key.len() + value.len(),
+                WriteOp::Delete(key) => key.len(),
+            })
+            .sum();
+        let (token, full) = self.commit(ops, options)?;
+        self.metrics.record_ingest(bytes as u64);
+        if full {
+            let backlog = self.inner.read().imm_memtables.len();
+            if !self.options.background_compaction || backlog > self.options.max_imm_memtables {
+                self.sync()?;
+            } else {
+                self.schedule_compaction()?;
+            }
+        }
+        Ok(token)
+    }
+
+    /// The entry a write puts into the memtable, if it is a valid one.
+    fn validate(&self, op: WriteOp) -> Result<(Bytes, Bytes)> {
+        let (key, value) = match op {
+            WriteOp::Put(key, value) => {
+                ensure!(!value.is_empty(), "value cannot be empty");
+                let max_value_size = match self.options.entry_checksums {
+                    // the checksum is stored as part of the value
+                    true => self
+                        .options
+                        .max_value_size
+                        .min(MAX_VALUE_SIZE - ENTRY_CHECKSUM_SIZE),
+                    false => self.options.max_value_size,
+                };
+                ensure!(
+                    value.len() <= max_value_size,
+                    "value of {} bytes exceeds the limit of {} bytes",
+                    value.len(),
+                    max_value_size
+                );
+                (key, value)
+            }
+            WriteOp::Delete(key) => (key, Bytes::new()),
+        };
+        ensure!(!key.is_empty(), "key cannot be empty");
+        ensure!(
+            key.len() <= self.options.max_key_size,
+            "key of {} bytes exceeds the limit of {} bytes",
+            key.len(),
+            self.options.max_key_size
+        );
+        Ok((key, value))
+    }
+
+    /// Validate, log and insert writes, all under one sequence. Returns the sequence and whether
+    /// a memtable is due for a flush: the one a single write took past its limit, or the one a
+    /// batch froze for not fitting in it.
+    fn commit(&self, ops: Vec<WriteOp>, options: WriteOptions) -> Result<(WriteToken, bool)> {
+        ensure!(
+            !(options.disable_wal && options.sync),
+            "a write cannot both skip the WAL and sync it"
+        );
+        let batch_size: usize = ops.iter().map(WriteOp::size).sum();
+        let entries = ops
+            .into_iter()
+            .map(|op| self.validate(op))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut wal = self.wal.lock();
+        if self.closing.load(Ordering::Acquire) {
+            return Err(LsmError::Closed.into());
+        }
+        let token = self.sequence.issue();
+        // only writers and `sync` freeze the memtable, both under the WAL lock, so this is the
+        // memtable the flush path sees until the inserts are done
+        let mut mem = self.inner.read().memtable.clone();
+        // a batch never straddles two memtables
+        let rotate = entries.len() > 1
+            && mem.size() > 0
+            && mem.size() + batch_size > self.options.memtable_size_limit;
+        if rotate {
+            let state_lock = self.state_lock.lock();
+            mem = self.update_state(&state_lock, |inner| {
+                inner.archive_mem_table();
+                inner.memtable.clone()
+            });
+        }
+        let mut ticket = None;
+        if !options.disable_wal {
+            match (&self.group_commit, entries.as_slice()) {
+                (_, []) => {}
+                (Some(group_commit), _) => ticket = Some(group_commit.enqueue(entries.clone())?),
+                (None, [(key, value)]) => wal.append(key, value)?,
+                (None, entries) => wal.append_batch(entries)?,
+            }
+            if options.sync && self.group_commit.is_none() {
+                wal.sync()?;
+            }
+        }
+        if let Some(cache) = &self.negative_cache {
+            let puts = entries.iter().filter(|(_, value)| !value.is_empty());
+            cache.invalidate(puts.map(|(key, _)| key), token);
+        }
+        let size = mem.size();
+        // the last write of a key is the only one put, so that no read sees the ones before it
+        for (key, value) in last_per_key(entries, |(key, _)| key) {
+            mem.put(key, value);
+        }
+        mem.note_seq(token.0);
+        self.sequence.mark_applied(token);
+        drop(wal);
+        // the write is readable meanwhile, as an unsynced one is before it reaches the disk
+        if let (Some(group_commit), Some(ticket)) = (&self.group_commit, ticket) {
+            group_commit.wait(ticket)?;
+        }
+
+        let limit = self.options.memtable_size_limit;
+        let crossed = size <= limit && mem.size() > limit;
+        Ok((token, rotate || crossed))
+    }
+
+    /// Send the records of `wal` down the write path without logging them again. A memtable
+    /// growing past its limit is frozen, the way the worker would before flushing it, so that
+    /// recovery never holds more than one memtable's worth of unfrozen data.
+    ///
+    /// Fails if the records cannot be applied. The inner error is the log's own, such as a
+    /// record failing its checksum, with the records before it applied.
+    fn replay_wal(&self, wal: &Wal) -> Result<Result<ReplayStats>> {
+        let options = WriteOptions {
+            disable_wal: true,
+            ..Default::default()
+        };
+        let mut apply_failed = false;
+        let replayed = wal.replay_batches(&mut |records| {
+            let ops = records
+                .into_iter()
+                .map(|record| match ValueKind::of_value(&record.value) {
+                    ValueKind::Delete => WriteOp::Delete(record.key),
+                    ValueKind::Put => WriteOp::Put(record.key, record.value),
+                })
+                .collect();
+            // a batch freezes the memtable it does not fit in by itself
+            let (_, rotate) = self
+                .commit(ops, options)
+                .inspect_err(|_| apply_failed = true)?;
+            if rotate {
+                let state_lock = self.state_lock.lock();
+                if self.inner.read().memtable.size() > self.options.memtable_size_limit {
+                    self.update_state(&state_lock, |inner| inner.archive_mem_table());
+                }
+            }
+            Ok(())
+        });
+
+        match replayed {
+            Ok(stats) => {
+                self.metrics.record_wal_replay(&stats);
+                Ok(Ok(stats))
+            }
+            Err(err) if apply_failed => Err(err),
+            Err(err) => Ok(Err(err)),
+        }
+    }
+
+    /// Persist data to disk.
+    ///
+    /// In day 3: flush the current memtable to disk as L0 SST.
+    /// In day 6: call `fsync` on WAL.
+    ///
+    /// Every frozen memtable is flushed along with the current one, oldest first. Reads go on
+    /// while the tables are written, finding the data in the frozen memtables until the tables
+    /// take their place.
+    pub fn sync(&self) -> Result<()> {
+        // writes wait for the WAL lock, so none lands in a memtable being flushed, or in the log
+        // before it is truncated
+        let mut wal = self.wal.lock();
+        let (frozen, first_sst_id) = {
+            let state_lock = self.state_lock.lock();
+            self.update_state(&state_lock, |inner| {
+                inner.archive_mem_table();
+                let frozen = inner.imm_memtables.clone();
+                let first_sst_id = inner.next_sst_id;
+                inner.next_sst_id += frozen.iter().filter(|mem| !mem.is_empty()).count();
+                (frozen, first_sst_id)
+            })
+        };
+
+        let mut flushed = Vec::with_capacity(frozen.len());
+        let mut paths = Vec::with_capacity(frozen.len());
+        let non_empty = frozen.iter().filter(|mem| !mem.is_empty());
+        let written = (first_sst_id..)
+            .zip(non_empty)
+            .try_for_each(|(sst_id, mem)| {
+                let path = self.path_of_sst(0, sst_id)?;
+                // written aside and synced, then moved into place, so that a crash never leaves
+                // a torn table under its name
+                let tmp = path_of_tmp_sst(&self.dir, sst_id);
+                paths.push(tmp.clone());
+                let mut sstable = mem
+                    .to_sst_with(sst_builder(&self.options).with_max_seq(mem.max_seq()))
+                    .export_with_scratch(
+                        sst_id,
+                        Some(self.cache.clone()),
+                        &tmp,
+                        &mut self.scratch.lock(),
+                    )?;
+                sstable.rename_file(&path)?;
+                *paths.last_mut().unwrap() = path;
+                let sstable = sstable
+                    .with_compressed_cache(self.compressed_cache.clone())
+                    .with_level_io(0, self.metrics.clone());
+                flushed.push(Arc::new(sstable));
+                Ok::<_, anyhow::Error>(())
+            });
+        if let Err(err) = written {
+            // the memtables stay frozen for the next flush, and open would trip on a torn table
+            for path in &paths {
+                let _ = std::fs::remove_file(path);
+            }
+            return Err(err);
+        }
+        for sstable in &flushed {
+            self.metrics.record_flush(sstable.file_size());
+        }
+        // the tables synced their data as they were built, their directory entries have to be on
+        // disk as well before the manifest records them and the log is truncated
+        sync_parent_dirs(paths.iter().map(PathBuf::as_path))?;
+
+        manifest::append_records(
+            &self.dir,
+            flushed.iter().map(|sst| ManifestRecord::AddTable {
+                level: 0,
+                id: sst.sst_id(),
+            }),
+        )?;
+        {
+            let state_lock = self.state_lock.lock();
+            self.update_state(&state_lock, |inner| {
+                // a compaction may have changed the SSTs meanwhile, but only writes holding the
+                // WAL lock freeze memtables, so the frozen ones are still the first in line
+                inner.imm_memtables.drain(..frozen.len());
+                for sst in &flushed {
+                    inner.sst_key_range.widen(sst);
+                }
+                inner.l0_sstables.extend(flushed);
+                self.file_count_boost(inner);
+            });
+        }
+        wal.truncate()?;
+
+        Ok(())
+    }
+
+    /// Replace the state with a copy `change` makes, under the state lock the caller holds.
+    fn update_state<R>(
+        &self,
+        _state_lock: &MutexGuard<()>,
+        change: impl FnOnce(&mut LsmStorageInner) -> R,
+    ) -> R {
+        let mut guard = self.inner.write();
+        let mut inner = guard.as_ref().clone();
+        let result = change(&mut inner);
+        *guard = Arc::new(inner);
+        result
+    }
+
+    #[cfg(test)]
+    pub(crate) fn num_imm_memtables(&self) -> usize {
+        self.inner.read().imm_memtables.len()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn memtable_len(&self) -> usize {
+        self.inner.read().memtable.len()
+    }
+
+    /// The ids of the SSTs of every level, L0 first.
+    #[cfg(test)]
+    pub(crate) fn sst_ids_by_level(&self) -> Vec<Vec<usize>> {
+        let inner = self.inner.read();
+        std::iter::once(&inner.l0_sstables)
+            .chain(&inner.levels)
+            .map(|level| level.iter().map(|sst| sst.sst_id()).collect())
+            .collect()
+    }
+
+    /// Where the block reads were served from, when there is a compressed block cache, see
+    /// [`LsmStorageOptions::compressed_block_cache_bytes`].
+    pub fn block_cache_stats(&self) -> Option<BlockCacheStats> {
+        self.compressed_cache.as_ref().map(|tier| tier.stats())
+    }
+
+    /// How the lookups of the cache of missing keys went, when there is one, see
+    /// [`LsmStorageOptions::negative_cache_capacity`].
+    pub fn negative_cache_stats(&self) -> Option<NegativeCacheStats> {
+        self.negative_cache.as_ref().map(|cache| cache.stats())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn block_cache(&self) -> &BlockCache {
+        &self.cache
+    }
+
+    /// Lose the writes that were not synced to the write-ahead log, as a power loss would.
+    #[cfg(test)]
+    pub(crate) fn simulate_power_loss(&self) -> Result<()> {
+        self.wal.lock().simulate_power_loss()
+    }
+
+    /// Create an iterator over a range of keys, e.g. `b"a"..b"c"`, or `..` for every key.
+    pub fn scan(&self, range: impl Into<KeyRange>) -> Result<ScanIter> {
+        self.scan_with(range.into(), &ReadOptions::default())
+    }
+
+    #[deprecated(note = "use `scan`, which takes a `KeyRange`")]
+    pub fn scan_bounds(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<ScanIter> {
+        self.scan((lower, upper))
+    }
+
+    #[deprecated(note = "use `scan`, which takes a `KeyRange`")]
+    pub fn scan_bytes(&self, lower: Bound<Bytes>, upper: Bound<Bytes>) -> Result<ScanIter> {
+        self.scan(KeyRange::from_bounds(lower, upper))
+    }
+
+    /// Like `scan`, reading the SSTs as `options` asks for. A scan that runs past the deadline
+    /// fails with [`LsmError::DeadlineExceeded`] and can be resumed after `set_deadline`.
+    pub fn scan_opt(&self, range: impl Into<KeyRange>, options: ReadOptions) -> Result<ScanIter> {
+        self.scan_with(range.into(), &options)
+    }
+
+    #[deprecated(note = "use `scan_opt`, which takes a `KeyRange`")]
+    pub fn scan_bounds_opt(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        options: ReadOptions,
+    ) -> Result<ScanIter> {
+        self.scan_opt((lower, upper), options)
+    }
+
+    /// Carry on with a scan from where `token` says it stopped, see [`ScanIter::resume_token`].
+    ///
+    /// The scan reads the storage as it is now, so the pages of a scan see the writes made in
+    /// between. To rather fail than see them, set [`ReadOptions::max_staleness`]: it counts from
+    /// the snapshot of the first page, so a zero age fails with [`LsmError::SnapshotTooOld`]
+    /// once anything was written, flushed or compacted since, and so does any limit once the
+    /// storage was reopened.
+    pub fn scan_resume(&self, token: &ResumeToken, options: ReadOptions) -> Result<ScanIter> {
+        if let Some(limit) = &options.max_staleness {
+            let age = if token.session == self.session {
+                SnapshotClock::since(&self.sequence, &self.metrics, token.taken_at).age()
+            } else {
+                // what happened in between is unknown
+                SnapshotAge {
+                    sequences_behind: u64::MAX,
+                    compactions_since: u64::MAX,
+                }
+            };
+            if age.exceeds(limit) {
+                return Err(LsmError::SnapshotTooOld {
+                    sequences_behind: age.sequences_behind,
+                    compactions_since: age.compactions_since,
+                }
+                .into());
+            }
+        }
+        let range = match &token.last_key {
+            Some(key) => {
+                KeyRange::from_bounds(Bound::Excluded(key.clone()), token.range.bounds().1.clone())
+            }
+            None => token.range.clone(),
+        };
+        let token = if token.session == self.session {
+            token.clone()
+        } else {
+            ResumeToken {
+                session: self.session,
+                taken_at: SnapshotClock::start(&self.sequence, &self.metrics).taken_at(),
+                ..token.clone()
+            }
+        };
+        self.scan_from(range, &options, token, false)
+    }
+
+    fn scan_with(&self, range: KeyRange, options: &ReadOptions) -> Result<ScanIter> {
+        let token = self.resume_token(&range);
+        self.scan_from(range, options, token, false)
+    }
+
+    /// The token of a scan of `range` starting now.
+    fn resume_token(&self, range: &KeyRange) -> ResumeToken {
+        ResumeToken {
+            session: self.session,
+            taken_at: SnapshotClock::start(&self.sequence, &self.metrics).taken_at(),
+            range: range.clone(),
+            last_key: None,
+        }
+    }
+
+    /// Like `scan`, but the deleted keys are reported as [`EntryOp::Delete`] rather than skipped,
+    /// for consumers that have to observe the deletes, e.g. replication. Only the newest version
+    /// of every key is reported.
+    ///
+    /// [`EntryOp::Delete`]: crate::lsm_iterator::EntryOp::Delete
+    pub fn scan_raw(&self, range: impl Into<KeyRange>) -> Result<RawScanIter> {
+        let range = range.into();
+        let token = self.resume_token(&range);
+        self.scan_from(range, &ReadOptions::default(), token, true)
+            .map(RawScanIter::new)
+    }
+
+    #[deprecated(note = "use `scan_raw`, which takes a `KeyRange`")]
+    pub fn scan_raw_bounds(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<RawScanIter> {
+        self.scan_raw((lower, upper))
+    }
+
+    /// Checks the bounds before building any iterator: a range that cannot hold a key, or that
+    /// the memtables and the SSTs hold none in, scans nothing without touching them, and an
+    /// inverted one is rejected. The snapshot age is
+    /// counted from the one of `token`.
+    fn scan_from(
+        &self,
+        range: KeyRange,
+        options: &ReadOptions,
+        token: ResumeToken,
+        keep_tombstones: bool,
+    ) -> Result<ScanIter> {
+        if range.is_inverted() && options.empty_on_inverted_bounds {
+            return Ok(ScanIter::empty(token));
+        }
+        range.check()?;
+        if range.is_empty() {
+            return Ok(ScanIter::empty(token));
+        }
+        let inner = self.inner.read().clone();
+        if inner.holds_none_within(&range) {
+            return Ok(ScanIter::empty(token));
+        }
+        let prefix = self
+            .options
+            .prefix_extractor
+            .as_ref()
+            .and_then(|extractor| Some((extractor, shared_prefix(extractor, &range)?)));
+        let clock = SnapshotClock::since(&self.sequence, &self.metrics, token.taken_at);
+        let cache_fill = options
+            .fill_cache
+            .then(|| Arc::new(ScanCacheFill::new(self.options.scan_fill_cache_limit)));
+        let mut iter = inner
+            .scan(&range, prefix, options, keep_tombstones, cache_fill)
+            .map(|iter| ScanIter::new(iter, token))?;
+        self.metrics
+            .record_unreadable_tables_skipped(iter.stats().skipped_tables.len() as u64);
+        iter.set_clock(clock, options.max_staleness);
+        Ok(iter)
+    }
+
+    /// Create an iterator over the keys starting with `prefix`. With a
+    /// [`LsmStorageOptions::prefix_extractor`], the tables that hold none of them are not read.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<ScanIter> {
+        let upper = match prefix_successor(prefix) {
+            Some(successor) => Bound::Excluded(successor),
+            None => Bound::Unbounded,
+        };
+        let range = KeyRange::from_bounds(Bound::Included(Bytes::copy_from_slice(prefix)), upper);
+        self.scan_with(range, &ReadOptions::default())
+    }
+
+    /// Work out what compacting `level`, or the level the built-in
+    /// [`LeveledPicker`](crate::compaction::LeveledPicker) would pick when `level` is `None`,
+    /// would read and write, without compacting anything.
+    pub fn plan_compaction(&self, level: Option<usize>) -> Result<CompactionPlan> {
+        let inner = self.inner.read().clone();
+        let min_files = self.live.read().l0_compaction_trigger;
+        match level {
+            Some(_) => plan_compaction(&inner.l0_sstables, &inner.levels, level, min_files),
+            None => pick_compaction(
+                &inner.l0_sstables,
+                &inner.levels,
+                min_files,
+                self.file_count_boost(&inner),
+            ),
+        }
+    }
+
+    /// The tables of the storage, as the compaction pickers see them.
+    pub fn describe_state(&self) -> LsmStateDescription {
+        let inner = self.inner.read().clone();
+        LsmStateDescription {
+            l0_sstables: inner.l0_sstables.clone(),
+            levels: inner.levels.clone(),
+            compacting: self.compacting.lock().clone(),
+            l0_compaction_trigger: self.live.read().l0_compaction_trigger,
+            file_count_boost: self.file_count_boost(&inner),
+        }
+    }
+
+    /// The boost of the compaction scores for the files of `inner`, recorded in the metrics.
+    fn file_count_boost(&self, inner: &LsmStorageInner) -> f64 {
+        let files = inner.num_sst_files();
+        let boost = file_count_boost(files, self.live.read().soft_max_files);
+        self.metrics.record_live_files(files as u64, boost);
+        boost
+    }
+
+    /// Run the compaction described by `plan`, failing if the inputs have changed since it was
+    /// made.
+    pub fn compact_with_plan(&self, plan: &CompactionPlan) -> Result<()> {
+        let current = self.plan_compaction(Some(plan.level))?;
+        ensure!(
+            current.inputs == plan.inputs,
+            "the compaction plan for level {} is stale",
+            plan.level
+        );
+        if plan.is_empty() {
+            return Ok(());
+        }
+        self.compact(plan.level)
+    }
+
+    /// Optimizing Space Amplification in RocksDB
+    /// https://www.cidrdb.org/cidr2017/papers/p82-dong-cidr17.pdf
+    ///
+    /// Compact all of `level` into the next level, along with the tables of the next level
+    /// within the key range it spans. An empty level is left as it is.
+    pub fn compact(&self, level: usize) -> Result<()> {
+        let task = {
+            let guard = self.inner.read();
+            let (upper, lower) = select_inputs(&guard.l0_sstables, &guard.levels, level)?;
+            CompactionTask::next_level(level, &upper, &lower)
+        };
+        if task.is_empty() {
+            return Ok(());
+        }
+        self.compact_task(&task)
+    }
+
+    /// Compact a single table of `level` into the next level, the one overlapping the most
+    /// tables there, along with the tables either level has within the key range they span.
+    /// Unlike [`compact`](Self::compact), the rest of the level stays put. A level without keys
+    /// is left as it is.
+    pub fn compact_level_to_next(&self, level: usize) -> Result<()> {
+        let task = {
+            let guard = self.inner.read();
+            let (upper, lower) = select_file_inputs(&guard.l0_sstables, &guard.levels, level)?;
+            CompactionTask::next_level(level, &upper, &lower)
+        };
+        if task.is_empty() {
+            return Ok(());
+        }
+        self.compact_task(&task)
+    }
+
+    /// Run `task`, such as a [`CompactionPicker`](crate::compaction::CompactionPicker) picks,
+    /// after checking it against the tables of the storage: every input is in the level the
+    /// task says and no running compaction reads it, the output goes below L0 and not above
+    /// any input, and every table within the key range of the inputs, down to the output level,
+    /// is an input too, but for the L0 tables newer than every L0 input. With tiering on, those
+    /// may not overlap the inputs either, as the hot output goes back into L0 after them. A task
+    /// failing the checks is an [`LsmError::InvalidArgument`], except for the conflict with a
+    /// running compaction.
+    ///
+    /// The merged entries stream to disk as the output tables fill, ending a table at
+    /// [`LsmStorageOptions::target_sst_size`]. With tiering on, the keys read often stay at the
+    /// first level of the inputs, in a table of their own, when the output goes below it.
+    pub fn compact_task(&self, task: &CompactionTask) -> Result<()> {
+        // with tiering on, the hot output of a task reading L0 goes back into L0
+        let hot_in_l0 =
+            self.access.is_some() && task.inputs.get(&0).is_some_and(|ids| !ids.is_empty());
+        let (inputs, inner, first_id, _reserved) = {
+            // no flush is between taking its id and adding its table to L0 while the WAL lock is
+            // held, so every table flushed later gets an id above the hot output
+            let _wal = hot_in_l0.then(|| self.wal.lock());
+            let mut compacting = self.compacting.lock();
+            let inner = self.inner.read().clone();
+            let inputs = resolve_task(task, &inner.l0_sstables, &inner.levels, &compacting)?;
+            if hot_in_l0 {
+                // the hot output takes an id above every L0 table, and would shadow a newer one
+                if let Some(id) = newer_l0_overlapping(&inner.l0_sstables, &inputs)? {
+                    return Err(LsmError::InvalidArgument(format!(
+                        "table {} of level 0 is newer than the inputs and overlaps them, which \
+                         the hot output would shadow",
+                        id
+                    ))
+                    .into());
+                }
+            }
+            let ids = task.input_ids().collect::<Vec<_>>();
+            compacting.extend(&ids);
+            let reserved = Reserved {
+                compacting: &self.compacting,
+                ids,
+            };
+            // the ids of the first output table and of the hot output, taken before a flush can
+            // take them
+            let tiered = (self.access.is_some() && inputs[0].0 < task.output_level) as usize;
+            let state_lock = self.state_lock.lock();
+            let first_id = self.update_state(&state_lock, |inner| {
+                inner.next_sst_id += 1 + tiered;
+                inner.next_sst_id - 1 - tiered
+            });
+            (inputs, inner, first_id, reserved)
+        };
+        let level = inputs[0].0;
+        let output_level = task.output_level;
+        let upper = &inputs[0].1;
+
+        // one iterator per sorted run, so the merge stays narrow however many tables there are
+        let mut runs = vec![];
+        for (input_level, tables) in &inputs {
+            match input_level {
+                0 => runs.extend(sorted_runs(tables)?),
+                _ => runs.push(tables.clone()),
+            }
+        }
+
+        let inputs = inputs.iter().flat_map(|(_, tables)| tables);
+        let bytes_read = inputs.clone().map(|sst| sst.file_size()).sum();
+        let level_bytes_read = upper.iter().map(|sst| sst.file_size()).sum();
+        // a table below the output may hold a value a dropped tombstone deletes
+        let drop_tombstones = may_drop_tombstones(
+            inputs.clone(),
+            &inner.levels,
+            output_level,
+            self.gc_horizon(),
+        )?;
+        // the outputs hold the latest write of any input
+        let max_seq = inputs.clone().map(|sst| sst.max_seq()).max().unwrap_or(0);
+        let access = self.access.as_ref().filter(|_| level < output_level);
+
+        let mut output = OutputTables::new(self, output_level, self.options.target_sst_size)
+            .with_first_id(first_id)
+            .with_max_seq(max_seq);
+        // with tiering on, the keys read often stay at the compacted level
+        let mut hot = access.map(|_| {
+            OutputTables::new(self, level, usize::MAX)
+                .with_first_id(first_id + 1)
+                .with_max_seq(max_seq)
+        });
+        let mut iter = merge_runs(runs)?;
+        let mut entries = 0;
+        while iter.is_valid() {
+            if entries % CANCEL_CHECK_ENTRIES == 0 {
+                // the tables written so far are deleted on the way out
+                self.cancel.check()?;
+            }
+            entries += 1;
+            if !(drop_tombstones && iter.value().is_empty()) {
+                match (access, &mut hot) {
+                    (Some(access), Some(hot)) if access.is_hot(iter.key()) => {
+                        hot.add(iter.key(), iter.value())?
+                    }
+                    _ => output.add(iter.key(), iter.value())?,
+                }
+            };
+            iter.next()?;
+        }
+        self.cancel.check()?;
+        output.finish()?;
+        if let Some(hot) = &mut hot {
+            hot.finish()?;
+            self.metrics.record_tiering(hot.entries, output.entries);
+        }
+
+        let level_bytes_written = hot.as_ref().map_or(0, |hot| hot.file_size());
+        self.metrics.record_compaction(CompactionSummary {
+            level,
+            output_level,
+            bytes_read,
+            bytes_written: output.file_size() + level_bytes_written,
+            level_bytes_read,
+            level_bytes_written,
+        });
+        // replace the input sstables with the new sstables in the output level
+        let compacted = inputs
+            .clone()
+            .map(|sst| sst.sst_id())
+            .collect::<HashSet<_>>();
+        // the additions go first, so that a crash cutting the write short keeps the data, if
+        // twice over
+        let added = output
+            .tables
+            .iter()
+            .map(|sst| (output_level, sst.sst_id()))
+            .chain(
+                hot.iter()
+                    .flat_map(|hot| &hot.tables)
+                    .map(|sst| (level, sst.sst_id())),
+            )
+            .map(|(level, id)| ManifestRecord::AddTable { level, id });
+        let removed = compacted
+            .iter()
+            .map(|&id| ManifestRecord::RemoveTable { id });
+        output.sync_dirs()?;
+        if let Some(hot) = &hot {
+            hot.sync_dirs()?;
+        }
+        manifest::append_records(&self.dir, added.chain(removed))?;
+        let sstables = output.take();
+        let hot_sstables = hot.as_mut().map_or(vec![], |hot| hot.take());
+        let state_lock = self.state_lock.lock();
+        self.update_state(&state_lock, |inner| {
+            if inner.levels.len() < output_level {
+                inner.levels.resize_with(output_level, Vec::new);
+            }
+            inner
+                .l0_sstables
+                .retain(|sst| !compacted.contains(&sst.sst_id()));
+            for tables in &mut inner.levels {
+                tables.retain(|sst| !compacted.contains(&sst.sst_id()));
+            }
+            // the outputs hold keys of the inputs, so the key range of the SSTs stays
+            for sstable in sstables {
+                insert_by_key(&mut inner.levels[output_level - 1], Arc::new(sstable));
+            }
+            for hot_sstable in hot_sstables {
+                match level {
+                    // before the tables flushed during the compaction, which hold newer data
+                    0 => {
+                        let l0 = &mut inner.l0_sstables;
+                        let idx = l0.partition_point(|sst| sst.sst_id() < hot_sstable.sst_id());
+                        l0.insert(idx, Arc::new(hot_sstable));
+                    }
+                    x => insert_by_key(&mut inner.levels[x - 1], Arc::new(hot_sstable)),
+                }
+            }
+            self.file_count_boost(inner);
+        });
+        // the files of the inputs are deleted once no reader holds them anymore
+        for sst in inputs {
+            sst.retire(self.retention.clone(), &self.dir);
+        }
+
+        Ok(())
+    }
+
+    /// Merge the newest L0 tables smaller than `threshold` bytes, when there are two or more,
+    /// into one table with a fresh id, keeping the tombstones. The older runs of small tables
+    /// stay until a compaction takes them: L0 is ordered by id, so only the newest tables can
+    /// give way to a table with a fresh one. Returns whether the tables were merged.
+    pub(super) fn merge_small_tables(&self, threshold: u64) -> Result<bool> {
+        let (run, id, _reserved) = {
+            // no flush is between taking its id and adding its table to L0 while the WAL lock is
+            // held, so every table flushed later gets an id above the merged one
+            let _wal = self.wal.lock();
+            let mut compacting = self.compacting.lock();
+            let state_lock = self.state_lock.lock();
+            let run = self.inner.read().newest_small_l0_run(threshold);
+            if run.len() < 2 || run.iter().any(|sst| compacting.contains(&sst.sst_id())) {
+                return Ok(false);
+            }
+            let ids = run.iter().map(|sst| sst.sst_id()).collect::<Vec<_>>();
+            compacting.extend(&ids);
+            let reserved = Reserved {
+                compacting: &self.compacting,
+                ids,
+            };
+            let id = self.update_state(&state_lock, |inner| {
+                inner.next_sst_id += 1;
+                inner.next_sst_id - 1
+            });
+            (run, id, reserved)
+        };
+
+        let max_seq = run.iter().map(|sst| sst.max_seq()).max().unwrap_or(0);
+        let mut output = OutputTables::new(self, 0, usize::MAX)
+            .with_first_id(id)
+            .with_max_seq(max_seq);
+        let newest_first = run.iter().rev().cloned().collect::<Vec<_>>();
+        let mut iter = merge_runs(sorted_runs(&newest_first)?)?;
+        while iter.is_valid() {
+            output.add(iter.key(), iter.value())?;
+            iter.next()?;
+        }
+        output.finish()?;
+        output.sync_dirs()?;
+
+        let merged = run.iter().map(|sst| sst.sst_id()).collect::<HashSet<_>>();
+        let added = output.tables.iter().map(|sst| ManifestRecord::AddTable {
+            level: 0,
+            id: sst.sst_id(),
+        });
+        let removed = merged.iter().map(|&id| ManifestRecord::RemoveTable { id });
+        manifest::append_records(&self.dir, added.chain(removed))?;
+        let sstables = output.take();
+        let state_lock = self.state_lock.lock();
+        self.update_state(&state_lock, |inner| {
+            inner
+                .l0_sstables
+                .retain(|sst| !merged.contains(&sst.sst_id()));
+            // the merged table holds the keys of the run, so the key range of the SSTs stays
+            for sstable in sstables {
+                let l0 = &mut inner.l0_sstables;
+                let idx = l0.partition_point(|sst| sst.sst_id() < sstable.sst_id());
+                l0.insert(idx, Arc::new(sstable));
+            }
+            self.file_count_boost(inner);
+        });
+        drop(state_lock);
+        // the files of the run are deleted once no reader holds them anymore, the reads to come
+        // find their blocks in the merged table
+        for sst in &run {
+            sst.invalidate_cached_blocks();
+            sst.retire(self.retention.clone(), &self.dir);
+        }
+        self.metrics.record_small_sst_merges(1);
+        Ok(true)
+    }
+
+    /// Where a new SST of `level` goes, creating its level directory if the layout has them.
+    fn path_of_sst(&self, level: usize, sst_id: usize) -> Result<std::path::PathBuf> {
+        let path = path_of_sst(&self.dir, self.options.sst_layout, level, sst_id);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Ok(path)
+    }
+}
@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::manifest::{DbId, OptionsFingerprint};
+use crate::quarantine::CorruptionReport;
+
+/// What `open` found, logged once the storage is up.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpenReport {
+    pub db_id: DbId,
+    pub dir: PathBuf,
+    /// Whether the database was created by this open.
+    pub created: bool,
+    /// Number of SSTs recovered, after the quarantined ones were left out.
+    pub recovered_tables: usize,
+    pub wal_records_replayed: u64,
+    /// Whether the storage was last closed with `close`, rather than dropped or killed.
+    pub clean_shutdown: bool,
+    pub fingerprint: OptionsFingerprint,
+    /// Seed of the blocks checked under
+    /// [`VerifyLevel::SampleBlocks`](crate::lsm_storage::VerifyLevel::SampleBlocks), to pass as
+    /// [`LsmStorageOptions::verify_seed`](crate::lsm_storage::LsmStorageOptions::verify_seed) to
+    /// check the same ones again.
+    pub verify_seed: Option<u64>,
+}
+
+impl std::fmt::Display for OpenReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} database {} at {}: recovered_tables={} wal_records_replayed={} \
+             clean_shutdown={} {}",
+            if self.created { "created" } else { "opened" },
+            self.db_id,
+            self.dir.display(),
+            self.recovered_tables,
+            self.wal_records_replayed,
+            self.clean_shutdown,
+            self.fingerprint
+        )?;
+        match self.verify_seed {
+            Some(seed) => write!(f, " verify_seed={}", seed),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What adopting the SSTs of a directory without a manifest did, see
+/// [`LsmStorage::adopt_legacy`](crate::lsm_storage::LsmStorage::adopt_legacy).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AdoptionReport {
+    /// Ids of the tables recorded in the new manifest, ordered by id.
+    pub adopted: Vec<usize>,
+    /// The files that failed to load, quarantined into `corrupt/`.
+    pub unreadable: Vec<CorruptionReport>,
+}
+
+impl std::fmt::Display for AdoptionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "adopted {} legacy SSTs {:?}",
+            self.adopted.len(),
+            self.adopted
+        )?;
+        for report in &self.unreadable {
+            write!(f, ", quarantined unreadable {}", report.file.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// The totals of a storage as it stops, logged by the first `stop`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CloseReport {
+    pub db_id: DbId,
+    pub uptime: Duration,
+    pub flushes: u64,
+    pub flush_bytes_written: u64,
+    pub compactions: u64,
+    pub bytes_ingested: u64,
+    pub live_files: u64,
+}
+
+impl std::fmt::Display for CloseReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "closed database {} after {:.3}s: flushes={} flush_bytes_written={} compactions={} \
+             bytes_ingested={} live_files={}",
+            self.db_id,
+            self.uptime.as_secs_f64(),
+            self.flushes,
+            self.flush_bytes_written,
+            self.compactions,
+            self.bytes_ingested,
+            self.live_files
+        )
+    }
+}
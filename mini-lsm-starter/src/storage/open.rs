@@ -0,0 +1,79 @@
+//! The steps of `open` that change the database directory. A crash may stop `open` anywhere,
+//! so each step can run again on what an interrupted run of it left, and they go in an order
+//! where no step undoes what the next open needs: the manifest is written once the tables it
+//! records are recovered, and the write-ahead log is only trimmed of its torn tail once its
+//! records are replayed.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::lifecycle::AdoptionReport;
+use super::state::LsmStorageInner;
+use crate::manifest::{self, DbId, OptionsFingerprint};
+use crate::quarantine::CorruptionReport;
+
+#[cfg(test)]
+thread_local! {
+    /// Fail the `open` of the current thread after this many more steps, as a crash would.
+    pub(crate) static OPEN_FAULT: std::cell::Cell<Option<usize>> = Default::default();
+}
+
+/// Mark the end of the step of `open` named `step`. In tests, this is where [`OPEN_FAULT`]
+/// stops `open`.
+pub(super) fn step_done(step: &str) -> Result<()> {
+    #[cfg(test)]
+    if let Some(steps) = OPEN_FAULT.with(|fault| fault.get()) {
+        OPEN_FAULT.with(|fault| fault.set(steps.checked_sub(1)));
+        anyhow::ensure!(steps > 0, "injected fault after {}", step);
+    }
+    #[cfg(not(test))]
+    let _ = step;
+    Ok(())
+}
+
+/// Delete what the writes interrupted by a crash left behind: the SSTs and the manifest written
+/// to a temporary file, and never renamed into place. Returns the number of files deleted.
+pub(super) fn remove_tmp_files(dir: &Path) -> Result<usize> {
+    let mut removed = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let is_tmp = entry
+            .file_name()
+            .to_str()
+            .map_or(false, |name| name.ends_with(".tmp"));
+        if is_tmp && entry.file_type()?.is_file() {
+            match std::fs::remove_file(entry.path()) {
+                Ok(()) => removed += 1,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Write the manifest of a directory that had none, recording the tables recovered into
+/// `inner`. The header and the tables go in a single write, so that a crash leaves no manifest
+/// at all and the next open adopts the tables again.
+pub(super) fn record_adoption(
+    dir: &Path,
+    db_id: &DbId,
+    fingerprint: &OptionsFingerprint,
+    inner: &LsmStorageInner,
+    unreadable: Vec<CorruptionReport>,
+) -> Result<AdoptionReport> {
+    let tables = std::iter::once(&inner.l0_sstables)
+        .chain(&inner.levels)
+        .enumerate()
+        .flat_map(|(level, ssts)| ssts.iter().map(move |sst| (level, sst.sst_id())))
+        .collect::<Vec<_>>();
+    manifest::write_with_tables(dir, db_id, fingerprint, tables.iter().copied())?;
+
+    let mut adopted = tables.into_iter().map(|(_, id)| id).collect::<Vec<_>>();
+    adopted.sort_unstable();
+    Ok(AdoptionReport {
+        adopted,
+        unreadable,
+    })
+}
@@ -0,0 +1,533 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use super::background::{RetryPolicy, MIN_NUM_SST_FILES_TO_COMPACT};
+use crate::access::TieringOptions;
+use crate::cancel::CancellationToken;
+use crate::compaction::CompactionConfig;
+use crate::error::LsmError;
+use crate::lsm_iterator::SnapshotAge;
+use crate::retention::TrashOptions;
+use crate::table::compressed_cache::CacheAdmission;
+
+/// Keys and values are length-prefixed with a `u16` in blocks.
+pub const MAX_KEY_SIZE: usize = u16::MAX as usize;
+
+pub const MAX_VALUE_SIZE: usize = u16::MAX as usize;
+
+/// How [`LsmStorage::open`] treats a data file that fails to load.
+///
+/// [`LsmStorage::open`]: crate::lsm_storage::LsmStorage::open
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RecoveryMode {
+    /// Refuse to open the database.
+    #[default]
+    Strict,
+    /// Quarantine the file into `corrupt/` and open the database without it.
+    BestEffort,
+}
+
+/// How much of the SSTs [`LsmStorage::open`] checks before opening the database. A table that
+/// fails the check is treated as [`LsmStorageOptions::recovery`] says.
+///
+/// [`LsmStorage::open`]: crate::lsm_storage::LsmStorage::open
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum VerifyLevel {
+    /// Only what loading a table takes, so corruption is found by the reads that trip on it.
+    #[default]
+    None,
+    /// Also check that the index of every table describes its data blocks, without reading them.
+    FootersOnly,
+    /// Also read and check `per_table` blocks of every table, picked at random with the seed
+    /// recorded in the [`OpenReport`](crate::lsm_storage::OpenReport).
+    SampleBlocks { per_table: usize },
+    /// Read and check every block.
+    Full,
+}
+
+/// Where the SSTs live in the database directory. Recovery reads the level of a table back from
+/// its location, and [`LsmStorage::open`] renames the tables laid out another way into place.
+///
+/// [`LsmStorage::open`]: crate::lsm_storage::LsmStorage::open
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SstLayout {
+    /// `{id}.sst`, with no level recorded. Every table is recovered into L0.
+    #[default]
+    Flat,
+    /// `L{level}/{id}.sst`.
+    LevelDirs,
+    /// `{id}_L{level}.sst`.
+    LevelSuffix,
+}
+
+/// Durability of a single write.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WriteOptions {
+    /// Skip the write-ahead log. The write is lost if the process crashes before its memtable is
+    /// flushed, which suits data that can be recomputed.
+    pub disable_wal: bool,
+    /// Wait for the write-ahead log to reach the disk before returning.
+    pub sync: bool,
+}
+
+type ExtractFn = dyn Fn(&[u8]) -> Option<&[u8]> + Send + Sync;
+
+/// Maps keys to the prefix the SSTs keep a Bloom filter of, so that prefix scans can skip the
+/// tables that hold no key with their prefix.
+///
+/// A key the function maps to `None` is not in the filter. A key it maps to `Some(p)` must start
+/// with `p`, and so must map every other key starting with `p`.
+#[derive(Clone)]
+pub struct PrefixExtractor {
+    name: String,
+    extract: Arc<ExtractFn>,
+}
+
+impl PrefixExtractor {
+    /// `name` is stored with the filters. Tables whose filters were built under another name
+    /// are scanned without them.
+    pub fn new(
+        name: impl Into<String>,
+        extract: impl Fn(&[u8]) -> Option<&[u8]> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            extract: Arc::new(extract),
+        }
+    }
+
+    /// The first `len` bytes of every key at least that long.
+    pub fn fixed(len: usize) -> Self {
+        Self::new(format!("fixed:{}", len), move |key| key.get(..len))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn extract<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        (self.extract)(key)
+    }
+}
+
+impl std::fmt::Debug for PrefixExtractor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PrefixExtractor").field(&self.name).finish()
+    }
+}
+
+/// Options for a single read.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ReadOptions {
+    /// Add the blocks read from disk to the block cache. Turn it off for one-off scans, so that
+    /// they do not evict the blocks other reads keep coming back to.
+    pub fill_cache: bool,
+    /// Fail with [`LsmError::DeadlineExceeded`] instead of reading another SST block after this
+    /// instant. Reads served by the memtables never check it.
+    pub deadline: Option<Instant>,
+    /// Scan nothing, instead of failing with [`LsmError::InvalidArgument`], when the lower
+    /// bound lies past the upper bound.
+    pub empty_on_inverted_bounds: bool,
+    /// Fail the `next` of a scan with [`LsmError::SnapshotTooOld`] once the storage moved on
+    /// further than this since the scan started, see [`ScanIter::snapshot_age`].
+    ///
+    /// [`ScanIter::snapshot_age`]: crate::lsm_iterator::ScanIter::snapshot_age
+    pub max_staleness: Option<SnapshotAge>,
+    /// Leave out of a scan the SSTs whose iterator cannot be created, e.g. on an IO error,
+    /// instead of failing the scan. The skipped tables are listed in [`ScanIter::stats`].
+    ///
+    /// [`ScanIter::stats`]: crate::lsm_iterator::ScanIter::stats
+    pub skip_unreadable: bool,
+    /// Fail the `next` of a scan with [`LsmError::Cancelled`] once this token is cancelled.
+    pub cancel: Option<CancellationToken>,
+    /// Fail the `next` of a scan with [`LsmError::Yielded`] once it skipped this many deleted
+    /// keys in a row, so that a long run of deletions does not hold up the caller for the whole
+    /// of it. The scan carries on from there on the next call. `None` skips them all.
+    pub max_tombstones_per_next: Option<usize>,
+    /// Blocks a scan reads from an SST at once at most. A scan starts a block at a time, and
+    /// doubles the blocks it reads at once after [`SEQUENTIAL_READS`] reads in a row, each
+    /// starting where the last one stopped, up to this many; a read anywhere else starts over.
+    /// Point reads always read a single block. 1 turns readahead off.
+    ///
+    /// [`SEQUENTIAL_READS`]: crate::table::SEQUENTIAL_READS
+    pub max_readahead_blocks: usize,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self {
+            fill_cache: true,
+            deadline: None,
+            empty_on_inverted_bounds: false,
+            max_staleness: None,
+            skip_unreadable: false,
+            cancel: None,
+            max_tombstones_per_next: None,
+            max_readahead_blocks: 16,
+        }
+    }
+}
+
+impl ReadOptions {
+    pub(crate) fn check_deadline(&self) -> Result<()> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(LsmError::DeadlineExceeded.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Options for opening an [`LsmStorage`].
+///
+/// The ones that affect how data is laid out are recorded in the manifest, see
+/// [`OptionsFingerprint`].
+///
+/// [`LsmStorage`]: crate::lsm_storage::LsmStorage
+/// [`OptionsFingerprint`]: crate::manifest::OptionsFingerprint
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct LsmStorageOptions {
+    pub recovery: RecoveryMode,
+    pub verify_on_open: VerifyLevel,
+    /// Seed of the blocks [`VerifyLevel::SampleBlocks`] picks, to check the same ones again.
+    /// `None` draws a new one on every open.
+    pub verify_seed: Option<u64>,
+    /// Name of the key ordering. Keys are always compared bytewise; the name guards against
+    /// opening a database with a build that orders keys differently.
+    pub comparator: String,
+    /// Largest key accepted by a write, at most [`MAX_KEY_SIZE`].
+    pub max_key_size: usize,
+    /// Largest value accepted by a write, at most [`MAX_VALUE_SIZE`].
+    pub max_value_size: usize,
+    /// Number of blocks the block cache holds.
+    pub block_cache_capacity: u64,
+    /// Bytes of blocks a second tier of the block cache holds as stored on disk, looked up
+    /// before reading a block from disk. `None`, the default, leaves the tier out.
+    pub compressed_block_cache_bytes: Option<u64>,
+    /// Number of keys a cache of the keys `get` found missing holds, so that probing them
+    /// again answers without reading the memtables or the tables, see
+    /// [`NegativeCache`](crate::negative_cache::NegativeCache). `None`, the default, or 0 leaves
+    /// the cache out.
+    pub negative_cache_capacity: Option<usize>,
+    /// Which tiers a block read from disk goes into when there are two.
+    pub block_cache_admission: CacheAdmission,
+    /// Bytes of blocks a scan reads from disk before it stops adding them to the block cache,
+    /// as if it had [`ReadOptions::fill_cache`] off, so that one long scan does not evict every
+    /// block point reads keep coming back to. Until then, it adds them a batch at a time.
+    /// `None` never stops. Point reads always fill the cache.
+    pub scan_fill_cache_limit: Option<u64>,
+    /// Record the blocks the block cache finds most often in the [`HOTSET`] file of the
+    /// database this often, and when [`LsmStorage::close`] closes the storage, see
+    /// [`LsmStorage::save_hotset`]. `None`, the default, records none.
+    ///
+    /// [`HOTSET`]: crate::hotset::HOTSET
+    /// [`LsmStorage::close`]: crate::lsm_storage::LsmStorage::close
+    /// [`LsmStorage::save_hotset`]: crate::lsm_storage::LsmStorage::save_hotset
+    pub hotset_interval: Option<Duration>,
+    /// At open, read the blocks the [`HOTSET`] file records back into the block cache, in the
+    /// background, so that the gets after a restart do not wait for the disk to warm it up. The
+    /// blocks no table holds any more are skipped.
+    ///
+    /// [`HOTSET`]: crate::hotset::HOTSET
+    pub warm_on_open: bool,
+    /// Compact a level once it has this many SSTs.
+    pub l0_compaction_trigger: usize,
+    /// Bytes of a data block of the SSTs written from now on, a power of two of at least 4096.
+    /// The tables written with another size are read as they are.
+    pub block_size: usize,
+    /// Bytes a memtable takes in, counted the way [`WriteBatch::size`] counts them, before it
+    /// is flushed.
+    ///
+    /// [`WriteBatch::size`]: crate::lsm_storage::WriteBatch::size
+    pub memtable_size_limit: usize,
+    /// Frozen memtables waiting for a flush, past which the write freezing another one flushes
+    /// them itself instead of leaving it to the compaction worker.
+    pub max_imm_memtables: usize,
+    /// Run the compaction worker. Off, writes flush a full memtable themselves and nothing is
+    /// compacted but by [`LsmStorage::compact`](crate::lsm_storage::LsmStorage::compact).
+    pub background_compaction: bool,
+    /// Open the database even if the checksum type, the format version or the size limits
+    /// differ from the ones it was written with, and record the new ones.
+    pub allow_format_change: bool,
+    /// At open, and every [`small_sst_merge_interval`](Self::small_sst_merge_interval), merge
+    /// the newest L0 tables smaller than this many bytes into one table. `None` skips the pass,
+    /// for fast opens.
+    pub small_sst_threshold: Option<u64>,
+    /// How often the background thread merges the small tables that flushes leave in L0,
+    /// with [`small_sst_threshold`](Self::small_sst_threshold) set. `None`, the default, only
+    /// merges them at open.
+    pub small_sst_merge_interval: Option<Duration>,
+    /// Keep a Bloom filter of the key prefixes in every new SST, for `scan_prefix`.
+    pub prefix_extractor: Option<PrefixExtractor>,
+    /// How the files that compactions and merges retire are deleted.
+    pub trash: TrashOptions,
+    pub sst_layout: SstLayout,
+    /// End every value in the SSTs and the write-ahead log with a checksum of its entry, checked
+    /// on the way to the user, so that corruption after a block is decoded is caught too. Costs
+    /// four bytes per entry, which also come off the largest value a write accepts.
+    pub entry_checksums: bool,
+    /// Past this many SSTs, compactions get more urgent in proportion to the overage and go
+    /// for the ones merging the most files, see
+    /// [`Metrics::file_count_boost`](crate::metrics::Metrics::file_count_boost). Writes are never
+    /// held back for it.
+    pub soft_max_files: Option<usize>,
+    /// Sample the point reads, and have compactions keep the keys read often in a table of
+    /// their own at the compacted level, while the others move down. Off by default.
+    pub tiering: Option<TieringOptions>,
+    /// How the compaction worker picks its compactions.
+    pub compaction: CompactionConfig,
+    /// Bytes of data blocks after which a compaction ends the table it writes and starts
+    /// another, so that a large compaction outputs several tables of about this size.
+    pub target_sst_size: usize,
+    /// Largest [`WriteBatch`](crate::lsm_storage::WriteBatch) accepted by a write, counted the
+    /// way the memtable counts its size. A batch always goes into a single memtable, so keep it
+    /// well under the memtable limit.
+    pub max_batch_bytes: usize,
+    /// Adopt into the manifest the SSTs of a directory that has none, as left by the versions
+    /// before the manifest, the way [`LsmStorage::adopt_legacy`] does. Off, such a directory
+    /// fails to open with [`LsmError::LegacyDirectory`]. On by default for this release only.
+    ///
+    /// [`LsmStorage::adopt_legacy`]: crate::lsm_storage::LsmStorage::adopt_legacy
+    pub adopt_legacy_files: bool,
+    /// Flush the memtables when [`LsmStorage::close`] closes the storage, so that the next open
+    /// has no write-ahead log to replay. Off, the log is synced and replayed at the next open.
+    ///
+    /// [`LsmStorage::close`]: crate::lsm_storage::LsmStorage::close
+    pub flush_on_close: bool,
+    /// How the compaction worker retries its flushes and compactions on transient IO errors.
+    pub background_retry: RetryPolicy,
+    /// Log the writes through a [`WalGroupCommit`](crate::wal::WalGroupCommit), so that
+    /// concurrent writes share one write and one sync of the log. Every logged write then
+    /// waits for the disk, as if [`WriteOptions::sync`] was on.
+    pub group_commit: bool,
+    /// How long the group commit waits after the first write of a group for others to join.
+    pub group_commit_timeout: Duration,
+}
+
+impl Default for LsmStorageOptions {
+    fn default() -> Self {
+        Self {
+            recovery: RecoveryMode::default(),
+            verify_on_open: VerifyLevel::default(),
+            verify_seed: None,
+            comparator: "bytewise".to_string(),
+            max_key_size: MAX_KEY_SIZE,
+            max_value_size: MAX_VALUE_SIZE,
+            block_cache_capacity: 1 << 20,
+            compressed_block_cache_bytes: None,
+            negative_cache_capacity: None,
+            block_cache_admission: CacheAdmission::default(),
+            scan_fill_cache_limit: Some(64 << 20),
+            hotset_interval: None,
+            warm_on_open: false,
+            l0_compaction_trigger: MIN_NUM_SST_FILES_TO_COMPACT,
+            block_size: 4096,
+            memtable_size_limit: 1_000_000,
+            max_imm_memtables: 4,
+            background_compaction: true,
+            allow_format_change: false,
+            small_sst_threshold: None,
+            small_sst_merge_interval: None,
+            prefix_extractor: None,
+            trash: TrashOptions::default(),
+            sst_layout: SstLayout::default(),
+            entry_checksums: false,
+            soft_max_files: None,
+            tiering: None,
+            compaction: CompactionConfig::default(),
+            target_sst_size: 2 << 20,
+            max_batch_bytes: 1 << 18,
+            adopt_legacy_files: true,
+            flush_on_close: true,
+            background_retry: RetryPolicy::default(),
+            group_commit: false,
+            group_commit_timeout: Duration::from_micros(100),
+        }
+    }
+}
+
+impl LsmStorageOptions {
+    /// See [`block_size`](Self::block_size).
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// See [`target_sst_size`](Self::target_sst_size).
+    pub fn with_target_sst_size(mut self, target_sst_size: usize) -> Self {
+        self.target_sst_size = target_sst_size;
+        self
+    }
+
+    /// See [`memtable_size_limit`](Self::memtable_size_limit).
+    pub fn with_memtable_size_limit(mut self, memtable_size_limit: usize) -> Self {
+        self.memtable_size_limit = memtable_size_limit;
+        self
+    }
+
+    /// See [`max_imm_memtables`](Self::max_imm_memtables).
+    pub fn with_max_imm_memtables(mut self, max_imm_memtables: usize) -> Self {
+        self.max_imm_memtables = max_imm_memtables;
+        self
+    }
+
+    /// See [`l0_compaction_trigger`](Self::l0_compaction_trigger).
+    pub fn with_l0_compaction_trigger(mut self, l0_compaction_trigger: usize) -> Self {
+        self.l0_compaction_trigger = l0_compaction_trigger;
+        self
+    }
+
+    /// See [`block_cache_capacity`](Self::block_cache_capacity).
+    pub fn with_block_cache_capacity(mut self, block_cache_capacity: u64) -> Self {
+        self.block_cache_capacity = block_cache_capacity;
+        self
+    }
+
+    /// See [`background_compaction`](Self::background_compaction).
+    pub fn with_background_compaction(mut self, background_compaction: bool) -> Self {
+        self.background_compaction = background_compaction;
+        self
+    }
+
+    /// See [`group_commit`](Self::group_commit) and
+    /// [`group_commit_timeout`](Self::group_commit_timeout).
+    pub fn with_group_commit(mut self, group_commit_timeout: Duration) -> Self {
+        self.group_commit = true;
+        self.group_commit_timeout = group_commit_timeout;
+        self
+    }
+
+    /// Check the options `open` cannot work with, the ones [`MutableOptions`] also changes
+    /// aside.
+    pub(super) fn validate(&self) -> Result<()> {
+        let invalid = |msg: &str| Err(LsmError::InvalidArgument(msg.to_string()).into());
+        if !self.block_size.is_power_of_two() || self.block_size < 4096 {
+            return invalid("block_size must be a power of two of at least 4096");
+        }
+        if self.memtable_size_limit == 0 {
+            return invalid("memtable_size_limit must be at least 1");
+        }
+        if self.max_imm_memtables == 0 {
+            return invalid("max_imm_memtables must be at least 1");
+        }
+        if self.target_sst_size == 0 {
+            return invalid("target_sst_size must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Options [`LsmStorage::set_options`](crate::lsm_storage::LsmStorage::set_options) changes on a
+/// running storage. The fields left `None` keep their value.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MutableOptions {
+    /// See [`LsmStorageOptions::block_cache_capacity`]. Shrinking the cache evicts blocks right
+    /// away, then in the background until it fits. The cache cannot grow past the capacity it
+    /// was opened with until the next open.
+    pub block_cache_capacity: Option<u64>,
+    /// See [`LsmStorageOptions::l0_compaction_trigger`]; the compaction worker uses the new
+    /// value from its next decision on.
+    pub l0_compaction_trigger: Option<usize>,
+    /// See [`LsmStorageOptions::soft_max_files`].
+    pub soft_max_files: Option<Option<usize>>,
+    /// See [`LsmStorageOptions::max_batch_bytes`].
+    pub max_batch_bytes: Option<usize>,
+}
+
+/// Options whose change would need the data rewritten, or that only `open` can act on.
+const IMMUTABLE_OPTIONS: &[&str] = &[
+    "comparator",
+    "checksum",
+    "format_version",
+    "block_size",
+    "max_key_size",
+    "max_value_size",
+    "entry_checksums",
+    "sst_layout",
+    "prefix_extractor",
+];
+
+impl MutableOptions {
+    /// Set the option `name` from its text form, e.g. from a config file. `soft_max_files`
+    /// takes `none` as well. Fails with [`LsmError::ImmutableOption`] for the options that
+    /// cannot change on a running storage.
+    pub fn set(mut self, name: &str, value: &str) -> Result<Self> {
+        let invalid =
+            || LsmError::InvalidArgument(format!("invalid value {:?} for {}", value, name));
+        match name {
+            "block_cache_capacity" => {
+                self.block_cache_capacity = Some(value.parse().map_err(|_| invalid())?)
+            }
+            "l0_compaction_trigger" => {
+                self.l0_compaction_trigger = Some(value.parse().map_err(|_| invalid())?)
+            }
+            "soft_max_files" => {
+                self.soft_max_files = Some(match value {
+                    "none" => None,
+                    value => Some(value.parse().map_err(|_| invalid())?),
+                })
+            }
+            "max_batch_bytes" => self.max_batch_bytes = Some(value.parse().map_err(|_| invalid())?),
+            name if IMMUTABLE_OPTIONS.contains(&name) => {
+                return Err(LsmError::ImmutableOption(name.to_string()).into())
+            }
+            name => {
+                return Err(LsmError::InvalidArgument(format!("unknown option {}", name)).into())
+            }
+        }
+        Ok(self)
+    }
+}
+
+/// The current values of the options [`MutableOptions`] changes, swapped as a whole.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct LiveOptions {
+    pub block_cache_capacity: u64,
+    pub l0_compaction_trigger: usize,
+    pub soft_max_files: Option<usize>,
+    pub max_batch_bytes: usize,
+}
+
+impl LiveOptions {
+    pub(super) fn new(options: &LsmStorageOptions) -> Self {
+        Self {
+            block_cache_capacity: options.block_cache_capacity,
+            l0_compaction_trigger: options.l0_compaction_trigger,
+            soft_max_files: options.soft_max_files,
+            max_batch_bytes: options.max_batch_bytes,
+        }
+    }
+
+    /// The values with `delta` applied, failing if any of them is invalid.
+    pub(super) fn with(self, delta: &MutableOptions) -> Result<Self> {
+        let next = Self {
+            block_cache_capacity: delta
+                .block_cache_capacity
+                .unwrap_or(self.block_cache_capacity),
+            l0_compaction_trigger: delta
+                .l0_compaction_trigger
+                .unwrap_or(self.l0_compaction_trigger),
+            soft_max_files: delta.soft_max_files.unwrap_or(self.soft_max_files),
+            max_batch_bytes: delta.max_batch_bytes.unwrap_or(self.max_batch_bytes),
+        };
+        let invalid = |msg: &str| Err(LsmError::InvalidArgument(msg.to_string()).into());
+        if next.l0_compaction_trigger == 0 {
+            return invalid("l0_compaction_trigger must be at least 1");
+        }
+        if next.soft_max_files == Some(0) {
+            return invalid("soft_max_files must be at least 1");
+        }
+        if next.max_batch_bytes == 0 {
+            return invalid("max_batch_bytes must be at least 1");
+        }
+        Ok(next)
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use super::SstLayout;
+use crate::manifest;
+
+/// Write-ahead log of the unflushed memtables.
+pub(super) static WAL_FILE: &str = "memtable.wal";
+
+/// Where SST `id` of `level` lives under `layout`. Level 0 is L0.
+pub(super) fn path_of_sst(dir: &Path, layout: SstLayout, level: usize, id: usize) -> PathBuf {
+    match layout {
+        SstLayout::Flat => dir.join(format!("{}.sst", id)),
+        SstLayout::LevelDirs => dir.join(format!("L{}", level)).join(format!("{}.sst", id)),
+        SstLayout::LevelSuffix => dir.join(format!("{}_L{}.sst", id, level)),
+    }
+}
+
+/// Where an SST is written before it is renamed into place.
+pub(super) fn path_of_tmp_sst(dir: &Path, id: usize) -> PathBuf {
+    dir.join(format!("{}.sst.tmp", id))
+}
+
+pub(super) fn path_of_wal(dir: &Path) -> PathBuf {
+    dir.join(WAL_FILE)
+}
+
+/// An SST found in the database directory.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) struct SstFile {
+    pub id: usize,
+    /// The level its location records, `None` in the flat layout.
+    pub level: Option<usize>,
+    pub path: PathBuf,
+}
+
+/// Parse `{id}.sst` or `{id}_L{level}.sst`.
+fn parse_sst_name(name: &str) -> Option<(usize, Option<usize>)> {
+    let stem = name.strip_suffix(".sst")?;
+    match stem.split_once("_L") {
+        Some((id, level)) => Some((id.parse().ok()?, Some(level.parse().ok()?))),
+        None => Some((stem.parse().ok()?, None)),
+    }
+}
+
+/// List the SSTs in `dir` under any layout, ordered by id.
+pub(super) fn sst_files(dir: &Path) -> Result<Vec<SstFile>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        if entry.file_type()?.is_dir() {
+            let level = match name.strip_prefix('L').and_then(|level| level.parse().ok()) {
+                Some(level) => level,
+                None => continue,
+            };
+            for entry in std::fs::read_dir(entry.path())? {
+                let entry = entry?;
+                if let Some((id, None)) = entry.file_name().to_str().and_then(parse_sst_name) {
+                    files.push(SstFile {
+                        id,
+                        level: Some(level),
+                        path: entry.path(),
+                    });
+                }
+            }
+        } else if let Some((id, level)) = parse_sst_name(name) {
+            files.push(SstFile {
+                id,
+                level,
+                path: entry.path(),
+            });
+        }
+    }
+    files.sort();
+    for pair in files.windows(2) {
+        anyhow::ensure!(
+            pair[0].id != pair[1].id,
+            "sst {} is both at {} and at {}",
+            pair[0].id,
+            pair[0].path.display(),
+            pair[1].path.display()
+        );
+    }
+    Ok(files)
+}
+
+/// Sync the directories holding `files`, so that the files created in them survive a power
+/// loss once they are recorded anywhere.
+pub(super) fn sync_parent_dirs<'a>(files: impl IntoIterator<Item = &'a Path>) -> Result<()> {
+    let dirs: BTreeSet<_> = files.into_iter().filter_map(|file| file.parent()).collect();
+    for dir in dirs {
+        std::fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// The SSTs in `dir` that its manifest records as removed: the inputs of the compactions and
+/// merges a crash, or the process exiting, kept from being deleted.
+pub(super) fn removed_sst_files(dir: &Path) -> Result<Vec<SstFile>> {
+    let removed = manifest::read_recorded(dir)?.removed;
+    let mut files = sst_files(dir)?;
+    files.retain(|file| removed.contains(&file.id));
+    Ok(files)
+}
+
+/// Rename the SSTs of `dir` laid out some other way into place under `layout`. A table of the
+/// flat layout has no level to go by and is taken for an L0 one; moving to the flat layout
+/// forgets the levels. Returns the tables, at their new paths.
+pub(super) fn migrate_layout(dir: &Path, layout: SstLayout) -> Result<Vec<SstFile>> {
+    let mut files = sst_files(dir)?;
+    for file in &mut files {
+        let level = file.level.unwrap_or(0);
+        let path = path_of_sst(dir, layout, level, file.id);
+        if path == file.path {
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(&file.path, &path)?;
+        file.path = path;
+        file.level = match layout {
+            SstLayout::Flat => None,
+            _ => Some(level),
+        };
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sst_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for name in ["10.sst", "2.sst", "3.sst.tmp", "x.sst", "4.wal", WAL_FILE] {
+            std::fs::write(dir.path().join(name), b"")?;
+        }
+        std::fs::create_dir(dir.path().join("trash"))?;
+        std::fs::write(dir.path().join("trash").join("5.sst"), b"")?;
+        std::fs::write(dir.path().join("7_L2.sst"), b"")?;
+        std::fs::create_dir(dir.path().join("L1"))?;
+        std::fs::write(dir.path().join("L1").join("6.sst"), b"")?;
+
+        let flat = |id| path_of_sst(dir.path(), SstLayout::Flat, 0, id);
+        assert_eq!(
+            sst_files(dir.path())?,
+            vec![
+                SstFile {
+                    id: 2,
+                    level: None,
+                    path: flat(2)
+                },
+                SstFile {
+                    id: 6,
+                    level: Some(1),
+                    path: path_of_sst(dir.path(), SstLayout::LevelDirs, 1, 6)
+                },
+                SstFile {
+                    id: 7,
+                    level: Some(2),
+                    path: path_of_sst(dir.path(), SstLayout::LevelSuffix, 2, 7)
+                },
+                SstFile {
+                    id: 10,
+                    level: None,
+                    path: flat(10)
+                },
+            ]
+        );
+        assert_eq!(flat(2), dir.path().join("2.sst"));
+        assert_eq!(
+            path_of_sst(dir.path(), SstLayout::LevelDirs, 3, 2),
+            dir.path().join("L3").join("2.sst")
+        );
+        assert_eq!(
+            path_of_sst(dir.path(), SstLayout::LevelSuffix, 3, 2),
+            dir.path().join("2_L3.sst")
+        );
+        assert_eq!(path_of_tmp_sst(dir.path(), 3), dir.path().join("3.sst.tmp"));
+        assert_eq!(path_of_wal(dir.path()), dir.path().join(WAL_FILE));
+
+        std::fs::write(dir.path().join("6_L1.sst"), b"")?;
+        assert!(sst_files(dir.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_layout() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = dir.path();
+        std::fs::write(dir.join("1.sst"), b"")?;
+        std::fs::write(dir.join("2_L1.sst"), b"")?;
+
+        let levels = |files: Vec<SstFile>| {
+            files
+                .into_iter()
+                .map(|file| (file.id, file.level))
+                .collect::<Vec<_>>()
+        };
+        let files = migrate_layout(dir, SstLayout::LevelDirs)?;
+        assert!(files.iter().all(|file| file.path.exists()));
+        assert_eq!(levels(files), vec![(1, Some(0)), (2, Some(1))]);
+        assert!(dir.join("L0").join("1.sst").exists());
+        assert!(dir.join("L1").join("2.sst").exists());
+
+        let files = migrate_layout(dir, SstLayout::LevelSuffix)?;
+        assert_eq!(levels(files), vec![(1, Some(0)), (2, Some(1))]);
+        assert!(dir.join("1_L0.sst").exists());
+        assert!(dir.join("2_L1.sst").exists());
+
+        assert_eq!(
+            levels(migrate_layout(dir, SstLayout::Flat)?),
+            vec![(1, None), (2, None)]
+        );
+        assert_eq!(levels(sst_files(dir)?), vec![(1, None), (2, None)]);
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+use std::ops::Bound;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use super::state::LsmStorageInner;
+use super::ReadOptions;
+use crate::key_range::KeyRange;
+use crate::lsm_iterator::{FusedIterator, LsmIterator};
+
+/// The storage as of [`LsmStorage::get_snapshot`](crate::lsm_storage::LsmStorage::get_snapshot):
+/// its reads agree with each other however many writes, flushes and compactions run meanwhile.
+///
+/// It holds the memtables and the tables of that point, so they stay in memory and on disk
+/// until it is dropped. Hold it for a batch of reads rather than for long.
+pub struct Snapshot {
+    inner: Arc<LsmStorageInner>,
+}
+
+impl Snapshot {
+    /// `inner` must not have a memtable anyone writes to.
+    pub(super) fn new(inner: Arc<LsmStorageInner>) -> Self {
+        Self { inner }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.get_opt(key, ReadOptions::default())
+    }
+
+    pub fn get_opt(&self, key: &[u8], options: ReadOptions) -> Result<Option<Bytes>> {
+        let value = self.inner.get(key, &options)?;
+        Ok(value.filter(|value| !value.is_empty()))
+    }
+
+    /// Get several keys at once, as of the snapshot. Values are returned in the order of `keys`.
+    pub fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Bytes>>> {
+        self.get_many_opt(keys, ReadOptions::default())
+    }
+
+    pub fn get_many_opt(&self, keys: &[&[u8]], options: ReadOptions) -> Result<Vec<Option<Bytes>>> {
+        let mut found = keys
+            .iter()
+            .map(|key| self.inner.get_from_memtables(key))
+            .collect::<Vec<_>>();
+        self.inner
+            .get_many_from_sstables(keys, &mut found, &options)?;
+        Ok(found
+            .into_iter()
+            .map(|value| value.filter(|value| !value.is_empty()))
+            .collect())
+    }
+
+    /// Create an iterator over a range of keys, as of the snapshot.
+    /// Fails with [`LsmError::InvalidArgument`] if the range is inverted.
+    ///
+    /// [`LsmError::InvalidArgument`]: crate::error::LsmError::InvalidArgument
+    pub fn scan(&self, range: impl Into<KeyRange>) -> Result<FusedIterator<LsmIterator>> {
+        let range = range.into();
+        range.check()?;
+        self.inner
+            .scan(&range, None, &ReadOptions::default(), false, None)
+    }
+
+    #[deprecated(note = "use `scan`, which takes a `KeyRange`")]
+    pub fn scan_bounds(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.scan((lower, upper))
+    }
+}
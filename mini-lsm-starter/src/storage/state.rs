@@ -0,0 +1,686 @@
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use super::paths::{sst_files, SstFile};
+use super::verify::TableVerifier;
+use super::{LsmStorageOptions, PrefixExtractor, ReadOptions};
+use crate::block::{Block, BlockIterator};
+use crate::compaction::NUM_LEVELS;
+use crate::error::LsmError;
+use crate::iterators::concat_iterator::SstConcatIterator;
+use crate::iterators::merge_iterator::MergeIterator;
+use crate::iterators::two_merge_iterator::TwoMergeIterator;
+use crate::key_range::KeyRange;
+use crate::lsm_iterator::{FusedIterator, LsmIterator, SharedSkipped};
+use crate::manifest;
+use crate::mem_table::{FrozenMemTable, MemTable};
+use crate::metrics::{Metrics, ReadKind};
+use crate::quarantine::CorruptionReport;
+use crate::table::compressed_cache::CompressedBlockCache;
+use crate::table::{
+    FileObject, SeekTarget, SharedCacheFill, SharedReadaheadPeak, SsTable, SsTableBuilder,
+    SsTableIterator,
+};
+
+pub use crate::table::block_cache::{BlockCache, CacheStats};
+
+/// The moka cache [`BlockCache`] wraps, which it used to be an alias of.
+#[deprecated(note = "use `BlockCache`, which wraps the moka cache")]
+pub type RawBlockCache = moka::sync::Cache<(usize, usize), Arc<Block>>;
+
+/// The SSTs of one of the levels below L0, sorted by key range.
+pub type Level = Vec<Arc<SsTable>>;
+
+/// An entry found in an SST: the table, the block, the index of the entry in the block and the
+/// value.
+pub(super) type SstEntryRef = (Arc<SsTable>, usize, usize, Bytes);
+
+/// Whether `sst` may hold `key`: not if the key lies outside its key range, which only takes
+/// its index to tell, nor if its key filter rules the key out. A table written before the block
+/// metas held their last key may hold any key from its first one on.
+fn may_hold(sst: &SsTable, key: &[u8]) -> bool {
+    sst.overlaps(Bound::Included(key), Bound::Included(key)) && sst.may_contain(key)
+}
+
+/// The table of `level` whose key range may take in `key`: the last one starting at or before
+/// it. The tables of a level do not overlap, so the next one starting past the key bounds it,
+/// and no other table needs a look. The tables without blocks sort first and never match.
+pub(super) fn table_for_key<'a>(level: &'a Level, key: &[u8]) -> Option<&'a Arc<SsTable>> {
+    let idx =
+        level.partition_point(|sst| sst.first_key().map_or(true, |first_key| first_key <= key));
+    level[..idx].last().filter(|sst| may_hold(sst, key))
+}
+
+/// Insert `sst` into `level` where its first key sorts it, like the levels are loaded.
+pub(super) fn insert_by_key(level: &mut Level, sst: Arc<SsTable>) {
+    let idx = level.partition_point(|other| other.first_key() <= sst.first_key());
+    level.insert(idx, sst);
+}
+
+/// A builder for the SSTs of a storage opened with `options`.
+pub(super) fn sst_builder(options: &LsmStorageOptions) -> SsTableBuilder {
+    let mut builder = SsTableBuilder::new(options.block_size);
+    if options.entry_checksums {
+        builder = builder.with_entry_checksums();
+    }
+    match &options.prefix_extractor {
+        Some(extractor) => builder.with_prefix_extractor(extractor.clone()),
+        None => builder,
+    }
+}
+
+/// Look up the keys `found` has no entry for, visited in `order`, in the table `pick` picks for
+/// each, reusing the last block read for the keys that fall in it as well.
+fn get_many_from_tables<'a>(
+    pick: impl Fn(&[u8]) -> Option<&'a Arc<SsTable>>,
+    keys: &[&[u8]],
+    order: &[usize],
+    found: &mut [Option<Bytes>],
+    options: &ReadOptions,
+) -> Result<()> {
+    let mut last_block: Option<(usize, usize, Arc<Block>)> = None;
+    for &idx in order {
+        if found[idx].is_some() {
+            continue;
+        }
+        let sstable = match pick(keys[idx]) {
+            Some(sstable) => sstable,
+            None => continue,
+        };
+        let block_idx = sstable.find_block_idx(keys[idx]);
+        let block = match &last_block {
+            Some((sst_id, last_idx, block))
+                if *sst_id == sstable.sst_id() && *last_idx == block_idx =>
+            {
+                block.clone()
+            }
+            _ => {
+                let block = sstable.read_block_with(block_idx, options)?;
+                sstable.record_read(ReadKind::Get, block_idx);
+                last_block = Some((sstable.sst_id(), block_idx, block.clone()));
+                block
+            }
+        };
+        let iter = BlockIterator::create_and_seek_to_key(block, keys[idx]);
+        iter.check()?;
+        if iter.is_valid() && iter.key() == keys[idx] {
+            found[idx] = Some(iter.value().clone());
+        }
+    }
+    Ok(())
+}
+
+/// The keys the SSTs of a state may hold: from the smallest first key of a table to the largest
+/// last key. Widened as tables are added, and kept as compactions retire tables, since their
+/// outputs hold keys of their inputs. It may thus run past the keys still held, never short.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(super) enum SstKeyRange {
+    /// No table holds a key.
+    #[default]
+    Empty,
+    /// `last` is `None` once a table that did not record its last key is added, as if the range
+    /// ran to the end.
+    Keys { first: Bytes, last: Option<Bytes> },
+}
+
+impl SstKeyRange {
+    /// Take in the keys of `sst`.
+    pub(super) fn widen(&mut self, sst: &SsTable) {
+        let index = sst.index();
+        let first = match index.first_key() {
+            Some(first_key) => first_key,
+            None => return,
+        };
+        let last = index.last_key();
+        match self {
+            Self::Empty => {
+                *self = Self::Keys {
+                    first: Bytes::copy_from_slice(first),
+                    last: last.map(Bytes::copy_from_slice),
+                }
+            }
+            Self::Keys {
+                first: lo,
+                last: hi,
+            } => {
+                if first < &lo[..] {
+                    *lo = Bytes::copy_from_slice(first);
+                }
+                match (&hi, last) {
+                    (Some(hi_key), Some(last)) if last <= &hi_key[..] => {}
+                    (Some(_), Some(last)) => *hi = Some(Bytes::copy_from_slice(last)),
+                    _ => *hi = None,
+                }
+            }
+        }
+    }
+
+    pub(super) fn contains(&self, key: &[u8]) -> bool {
+        match self {
+            Self::Empty => false,
+            Self::Keys { first, last: None } => &first[..] <= key,
+            Self::Keys {
+                first,
+                last: Some(last),
+            } => &first[..] <= key && key <= &last[..],
+        }
+    }
+
+    /// Whether the range overlaps the bounds, the way [`SsTable::overlaps`] tells for a table.
+    pub(super) fn overlaps(&self, range: &KeyRange) -> bool {
+        let (first, last) = match self {
+            Self::Empty => return false,
+            Self::Keys { first, last } => (first, last),
+        };
+        let below_upper = match range.upper() {
+            Bound::Included(upper) => &first[..] <= upper,
+            Bound::Excluded(upper) => &first[..] < upper,
+            Bound::Unbounded => true,
+        };
+        let above_lower = match (last, range.lower()) {
+            (Some(last), Bound::Included(lower)) => &last[..] >= lower,
+            (Some(last), Bound::Excluded(lower)) => &last[..] > lower,
+            _ => true,
+        };
+        below_upper && above_lower
+    }
+}
+
+/// What recovery found in the directory that the manifest does not agree with.
+#[derive(Debug, Default)]
+pub(super) struct Unrecorded {
+    /// SSTs the manifest never recorded, e.g. the outputs of a compaction a crash cut short
+    /// before it recorded them, or copies of other tables.
+    pub orphans: Vec<SstFile>,
+    /// The tables the manifest records as live whose files are missing.
+    pub missing: Vec<usize>,
+    /// The tables the manifest records as live that recovery went on without, as `on_corrupt`
+    /// let it.
+    pub dropped: Vec<usize>,
+}
+
+#[derive(Clone)]
+pub struct LsmStorageInner {
+    /// The current memtable.
+    pub(super) memtable: Arc<MemTable>,
+    /// Immutable memTables, from earliest to latest.
+    pub(super) imm_memtables: Vec<Arc<FrozenMemTable>>,
+    /// L0 SsTables, from earliest to latest.
+    pub(super) l0_sstables: Vec<Arc<SsTable>>,
+    /// L1 - L6 SsTables, sorted by key range.
+    pub(super) levels: Vec<Level>,
+    /// The next SSTable ID.
+    pub(super) next_sst_id: usize, // TODO:
+    /// The keys the SSTs may hold, kept up to date by whoever adds a table.
+    pub(super) sst_key_range: SstKeyRange,
+}
+
+impl LsmStorageInner {
+    pub(super) fn create() -> Self {
+        Self {
+            memtable: Arc::new(MemTable::create()),
+            imm_memtables: vec![],
+            l0_sstables: vec![],
+            levels: vec![vec![]; NUM_LEVELS - 1],
+            next_sst_id: 0,
+            sst_key_range: SstKeyRange::Empty,
+        }
+    }
+
+    /// Whether the memtables and the SSTs hold no entry at all, not even a tombstone. Reads no
+    /// block.
+    pub(super) fn is_empty(&self) -> bool {
+        self.memtable.is_empty()
+            && self.imm_memtables.iter().all(|mem| mem.is_empty())
+            && self.sst_key_range == SstKeyRange::Empty
+    }
+
+    /// Whether no key within the bounds can be found: the memtables are empty and the SSTs hold
+    /// no key within them.
+    pub(super) fn holds_none_within(&self, range: &KeyRange) -> bool {
+        self.memtable.is_empty()
+            && self.imm_memtables.iter().all(|mem| mem.is_empty())
+            && !self.sst_key_range.overlaps(range)
+    }
+
+    /// The newest entry of `key`, from the memtables, then the SSTs. A tombstone is returned as
+    /// an empty value, and hides the entries older than it.
+    pub fn get(&self, key: &[u8], options: &ReadOptions) -> Result<Option<Bytes>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        if let Some(v) = self.get_from_memtables(key) {
+            return Ok(Some(v));
+        }
+
+        // the newest table holding the key, whose tombstone hides the older ones
+        Ok(self
+            .locate_in_sstables(key, options)?
+            .map(|(_, _, _, value)| value))
+    }
+
+    /// Look `key` up in the memtables only. A tombstone is returned as an empty value.
+    pub(super) fn get_from_memtables(&self, key: &[u8]) -> Option<Bytes> {
+        self.memtable
+            .get(key)
+            .or_else(|| self.imm_memtables.iter().rev().find_map(|mem| mem.get(key)))
+    }
+
+    /// The commit sequence of the latest write the SSTs hold, 0 if none records one, for the
+    /// sequence to carry on from at open.
+    pub(super) fn max_sst_seq(&self) -> u64 {
+        self.l0_sstables
+            .iter()
+            .chain(self.levels.iter().flatten())
+            .map(|sst| sst.max_seq())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Look up the keys `found` has no entry for in the SSTs, newest first: L0, then one table
+    /// at most per level below it. The keys are visited in order, so that neighbouring keys share
+    /// a block read.
+    pub(super) fn get_many_from_sstables(
+        &self,
+        keys: &[&[u8]],
+        found: &mut [Option<Bytes>],
+        options: &ReadOptions,
+    ) -> Result<()> {
+        let mut order = (0..keys.len())
+            .filter(|&idx| self.sst_key_range.contains(keys[idx]))
+            .collect::<Vec<_>>();
+        order.sort_by_key(|&idx| keys[idx]);
+
+        for sstable in self.l0_sstables.iter().rev() {
+            let pick = |key: &[u8]| Some(sstable).filter(|sst| may_hold(sst, key));
+            get_many_from_tables(pick, keys, &order, found, options)?;
+        }
+        for level in &self.levels {
+            get_many_from_tables(
+                |key| table_for_key(level, key),
+                keys,
+                &order,
+                found,
+                options,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Find the newest entry of `key` in the SSTs: its table, its block, its index in the block
+    /// and its value, which is empty for a tombstone.
+    pub(super) fn locate_in_sstables(
+        &self,
+        key: &[u8],
+        options: &ReadOptions,
+    ) -> Result<Option<SstEntryRef>> {
+        if !self.sst_key_range.contains(key) {
+            return Ok(None);
+        }
+        let l0 = self
+            .l0_sstables
+            .iter()
+            .rev()
+            .filter(|sst| may_hold(sst, key));
+        // each level below L0 is newer than the ones below it
+        let levels = self
+            .levels
+            .iter()
+            .filter_map(|level| table_for_key(level, key));
+        for sstable in l0.chain(levels) {
+            let block_idx = sstable.find_block_idx(key);
+            let block = sstable.read_block_with(block_idx, options)?;
+            sstable.record_read(ReadKind::Get, block_idx);
+            let iter = BlockIterator::create_and_seek_to_key(block, key);
+            iter.check()?;
+            if iter.is_valid() && iter.key() == key {
+                let value = iter.value().clone();
+                return Ok(Some((sstable.clone(), block_idx, iter.entry_idx(), value)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read the blocks of every SST holding keys from `first` to `last` into the block cache,
+    /// skipping the ones that fail to read, and return the number of blocks visited.
+    pub(super) fn warm_blocks(&self, first: &[u8], last: &[u8]) -> usize {
+        let bounds = (Bound::Included(first), Bound::Included(last));
+        let mut visited = 0;
+        let tables = self.l0_sstables.iter().chain(self.levels.iter().flatten());
+        for sstable in tables.filter(|sst| sst.overlaps(bounds.0, bounds.1)) {
+            for block_idx in sstable.find_block_idx(first)..=sstable.find_block_idx(last) {
+                let _ = sstable.read_block_cached(block_idx);
+                visited += 1;
+            }
+        }
+        visited
+    }
+
+    /// Scan the keys within `range`. With a `prefix` every one of them shares, the SSTs whose
+    /// prefix filter rules it out are skipped. With `keep_tombstones` the deleted keys are
+    /// yielded as empty values. The blocks the SSTs read from disk go to the cache through
+    /// `cache_fill`, if any.
+    pub fn scan(
+        &self,
+        range: &KeyRange,
+        prefix: Option<(&PrefixExtractor, &[u8])>,
+        options: &ReadOptions,
+        keep_tombstones: bool,
+        cache_fill: Option<SharedCacheFill>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        let deadline = Arc::new(Mutex::new(options.deadline));
+        let (lower, upper) = range.bounds();
+        let mut mem_iters = vec![Box::new(
+            self.memtable.scan_bytes(lower.clone(), upper.clone()),
+        )];
+        mem_iters.extend(
+            self.imm_memtables
+                .iter()
+                .map(|tbl| Box::new(tbl.scan_bytes(lower.clone(), upper.clone()))),
+        );
+
+        let skipped = SharedSkipped::default();
+        let readahead_peak = SharedReadaheadPeak::default();
+        let open = {
+            let (lower, upper) = (lower.clone(), upper.clone());
+            let (options, deadline) = (options.clone(), deadline.clone());
+            let (cache_fill, skipped) = (cache_fill.clone(), skipped.clone());
+            let readahead_peak = readahead_peak.clone();
+            move |sst: Arc<SsTable>| -> Result<Option<SsTableIterator>> {
+                let id = sst.sst_id();
+                let target =
+                    SeekTarget::Range(lower.as_ref().map(|key| key.as_ref()), upper.clone());
+                match SsTableIterator::with_deadline(
+                    sst,
+                    target,
+                    options.clone(),
+                    deadline.clone(),
+                    cache_fill.clone(),
+                    readahead_peak.clone(),
+                ) {
+                    Ok(iter) => Ok(Some(iter)),
+                    // running out of time is not the table's fault
+                    Err(err)
+                        if options.skip_unreadable
+                            && err.downcast_ref::<LsmError>()
+                                != Some(&LsmError::DeadlineExceeded) =>
+                    {
+                        skipped.lock().push(id);
+                        Ok(None)
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+        };
+        // a table outside the bounds is left out, not opened to find that it has nothing there
+        let wanted = |sst: &&Arc<SsTable>| {
+            sst.overlaps(range.lower(), range.upper())
+                && prefix.map_or(true, |(extractor, prefix)| {
+                    sst.may_contain_prefix(extractor, prefix)
+                })
+        };
+
+        // newest first, so that the merge prefers its version of a key
+        let mut l0_iters = vec![];
+        for sst in self.l0_sstables.iter().rev().filter(wanted) {
+            l0_iters.extend(open(sst.clone())?.map(Box::new));
+        }
+        // the tables of a level do not overlap, so each level is a single run, opened table by
+        // table as the scan reaches it
+        let mut level_iters = vec![];
+        for level in &self.levels {
+            let tables: Vec<_> = level.iter().filter(wanted).cloned().collect();
+            if !tables.is_empty() {
+                let iter = SstConcatIterator::with_opener(tables, Box::new(open.clone()))?;
+                level_iters.push(Box::new(iter));
+            }
+        }
+
+        let two = TwoMergeIterator::create(
+            MergeIterator::create(mem_iters),
+            TwoMergeIterator::create(
+                MergeIterator::create(l0_iters),
+                MergeIterator::create(level_iters),
+            )?,
+        )?;
+
+        Ok(FusedIterator::new(
+            LsmIterator::new(two, deadline)
+                .with_skipped(skipped)
+                .with_cache_fill(cache_fill)
+                .with_readahead_peak(readahead_peak)
+                .with_cancel(options.cancel.clone())
+                .with_tombstones(keep_tombstones)
+                .with_tombstone_budget(options.max_tombstones_per_next)
+                .skip_leading_tombstones()?,
+        ))
+    }
+
+    /// Load the SSTs the manifest of `dir` records as live into their recorded levels. The
+    /// other SSTs found in `dir` are left out: the ones recorded as removed, see
+    /// `removed_sst_files`, and the ones never recorded, which come back as orphans. A directory
+    /// without a manifest, as the versions from before it left, has every SST loaded into the
+    /// level its location records, L0 if none. Tables that fail to load or to pass `verifier`
+    /// are handed to `on_corrupt`, which decides whether recovery goes on without them. The
+    /// reads of the tables count towards their level in `metrics`.
+    pub(super) fn recover(
+        dir: &Path,
+        cache: &Arc<BlockCache>,
+        compressed_cache: &Option<Arc<CompressedBlockCache>>,
+        metrics: &Arc<Metrics>,
+        verifier: &mut TableVerifier,
+        mut on_corrupt: impl FnMut(CorruptionReport) -> Result<()>,
+    ) -> Result<(Self, Unrecorded)> {
+        let mut inner = Self::create();
+        let mut unrecorded = Unrecorded::default();
+        let managed = manifest::read_header(dir)?.is_some();
+        let mut recorded = manifest::read_recorded(dir)?;
+        if let Some(id) = recorded.max_id() {
+            // the ids of removed tables are not handed out again, even once their files are gone
+            inner.next_sst_id = id + 1;
+        }
+
+        for file in sst_files(dir)? {
+            inner.next_sst_id = inner.next_sst_id.max(file.id + 1);
+            if recorded.removed.contains(&file.id) {
+                continue;
+            }
+            let level = match (managed, recorded.live.remove(&file.id)) {
+                (true, None) => {
+                    unrecorded.orphans.push(file);
+                    continue;
+                }
+                (true, level) => level,
+                (false, _) => file.level,
+            };
+            let table = FileObject::open(&file.path)
+                .and_then(|object| SsTable::open(file.id, Some(cache.clone()), object))
+                .map(|table| table.with_compressed_cache(compressed_cache.clone()));
+            let table = match table {
+                Ok(table) => Arc::new(table.with_level_io(level.unwrap_or(0), metrics.clone())),
+                Err(err) => {
+                    on_corrupt(CorruptionReport::new(file.path, "open sst", &err))?;
+                    if managed {
+                        unrecorded.dropped.push(file.id);
+                    }
+                    continue;
+                }
+            };
+            if let Err(err) = verifier.verify(&table) {
+                on_corrupt(CorruptionReport::new(file.path, "verify sst", &err))?;
+                if managed {
+                    unrecorded.dropped.push(file.id);
+                }
+                continue;
+            }
+            match level {
+                None | Some(0) => inner.l0_sstables.push(table),
+                Some(level) => {
+                    if inner.levels.len() < level {
+                        inner.levels.resize_with(level, Vec::new);
+                    }
+                    inner.levels[level - 1].push(table);
+                }
+            }
+        }
+        for level in &mut inner.levels {
+            level.sort_by_key(|sst| sst.index().first_key_bytes());
+        }
+        for sst in inner
+            .l0_sstables
+            .iter()
+            .chain(inner.levels.iter().flatten())
+        {
+            inner.sst_key_range.widen(sst);
+        }
+        unrecorded.missing = recorded.live.into_keys().collect();
+
+        Ok((inner, unrecorded))
+    }
+
+    /// The newest L0 tables smaller than `threshold` bytes, up to the first larger one, oldest
+    /// first.
+    pub(super) fn newest_small_l0_run(&self, threshold: u64) -> Vec<Arc<SsTable>> {
+        let small = self
+            .l0_sstables
+            .iter()
+            .rev()
+            .take_while(|sst| sst.file_size() < threshold)
+            .count();
+        self.l0_sstables[self.l0_sstables.len() - small..].to_vec()
+    }
+
+    /// Entries in the memtables plus the entries the SSTs recorded as live, counting shadowed
+    /// versions and keys deleted by a newer table as well. Reads no block.
+    pub(super) fn approximate_len(&self) -> u64 {
+        let memtables = self.memtable.len() as u64
+            + self
+                .imm_memtables
+                .iter()
+                .map(|mem| mem.len() as u64)
+                .sum::<u64>();
+        let tables = self
+            .l0_sstables
+            .iter()
+            .chain(self.levels.iter().flatten())
+            .filter_map(|sst| sst.properties())
+            .map(|properties| properties.num_live_entries())
+            .sum::<u64>();
+        memtables + tables
+    }
+
+    /// Whether the memtables are empty and no SST holds anything but tombstones. A table
+    /// written without properties counts as holding data unless it has no block.
+    pub(super) fn is_empty_estimate(&self) -> bool {
+        self.memtable.is_empty()
+            && self.imm_memtables.iter().all(|mem| mem.is_empty())
+            && self
+                .l0_sstables
+                .iter()
+                .chain(self.levels.iter().flatten())
+                .all(|sst| match sst.properties() {
+                    Some(properties) => properties.num_live_entries() == 0,
+                    None => sst.num_of_blocks() == 0,
+                })
+    }
+
+    /// Number of SSTs of every level.
+    pub(super) fn num_sst_files(&self) -> usize {
+        self.l0_sstables.len() + self.levels.iter().map(Vec::len).sum::<usize>()
+    }
+
+    /// Check the invariants of the state: L0 is ordered by id, `next_sst_id` is past every
+    /// table, and the tables of each level are sorted and do not overlap.
+    pub(super) fn validate(&self) -> Result<()> {
+        for pair in self.l0_sstables.windows(2) {
+            anyhow::ensure!(
+                pair[0].sst_id() < pair[1].sst_id(),
+                "L0 has sst {} before sst {}",
+                pair[0].sst_id(),
+                pair[1].sst_id()
+            );
+        }
+
+        for sst in self.l0_sstables.iter().chain(self.levels.iter().flatten()) {
+            anyhow::ensure!(
+                sst.sst_id() < self.next_sst_id,
+                "sst {} is not below the next sst id {}",
+                sst.sst_id(),
+                self.next_sst_id
+            );
+        }
+
+        for (level, ssts) in self.levels.iter().enumerate() {
+            let mut last_key: Option<Bytes> = None;
+            for sst in ssts {
+                let (first, last) = match sst.key_range()? {
+                    Some(range) => range,
+                    None => continue,
+                };
+                if let Some(prev) = &last_key {
+                    anyhow::ensure!(
+                        prev < &first,
+                        "sst {} overlaps the sst before it in L{}",
+                        sst.sst_id(),
+                        level + 1
+                    );
+                }
+                last_key = Some(last);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn archive_mem_table(&mut self) {
+        let memtable = std::mem::replace(&mut self.memtable, Arc::new(MemTable::create()));
+        self.imm_memtables.push(memtable.freeze());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm_storage::SstLayout;
+    use crate::storage::paths::path_of_sst;
+
+    fn sst(dir: &Path, id: usize, keys: &[&str]) -> Result<Arc<SsTable>> {
+        let mut builder = SsTableBuilder::new(128);
+        for key in keys {
+            builder.add(key.as_bytes(), b"value");
+        }
+        let path = path_of_sst(dir, SstLayout::Flat, 0, id);
+        Ok(Arc::new(builder.export(id, None, path)?))
+    }
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = dir.path();
+        let mut inner = LsmStorageInner::create();
+        inner.l0_sstables = vec![sst(dir, 1, &["a"])?, sst(dir, 4, &["a"])?];
+        inner.levels = vec![vec![sst(dir, 2, &["a", "c"])?, sst(dir, 3, &["d", "f"])?]];
+        inner.next_sst_id = 5;
+        inner.validate()?;
+
+        let mut stale_id = inner.clone();
+        stale_id.next_sst_id = 4;
+        assert!(stale_id.validate().is_err());
+
+        let mut unordered = inner.clone();
+        unordered.l0_sstables.reverse();
+        assert!(unordered.validate().is_err());
+
+        let mut overlapping = inner;
+        overlapping.levels[0][1] = sst(dir, 5, &["c", "f"])?;
+        overlapping.next_sst_id = 6;
+        assert!(overlapping.validate().is_err());
+        Ok(())
+    }
+}
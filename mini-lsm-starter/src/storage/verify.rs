@@ -0,0 +1,63 @@
+use anyhow::Result;
+
+use super::options::VerifyLevel;
+use crate::table::SsTable;
+
+/// A seed for the blocks to sample when none is given.
+pub(super) fn random_seed() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    // every RandomState is seeded afresh from the OS, so there is no need for a rand crate
+    RandomState::new().build_hasher().finish()
+}
+
+/// Checks the SSTs at open as much as a [`VerifyLevel`] asks for.
+pub(super) struct TableVerifier {
+    level: VerifyLevel,
+    /// State of a SplitMix64 generator, which is plenty to pick blocks with.
+    state: u64,
+}
+
+impl TableVerifier {
+    pub fn new(level: VerifyLevel, seed: u64) -> Self {
+        Self { level, state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// `count` distinct block indexes out of `num_blocks`, in the order they were drawn.
+    fn sample(&mut self, num_blocks: usize, count: usize) -> Vec<usize> {
+        let mut blocks: Vec<usize> = (0..num_blocks).collect();
+        let count = count.min(num_blocks);
+        // the first `count` steps of a Fisher-Yates shuffle
+        for idx in 0..count {
+            let pick = idx + (self.next_u64() % (num_blocks - idx) as u64) as usize;
+            blocks.swap(idx, pick);
+        }
+        blocks.truncate(count);
+        blocks
+    }
+
+    pub fn verify(&mut self, table: &SsTable) -> Result<()> {
+        let blocks = match self.level {
+            VerifyLevel::None => return Ok(()),
+            VerifyLevel::FootersOnly => vec![],
+            VerifyLevel::SampleBlocks { per_table } => {
+                self.sample(table.num_of_blocks(), per_table)
+            }
+            VerifyLevel::Full => (0..table.num_of_blocks()).collect(),
+        };
+        table.verify_index()?;
+        for block_idx in blocks {
+            table.verify_block(block_idx)?;
+        }
+        Ok(())
+    }
+}
@@ -1,19 +1,177 @@
+pub mod block_cache;
+mod bloom;
 mod builder;
+mod cache_fill;
+pub mod compressed_cache;
+mod index;
+pub mod index_block;
 mod iterator;
+mod readahead;
+pub mod reader;
 
-use std::cmp::max;
 use std::io::Write;
+use std::ops::Bound;
 use std::os::unix::fs::FileExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::Result;
+use bloom::{BloomFilter, PrefixFilter};
 pub use builder::SsTableBuilder;
+#[cfg(test)]
+pub(crate) use builder::PEAK_BLOCKS_HELD;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-pub use iterator::SsTableIterator;
+pub use cache_fill::{ScanCacheFill, SharedCacheFill, SCAN_FILL_BATCH};
+use compressed_cache::{CacheAdmission, CompressedBlockCache};
+pub use index::FencedIndex;
+use index_block::{IndexBlock, SliceRef, TableIndex};
+pub use iterator::{SeekTarget, SharedDeadline, SsTableIterator};
+use parking_lot::Mutex;
+pub use readahead::{ReadaheadState, SharedReadaheadPeak, SEQUENTIAL_READS};
+
+use crate::block::{strip_entry_checksum, Block};
+use crate::error::LsmError;
+use crate::format::{FooterLayout, BLOCK_META, FOOTER_V1, FOOTER_V2};
+use crate::lsm_storage::{BlockCache, PrefixExtractor, ReadOptions};
+use crate::metrics::{Metrics, ReadKind};
+use crate::retention::{FileId, FileRetention, RetireOnDrop};
+
+/// Marks a footer that also points at a prefix filter, see [`SsTable`].
+const PREFIX_FILTER_MAGIC: u32 = 0x5bf1_17e2;
+
+/// Marks a footer of a table whose values end with their entry checksum, see [`SsTable`].
+const ENTRY_CHECKSUM_MAGIC: u32 = 0xec5e_c5a1;
+
+/// Marks the [`TableProperties`] in the footer of a table, see [`SsTable`].
+const PROPERTIES_MAGIC: u32 = 0x9a0b_e271;
+
+/// Marks a footer that also points at a filter of the keys, see [`SsTable`].
+const KEY_FILTER_MAGIC: u32 = 0x6b3f_1170;
+
+/// Marks a footer of a table whose blocks start with their compression tag, see [`SsTable`].
+const COMPRESSION_MAGIC: u32 = 0xc0de_c1a9;
+
+/// Marks a footer of a table whose block metas hold the last key of their block, see
+/// [`SsTable`].
+const LAST_KEY_MAGIC: u32 = 0x1a57_4e75;
+
+/// Marks a footer that also points at an [`IndexBlock`], see [`SsTable`].
+const INDEX_BLOCK_MAGIC: u32 = 0x1dc5_b10c;
+
+/// Marks the commit sequence of the latest write a table holds, see [`SsTable`].
+const MAX_SEQ_MAGIC: u32 = 0x5e9a_4a11;
 
-use crate::block::Block;
-use crate::lsm_storage::BlockCache;
+/// Ends every table, in its footer, to tell it from a corrupt or unrelated file, see [`SsTable`].
+pub const SSTABLE_MAGIC: u32 = 0x6c73_6d21;
+
+/// Version of the table format, in the footer of every table. A table of a later version does
+/// not open. Version 2 added the meta checksum to the footer.
+pub const FORMAT_VERSION: u16 = 2;
+
+/// Bytes of the footer that ends every table written by this build.
+const FOOTER_LEN: usize = FOOTER_V2.len;
+
+/// The meta section is read back this many bytes at a time to check its checksum.
+const META_CHECK_CHUNK: u64 = 64 << 10;
+
+/// Encode the footer of a table whose meta blocks start at `meta_offset`, with `meta_checksum`
+/// the CRC32 of everything from there to the footer, laid out as [`FOOTER_V2`].
+fn encode_footer(meta_offset: u32, meta_checksum: u32, buf: &mut Vec<u8>) {
+    let layout = &FOOTER_V2;
+    let mut footer = [0; FOOTER_LEN];
+    layout.meta_offset.put(&mut footer, meta_offset.into());
+    if let Some(field) = layout.meta_checksum {
+        field.put(&mut footer, meta_checksum.into());
+    }
+    layout
+        .format_version
+        .put(&mut footer, FORMAT_VERSION.into());
+    layout.magic.put(&mut footer, SSTABLE_MAGIC.into());
+    let checksum = crc32fast::hash(&footer[..layout.checksum.offset]) as u16;
+    layout.checksum.put(&mut footer, checksum.into());
+    buf.extend_from_slice(&footer);
+}
+
+/// A decoded footer.
+struct Footer {
+    meta_offset: u64,
+    /// `None` for a version 1 table.
+    meta_checksum: Option<u32>,
+    len: u64,
+}
+
+/// Check the footer of sst `id` at the end of `tail`, the last bytes of the table, read from
+/// `tail_offset` on. The fields ending the footer, from the format version on, are in the same
+/// place in every version.
+fn decode_footer(id: usize, tail: &[u8], tail_offset: u64) -> Result<Footer> {
+    anyhow::ensure!(
+        tail.len() >= FOOTER_V1.len,
+        "sst {} is too small: {} bytes",
+        id,
+        tail.len()
+    );
+    // the fields from the format version on end the footer of every version, so the layout of
+    // version 1 reads them in any of them
+    let shared = &tail[tail.len() - FOOTER_V1.len..];
+    let version = FOOTER_V1.format_version.get(shared) as u16;
+    let magic = FOOTER_V1.magic.get(shared) as u32;
+    anyhow::ensure!(
+        magic == SSTABLE_MAGIC,
+        "sst {} is not a mini-lsm table: its footer has magic {:#010x}, not {:#010x}",
+        id,
+        magic,
+        SSTABLE_MAGIC
+    );
+    let layout = match FooterLayout::of_version(version) {
+        Some(layout) => layout,
+        None => anyhow::bail!(
+            "sst {} has format version {}, which this build cannot read: it reads versions up \
+             to {}",
+            id,
+            version,
+            FORMAT_VERSION
+        ),
+    };
+    anyhow::ensure!(
+        tail.len() >= layout.len,
+        "sst {} is too small: {} bytes",
+        id,
+        tail.len()
+    );
+    let footer = &tail[tail.len() - layout.len..];
+    let checksum = layout.checksum.get(footer) as u16;
+    let expected = crc32fast::hash(&footer[..layout.checksum.offset]) as u16;
+    if checksum != expected {
+        return Err(LsmError::ChecksumMismatch {
+            what: format!("sst {} has a corrupt footer", id),
+            offset: tail_offset + (tail.len() - layout.len) as u64,
+            expected: expected.into(),
+            actual: checksum.into(),
+        }
+        .into());
+    }
+    Ok(Footer {
+        meta_offset: layout.meta_offset.get(footer),
+        meta_checksum: layout.meta_checksum.map(|field| field.get(footer) as u32),
+        len: layout.len as u64,
+    })
+}
+
+/// Counts a table records about its entries when it is built, so that they are known without
+/// reading any block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TableProperties {
+    pub num_entries: u64,
+    /// Entries with an empty value, i.e. deletions.
+    pub num_tombstones: u64,
+}
+
+impl TableProperties {
+    /// Entries that are not deletions. Entries shadowed by a newer table still count.
+    pub fn num_live_entries(&self) -> u64 {
+        self.num_entries - self.num_tombstones
+    }
+}
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct BlockMeta {
@@ -21,20 +179,22 @@ pub struct BlockMeta {
     pub offset: usize,
     /// The first key of the data block, mainly used for index purpose.
     pub first_key: Bytes,
+    /// The last key of the data block, which tells a key past it apart from a key within it
+    /// without reading the block.
+    pub last_key: Bytes,
 }
 
 impl BlockMeta {
-    /// Encode block meta to a buffer.
-    /// You may add extra fields to the buffer,
-    /// in order to help keep track of `first_key` when decoding from the same buffer in the future.
-    ///
-    /// | offset | key len | first_key |
+    /// Encode block meta to a buffer, laid out as [`BLOCK_META`].
     pub fn encode_block_meta(block_meta: &[BlockMeta], buf: &mut Vec<u8>) {
+        let layout = &BLOCK_META;
         let mut bytes = BytesMut::new();
         for meta in block_meta {
-            bytes.put_u32_le(meta.offset as _);
-            bytes.put_u16_le(meta.first_key.len() as _);
-            bytes.extend_from_slice(&meta.first_key);
+            bytes.put_uint_le(meta.offset as u64, layout.offset_size);
+            for key in [&meta.first_key, &meta.last_key] {
+                bytes.put_uint_le(key.len() as u64, layout.key_len_size);
+                bytes.extend_from_slice(key);
+            }
         }
         buf.extend_from_slice(&bytes);
     }
@@ -44,55 +204,150 @@ impl BlockMeta {
         let mut buf = buf;
         let mut vec: Vec<BlockMeta> = vec![];
         while buf.has_remaining() {
-            let offset = buf.get_u32_le() as usize;
-            let key_len = buf.get_u16_le() as usize;
+            let offset = buf.get_uint_le(BLOCK_META.offset_size) as usize;
+            let key_len = buf.get_uint_le(BLOCK_META.key_len_size) as usize;
             let first_key = buf.copy_to_bytes(key_len);
+            let key_len = buf.get_uint_le(BLOCK_META.key_len_size) as usize;
+            let last_key = buf.copy_to_bytes(key_len);
 
-            vec.push(Self { offset, first_key })
+            vec.push(Self {
+                offset,
+                first_key,
+                last_key,
+            })
         }
 
         vec
     }
 }
 
+#[cfg(test)]
+thread_local! {
+    /// Added to every file read made by the current thread, to simulate a slow disk.
+    pub(crate) static READ_LATENCY: std::cell::Cell<std::time::Duration> = Default::default();
+    /// The files the current thread read from, one entry per read.
+    pub(crate) static FILES_READ: std::cell::RefCell<Vec<PathBuf>> = Default::default();
+    /// The bytes of every file read of the current thread, in the order of `FILES_READ`.
+    pub(crate) static READ_LENGTHS: std::cell::RefCell<Vec<u64>> = Default::default();
+    /// The ids of the tables whose key filter the current thread probed, one entry per probe.
+    pub(crate) static KEY_FILTER_PROBES: std::cell::RefCell<Vec<usize>> = Default::default();
+    /// The insertions into the block cache the current thread made, counting a batch of a scan
+    /// as one.
+    pub(crate) static CACHE_INSERTS: std::cell::Cell<usize> = Default::default();
+    /// Fail this many of the next file writes of the current thread with a transient error.
+    pub(crate) static WRITE_FAULTS: std::cell::Cell<usize> = Default::default();
+}
+
+/// Fail the write if [`WRITE_FAULTS`] says so.
+#[cfg(test)]
+fn inject_write_fault() -> std::io::Result<()> {
+    let faults = WRITE_FAULTS.with(|faults| faults.get());
+    if faults == 0 {
+        return Ok(());
+    }
+    WRITE_FAULTS.with(|count| count.set(faults - 1));
+    Err(std::io::Error::new(
+        std::io::ErrorKind::WouldBlock,
+        "injected write fault",
+    ))
+}
+
 /// A file object.
 pub struct FileObject {
     size: u64,
     file: std::fs::File,
+    path: PathBuf,
 }
 
 impl FileObject {
+    /// Read `len` bytes at `offset`, failing with [`LsmError::Corruption`] unless they all lie
+    /// within the file.
     pub fn read(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        if offset.checked_add(len).map_or(true, |end| end > self.size) {
+            return Err(LsmError::Corruption {
+                file: self.path.clone(),
+                requested: (offset, len),
+                size: self.size,
+            }
+            .into());
+        }
+
+        #[cfg(test)]
+        {
+            std::thread::sleep(READ_LATENCY.with(|latency| latency.get()));
+            FILES_READ.with(|files| files.borrow_mut().push(self.path.clone()));
+            READ_LENGTHS.with(|lengths| lengths.borrow_mut().push(len));
+        }
+
         let mut buf = vec![0u8; len as _];
         self.file.read_exact_at(buf.as_mut(), offset)?;
         Ok(buf)
     }
 
+    /// Read from `offset` to the end of the file.
+    pub fn read_to_end_from(&self, offset: u64) -> Result<Vec<u8>> {
+        self.read(offset, self.size.saturating_sub(offset))
+    }
+
     pub fn size(&self) -> u64 {
         self.size
     }
 
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     /// Create a new file object (day 2) and write the file to the disk (day 4).
     pub fn create(path: &Path, data: Vec<u8>) -> Result<Self> {
         let mut file = std::fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open(path)?;
+        #[cfg(test)]
+        inject_write_fault()?;
         file.write_all(&data)?;
         file.flush()?;
 
         Ok(Self {
             size: data.len() as _,
             file,
+            path: path.to_path_buf(),
         })
     }
 
+    /// Append `data` to a file created by `create`.
+    pub fn append(&mut self, data: &[u8]) -> Result<()> {
+        #[cfg(test)]
+        inject_write_fault()?;
+        self.file.write_all(data)?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Move the file to `path`, keeping it open.
+    pub fn rename(&mut self, path: &Path) -> Result<()> {
+        std::fs::rename(&self.path, path)?;
+        self.path = path.to_path_buf();
+        Ok(())
+    }
+
+    /// Sync the data written to the disk.
+    pub fn sync(&self) -> Result<()> {
+        self.file.sync_all()?;
+        Ok(())
+    }
+
     pub fn open(path: &Path) -> Result<Self> {
         let file = std::fs::OpenOptions::new().read(true).open(path)?;
         let size = file.metadata()?.len();
 
-        Ok(Self { size, file })
+        Ok(Self {
+            size,
+            file,
+            path: path.to_path_buf(),
+        })
     }
 }
 
@@ -101,100 +356,788 @@ impl FileObject {
 /// -------------------------------------------------------------------------------------------------------
 /// | Data Block #1 | ... | Data Block #N | Meta Block #1 | ... | Meta Block #N | Meta Block Offset (u32) |
 /// -------------------------------------------------------------------------------------------------------
+///
+/// The meta block offset is the first field of the 16-byte footer,
+/// `| Meta Block Offset (u32) | Meta Checksum (u32) | Format Version (u16) | Magic (u32) |
+/// Footer Checksum (u16) |`, so "right before the meta block offset" below means right before
+/// the footer. The meta checksum is the CRC32 of everything from the meta blocks to the footer,
+/// checked at open before any of it is decoded. The tables of format version 1 have a 12-byte
+/// footer without it.
+///
+/// A table built with a prefix extractor has its prefix filter right after the meta blocks,
+/// and a longer Extra: `| Filter Offset (u32) | Magic (u32) | Meta Block Offset (u32) |`.
+/// A table with properties has `| Num Entries (u32) | Num Tombstones (u32) | Properties Magic (u32) |`
+/// right before that, then a table with entry checksums has `| Entry Checksum Magic (u32) |`
+/// right before the meta block offset. Tables written before properties existed have none.
+/// A table with a key filter has it between the data blocks and the meta blocks, and
+/// `| Key Filter Offset (u32) | Key Filter Magic (u32) |` right before its properties.
+/// A table with compressed blocks, each starting with the tag of its
+/// [`CompressionType`](crate::compression::CompressionType), has `| Compression Magic (u32) |`
+/// right before the meta block offset, after the entry checksum magic. A table whose block metas
+/// hold the last key of their block, as [`BlockMeta::encode_block_meta`] writes them, has
+/// `| Last Key Magic (u32) |` right before the meta block offset, after all of those; the
+/// metas of the tables written before have no last key. A table with more blocks than fit in a
+/// slice of its [`IndexBlock`] has the index block between the meta blocks and the prefix filter,
+/// and `| Index Block Offset (u32) | Index Block Magic (u32) |` right before the meta block
+/// offset, after the last key magic. A table built with [`SsTableBuilder::with_max_seq`] ends its
+/// Extra with `| Max Sequence (u64) | Max Sequence Magic (u32) |`, the commit sequence of the
+/// latest write it holds; the tables without one count as older than every write. Only the
+/// index block is decoded at open, although the whole meta section is read through once for its
+/// checksum.
 pub struct SsTable {
     id: usize,
     /// The actual storage unit of SsTable, the format is as above.
     file: FileObject,
     /// The meta blocks that hold info for data blocks.
-    block_metas: Vec<BlockMeta>,
-    /// The offset that indicates the start point of meta blocks in `file`.
-    block_meta_offset: usize,
+    index: TableIndex,
+    /// Where the data blocks end in `file`: at the key filter if there is one, else at the meta
+    /// blocks.
+    data_end: usize,
+    key_filter: Option<BloomFilter>,
+    prefix_filter: Option<PrefixFilter>,
+    /// Every value ends with the checksum of its entry.
+    entry_checksums: bool,
+    /// Every block starts with its compression tag.
+    compressed: bool,
+    properties: Option<TableProperties>,
+    /// The commit sequence of the latest write the table holds, 0 if it was written before the
+    /// tables recorded it.
+    max_seq: u64,
 
     cache: Option<Arc<BlockCache>>,
+    /// The tier `read_block_cached` looks in after `cache`, before the disk.
+    compressed_cache: Option<Arc<CompressedBlockCache>>,
+    /// The level of the table, and the metrics its reads count towards.
+    level_io: Option<(usize, Arc<Metrics>)>,
+    /// Where the reads of the table have been going, see [`ReadaheadState`].
+    readahead: Mutex<ReadaheadState>,
+    retire_on_drop: RetireOnDrop,
 }
 
 impl SsTable {
     #[cfg(test)]
     pub(crate) fn open_for_test(file: FileObject) -> Result<Self> {
-        Self::open(0, Some(Arc::new(moka::sync::Cache::new(128))), file)
+        Self::open(0, Some(Arc::new(BlockCache::new(128))), file)
     }
 
     /// Open SSTable from a file.
     pub fn open(id: usize, block_cache: Option<Arc<BlockCache>>, file: FileObject) -> Result<Self> {
-        let tail = file.read(file.size() - 4, 4)?;
-        let start = u32::from_le_bytes(tail.as_slice().try_into().unwrap()) as u64;
-        let buf = file.read(start, file.size() - 4 - start)?;
+        let tail_len = file.size().min(FOOTER_LEN as u64);
+        let tail = file.read(file.size() - tail_len, tail_len)?;
+        let footer = decode_footer(id, &tail, file.size() - tail_len)?;
+        let (start, footer_len) = (footer.meta_offset, footer.len);
+        if start > file.size() - footer_len {
+            anyhow::bail!(
+                "sst {} has block meta offset {} beyond its size {}",
+                id,
+                start,
+                file.size()
+            );
+        }
+        if let Some(expected) = footer.meta_checksum {
+            let mut hasher = crc32fast::Hasher::new();
+            let mut pos = start;
+            while pos < file.size() - footer_len {
+                let len = META_CHECK_CHUNK.min(file.size() - footer_len - pos);
+                hasher.update(&file.read(pos, len)?);
+                pos += len;
+            }
+            let actual = hasher.finalize();
+            if actual != expected {
+                return Err(LsmError::ChecksumMismatch {
+                    what: format!("sst {} has a corrupt meta section", id),
+                    offset: start,
+                    expected,
+                    actual,
+                }
+                .into());
+            }
+        }
+        // the Extra trailers, read back to front
+        let mut end = file.size() - footer_len;
+        let read_u32 = |pos: u64| -> Result<u32> {
+            Ok(u32::from_le_bytes(file.read(pos, 4)?.try_into().unwrap()))
+        };
+        let max_seq = if end >= start + 12 && read_u32(end - 4)? == MAX_SEQ_MAGIC {
+            end -= 12;
+            u64::from_le_bytes(file.read(end, 8)?.try_into().unwrap())
+        } else {
+            0
+        };
+        let index_offset = if end >= start + 8 && read_u32(end - 4)? == INDEX_BLOCK_MAGIC {
+            let offset = read_u32(end - 8)? as u64;
+            end -= 8;
+            anyhow::ensure!(
+                (start..=end).contains(&offset),
+                "sst {} has index block offset {} outside of its meta, which starts at {}",
+                id,
+                offset,
+                start
+            );
+            Some(offset)
+        } else {
+            None
+        };
+        let last_keys = end >= start + 4 && read_u32(end - 4)? == LAST_KEY_MAGIC;
+        if last_keys {
+            end -= 4;
+        }
+        let compressed = end >= start + 4 && read_u32(end - 4)? == COMPRESSION_MAGIC;
+        if compressed {
+            end -= 4;
+        }
+        let entry_checksums = end >= start + 4 && read_u32(end - 4)? == ENTRY_CHECKSUM_MAGIC;
+        if entry_checksums {
+            end -= 4;
+        }
+        let properties = if end >= start + 12 && read_u32(end - 4)? == PROPERTIES_MAGIC {
+            let properties = TableProperties {
+                num_entries: read_u32(end - 12)? as u64,
+                num_tombstones: read_u32(end - 8)? as u64,
+            };
+            end -= 12;
+            anyhow::ensure!(
+                properties.num_tombstones <= properties.num_entries,
+                "sst {} has more tombstones ({}) than entries ({})",
+                id,
+                properties.num_tombstones,
+                properties.num_entries
+            );
+            Some(properties)
+        } else {
+            None
+        };
+        let key_filter_offset = if end >= start + 8 && read_u32(end - 4)? == KEY_FILTER_MAGIC {
+            let offset = read_u32(end - 8)? as u64;
+            end -= 8;
+            anyhow::ensure!(
+                offset <= start,
+                "sst {} has key filter offset {} past its meta, which starts at {}",
+                id,
+                offset,
+                start
+            );
+            Some(offset)
+        } else {
+            None
+        };
+        let filter_offset = if end >= start + 8 && read_u32(end - 4)? == PREFIX_FILTER_MAGIC {
+            let offset = read_u32(end - 8)? as u64;
+            end -= 8;
+            anyhow::ensure!(
+                (start..=end).contains(&offset),
+                "sst {} has prefix filter offset {} outside of its meta, which starts at {}",
+                id,
+                offset,
+                start
+            );
+            Some(offset)
+        } else {
+            None
+        };
+
+        // the metas, then the index block, then the prefix filter, each bounding the one before
+        let filter_start = filter_offset.unwrap_or(end);
+        let prefix_filter = match filter_offset {
+            Some(filter_offset) => Some(PrefixFilter::decode(
+                &file.read(filter_offset, end - filter_offset)?,
+            )?),
+            None => None,
+        };
+        let index = match index_offset {
+            Some(index_offset) => {
+                anyhow::ensure!(
+                    index_offset <= filter_start,
+                    "sst {} has index block offset {} past its prefix filter at {}",
+                    id,
+                    index_offset,
+                    filter_start
+                );
+                let block =
+                    IndexBlock::decode(&file.read(index_offset, filter_start - index_offset)?)?;
+                anyhow::ensure!(
+                    start + block.metas_len() as u64 <= index_offset,
+                    "sst {} has block metas of {} bytes from offset {}, past its index block at {}",
+                    id,
+                    block.metas_len(),
+                    start,
+                    index_offset
+                );
+                TableIndex::sliced(block, start)
+            }
+            None => {
+                let buf = file.read(start, filter_start - start)?;
+                TableIndex::Full(FencedIndex::decode(&buf, last_keys))
+            }
+        };
+        let key_filter = match key_filter_offset {
+            Some(offset) => Some(BloomFilter::decode(&file.read(offset, start - offset)?)?),
+            None => None,
+        };
 
         Ok(Self {
             id,
             file,
-            block_metas: BlockMeta::decode_block_meta(buf.as_slice()),
-            block_meta_offset: start as usize,
+            index,
+            data_end: key_filter_offset.unwrap_or(start) as usize,
+            key_filter,
+            prefix_filter,
+            entry_checksums,
+            compressed,
+            properties,
+            max_seq,
             cache: block_cache,
+            compressed_cache: None,
+            level_io: None,
+            readahead: Mutex::default(),
+            retire_on_drop: RetireOnDrop::default(),
         })
     }
 
+    /// Look the blocks missing from the block cache up in `compressed_cache` before the disk.
+    /// Only used along with a block cache.
+    pub fn with_compressed_cache(
+        self,
+        compressed_cache: Option<Arc<CompressedBlockCache>>,
+    ) -> Self {
+        Self {
+            compressed_cache,
+            ..self
+        }
+    }
+
+    /// Count the blocks read from the table towards `level` in `metrics`, see
+    /// [`Metrics::level_io`].
+    pub fn with_level_io(self, level: usize, metrics: Arc<Metrics>) -> Self {
+        Self {
+            level_io: Some((level, metrics)),
+            ..self
+        }
+    }
+
+    /// The level of the table, if it was given one by `with_level_io`.
+    pub fn level(&self) -> Option<usize> {
+        self.level_io.as_ref().map(|(level, _)| *level)
+    }
+
+    /// Count a read of block `block_idx` by `kind` towards the level of the table, if it has one.
+    /// A point read also goes into the readahead hint of the table, which it resets unless it
+    /// reads the block a scan would have read next.
+    pub(crate) fn record_read(&self, kind: ReadKind, block_idx: usize) {
+        if kind == ReadKind::Get {
+            let mut hint = self.readahead.lock();
+            hint.access(block_idx, 1);
+            hint.read(block_idx, 1);
+        }
+        if let Some((level, metrics)) = &self.level_io {
+            if let (Ok(lo), Ok(hi)) = (self.block_offset(block_idx), self.block_end(block_idx)) {
+                metrics.record_read(kind, *level, hi.saturating_sub(lo) as u64);
+            }
+        }
+    }
+
     /// Read a block from the disk.
     pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
-        let lo = self.block_metas[block_idx].offset as u64;
-        let hi = match self.block_metas.get(block_idx + 1) {
-            Some(&BlockMeta { offset, .. }) => offset,
-            None => self.block_meta_offset,
-        } as u64;
+        self.decode_block(&self.read_encoded_block(block_idx)?)
+            .map_err(|err| match err.downcast::<LsmError>() {
+                // the block does not know where it was read from
+                Ok(LsmError::ChecksumMismatch {
+                    expected, actual, ..
+                }) => LsmError::ChecksumMismatch {
+                    what: format!("sst {} has a corrupt block {}", self.id, block_idx),
+                    offset: self.block_offset(block_idx).unwrap_or_default() as u64,
+                    expected,
+                    actual,
+                }
+                .into(),
+                Ok(err) => err.into(),
+                Err(err) => err,
+            })
+    }
+
+    /// Read a block from the disk as it is stored.
+    fn read_encoded_block(&self, block_idx: usize) -> Result<Vec<u8>> {
+        let lo = self.block_offset(block_idx)? as u64;
+        let hi = self.block_end(block_idx)? as u64;
+        anyhow::ensure!(
+            lo <= hi,
+            "{}: block {} ends at offset {} before it starts at {}",
+            self.file.path().display(),
+            block_idx,
+            hi,
+            lo
+        );
+        self.file.read(lo, hi - lo)
+    }
 
-        Ok(Arc::new(Block::decode(&self.file.read(lo, hi - lo)?)))
+    fn decode_block(&self, data: &[u8]) -> Result<Arc<Block>> {
+        let block = match self.compressed {
+            true => Block::decode_compressed(data)?,
+            false => Block::decode(data)?,
+        };
+        Ok(Arc::new(block.with_entry_checksums(self.entry_checksums)))
     }
 
     /// Read a block from disk, with block cache. (Day 4)
     pub fn read_block_cached(&self, block_idx: usize) -> Result<Arc<Block>> {
-        match &self.cache {
-            Some(cache) => cache
-                .try_get_with((self.id, block_idx), || self.read_block(block_idx))
-                .map_err(|err| anyhow::anyhow!(err)),
+        match (&self.cache, &self.compressed_cache) {
+            (Some(cache), Some(tier)) => self.read_block_tiered(cache, tier, block_idx, true),
+            (Some(cache), None) => cache.get_or_try_insert_with((self.id, block_idx), || {
+                #[cfg(test)]
+                CACHE_INSERTS.with(|inserts| inserts.set(inserts.get() + 1));
+                self.read_block(block_idx)
+            }),
             _ => self.read_block(block_idx),
         }
     }
 
-    pub fn __find_block_idx(&self, key: &[u8]) -> Result<usize, usize> {
-        self.block_metas
-            .binary_search_by(|meta| meta.first_key.as_ref().cmp(key))
-            .map_err(|insert| {
-                if insert == self.num_of_blocks() {
-                    return max(0, insert as isize - 1) as usize;
+    /// Read a block the way `options` asks for: give up once the deadline has passed, and only
+    /// add a block read from disk to the cache when `fill_cache` is set.
+    pub fn read_block_with(&self, block_idx: usize, options: &ReadOptions) -> Result<Arc<Block>> {
+        options.check_deadline()?;
+        match (&self.cache, &self.compressed_cache) {
+            (Some(cache), Some(tier)) if !options.fill_cache => {
+                self.read_block_tiered(cache, tier, block_idx, false)
+            }
+            (Some(cache), None) if !options.fill_cache => match cache.get(&(self.id, block_idx)) {
+                Some(block) => Ok(block),
+                None => self.read_block(block_idx),
+            },
+            _ => self.read_block_cached(block_idx),
+        }
+    }
+
+    /// Read a block for a scan, like [`read_block_with`](Self::read_block_with), except that a
+    /// block read from disk goes to `fill`, which adds it to the cache later, or not at all.
+    /// Only the block cache alone is filled this way; with a compressed tier, the block goes
+    /// through [`read_block_with`](Self::read_block_with).
+    pub fn read_block_staged(
+        &self,
+        block_idx: usize,
+        options: &ReadOptions,
+        fill: &ScanCacheFill,
+    ) -> Result<Arc<Block>> {
+        match (&self.cache, &self.compressed_cache) {
+            (Some(cache), None) if options.fill_cache => {
+                options.check_deadline()?;
+                let key = (self.id, block_idx);
+                if let Some(block) = cache.get(&key) {
+                    return Ok(block);
                 }
+                let block = self.read_block(block_idx)?;
+                fill.stage(cache, key, block.clone());
+                Ok(block)
+            }
+            _ => self.read_block_with(block_idx, options),
+        }
+    }
 
-                if self.block_metas[insert].first_key.as_ref() > key {
-                    let last = self
-                        .read_block_cached(max(0, insert as isize - 1) as _)
-                        .ok()
-                        .map(|x| x.last() >= Some(key));
-
-                    if last == Some(true) {
-                        return max(0, insert as isize - 1) as usize;
-                    } else {
-                        return insert;
-                    }
+    /// Read blocks from `first` on for a scan reading the table in order: at most `max` of them,
+    /// from disk in one read, stopping before the first one the block cache holds. They go to
+    /// the cache the way [`read_block_staged`](Self::read_block_staged) sends them, or the way
+    /// [`read_block_with`](Self::read_block_with) does without `fill`. A block the cache holds
+    /// comes back alone, and a table with a compressed tier reads a block at a time, like they
+    /// do.
+    pub(crate) fn read_blocks_ahead(
+        &self,
+        first: usize,
+        max: usize,
+        options: &ReadOptions,
+        fill: Option<&ScanCacheFill>,
+    ) -> Result<Vec<Arc<Block>>> {
+        let cached = |block_idx| match &self.cache {
+            Some(cache) => cache.contains_key(&(self.id, block_idx)),
+            None => false,
+        };
+        if max <= 1 || self.compressed_cache.is_some() {
+            let block = match fill {
+                Some(fill) => self.read_block_staged(first, options, fill),
+                None => self.read_block_with(first, options),
+            }?;
+            return Ok(vec![block]);
+        }
+        options.check_deadline()?;
+        if let Some(block) = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.get(&(self.id, first)))
+        {
+            return Ok(vec![block]);
+        }
+        let last = (first + max).min(self.num_of_blocks());
+        let end = (first + 1..last)
+            .find(|&block_idx| cached(block_idx))
+            .unwrap_or(last);
+
+        let lo = self.block_offset(first)?;
+        let hi = self.block_end(end - 1)?;
+        anyhow::ensure!(
+            lo <= hi,
+            "{}: blocks {} to {} end at offset {} before they start at {}",
+            self.file.path().display(),
+            first,
+            end - 1,
+            hi,
+            lo
+        );
+        let data = self.file.read(lo as u64, (hi - lo) as u64)?;
+        let mut blocks = Vec::with_capacity(end - first);
+        for block_idx in first..end {
+            let (start, stop) = (self.block_offset(block_idx)?, self.block_end(block_idx)?);
+            anyhow::ensure!(
+                lo <= start && start <= stop && stop <= hi,
+                "{}: block {} spans offsets {} to {}, outside blocks {} to {}",
+                self.file.path().display(),
+                block_idx,
+                start,
+                stop,
+                first,
+                end - 1
+            );
+            let block = self.decode_block(&data[start - lo..stop - lo])?;
+            match (&self.cache, fill) {
+                (Some(cache), Some(fill)) if options.fill_cache => {
+                    fill.stage(cache, (self.id, block_idx), block.clone())
                 }
-                return insert;
-            })
+                (Some(cache), None) if options.fill_cache => {
+                    #[cfg(test)]
+                    CACHE_INSERTS.with(|inserts| inserts.set(inserts.get() + 1));
+                    cache.insert((self.id, block_idx), block.clone());
+                }
+                _ => {}
+            }
+            blocks.push(block);
+        }
+        Ok(blocks)
+    }
+
+    /// The readahead hint of the table, for an iterator to start from.
+    pub(crate) fn readahead_hint(&self) -> ReadaheadState {
+        *self.readahead.lock()
+    }
+
+    /// Leave `state` as the readahead hint of the table, after a read of an iterator.
+    pub(crate) fn set_readahead_hint(&self, state: ReadaheadState) {
+        *self.readahead.lock() = state;
+    }
+
+    /// Look a block up in `cache`, then in `tier`, then on disk. With `fill`, a block found in
+    /// `tier` moves up to `cache`, and one read from disk goes into the tiers the admission
+    /// policy of `tier` says.
+    fn read_block_tiered(
+        &self,
+        cache: &BlockCache,
+        tier: &CompressedBlockCache,
+        block_idx: usize,
+        fill: bool,
+    ) -> Result<Arc<Block>> {
+        let key = (self.id, block_idx);
+        if let Some(block) = cache.get(&key) {
+            tier.record_hit();
+            return Ok(block);
+        }
+        if let Some(data) = tier.get(&key) {
+            tier.record_compressed_hit();
+            let block = self.decode_block(&data)?;
+            if fill {
+                cache.insert(key, block.clone());
+            }
+            return Ok(block);
+        }
+        let data = Bytes::from(self.read_encoded_block(block_idx)?);
+        tier.record_disk_read();
+        let block = self.decode_block(&data)?;
+        if fill {
+            tier.insert(key, data);
+            if tier.admission() == CacheAdmission::Both {
+                cache.insert(key, block.clone());
+            }
+        }
+        Ok(block)
+    }
+
+    /// Move the file of the table to `path`, e.g. from where it was written into place.
+    pub(crate) fn rename_file(&mut self, path: &Path) -> Result<()> {
+        self.file.rename(path)
+    }
+
+    /// Drop the blocks of the table from the caches, for a table whose data moved elsewhere.
+    pub(crate) fn invalidate_cached_blocks(&self) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate_table(self.id, self.num_of_blocks());
+        }
+        if let Some(tier) = &self.compressed_cache {
+            for block_idx in 0..self.num_of_blocks() {
+                tier.invalidate(&(self.id, block_idx));
+            }
+        }
+    }
+
+    /// Check that the blocks the index lists are in order and tile the data section, which
+    /// `open` takes for granted.
+    pub fn verify_index(&self) -> Result<()> {
+        let mut expected = 0;
+        for block_idx in 0..self.num_of_blocks() {
+            let offset = self.block_offset(block_idx)?;
+            let end = self.block_end(block_idx)?;
+            anyhow::ensure!(
+                offset == expected && offset < end,
+                "sst {} has block {} at offset {}, expected a non-empty block at {}",
+                self.id,
+                block_idx,
+                offset,
+                expected
+            );
+            expected = end;
+        }
+        anyhow::ensure!(
+            expected == self.data_end,
+            "sst {} has blocks up to offset {} but its data ends at {}",
+            self.id,
+            expected,
+            self.data_end
+        );
+        Ok(())
+    }
+
+    /// Read block `block_idx` from disk, bypassing the cache, and check that every entry lies
+    /// within it and, in a table with entry checksums, matches its checksum.
+    pub fn verify_block(&self, block_idx: usize) -> Result<()> {
+        let block = self.read_block(block_idx)?;
+        for entry_idx in 0..block.num_entries() {
+            let (key, value) = block.entry(entry_idx).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "sst {} has entry {} of block {} outside of the block",
+                    self.id,
+                    entry_idx,
+                    block_idx
+                )
+            })?;
+            if self.entry_checksums {
+                strip_entry_checksum(&key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The metas of the slice of the index holding block `block_idx`, and the index of the
+    /// first block of the slice.
+    fn meta_slice(&self, block_idx: usize) -> Result<(SliceRef<'_>, usize)> {
+        self.index
+            .slice_of(block_idx, |offset, len| self.file.read(offset, len))
+    }
+
+    /// Where block `block_idx` starts in the file.
+    fn block_offset(&self, block_idx: usize) -> Result<usize> {
+        let (slice, base) = self.meta_slice(block_idx)?;
+        Ok(slice.offset(block_idx - base))
+    }
+
+    /// Where block `block_idx` ends in the file.
+    fn block_end(&self, block_idx: usize) -> Result<usize> {
+        if block_idx + 1 < self.index.len() {
+            self.block_offset(block_idx + 1)
+        } else {
+            Ok(self.data_end)
+        }
     }
 
-    /// Find the block that may contain `key`.
-    /// Note: You may want to make use of the `first_key` stored in `BlockMeta`.
-    /// You may also assume the key-value pairs stored in each consecutive block are sorted.
+    /// The block a lookup or a seek of `key` starts at: the first one whose last key is not
+    /// before `key`, which is the only block that may hold `key` and the one holding the first
+    /// entry at or after it. At the boundaries:
+    ///
+    /// - a key equal to the first key of a block: that block, unless the block before it ends
+    ///   with the key as well, as a key repeated across the boundary does;
+    /// - a key past the last key of a block and before the first key of the next one: the next
+    ///   block;
+    /// - a key before the first key of the table: the first block;
+    /// - a key past the last key of the table: the last block, in which reads find nothing at or
+    ///   after the key.
     pub fn find_block_idx(&self, key: &[u8]) -> usize {
-        self.__find_block_idx(key)
-            .unwrap_or_else(std::convert::identity)
+        let first = self.index.search_slices(key);
+        let (slice, base) = match self.meta_slice(first) {
+            Ok(found) => found,
+            // the read of the block fails in turn
+            Err(_) => return first,
+        };
+        // the first block whose first key is not before the key; the blocks before the one
+        // before it all end before the key
+        let (idx, exact) = match slice.search(key) {
+            Ok(idx) => (base + idx, true),
+            Err(idx) => (base + idx, false),
+        };
+        let prev = match idx.checked_sub(1) {
+            Some(prev) => prev,
+            None => return idx,
+        };
+        if idx == self.num_of_blocks() {
+            return prev;
+        }
+        let reaches_key = |slice: &FencedIndex, base: usize| match slice.last_key(prev - base) {
+            Some(last) => last >= key,
+            // the table was written before the metas held the last key of the block, and before
+            // a key could repeat across a block boundary
+            None if exact => false,
+            // the metas of the table do not hold it, the block does
+            None => match self.read_block_cached(prev) {
+                Ok(block) => block.last().as_deref() >= Some(key),
+                Err(_) => false,
+            },
+        };
+        let reaches_key = if prev >= base {
+            reaches_key(&slice, base)
+        } else {
+            // the block before the first one of the slice, in the slice before
+            match self.meta_slice(prev) {
+                Ok((slice, base)) => reaches_key(&slice, base),
+                Err(_) => false,
+            }
+        };
+        if reaches_key {
+            prev
+        } else {
+            idx
+        }
+    }
+
+    /// Whether the key range of the table overlaps the bounds, which only takes its index to
+    /// tell. A table written before the block metas held the last key of their block is taken to
+    /// run up to the upper bound.
+    pub fn overlaps(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> bool {
+        let first_key = match self.first_key() {
+            Some(first_key) => first_key,
+            None => return false,
+        };
+        let below_upper = match upper {
+            Bound::Included(upper) => first_key <= upper,
+            Bound::Excluded(upper) => first_key < upper,
+            Bound::Unbounded => true,
+        };
+        let above_lower = match (self.last_key(), lower) {
+            (Some(last_key), Bound::Included(lower)) => last_key >= lower,
+            (Some(last_key), Bound::Excluded(lower)) => last_key > lower,
+            _ => true,
+        };
+        below_upper && above_lower
     }
 
     /// Get number of data blocks.
     pub fn num_of_blocks(&self) -> usize {
-        self.block_metas.len()
+        self.index.len()
+    }
+
+    /// The block metas, built from the index on every call. A table with an [`IndexBlock`]
+    /// decodes the slices of its metas it does not keep.
+    pub fn block_metas(&self) -> Result<Vec<BlockMeta>> {
+        let mut metas = Vec::with_capacity(self.num_of_blocks());
+        while metas.len() < self.num_of_blocks() {
+            let (slice, _) = self.meta_slice(metas.len())?;
+            metas.extend(slice.to_metas());
+        }
+        Ok(metas)
+    }
+
+    pub fn index(&self) -> &TableIndex {
+        &self.index
+    }
+
+    /// Whether the values of the table end with their entry checksum.
+    /// The counts recorded when the table was built, if it was built with them.
+    pub fn properties(&self) -> Option<TableProperties> {
+        self.properties
+    }
+
+    pub fn has_entry_checksums(&self) -> bool {
+        self.entry_checksums
+    }
+
+    pub fn sst_id(&self) -> usize {
+        self.id
+    }
+
+    /// The commit sequence of the latest write the table holds, 0 for a table written before the
+    /// tables recorded it.
+    pub fn max_seq(&self) -> u64 {
+        self.max_seq
     }
-}
 
-pub fn is_true(x: bool) -> bool {
-    x
+    /// Mark the file of the table obsolete in `retention`, the retention of the database at
+    /// `dir`, once the last reference to the table is dropped, e.g. after a compaction replaced
+    /// it. Readers still holding the table read on from the file meanwhile.
+    pub fn retire(&self, retention: Arc<FileRetention>, dir: &Path) {
+        let path = self.file.path().to_path_buf();
+        self.retire_on_drop
+            .retire(retention, dir, FileId::Sst(self.id), path);
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.file.size()
+    }
+
+    /// Whether the table may hold `key`. Without a key filter, the table may hold anything.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        match &self.key_filter {
+            Some(filter) => {
+                #[cfg(test)]
+                KEY_FILTER_PROBES.with(|probes| probes.borrow_mut().push(self.id));
+                filter.may_contain(BloomFilter::hash(key))
+            }
+            None => true,
+        }
+    }
+
+    /// Whether the table may hold a key whose prefix is `prefix`, as picked by `extractor`.
+    /// Without a prefix filter built by the same extractor, the table may hold anything.
+    pub fn may_contain_prefix(&self, extractor: &PrefixExtractor, prefix: &[u8]) -> bool {
+        match &self.prefix_filter {
+            Some(filter) if filter.extractor == extractor.name() => {
+                filter.bloom.may_contain(BloomFilter::hash(prefix))
+            }
+            _ => true,
+        }
+    }
+
+    /// The first key of the table, from its index, or `None` if it has no block.
+    pub fn first_key(&self) -> Option<&[u8]> {
+        self.index.first_key()
+    }
+
+    /// The last key of the table, from its index, or `None` if it has no block or was written
+    /// before the block metas held the last key of their block. See `key_range` for one that
+    /// reads it from the last block of such a table.
+    pub fn last_key(&self) -> Option<&[u8]> {
+        self.index.last_key()
+    }
+
+    /// The first and the last key of the table, or `None` if it is empty. Only the tables
+    /// without the last key in their index read their last block for it.
+    pub fn key_range(&self) -> Result<Option<(Bytes, Bytes)>> {
+        let first = match self.index.first_key_bytes() {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+        if let Some(last) = self.last_key() {
+            return Ok(Some((first, Bytes::copy_from_slice(last))));
+        }
+        let block = self.read_block_cached(self.num_of_blocks() - 1)?;
+        let last = block.last().map_or_else(|| first.clone(), Bytes::from);
+        Ok(Some((first, last)))
+    }
+
+    /// Size of the data blocks whose first key is within one of the inclusive `ranges`.
+    pub fn data_bytes_within(&self, ranges: &[(Bytes, Bytes)]) -> Result<u64> {
+        let metas = self.block_metas()?;
+        let end = |idx: usize| metas.get(idx + 1).map_or(self.data_end, |meta| meta.offset);
+        Ok((0..metas.len())
+            .filter(|&idx| {
+                let first_key = &metas[idx].first_key[..];
+                ranges
+                    .iter()
+                    .any(|(lower, upper)| &lower[..] <= first_key && first_key <= &upper[..])
+            })
+            .map(|idx| (end(idx) - metas[idx].offset) as u64)
+            .sum())
+    }
 }
 
 #[cfg(test)]
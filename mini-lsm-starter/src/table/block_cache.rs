@@ -0,0 +1,168 @@
+//! The cache of decoded blocks the tables of a database share, keyed by the SST id and the index
+//! of the block. It wraps a moka cache, which stays out of the public API.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use moka::sync::ConcurrentCacheExt;
+
+use crate::block::Block;
+use crate::error::LsmError;
+
+/// How a [`BlockCache`] has been used since it was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups that found the block.
+    pub hits: u64,
+    /// Lookups that did not.
+    pub misses: u64,
+    /// Blocks held.
+    pub entry_count: u64,
+    /// Blocks it holds at most, see [`BlockCache::resize`].
+    pub capacity: u64,
+}
+
+/// A cached block and the lookups that found it.
+#[derive(Clone)]
+struct CachedBlock {
+    block: Arc<Block>,
+    hits: Arc<AtomicU64>,
+}
+
+impl CachedBlock {
+    fn new(block: Arc<Block>) -> Self {
+        Self {
+            block,
+            hits: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+/// Decoded blocks, up to a number of them. See
+/// [`LsmStorageOptions::block_cache_capacity`](crate::lsm_storage::LsmStorageOptions::block_cache_capacity).
+pub struct BlockCache {
+    blocks: moka::sync::Cache<(usize, usize), CachedBlock>,
+    capacity: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockCache {
+    /// A cache of up to `capacity` blocks.
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            blocks: moka::sync::Cache::new(capacity),
+            capacity: AtomicU64::new(capacity),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The block `key` names, if cached.
+    pub fn get(&self, key: &(usize, usize)) -> Option<Arc<Block>> {
+        let cached = self.blocks.get(key);
+        self.record_lookup(cached.as_ref());
+        cached.map(|cached| cached.block)
+    }
+
+    /// The block `key` names, from the cache, or from `init`, which is added to the cache when it
+    /// succeeds. Concurrent callers for the same key wait for one `init`. An [`LsmError`] from
+    /// `init` comes back as it is, for the caller to match.
+    pub fn get_or_try_insert_with(
+        &self,
+        key: (usize, usize),
+        init: impl FnOnce() -> Result<Arc<Block>>,
+    ) -> Result<Arc<Block>> {
+        let mut missed = false;
+        let cached = self.blocks.try_get_with(key, || {
+            missed = true;
+            init().map(CachedBlock::new)
+        });
+        self.record_lookup(cached.as_ref().ok().filter(|_| !missed));
+        cached
+            .map(|cached| cached.block)
+            .map_err(|err| match err.downcast_ref::<LsmError>() {
+                Some(err) => err.clone().into(),
+                None => anyhow::anyhow!(err),
+            })
+    }
+
+    pub fn insert(&self, key: (usize, usize), block: Arc<Block>) {
+        self.blocks.insert(key, CachedBlock::new(block))
+    }
+
+    pub fn contains_key(&self, key: &(usize, usize)) -> bool {
+        self.blocks.contains_key(key)
+    }
+
+    pub fn invalidate(&self, key: &(usize, usize)) {
+        self.blocks.invalidate(key)
+    }
+
+    /// Drop the first `num_blocks` blocks of SST `sst_id`, e.g. for a table that replaces it
+    /// under the same id.
+    pub fn invalidate_table(&self, sst_id: usize, num_blocks: usize) {
+        for block_idx in 0..num_blocks {
+            self.blocks.invalidate(&(sst_id, block_idx));
+        }
+    }
+
+    pub fn invalidate_all(&self) {
+        self.blocks.invalidate_all()
+    }
+
+    /// Number of blocks held, once the insertions and evictions moka has pending are applied.
+    pub fn entry_count(&self) -> u64 {
+        self.blocks.sync();
+        self.blocks.entry_count()
+    }
+
+    /// Hold at most `capacity` blocks from now on, evicting blocks until it does. It never holds
+    /// more than the capacity it was created with.
+    pub fn resize(&self, capacity: u64) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+        let excess = self.entry_count().saturating_sub(capacity);
+        for (key, _) in self.blocks.iter().take(excess as usize) {
+            self.blocks.invalidate(&key);
+        }
+    }
+
+    /// Up to `limit` of the blocks held, the ones lookups found most often since they were
+    /// cached first. The blocks no lookup found since they were cached are left out.
+    pub fn hottest(&self, limit: usize) -> Vec<Arc<Block>> {
+        let mut blocks = self
+            .blocks
+            .iter()
+            .map(|(_, cached)| (cached.hits.load(Ordering::Relaxed), cached.block))
+            .filter(|(hits, _)| *hits > 0)
+            .collect::<Vec<_>>();
+        blocks.sort_by_key(|(hits, _)| std::cmp::Reverse(*hits));
+        blocks
+            .into_iter()
+            .take(limit)
+            .map(|(_, block)| block)
+            .collect()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entry_count: self.entry_count(),
+            capacity: self.capacity.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Count a lookup, which found `cached` if any.
+    fn record_lookup(&self, cached: Option<&CachedBlock>) {
+        let counter = match cached {
+            Some(cached) => {
+                cached.hits.fetch_add(1, Ordering::Relaxed);
+                &self.hits
+            }
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,103 @@
+use anyhow::Result;
+use bytes::{Buf, BufMut, Bytes};
+
+/// A Bloom filter over 32-bit hashes, probed by double hashing.
+///
+/// | bits | k (u8) |
+#[derive(Clone, Debug)]
+pub struct BloomFilter {
+    bits: Bytes,
+    k: u8,
+}
+
+impl BloomFilter {
+    /// FNV-1a, the hash the filters are built from.
+    pub fn hash(key: &[u8]) -> u32 {
+        key.iter().fold(0x811c_9dc5, |hash: u32, &byte| {
+            (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+        })
+    }
+
+    /// Bits per key for a false-positive rate of `fpr`.
+    pub fn bits_per_key(fpr: f64) -> usize {
+        (-fpr.ln() / (std::f64::consts::LN_2 * std::f64::consts::LN_2)).ceil() as usize
+    }
+
+    /// Build a filter holding `hashes`, with about `bits_per_key` bits for each of them.
+    pub fn build(hashes: &[u32], bits_per_key: usize) -> Self {
+        // ln(2) * bits per key probes give the lowest false-positive rate
+        let k = (bits_per_key as f64 * 0.69).clamp(1.0, 30.0) as u8;
+        let nbits = (hashes.len() * bits_per_key).max(64);
+        let nbytes = (nbits + 7) / 8;
+        let nbits = nbytes * 8;
+
+        let mut bits = vec![0u8; nbytes];
+        for &hash in hashes {
+            for bit in Self::probes(hash, k, nbits) {
+                bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        Self {
+            bits: bits.into(),
+            k,
+        }
+    }
+
+    pub fn may_contain(&self, hash: u32) -> bool {
+        let nbits = self.bits.len() * 8;
+        if nbits == 0 {
+            return true;
+        }
+        Self::probes(hash, self.k, nbits).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn probes(hash: u32, k: u8, nbits: usize) -> impl Iterator<Item = usize> {
+        let delta = hash.rotate_left(15);
+        (0..k as u32).map(move |i| hash.wrapping_add(i.wrapping_mul(delta)) as usize % nbits)
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.bits);
+        buf.put_u8(self.k);
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let (&k, bits) = buf
+            .split_last()
+            .ok_or_else(|| anyhow::anyhow!("bloom filter is empty"))?;
+        anyhow::ensure!((1..=30).contains(&k), "bloom filter has {} probes", k);
+        Ok(Self {
+            bits: Bytes::copy_from_slice(bits),
+            k,
+        })
+    }
+}
+
+/// The prefix filter of an SST, along with the name of the extractor that built it. A table
+/// read with another extractor does not use the filter.
+///
+/// | name len (u16) | name | bloom filter |
+#[derive(Clone, Debug)]
+pub struct PrefixFilter {
+    pub extractor: String,
+    pub bloom: BloomFilter,
+}
+
+impl PrefixFilter {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u16_le(self.extractor.len() as _);
+        buf.extend_from_slice(self.extractor.as_bytes());
+        self.bloom.encode(buf);
+    }
+
+    pub fn decode(mut buf: &[u8]) -> Result<Self> {
+        anyhow::ensure!(buf.remaining() >= 2, "prefix filter is truncated");
+        let len = buf.get_u16_le() as usize;
+        anyhow::ensure!(buf.remaining() >= len, "prefix filter is truncated");
+        let extractor = String::from_utf8(buf[..len].to_vec())?;
+        Ok(Self {
+            extractor,
+            bloom: BloomFilter::decode(&buf[len..])?,
+        })
+    }
+}
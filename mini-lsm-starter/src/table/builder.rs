@@ -2,20 +2,66 @@ use std::path::Path;
 use std::sync::Arc;
 
 use anyhow::Result;
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 
-use super::{Block, BlockMeta, FileObject, SsTable};
-use crate::block::BlockBuilder;
-use crate::lsm_storage::BlockCache;
+use super::bloom::{BloomFilter, PrefixFilter};
+use super::index_block::{IndexBlock, TableIndex, DEFAULT_INDEX_SLICE_LEN};
+use super::{
+    encode_footer, Block, BlockMeta, FencedIndex, FileObject, SsTable, TableProperties,
+    COMPRESSION_MAGIC, ENTRY_CHECKSUM_MAGIC, INDEX_BLOCK_MAGIC, KEY_FILTER_MAGIC, LAST_KEY_MAGIC,
+    MAX_SEQ_MAGIC, PREFIX_FILTER_MAGIC, PROPERTIES_MAGIC,
+};
+use crate::block::{BlockBuilder, EncodeScratch};
+use crate::compression::CompressionType;
+use crate::lsm_storage::{BlockCache, PrefixExtractor};
+use crate::retention::RetireOnDrop;
+
+/// Bits of prefix filter per distinct prefix, for about 1% false positives.
+const PREFIX_BITS_PER_KEY: usize = 10;
+
+/// False-positive rate of the key filter, unless `with_bloom_fpr` or `with_bloom_bits_per_key`
+/// say otherwise.
+const DEFAULT_BLOOM_FPR: f64 = 0.01;
+
+#[cfg(test)]
+thread_local! {
+    /// The most finished blocks a builder of the current thread held in memory at once.
+    pub(crate) static PEAK_BLOCKS_HELD: std::cell::Cell<usize> = Default::default();
+}
+
+/// A block the builder is done with.
+enum FinishedBlock {
+    /// Encoded on export, into the scratch buffer.
+    Plain(Block),
+    /// Compressed, behind its codec tag.
+    Compressed(Bytes),
+}
 
 /// Builds an SSTable from key-value pairs.
 pub struct SsTableBuilder {
     pub(super) meta: Vec<BlockMeta>,
     builder: BlockBuilder,
-    blocks: Vec<Block>,
+    blocks: Vec<FinishedBlock>,
     // Add other fields you need.
     block_size: usize,
     offset: usize,
+    /// The extractor and the hashes of the prefixes of the keys added so far.
+    prefixes: Option<(PrefixExtractor, Vec<u32>)>,
+    entry_checksums: bool,
+    properties: TableProperties,
+    /// The hashes of the keys added so far, for the key filter.
+    key_hashes: Vec<u32>,
+    /// Bits of key filter per key. `None` leaves the key filter out.
+    bloom_bits_per_key: Option<usize>,
+    compression: CompressionType,
+    /// Blocks per slice of the index block, which only a table with more blocks has.
+    index_slice_len: usize,
+    /// The commit sequence of the latest write the table holds.
+    max_seq: u64,
+    /// The file the blocks go to as soon as they are finished, see `stream_to`.
+    stream: Option<(FileObject, EncodeScratch)>,
+    /// Why writing a block to the stream failed, returned by the export.
+    stream_error: Option<anyhow::Error>,
 }
 
 impl SsTableBuilder {
@@ -27,34 +73,179 @@ impl SsTableBuilder {
             blocks: vec![],
             block_size,
             offset: 0,
+            prefixes: None,
+            entry_checksums: false,
+            properties: TableProperties::default(),
+            key_hashes: vec![],
+            bloom_bits_per_key: Some(BloomFilter::bits_per_key(DEFAULT_BLOOM_FPR)),
+            compression: CompressionType::None,
+            index_slice_len: DEFAULT_INDEX_SLICE_LEN,
+            max_seq: 0,
+            stream: None,
+            stream_error: None,
         }
     }
 
+    /// Size the key filter for a false-positive rate of `rate`, within `(0, 1)`.
+    pub fn with_bloom_fpr(mut self, rate: f64) -> Self {
+        assert!(
+            rate > 0.0 && rate < 1.0,
+            "bloom false-positive rate {} is not within (0, 1)",
+            rate
+        );
+        self.bloom_bits_per_key = Some(BloomFilter::bits_per_key(rate));
+        self
+    }
+
+    /// Size the key filter at `bits` bits per key, at least one. Ten bits give about 1% false
+    /// positives, and every bit more cuts the rate by about 40%.
+    pub fn with_bloom_bits_per_key(mut self, bits: usize) -> Self {
+        assert!(bits > 0, "a key filter takes at least one bit per key");
+        self.bloom_bits_per_key = Some(bits);
+        self
+    }
+
+    /// Leave the key filter out, like the tables written before there was one, so that every
+    /// lookup reads a block.
+    pub fn without_key_filter(mut self) -> Self {
+        self.bloom_bits_per_key = None;
+        self
+    }
+
+    /// Compress every block with `ty`. A codec this build does not have, see
+    /// [`CompressionType::is_supported`], stores the blocks uncompressed, behind the tag of `None`.
+    pub fn with_compression(mut self, ty: CompressionType) -> Self {
+        self.compression = ty;
+        self
+    }
+
+    /// Record `seq` as the commit sequence of the latest write the table holds, see
+    /// [`SsTable::max_seq`].
+    pub fn with_max_seq(mut self, seq: u64) -> Self {
+        self.max_seq = seq;
+        self
+    }
+
+    /// Index the block metas `len` blocks at a time in an [`IndexBlock`], so that opening the
+    /// table reads one entry per slice rather than every meta. A table with no more than `len`
+    /// blocks has no index block.
+    pub fn with_index_slice_len(mut self, len: usize) -> Self {
+        assert!(len > 0, "index slices must hold at least one block");
+        self.index_slice_len = len;
+        self
+    }
+
+    /// End every value with the checksum of its entry, checked whenever it is read back. Call
+    /// it before adding any key.
+    pub fn with_entry_checksums(mut self) -> Self {
+        self.entry_checksums = true;
+        self.builder = self.new_block();
+        self
+    }
+
+    /// Create the file at `path` and write every block to it as soon as it is finished, rather
+    /// than hold them all until the export, so that a large table takes a block of memory
+    /// rather than its size. The export then has to go to `path` too, and fails if writing any
+    /// block did. Call it before adding any key.
+    pub fn stream_to(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        assert!(self.meta.is_empty(), "stream_to after blocks were finished");
+        let file = FileObject::create(path.as_ref(), vec![])?;
+        self.stream = Some((file, EncodeScratch::new()));
+        Ok(self)
+    }
+
+    fn new_block(&self) -> BlockBuilder {
+        let builder = BlockBuilder::new(self.block_size);
+        if self.entry_checksums {
+            builder.with_entry_checksums()
+        } else {
+            builder
+        }
+    }
+
+    /// Also build a Bloom filter of the key prefixes `extractor` picks. Call it before adding
+    /// any key.
+    pub fn with_prefix_extractor(mut self, extractor: PrefixExtractor) -> Self {
+        self.prefixes = Some((extractor, vec![]));
+        self
+    }
+
     /// Adds a key-value pair to SSTable.
     /// Note: You should split a new block when the current block is full.(`std::mem::replace` may be of help here)
     pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        if let Some((extractor, hashes)) = &mut self.prefixes {
+            if let Some(prefix) = extractor.extract(key) {
+                // keys come in order, so a prefix repeats back to back
+                let hash = BloomFilter::hash(prefix);
+                if hashes.last() != Some(&hash) {
+                    hashes.push(hash);
+                }
+            }
+        }
+        if self.bloom_bits_per_key.is_some() {
+            self.key_hashes.push(BloomFilter::hash(key));
+        }
+        self.properties.num_entries += 1;
+        if value.is_empty() {
+            self.properties.num_tombstones += 1;
+        }
         while !self.builder.add(key, value) {
-            let builder = std::mem::replace(&mut self.builder, BlockBuilder::new(self.block_size));
-            let block = builder.build();
-
-            self.meta.push(BlockMeta {
-                offset: self.offset,
-                first_key: Bytes::copy_from_slice(block.slice_at(0)),
-            });
-            self.offset += block.len();
+            let next = self.new_block();
+            let builder = std::mem::replace(&mut self.builder, next);
+            self.finish_block(builder);
+        }
+    }
 
-            self.blocks.push(block);
+    /// Like `add`, unless the blocks already take `limit` bytes and the entry would start
+    /// another one: then the entry is left out and the call returns false, for the entry to
+    /// start the next table instead.
+    pub fn add_within(&mut self, key: &[u8], value: &[u8], limit: usize) -> bool {
+        if self.estimated_size() >= limit && !self.builder.fits(key, value) {
+            return false;
         }
+        self.add(key, value);
+        true
     }
 
-    fn exact_size(&self) -> usize {
-        self.blocks.iter().fold(0, |acc, blk| acc + blk.len()) + self.builder.size()
+    /// Record where `builder` lands in the file, and write its block out when streaming, or
+    /// keep it for the export.
+    fn finish_block(&mut self, builder: BlockBuilder) {
+        self.meta.push(BlockMeta {
+            offset: self.offset,
+            first_key: Bytes::copy_from_slice(builder.first_key()),
+            last_key: Bytes::copy_from_slice(builder.last_key()),
+        });
+        let block = match self.compression {
+            CompressionType::None => FinishedBlock::Plain(builder.build()),
+            ty => FinishedBlock::Compressed(builder.build_compressed(ty)),
+        };
+        self.offset += match &block {
+            FinishedBlock::Plain(block) => block.encoded_len(),
+            FinishedBlock::Compressed(data) => data.len(),
+        };
+        match &mut self.stream {
+            // the first failed write is held for the export, which the later blocks are lost to
+            Some((file, scratch)) if self.stream_error.is_none() => {
+                let written = match &block {
+                    FinishedBlock::Plain(block) => file.append(scratch.encode(block)),
+                    FinishedBlock::Compressed(data) => file.append(data),
+                };
+                self.stream_error = written.err();
+            }
+            Some(_) => {}
+            None => self.blocks.push(block),
+        }
+        #[cfg(test)]
+        PEAK_BLOCKS_HELD.with(|peak| peak.set(peak.get().max(self.blocks.len())));
     }
 
-    /// Get the estimated size of the SSTable.
-    /// Since the data blocks contain much more data than meta blocks, just return the size of data blocks here.
+    /// Get the estimated size of the SSTable: the bytes its data blocks take in the file,
+    /// exactly. The meta blocks, which take much less, are left out.
     pub fn estimated_size(&self) -> usize {
-        self.exact_size()
+        match self.builder.is_empty() {
+            true => self.offset,
+            false => self.offset + self.builder.encoded_len(),
+        }
     }
 
     /// Builds the SSTable and writes it to the given path. No need to actually write to disk until
@@ -65,34 +256,144 @@ impl SsTableBuilder {
         block_cache: Option<Arc<BlockCache>>,
         path: impl AsRef<Path>,
     ) -> Result<SsTable> {
-        let mut blocks = self.blocks;
-        let mut block_metas = self.meta;
+        self.export_with_scratch(id, block_cache, path, &mut EncodeScratch::new())
+    }
+
+    /// Like `export`, but encodes every block into `scratch` and writes it out before the next
+    /// one, so flushes and compactions can share one buffer.
+    pub fn export_with_scratch(
+        mut self,
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        path: impl AsRef<Path>,
+        scratch: &mut EncodeScratch,
+    ) -> Result<SsTable> {
         if !self.builder.is_empty() {
-            let block = self.builder.build();
-            block_metas.push(BlockMeta {
-                offset: self.offset,
-                first_key: Bytes::copy_from_slice(block.slice_at(0)),
-            });
-            blocks.push(block);
+            let builder = std::mem::replace(&mut self.builder, BlockBuilder::new(0));
+            self.finish_block(builder);
         }
+        if let Some(err) = self.stream_error.take() {
+            return Err(err);
+        }
+        let blocks = std::mem::take(&mut self.blocks);
+        let mut block_metas = std::mem::take(&mut self.meta);
 
-        let mut buf = blocks.iter().fold(BytesMut::new(), |mut acc, blk| {
-            acc.extend_from_slice(&blk.encode());
-            acc
+        // the metas point at where the blocks actually land, whatever `offset` estimated
+        let mut file = match self.stream.take() {
+            Some((file, _)) => {
+                anyhow::ensure!(
+                    file.path() == path.as_ref(),
+                    "sst streamed to {} cannot be exported to {}",
+                    file.path().display(),
+                    path.as_ref().display()
+                );
+                file
+            }
+            None => FileObject::create(path.as_ref(), vec![])?,
+        };
+        // streamed, the blocks are in the file already and `blocks` is empty
+        for (block, meta) in blocks.iter().zip(block_metas.iter_mut()) {
+            let position = file.size() as usize;
+            debug_assert_eq!(
+                meta.offset, position,
+                "block offset drifted from the encoding"
+            );
+            meta.offset = position;
+            match block {
+                FinishedBlock::Plain(block) => file.append(scratch.encode(block))?,
+                FinishedBlock::Compressed(data) => file.append(data)?,
+            }
+        }
+        scratch.shrink();
+
+        let data_end = file.size() as usize;
+        let key_filter = match self.bloom_bits_per_key {
+            Some(_) if self.key_hashes.is_empty() => None,
+            None => None,
+            Some(bits_per_key) => {
+                let filter = BloomFilter::build(&self.key_hashes, bits_per_key);
+                let mut vec = vec![];
+                filter.encode(&mut vec);
+                file.append(&vec)?;
+                Some(filter)
+            }
+        };
+
+        let prefix_filter = self.prefixes.map(|(extractor, hashes)| PrefixFilter {
+            extractor: extractor.name().to_string(),
+            bloom: BloomFilter::build(&hashes, PREFIX_BITS_PER_KEY),
         });
-        let offset = buf.len();
+        let offset = file.size() as usize;
 
         let mut vec = vec![];
-        BlockMeta::encode_block_meta(&block_metas, &mut vec);
-        buf.extend_from_slice(&vec);
-        buf.extend_from_slice(&(offset as u32).to_le_bytes());
+        let index_block = match block_metas.len() > self.index_slice_len {
+            true => Some(IndexBlock::encode_metas(
+                &block_metas,
+                self.index_slice_len,
+                &mut vec,
+            )),
+            false => {
+                BlockMeta::encode_block_meta(&block_metas, &mut vec);
+                None
+            }
+        };
+        let index_offset = offset + vec.len();
+        if let Some(block) = &index_block {
+            block.encode(&mut vec);
+        }
+        if let Some(filter) = &prefix_filter {
+            let filter_offset = offset + vec.len();
+            filter.encode(&mut vec);
+            vec.extend_from_slice(&(filter_offset as u32).to_le_bytes());
+            vec.extend_from_slice(&PREFIX_FILTER_MAGIC.to_le_bytes());
+        }
+        if key_filter.is_some() {
+            vec.extend_from_slice(&(data_end as u32).to_le_bytes());
+            vec.extend_from_slice(&KEY_FILTER_MAGIC.to_le_bytes());
+        }
+        vec.extend_from_slice(&(self.properties.num_entries as u32).to_le_bytes());
+        vec.extend_from_slice(&(self.properties.num_tombstones as u32).to_le_bytes());
+        vec.extend_from_slice(&PROPERTIES_MAGIC.to_le_bytes());
+        if self.entry_checksums {
+            vec.extend_from_slice(&ENTRY_CHECKSUM_MAGIC.to_le_bytes());
+        }
+        if self.compression != CompressionType::None {
+            vec.extend_from_slice(&COMPRESSION_MAGIC.to_le_bytes());
+        }
+        vec.extend_from_slice(&LAST_KEY_MAGIC.to_le_bytes());
+        if index_block.is_some() {
+            vec.extend_from_slice(&(index_offset as u32).to_le_bytes());
+            vec.extend_from_slice(&INDEX_BLOCK_MAGIC.to_le_bytes());
+        }
+        if self.max_seq > 0 {
+            vec.extend_from_slice(&self.max_seq.to_le_bytes());
+            vec.extend_from_slice(&MAX_SEQ_MAGIC.to_le_bytes());
+        }
+        let meta_checksum = crc32fast::hash(&vec);
+        encode_footer(offset as u32, meta_checksum, &mut vec);
+        file.append(&vec)?;
+        // the table is recorded in the manifest, and the log it holds truncated, once it is built
+        file.sync()?;
 
         Ok(SsTable {
             id,
-            file: FileObject::create(path.as_ref(), buf.to_vec())?,
-            block_metas,
-            block_meta_offset: offset,
+            file,
+            index: match index_block {
+                Some(block) => TableIndex::sliced(block, offset as u64),
+                None => TableIndex::Full(FencedIndex::from_metas(&block_metas)),
+            },
+            data_end,
+            key_filter,
+            prefix_filter,
+            entry_checksums: self.entry_checksums,
+            compressed: self.compression != CompressionType::None,
+            properties: Some(self.properties),
+            max_seq: self.max_seq,
             cache: block_cache,
+            compressed_cache: None,
+            level_io: None,
+            readahead: Default::default(),
+            retire_on_drop: RetireOnDrop::default(),
         })
     }
 
@@ -0,0 +1,85 @@
+//! The block cache insertions of a scan, made in batches rather than on every block read from
+//! disk, and not at all once the scan has read a lot.
+
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::block::Block;
+use crate::lsm_storage::BlockCache;
+
+/// Number of blocks a scan reads from disk before adding them to the block cache.
+pub const SCAN_FILL_BATCH: usize = 16;
+
+/// The blocks a scan read from disk, on their way to the block cache. Shared by the table
+/// iterators of a scan, like its deadline.
+pub type SharedCacheFill = Arc<ScanCacheFill>;
+
+#[derive(Default)]
+struct FillState {
+    cache: Option<Arc<BlockCache>>,
+    staged: Vec<((usize, usize), Arc<Block>)>,
+    bytes_read: u64,
+    stopped: bool,
+}
+
+/// See [`LsmStorageOptions::scan_fill_cache_limit`](crate::lsm_storage::LsmStorageOptions::scan_fill_cache_limit).
+pub struct ScanCacheFill {
+    limit: Option<u64>,
+    state: Mutex<FillState>,
+}
+
+impl ScanCacheFill {
+    /// Stop filling the cache once the scan has read more than `limit` bytes of blocks.
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit,
+            state: Mutex::new(FillState::default()),
+        }
+    }
+
+    /// Stage `block`, read from disk, for `cache`, adding the staged blocks to it once there are
+    /// [`SCAN_FILL_BATCH`] of them. Past the limit, the block is left out.
+    pub(crate) fn stage(&self, cache: &Arc<BlockCache>, key: (usize, usize), block: Arc<Block>) {
+        let mut state = self.state.lock();
+        state.bytes_read += block.encoded_len() as u64;
+        if self.limit.map_or(false, |limit| state.bytes_read > limit) {
+            state.stopped = true;
+        }
+        if state.stopped {
+            return;
+        }
+        state.cache.get_or_insert_with(|| cache.clone());
+        state.staged.push((key, block));
+        if state.staged.len() >= SCAN_FILL_BATCH {
+            state.flush();
+        }
+    }
+
+    /// Whether the scan read past the limit and stopped filling the cache.
+    pub fn stopped(&self) -> bool {
+        self.state.lock().stopped
+    }
+}
+
+impl FillState {
+    fn flush(&mut self) {
+        let cache = match &self.cache {
+            Some(cache) if !self.staged.is_empty() => cache,
+            _ => return,
+        };
+        // moka takes no batches, but the insertions at least come in one go
+        for (key, block) in self.staged.drain(..) {
+            cache.insert(key, block);
+        }
+        #[cfg(test)]
+        super::CACHE_INSERTS.with(|inserts| inserts.set(inserts.get() + 1));
+    }
+}
+
+impl Drop for ScanCacheFill {
+    /// Add the blocks still staged, read before the scan ended or went past the limit.
+    fn drop(&mut self) {
+        self.state.get_mut().flush();
+    }
+}
@@ -0,0 +1,105 @@
+//! The second tier of the block cache: blocks as the SST stores them, before they are decoded.
+//! A block takes fewer bytes there than decoded, and decoding it again costs less than reading
+//! it from disk. The tier holds the blocks as they are on disk: compressed for the tables built
+//! with a [`CompressionType`](crate::compression::CompressionType), encoded for the others.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::Bytes;
+use moka::sync::ConcurrentCacheExt;
+
+/// Which tiers a block read from disk goes into.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CacheAdmission {
+    /// Both tiers.
+    #[default]
+    Both,
+    /// The compressed tier only. The block makes it to the decoded tier once it is read again,
+    /// which keeps blocks read once, e.g. by a scan, from pushing the hot ones out.
+    OnSecondRead,
+}
+
+/// Where the block reads of the tables sharing a [`CompressedBlockCache`] were served from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockCacheStats {
+    /// Decoded blocks found in the block cache.
+    pub hits: u64,
+    /// Blocks found in the compressed tier, and decoded.
+    pub compressed_hits: u64,
+    /// Blocks read from disk.
+    pub disk_reads: u64,
+}
+
+/// Blocks as stored on disk, weighed by their size in bytes. See
+/// [`LsmStorageOptions::compressed_block_cache_bytes`](crate::lsm_storage::LsmStorageOptions::compressed_block_cache_bytes).
+pub struct CompressedBlockCache {
+    blocks: moka::sync::Cache<(usize, usize), Bytes>,
+    admission: CacheAdmission,
+    hits: AtomicU64,
+    compressed_hits: AtomicU64,
+    disk_reads: AtomicU64,
+}
+
+impl CompressedBlockCache {
+    pub fn new(capacity_bytes: u64, admission: CacheAdmission) -> Self {
+        Self {
+            blocks: moka::sync::Cache::builder()
+                .max_capacity(capacity_bytes)
+                .weigher(|_, block: &Bytes| block.len().try_into().unwrap_or(u32::MAX))
+                .build(),
+            admission,
+            hits: AtomicU64::new(0),
+            compressed_hits: AtomicU64::new(0),
+            disk_reads: AtomicU64::new(0),
+        }
+    }
+
+    pub fn admission(&self) -> CacheAdmission {
+        self.admission
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        BlockCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            compressed_hits: self.compressed_hits.load(Ordering::Relaxed),
+            disk_reads: self.disk_reads.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Number of blocks held, once the insertions and evictions moka has pending are applied.
+    pub fn entry_count(&self) -> u64 {
+        self.blocks.sync();
+        self.blocks.entry_count()
+    }
+
+    /// Bytes of blocks held, like [`Self::entry_count`].
+    pub fn weighted_size(&self) -> u64 {
+        self.blocks.sync();
+        self.blocks.weighted_size()
+    }
+
+    pub(crate) fn get(&self, key: &(usize, usize)) -> Option<Bytes> {
+        self.blocks.get(key)
+    }
+
+    pub(crate) fn insert(&self, key: (usize, usize), block: Bytes) {
+        self.blocks.insert(key, block)
+    }
+
+    pub(crate) fn invalidate(&self, key: &(usize, usize)) {
+        self.blocks.invalidate(key)
+    }
+
+    pub(crate) fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_compressed_hit(&self) {
+        self.compressed_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_disk_read(&self) {
+        self.disk_reads.fetch_add(1, Ordering::Relaxed);
+    }
+}
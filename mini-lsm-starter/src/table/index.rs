@@ -0,0 +1,157 @@
+use bytes::{Buf, Bytes};
+
+use super::BlockMeta;
+use crate::format::BLOCK_META;
+
+#[cfg(test)]
+thread_local! {
+    /// Key comparisons made by the searches of the current thread.
+    pub(crate) static COMPARISONS: std::cell::Cell<usize> = Default::default();
+}
+
+/// Where a block lives and where its first and last keys lie in the key buffer of a
+/// [`FencedIndex`]. The last key follows the first one.
+#[derive(Clone, Copy, Debug)]
+struct Fence {
+    key_offset: u32,
+    key_len: u16,
+    last_key_len: u16,
+    file_offset: u32,
+}
+
+/// The block metas of a table, with the keys back to back in one buffer so that a binary search
+/// does not chase a pointer per block.
+#[derive(Clone, Debug, Default)]
+pub struct FencedIndex {
+    keys: Bytes,
+    fences: Vec<Fence>,
+    /// The metas hold the last key of their block, which tables written before they did lack.
+    last_keys: bool,
+}
+
+impl FencedIndex {
+    pub fn from_metas(metas: &[BlockMeta]) -> Self {
+        let len = metas
+            .iter()
+            .map(|meta| meta.first_key.len() + meta.last_key.len())
+            .sum();
+        let mut keys = Vec::with_capacity(len);
+        let mut fences = Vec::with_capacity(metas.len());
+        for meta in metas {
+            fences.push(Fence {
+                key_offset: keys.len() as u32,
+                key_len: meta.first_key.len() as u16,
+                last_key_len: meta.last_key.len() as u16,
+                file_offset: meta.offset as u32,
+            });
+            keys.extend_from_slice(&meta.first_key);
+            keys.extend_from_slice(&meta.last_key);
+        }
+        Self {
+            keys: keys.into(),
+            fences,
+            last_keys: true,
+        }
+    }
+
+    /// Decode the block metas encoded by [`BlockMeta::encode_block_meta`] straight into an index.
+    /// Without `last_keys`, the metas are the ones written before they held the last key of
+    /// their block: `| offset | key len | first_key |`.
+    pub fn decode(mut buf: &[u8], last_keys: bool) -> Self {
+        let mut keys = Vec::with_capacity(buf.len());
+        let mut fences = vec![];
+        while buf.has_remaining() {
+            let file_offset = buf.get_uint_le(BLOCK_META.offset_size) as u32;
+            let key_offset = keys.len() as u32;
+            let key_len = buf.get_uint_le(BLOCK_META.key_len_size) as u16;
+            keys.extend_from_slice(&buf[..key_len as usize]);
+            buf.advance(key_len as usize);
+            let last_key_len = match last_keys {
+                true => buf.get_uint_le(BLOCK_META.key_len_size) as u16,
+                false => 0,
+            };
+            keys.extend_from_slice(&buf[..last_key_len as usize]);
+            buf.advance(last_key_len as usize);
+            fences.push(Fence {
+                key_offset,
+                key_len,
+                last_key_len,
+                file_offset,
+            });
+        }
+        Self {
+            keys: keys.into(),
+            fences,
+            last_keys,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.fences.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fences.is_empty()
+    }
+
+    /// Offset of block `idx` in the file.
+    pub fn offset(&self, idx: usize) -> usize {
+        self.fences[idx].file_offset as usize
+    }
+
+    pub fn first_key(&self, idx: usize) -> &[u8] {
+        let fence = &self.fences[idx];
+        let start = fence.key_offset as usize;
+        &self.keys[start..start + fence.key_len as usize]
+    }
+
+    /// Like `first_key`, sharing the buffer of the index.
+    pub fn first_key_bytes(&self, idx: usize) -> Bytes {
+        let fence = &self.fences[idx];
+        let start = fence.key_offset as usize;
+        self.keys.slice(start..start + fence.key_len as usize)
+    }
+
+    /// The last key of block `idx`, unless the table was written before the metas held it.
+    pub fn last_key(&self, idx: usize) -> Option<&[u8]> {
+        let fence = &self.fences[idx];
+        let start = fence.key_offset as usize + fence.key_len as usize;
+        self.last_keys
+            .then(|| &self.keys[start..start + fence.last_key_len as usize])
+    }
+
+    /// Like `last_key`, sharing the buffer of the index.
+    pub fn last_key_bytes(&self, idx: usize) -> Option<Bytes> {
+        let fence = &self.fences[idx];
+        let start = fence.key_offset as usize + fence.key_len as usize;
+        self.last_keys
+            .then(|| self.keys.slice(start..start + fence.last_key_len as usize))
+    }
+
+    /// Binary search the first keys for `key`, like `slice::binary_search`.
+    pub fn search(&self, key: &[u8]) -> Result<usize, usize> {
+        let idx = self.fences.partition_point(|fence| {
+            #[cfg(test)]
+            COMPARISONS.with(|count| count.set(count.get() + 1));
+            let start = fence.key_offset as usize;
+            &self.keys[start..start + fence.key_len as usize] < key
+        });
+        if idx < self.len() && self.first_key(idx) == key {
+            Ok(idx)
+        } else {
+            Err(idx)
+        }
+    }
+
+    /// The metas the index was built from. Their last key is empty if the table was written
+    /// before the metas held it.
+    pub fn to_metas(&self) -> Vec<BlockMeta> {
+        (0..self.len())
+            .map(|idx| BlockMeta {
+                offset: self.offset(idx),
+                first_key: self.first_key_bytes(idx),
+                last_key: self.last_key_bytes(idx).unwrap_or_default(),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,272 @@
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::{Buf, BufMut, Bytes};
+use lru::LruCache;
+use parking_lot::Mutex;
+
+use super::{BlockMeta, FencedIndex};
+
+/// Data blocks per entry of an [`IndexBlock`], unless the builder says otherwise.
+pub const DEFAULT_INDEX_SLICE_LEN: usize = 128;
+
+/// Slices of block metas a table keeps decoded, see [`TableIndex::Sliced`].
+const CACHED_SLICES: usize = 8;
+
+#[cfg(test)]
+thread_local! {
+    /// Slices of block metas decoded by the current thread.
+    pub(crate) static META_SLICES_READ: std::cell::Cell<usize> = Default::default();
+}
+
+/// The sparse index of a table with many blocks: one entry per slice of `slice_len` blocks,
+/// holding the first key of the slice, the last key of the slice, and where the metas of its
+/// blocks start within the meta section. Encoded as the entries, in the layout of
+/// [`BlockMeta::encode_block_meta`] with the offset pointing at the metas, then
+/// `| slice_len (u32) | num_blocks (u32) | metas_len (u32) |`.
+#[derive(Clone, Debug)]
+pub struct IndexBlock {
+    slices: FencedIndex,
+    slice_len: usize,
+    num_blocks: usize,
+    /// The bytes the encoded block metas take, from the start of the meta section.
+    metas_len: usize,
+}
+
+impl IndexBlock {
+    /// Encode `metas` into `buf` a slice of `slice_len` blocks at a time, and index the slices.
+    pub fn encode_metas(metas: &[BlockMeta], slice_len: usize, buf: &mut Vec<u8>) -> Self {
+        let start = buf.len();
+        let mut entries = Vec::with_capacity(metas.len() / slice_len + 1);
+        for slice in metas.chunks(slice_len) {
+            entries.push(BlockMeta {
+                offset: buf.len() - start,
+                first_key: slice[0].first_key.clone(),
+                last_key: slice[slice.len() - 1].last_key.clone(),
+            });
+            BlockMeta::encode_block_meta(slice, buf);
+        }
+        Self {
+            slices: FencedIndex::from_metas(&entries),
+            slice_len,
+            num_blocks: metas.len(),
+            metas_len: buf.len() - start,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        BlockMeta::encode_block_meta(&self.slices.to_metas(), buf);
+        buf.put_u32_le(self.slice_len as u32);
+        buf.put_u32_le(self.num_blocks as u32);
+        buf.put_u32_le(self.metas_len as u32);
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        anyhow::ensure!(
+            buf.len() >= 12,
+            "index block of {} bytes is too short",
+            buf.len()
+        );
+        let (entries, mut tail) = buf.split_at(buf.len() - 12);
+        let slice_len = tail.get_u32_le() as usize;
+        let num_blocks = tail.get_u32_le() as usize;
+        let metas_len = tail.get_u32_le() as usize;
+        let slices = FencedIndex::decode(entries, true);
+        anyhow::ensure!(
+            num_blocks > 0
+                && slice_len > 0
+                && slices.len() == (num_blocks + slice_len - 1) / slice_len,
+            "index block has {} entries for {} blocks of {} per slice",
+            slices.len(),
+            num_blocks,
+            slice_len
+        );
+        for idx in 0..slices.len() {
+            let end = match idx + 1 < slices.len() {
+                true => slices.offset(idx + 1),
+                false => metas_len,
+            };
+            anyhow::ensure!(
+                slices.offset(idx) < end,
+                "index block has slice {} at meta offset {}, not before {}",
+                idx,
+                slices.offset(idx),
+                end
+            );
+        }
+        Ok(Self {
+            slices,
+            slice_len,
+            num_blocks,
+            metas_len,
+        })
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.num_blocks
+    }
+
+    pub fn slice_len(&self) -> usize {
+        self.slice_len
+    }
+
+    /// The bytes the encoded block metas take, from the start of the meta section.
+    pub fn metas_len(&self) -> usize {
+        self.metas_len
+    }
+
+    /// The slice that holds block `block_idx`.
+    pub fn slice_of(&self, block_idx: usize) -> usize {
+        block_idx / self.slice_len
+    }
+
+    /// Where the metas of slice `slice` start and end within the meta section.
+    pub fn slice_range(&self, slice: usize) -> (usize, usize) {
+        let end = match slice + 1 < self.slices.len() {
+            true => self.slices.offset(slice + 1),
+            false => self.metas_len,
+        };
+        (self.slices.offset(slice), end)
+    }
+
+    /// The slice that may hold `key`: the last one whose first key is not past it, or the first
+    /// one if they all are.
+    pub fn search(&self, key: &[u8]) -> usize {
+        match self.slices.search(key) {
+            Ok(slice) => slice,
+            Err(insert) => insert.saturating_sub(1),
+        }
+    }
+
+    pub fn first_key(&self) -> &[u8] {
+        self.slices.first_key(0)
+    }
+
+    pub fn last_key(&self) -> &[u8] {
+        self.slices.last_key(self.slices.len() - 1).unwrap()
+    }
+}
+
+/// The block metas of a table, as [`SsTable`](super::SsTable) reads them.
+pub enum TableIndex {
+    /// Every block meta, decoded at open.
+    Full(FencedIndex),
+    /// Only the [`IndexBlock`], decoded at open. The metas are decoded a slice at a time, from
+    /// the meta section at `meta_start` in the file, as lookups need them.
+    Sliced {
+        block: IndexBlock,
+        meta_start: u64,
+        slices: Mutex<LruCache<usize, Arc<FencedIndex>>>,
+    },
+}
+
+impl TableIndex {
+    pub fn sliced(block: IndexBlock, meta_start: u64) -> Self {
+        Self::Sliced {
+            block,
+            meta_start,
+            slices: Mutex::new(LruCache::new(NonZeroUsize::new(CACHED_SLICES).unwrap())),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Full(index) => index.len(),
+            Self::Sliced { block, .. } => block.num_blocks(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The first key of the table, if it has any block.
+    pub fn first_key(&self) -> Option<&[u8]> {
+        match self {
+            Self::Full(index) => (!index.is_empty()).then(|| index.first_key(0)),
+            Self::Sliced { block, .. } => Some(block.first_key()),
+        }
+    }
+
+    /// Like `first_key`, sharing the buffer of the index.
+    pub fn first_key_bytes(&self) -> Option<Bytes> {
+        match self {
+            Self::Full(index) => (!index.is_empty()).then(|| index.first_key_bytes(0)),
+            Self::Sliced { block, .. } => Some(block.slices.first_key_bytes(0)),
+        }
+    }
+
+    /// The last key of the table, unless it has no block or was written before the metas held
+    /// the last key of their block.
+    pub fn last_key(&self) -> Option<&[u8]> {
+        match self {
+            Self::Full(index) if index.is_empty() => None,
+            Self::Full(index) => index.last_key(index.len() - 1),
+            Self::Sliced { block, .. } => Some(block.last_key()),
+        }
+    }
+
+    /// The metas of the slice holding block `block_idx`, and the index of the first block of the
+    /// slice. A full index is a single slice. `read` reads the given range of the file.
+    pub fn slice_of(
+        &self,
+        block_idx: usize,
+        read: impl FnOnce(u64, u64) -> Result<Vec<u8>>,
+    ) -> Result<(SliceRef<'_>, usize)> {
+        match self {
+            Self::Full(index) => Ok((SliceRef::Full(index), 0)),
+            Self::Sliced {
+                block,
+                meta_start,
+                slices,
+            } => {
+                let slice = block.slice_of(block_idx);
+                if let Some(metas) = slices.lock().get(&slice) {
+                    return Ok((SliceRef::Sliced(metas.clone()), slice * block.slice_len()));
+                }
+                let (lo, hi) = block.slice_range(slice);
+                let data = read(meta_start + lo as u64, (hi - lo) as u64)?;
+                #[cfg(test)]
+                META_SLICES_READ.with(|count| count.set(count.get() + 1));
+                let metas = Arc::new(FencedIndex::decode(&data, true));
+                anyhow::ensure!(
+                    metas.len()
+                        == block
+                            .slice_len()
+                            .min(block.num_blocks() - slice * block.slice_len()),
+                    "slice {} of the block metas holds {} blocks",
+                    slice,
+                    metas.len()
+                );
+                slices.lock().put(slice, metas.clone());
+                Ok((SliceRef::Sliced(metas), slice * block.slice_len()))
+            }
+        }
+    }
+
+    /// The slice that may hold `key`, as a block index within it, see [`IndexBlock::search`].
+    pub fn search_slices(&self, key: &[u8]) -> usize {
+        match self {
+            Self::Full(_) => 0,
+            Self::Sliced { block, .. } => block.search(key) * block.slice_len(),
+        }
+    }
+}
+
+/// A slice of the metas of a [`TableIndex`].
+pub enum SliceRef<'a> {
+    Full(&'a FencedIndex),
+    Sliced(Arc<FencedIndex>),
+}
+
+impl std::ops::Deref for SliceRef<'_> {
+    type Target = FencedIndex;
+
+    fn deref(&self) -> &FencedIndex {
+        match self {
+            Self::Full(index) => index,
+            Self::Sliced(index) => index,
+        }
+    }
+}
@@ -1,12 +1,32 @@
 use std::ops::Bound;
 use std::sync::Arc;
+use std::time::Instant;
 
 use anyhow::Result;
 use bytes::Bytes;
+use parking_lot::Mutex;
 
-use super::SsTable;
-use crate::block::BlockIterator;
+use super::readahead::Readahead;
+use super::{ScanCacheFill, SharedCacheFill, SharedReadaheadPeak, SsTable};
+use crate::block::{Block, BlockIterator};
 use crate::iterators::StorageIterator;
+use crate::lsm_storage::ReadOptions;
+use crate::metrics::ReadKind;
+
+/// Where a new [`SsTableIterator`] starts, and where it stops.
+#[derive(Clone, Debug)]
+pub enum SeekTarget<'a> {
+    /// Every key in the table.
+    First,
+    /// The keys >= the given key.
+    Key(&'a [u8]),
+    /// The keys within the bounds. The upper bound is kept as is, so passing a clone of an
+    /// existing `Bytes` does not copy the key.
+    Range(Bound<&'a [u8]>, Bound<Bytes>),
+}
+
+/// A read deadline shared by the table iterators of one scan, so that the scan can move it.
+pub type SharedDeadline = Arc<Mutex<Option<Instant>>>;
 
 /// An iterator over the contents of an SSTable.
 pub struct SsTableIterator {
@@ -15,70 +35,222 @@ pub struct SsTableIterator {
     iter: BlockIterator,
     upper: Bound<Bytes>,
     in_bounds: bool,
+    options: ReadOptions,
+    deadline: SharedDeadline,
+    fill: Option<SharedCacheFill>,
+    /// What the blocks read count as towards the level of the table, if anything.
+    kind: Option<ReadKind>,
+    readahead: Readahead,
 }
 
 impl SsTableIterator {
-    /// Create a new iterator and seek to the first key-value pair in the first data block.
-    pub fn create_and_seek_to_first(table: Arc<SsTable>) -> Result<Self> {
-        let blk_idx = 0;
-        let block = table.read_block_cached(blk_idx)?;
-        let iter = BlockIterator::create_and_seek_to_first(block);
+    /// Create a new iterator positioned at the first key of `target`.
+    pub fn new(table: Arc<SsTable>, target: SeekTarget, options: ReadOptions) -> Result<Self> {
+        let deadline = Arc::new(Mutex::new(options.deadline));
+        let readahead = Readahead::new(table.readahead_hint(), options.max_readahead_blocks);
+        Self::create(table, target, options, deadline, None, None, readahead)
+    }
+
+    /// Like `new`, but reads blocks until `deadline` rather than `options.deadline`, and leaves
+    /// the blocks it reads from disk to `fill`, if any, to add to the cache. The blocks count as
+    /// scan reads of the level of the table, see [`SsTable::with_level_io`], and the most it
+    /// reads at once goes into `peak`.
+    pub(crate) fn with_deadline(
+        table: Arc<SsTable>,
+        target: SeekTarget,
+        options: ReadOptions,
+        deadline: SharedDeadline,
+        fill: Option<SharedCacheFill>,
+        peak: SharedReadaheadPeak,
+    ) -> Result<Self> {
+        let mut readahead = Readahead::new(table.readahead_hint(), options.max_readahead_blocks);
+        readahead.set_peak(peak);
+        let kind = Some(ReadKind::Scan);
+        Self::create(table, target, options, deadline, fill, kind, readahead)
+    }
 
-        Ok(Self {
+    fn create(
+        table: Arc<SsTable>,
+        target: SeekTarget,
+        options: ReadOptions,
+        deadline: SharedDeadline,
+        fill: Option<SharedCacheFill>,
+        kind: Option<ReadKind>,
+        mut readahead: Readahead,
+    ) -> Result<Self> {
+        let (lower, upper) = match target {
+            SeekTarget::First => (Bound::Unbounded, Bound::Unbounded),
+            SeekTarget::Key(key) => (Bound::Included(key), Bound::Unbounded),
+            SeekTarget::Range(lower, upper) => (lower, upper),
+        };
+        let read_options = ReadOptions {
+            deadline: *deadline.lock(),
+            ..options.clone()
+        };
+        if let Bound::Included(key) | Bound::Excluded(key) = &upper {
+            readahead.set_last_block(table.find_block_idx(key));
+        }
+        let (blk_idx, iter) = Self::position(
+            &table,
+            lower,
+            &read_options,
+            fill.as_deref(),
+            kind,
+            &mut readahead,
+        )?;
+
+        let mut this = Self {
             table,
             blk_idx,
             iter,
-            upper: Bound::Unbounded,
+            upper,
             in_bounds: true,
-        })
+            options,
+            deadline,
+            fill,
+            kind,
+            readahead,
+        };
+        this.check_upper();
+        Ok(this)
+    }
+
+    /// Create a new iterator and seek to the first key-value pair in the first data block.
+    pub fn create_and_seek_to_first(table: Arc<SsTable>) -> Result<Self> {
+        Self::new(table, SeekTarget::First, ReadOptions::default())
     }
 
     /// Seek to the first key-value pair in the first data block.
     pub fn seek_to_first(&mut self) -> Result<()> {
-        self.blk_idx = 0;
-        let block = self.table.read_block_cached(self.blk_idx)?;
-        self.iter = BlockIterator::create_and_seek_to_first(block);
-
-        Ok(())
+        self.seek(Bound::Unbounded)
     }
 
     /// Create a new iterator and seek to the first key-value pair which >= `key`.
     pub fn create_and_seek_to_key(table: Arc<SsTable>, key: &[u8]) -> Result<Self> {
-        let blk_idx = std::cmp::min(table.find_block_idx(key), table.num_of_blocks() - 1);
-        let block = table.read_block_cached(blk_idx)?;
-        let iter = BlockIterator::create_and_seek_to_key(block.clone(), key);
-
-        Ok(Self {
-            table,
-            blk_idx,
-            iter,
-            upper: Bound::Unbounded,
-            in_bounds: true,
-        })
+        Self::new(table, SeekTarget::Key(key), ReadOptions::default())
     }
 
     /// Seek to the first key-value pair which >= `key`.
     /// Note: You probably want to review the handout for detailed explanation when implementing this function.
     pub fn seek_to_key(&mut self, key: &[u8]) -> Result<()> {
-        self.iter = Self::create_and_seek_to_key(self.table.clone(), key)?.iter;
+        self.seek(Bound::Included(key))
+    }
 
+    /// Create a new iterator over the keys within `lower` and `upper`.
+    pub fn by_range(table: Arc<SsTable>, lower: Bound<&[u8]>, upper: Bound<Bytes>) -> Result<Self> {
+        Self::new(
+            table,
+            SeekTarget::Range(lower, upper),
+            ReadOptions::default(),
+        )
+    }
+
+    /// Blocks the next read of the iterator takes at most, which grows as it reads the table in
+    /// order, see [`ReadOptions::max_readahead_blocks`].
+    pub fn readahead(&self) -> usize {
+        self.readahead.state().window()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn upper_bound(&self) -> &Bound<Bytes> {
+        &self.upper
+    }
+
+    fn read_options(&self) -> ReadOptions {
+        ReadOptions {
+            deadline: *self.deadline.lock(),
+            ..self.options.clone()
+        }
+    }
+
+    fn seek(&mut self, lower: Bound<&[u8]>) -> Result<()> {
+        self.readahead.clear();
+        (self.blk_idx, self.iter) = Self::position(
+            &self.table,
+            lower,
+            &self.read_options(),
+            self.fill.as_deref(),
+            self.kind,
+            &mut self.readahead,
+        )?;
+        self.in_bounds = true;
+        self.check_upper();
         Ok(())
     }
 
-    pub fn by_range(table: Arc<SsTable>, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<Self> {
-        let mut this = match lower {
-            Bound::Included(lo) => Self::create_and_seek_to_key(table, lo)?,
-            Bound::Excluded(lo) => {
-                let mut this = Self::create_and_seek_to_key(table, lo)?;
-                if this.iter.is_valid() && this.iter.key() == lo {
-                    this.iter.next();
-                }
-                this
+    /// Read block `blk_idx`, along with the blocks after it that `readahead` says to read at
+    /// the same time, unless it read the block ahead already.
+    fn read_block(
+        table: &SsTable,
+        blk_idx: usize,
+        options: &ReadOptions,
+        fill: Option<&ScanCacheFill>,
+        kind: Option<ReadKind>,
+        readahead: &mut Readahead,
+    ) -> Result<Arc<Block>> {
+        options.check_deadline()?;
+        let block = match readahead.take(blk_idx) {
+            Some(block) => block,
+            None => {
+                let max = readahead.access(blk_idx);
+                let blocks = table.read_blocks_ahead(blk_idx, max, options, fill)?;
+                let block = readahead.read(blk_idx, blocks);
+                table.set_readahead_hint(readahead.state());
+                block
             }
-            Bound::Unbounded => Self::create_and_seek_to_first(table)?,
         };
-        this.upper = upper.map(Bytes::copy_from_slice);
-        Ok(this)
+        if let Some(kind) = kind {
+            table.record_read(kind, blk_idx);
+        }
+        Ok(block)
+    }
+
+    /// Find the first entry after `lower`, moving past blocks that have no such entry.
+    fn position(
+        table: &SsTable,
+        lower: Bound<&[u8]>,
+        options: &ReadOptions,
+        fill: Option<&ScanCacheFill>,
+        kind: Option<ReadKind>,
+        readahead: &mut Readahead,
+    ) -> Result<(usize, BlockIterator)> {
+        let mut blk_idx = match lower {
+            Bound::Included(key) | Bound::Excluded(key) => table.find_block_idx(key),
+            Bound::Unbounded => 0,
+        };
+        let block = Self::read_block(table, blk_idx, options, fill, kind, readahead)?;
+        let mut iter = match lower {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                BlockIterator::create_and_seek_to_key(block, key)
+            }
+            Bound::Unbounded => BlockIterator::create_and_seek_to_first(block),
+        };
+        if let Bound::Excluded(key) = lower {
+            if iter.is_valid() && iter.key() == key {
+                iter.next();
+            }
+        }
+        iter.check()?;
+
+        while !iter.is_valid() && blk_idx + 1 < table.num_of_blocks() {
+            blk_idx += 1;
+            let block = Self::read_block(table, blk_idx, options, fill, kind, readahead)?;
+            iter = BlockIterator::create_and_seek_to_first(block);
+            iter.check()?;
+        }
+
+        Ok((blk_idx, iter))
+    }
+
+    fn check_upper(&mut self) {
+        if !self.iter.is_valid() {
+            return;
+        }
+        match &self.upper {
+            Bound::Included(hi) if self.iter.key() > hi => self.in_bounds = false,
+            Bound::Excluded(hi) if self.iter.key() >= hi => self.in_bounds = false,
+            _ => {}
+        }
     }
 }
 
@@ -100,28 +272,30 @@ impl StorageIterator for SsTableIterator {
 
     /// Move to the next `key` in the block.
     /// Note: You may want to check if the current block iterator is valid after the move.
+    ///
+    /// The iterator does not move when reading the next block fails, so it can be retried.
     fn next(&mut self) -> Result<()> {
-        self.iter.next();
-
-        if self.iter.is_valid() {
-            match &self.upper {
-                Bound::Included(hi) if self.key() > hi => self.in_bounds = false,
-                Bound::Excluded(hi) if self.key() >= hi => self.in_bounds = false,
-                _ => {},
-            };
+        // a block iterator past its end starts over, so never move an exhausted one
+        if !self.is_valid() {
             return Ok(());
         }
 
-        self.blk_idx += 1;
-
-        if self.blk_idx == self.table.num_of_blocks() {
-            self.in_bounds = false;
-
-            return Ok(()); // TODO: ??? return Err(anyhow!("iterator reached the end"));
+        if self.iter.is_last() && self.blk_idx + 1 < self.table.num_of_blocks() {
+            let block = Self::read_block(
+                &self.table,
+                self.blk_idx + 1,
+                &self.read_options(),
+                self.fill.as_deref(),
+                self.kind,
+                &mut self.readahead,
+            )?;
+            self.blk_idx += 1;
+            self.iter = BlockIterator::create_and_seek_to_first(block);
+        } else {
+            self.iter.next();
         }
-
-        let block = self.table.read_block_cached(self.blk_idx)?;
-        self.iter = BlockIterator::create_and_seek_to_first(block);
+        self.iter.check()?;
+        self.check_upper();
 
         Ok(())
     }
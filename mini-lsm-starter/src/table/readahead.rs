@@ -0,0 +1,128 @@
+//! Readahead for the reads of an SST in key order: a run of sequential block reads makes the
+//! next one take more blocks at once, doubling up to a maximum, and a read anywhere else drops
+//! back to a single block.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::block::Block;
+
+/// Sequential reads in a row before the reads start taking more than one block.
+pub const SEQUENTIAL_READS: usize = 2;
+
+/// The most blocks the table iterators of a scan read at once, shared by them like the deadline.
+pub type SharedReadaheadPeak = Arc<AtomicUsize>;
+
+/// Where the reads of a table have been going. A table keeps one as a hint, which its iterators
+/// start from and the point reads of the table reset, so that a scan resumed where the last one
+/// stopped keeps reading ahead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReadaheadState {
+    /// The block after the ones the last read took, which a sequential read starts at.
+    next: Option<usize>,
+    /// Sequential reads in a row.
+    run: usize,
+    /// Blocks the next read takes, 0 before the first one.
+    window: usize,
+}
+
+impl ReadaheadState {
+    /// Blocks the next read takes.
+    pub fn window(&self) -> usize {
+        self.window.max(1)
+    }
+
+    /// Record a read starting at block `blk_idx` and return the blocks it may take, at most
+    /// `max`. The reads that do not start where the last one stopped take a single block.
+    pub fn access(&mut self, blk_idx: usize, max: usize) -> usize {
+        if self.next == Some(blk_idx) {
+            self.run += 1;
+            if self.run >= SEQUENTIAL_READS {
+                self.window = self.window() * 2;
+            }
+        } else {
+            self.run = 0;
+            self.window = 1;
+        }
+        self.window = self.window.min(max.max(1));
+        self.window()
+    }
+
+    /// Record that the read starting at block `blk_idx` took `count` blocks.
+    pub fn read(&mut self, blk_idx: usize, count: usize) {
+        self.next = Some(blk_idx + count);
+    }
+}
+
+/// The readahead of a table iterator: its state, and the blocks read ahead that it has not
+/// reached yet.
+#[derive(Default)]
+pub(crate) struct Readahead {
+    state: ReadaheadState,
+    max: usize,
+    /// The block after the last one the iterator may need, if it stops before the end.
+    end: Option<usize>,
+    buffered: VecDeque<(usize, Arc<Block>)>,
+    peak: Option<SharedReadaheadPeak>,
+}
+
+impl Readahead {
+    /// Readahead from `state` on, taking at most `max` blocks at once.
+    pub fn new(state: ReadaheadState, max: usize) -> Self {
+        Self {
+            state,
+            max,
+            ..Default::default()
+        }
+    }
+
+    pub fn state(&self) -> ReadaheadState {
+        self.state
+    }
+
+    pub fn set_peak(&mut self, peak: SharedReadaheadPeak) {
+        self.peak = Some(peak);
+    }
+
+    /// Read nothing ahead past block `last`, which holds the upper bound of the iterator.
+    pub fn set_last_block(&mut self, last: usize) {
+        self.end = Some(last + 1);
+    }
+
+    /// Block `blk_idx`, if it was read ahead. The blocks before it are dropped.
+    pub fn take(&mut self, blk_idx: usize) -> Option<Arc<Block>> {
+        while let Some((idx, block)) = self.buffered.pop_front() {
+            if idx == blk_idx {
+                return Some(block);
+            }
+        }
+        None
+    }
+
+    /// Drop the blocks read ahead, for a seek.
+    pub fn clear(&mut self) {
+        self.buffered.clear();
+    }
+
+    /// Record a read starting at block `blk_idx` and return the blocks it may take.
+    pub fn access(&mut self, blk_idx: usize) -> usize {
+        let window = self.state.access(blk_idx, self.max);
+        match self.end {
+            Some(end) => window.min(end.saturating_sub(blk_idx)).max(1),
+            None => window,
+        }
+    }
+
+    /// Keep `blocks`, read from `blk_idx` on, and return the first one.
+    pub fn read(&mut self, blk_idx: usize, blocks: Vec<Arc<Block>>) -> Arc<Block> {
+        self.state.read(blk_idx, blocks.len());
+        if let Some(peak) = &self.peak {
+            peak.fetch_max(blocks.len(), Ordering::Relaxed);
+        }
+        let mut blocks = (blk_idx..).zip(blocks);
+        let (_, first) = blocks.next().expect("a read takes at least one block");
+        self.buffered.extend(blocks);
+        first
+    }
+}
@@ -0,0 +1,135 @@
+//! Reads an SST file on its own, without an [`LsmStorage`](crate::lsm_storage::LsmStorage), e.g.
+//! the files of a checkpoint or a backup, for analytics to export.
+
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use super::{FileObject, SsTable, SsTableIterator};
+use crate::iterators::StorageIterator;
+
+/// Whether an entry sets its key or deletes it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    Put,
+    /// Stored as an empty value.
+    Delete,
+}
+
+impl EntryKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntryKind::Put => "put",
+            EntryKind::Delete => "delete",
+        }
+    }
+}
+
+/// An entry of the table, as [`SstReader::iter`] yields it. SSTs do not store the sequence of
+/// their entries, so there is none to report.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SstEntry {
+    pub key: Bytes,
+    /// Empty for a deletion.
+    pub value: Bytes,
+    pub kind: EntryKind,
+}
+
+/// How [`SstReader::export_csv`] writes keys and values, which are arbitrary bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EscapeMode {
+    /// Printable ASCII as is, other bytes and the backslash as `\xNN`. Fields holding a comma or
+    /// a double quote are quoted, as in RFC 4180.
+    #[default]
+    Backslash,
+    /// Lowercase hex of every byte.
+    Hex,
+}
+
+impl EscapeMode {
+    fn escape(&self, bytes: &[u8]) -> String {
+        match self {
+            EscapeMode::Hex => bytes.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            EscapeMode::Backslash => {
+                let mut field: String = bytes
+                    .iter()
+                    .map(|&byte| match byte {
+                        b'\\' => "\\x5c".to_string(),
+                        b' '..=b'~' => (byte as char).to_string(),
+                        _ => format!("\\x{:02x}", byte),
+                    })
+                    .collect();
+                if field.contains(|c| c == ',' || c == '"') {
+                    field = format!("\"{}\"", field.replace('"', "\"\""));
+                }
+                field
+            }
+        }
+    }
+}
+
+/// A read-only, uncached view of a single SST file.
+pub struct SstReader {
+    table: Arc<SsTable>,
+}
+
+impl SstReader {
+    /// Open the table at `path`, failing if its footer does not describe the file.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let table = SsTable::open(0, None, FileObject::open(path.as_ref())?)?;
+        Ok(Self {
+            table: Arc::new(table),
+        })
+    }
+
+    pub fn table(&self) -> &SsTable {
+        &self.table
+    }
+
+    /// Every entry of the table in key order, deletions included. Stops after the first error.
+    pub fn iter(&self) -> impl Iterator<Item = Result<SstEntry>> {
+        let mut iter = match self.table.num_of_blocks() {
+            0 => None,
+            _ => Some(SsTableIterator::create_and_seek_to_first(
+                self.table.clone(),
+            )),
+        };
+        std::iter::from_fn(move || {
+            let mut current = match iter.take()? {
+                Ok(current) => current,
+                Err(err) => return Some(Err(err)),
+            };
+            if !current.is_valid() {
+                return None;
+            }
+            let key = current.key().clone();
+            let value = current.value().clone();
+            let kind = match value.is_empty() {
+                true => EntryKind::Delete,
+                false => EntryKind::Put,
+            };
+            // an error moving on is yielded after the entry, and ends the iteration
+            iter = Some(current.next().map(|()| current));
+            Some(Ok(SstEntry { key, value, kind }))
+        })
+    }
+
+    /// Write the table as CSV, a `key,value,kind` header then one line per entry.
+    pub fn export_csv(&self, w: &mut dyn Write, escape: EscapeMode) -> Result<()> {
+        writeln!(w, "key,value,kind")?;
+        for entry in self.iter() {
+            let entry = entry?;
+            writeln!(
+                w,
+                "{},{},{}",
+                escape.escape(&entry.key),
+                escape.escape(&entry.value),
+                entry.kind.as_str()
+            )?;
+        }
+        Ok(())
+    }
+}
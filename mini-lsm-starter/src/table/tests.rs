@@ -1,12 +1,33 @@
+use std::ops::Bound;
 use std::sync::Arc;
 
 use bytes::Bytes;
 use tempfile::{tempdir, TempDir};
 
+use super::index_block::DEFAULT_INDEX_SLICE_LEN;
 use super::*;
+use crate::compression::CompressionType;
+use crate::error::LsmError;
 use crate::iterators::StorageIterator;
+use crate::lsm_storage::PrefixExtractor;
 use crate::table::SsTableBuilder;
 
+/// The offset of the meta blocks, from the footer of the table `data`.
+fn meta_offset_of(data: &[u8]) -> usize {
+    let footer = data.len() - FOOTER_LEN;
+    u32::from_le_bytes(data[footer..footer + 4].try_into().unwrap()) as usize
+}
+
+/// Recompute the checksums in the footer of the table `data`, after editing its meta section.
+fn reseal(data: &mut [u8]) {
+    let footer = data.len() - FOOTER_LEN;
+    let meta_checksum = crc32fast::hash(&data[meta_offset_of(data)..footer]);
+    data[footer + 4..footer + 8].copy_from_slice(&meta_checksum.to_le_bytes());
+    let checksum = crc32fast::hash(&data[footer..data.len() - 2]) as u16;
+    let len = data.len();
+    data[len - 2..].copy_from_slice(&checksum.to_le_bytes());
+}
+
 #[test]
 fn test_sst_build_single_key() {
     let mut builder = SsTableBuilder::new(16);
@@ -61,9 +82,9 @@ fn test_sst_build_all() {
 #[test]
 fn test_sst_decode() {
     let (_dir, sst) = generate_sst();
-    let meta = sst.block_metas.clone();
+    let meta = sst.block_metas().unwrap();
     let new_sst = SsTable::open_for_test(sst.file).unwrap();
-    assert_eq!(new_sst.block_metas, meta);
+    assert_eq!(new_sst.block_metas().unwrap(), meta);
 }
 
 fn as_bytes(x: &[u8]) -> Bytes {
@@ -128,3 +149,955 @@ fn test_sst_seek_key() {
         iter.seek_to_key(b"k").unwrap();
     }
 }
+
+#[test]
+fn test_sst_by_range_keeps_upper_bound() {
+    use std::ops::Bound;
+
+    let (_dir, sst) = generate_sst();
+    let sst = Arc::new(sst);
+    let upper = Bytes::from(key_of(10));
+    let iter =
+        SsTableIterator::by_range(sst, Bound::Unbounded, Bound::Included(upper.clone())).unwrap();
+    match iter.upper_bound() {
+        Bound::Included(key) => assert_eq!(key.as_ptr(), upper.as_ptr()),
+        _ => panic!("unexpected upper bound"),
+    }
+}
+
+#[test]
+fn test_sst_export_reuses_scratch() {
+    use crate::block::EncodeScratch;
+
+    let mut builder = SsTableBuilder::new(128);
+    for idx in 0..4000 {
+        builder.add(
+            format!("key_{:05}", idx).as_bytes(),
+            format!("value_{:010}", idx).as_bytes(),
+        );
+    }
+    let dir = tempdir().unwrap();
+    let mut scratch = EncodeScratch::new();
+    let sst = builder
+        .export_with_scratch(1, None, dir.path().join("1.sst"), &mut scratch)
+        .unwrap();
+    assert!(sst.num_of_blocks() >= 1000);
+    // only the first block grows the buffer
+    assert_eq!(scratch.allocations(), 1);
+
+    // a big block is not kept around once the file is done
+    let mut builder = SsTableBuilder::new(4096);
+    builder.add(b"key", &[b'x'; 3000]);
+    builder
+        .export_with_scratch(2, None, dir.path().join("2.sst"), &mut scratch)
+        .unwrap();
+    let mut builder = SsTableBuilder::new(128);
+    builder.add(b"key", b"value");
+    builder
+        .export_with_scratch(3, None, dir.path().join("3.sst"), &mut scratch)
+        .unwrap();
+    assert!(scratch.capacity() < 4096);
+}
+
+fn collect_keys(mut iter: SsTableIterator) -> Vec<Vec<u8>> {
+    let mut keys = vec![];
+    while iter.is_valid() {
+        keys.push(iter.key().to_vec());
+        iter.next().unwrap();
+    }
+    keys
+}
+
+#[test]
+fn test_sst_seek_targets() {
+    use std::ops::Bound;
+
+    use crate::lsm_storage::ReadOptions;
+
+    let (_dir, sst) = generate_sst();
+    let sst = Arc::new(sst);
+    assert!(sst.num_of_blocks() > 2);
+    let new = |target: SeekTarget| {
+        SsTableIterator::new(sst.clone(), target, ReadOptions::default()).unwrap()
+    };
+    let expected = |range: std::ops::Range<usize>| range.map(key_of).collect::<Vec<_>>();
+
+    assert_eq!(
+        collect_keys(new(SeekTarget::First)),
+        expected(0..num_of_keys())
+    );
+    assert_eq!(
+        collect_keys(new(SeekTarget::Key(&key_of(10)))),
+        expected(10..num_of_keys())
+    );
+    // between two keys
+    assert_eq!(
+        collect_keys(new(SeekTarget::Key(b"key_051"))),
+        expected(11..num_of_keys())
+    );
+    assert!(!new(SeekTarget::Key(b"key_999")).is_valid());
+
+    // the last key of a block is excluded, so the iterator starts on the next block
+    let first = sst.block_metas().unwrap()[1].first_key.to_vec();
+    let idx = (0..num_of_keys()).find(|&i| key_of(i) == first).unwrap();
+    let last_of_first_block = key_of(idx - 1);
+    assert_eq!(
+        collect_keys(new(SeekTarget::Range(
+            Bound::Excluded(&last_of_first_block),
+            Bound::Included(Bytes::from(key_of(idx + 1)))
+        ))),
+        expected(idx..idx + 2)
+    );
+
+    // the upper bound applies to the first key as well
+    assert!(!new(SeekTarget::Range(
+        Bound::Included(&key_of(50)),
+        Bound::Excluded(Bytes::from(key_of(50)))
+    ))
+    .is_valid());
+    assert_eq!(
+        collect_keys(new(SeekTarget::Range(
+            Bound::Unbounded,
+            Bound::Excluded(Bytes::from(first))
+        ))),
+        expected(0..idx)
+    );
+}
+
+#[test]
+fn test_sst_read_without_filling_cache() {
+    use crate::lsm_storage::ReadOptions;
+
+    let (dir, _) = generate_sst();
+    let cache = Arc::new(BlockCache::new(128));
+    let file = FileObject::open(&dir.path().join("1.sst")).unwrap();
+    let sst = Arc::new(SsTable::open(0, Some(cache.clone()), file).unwrap());
+    let options = ReadOptions {
+        fill_cache: false,
+        ..Default::default()
+    };
+    let keys = collect_keys(SsTableIterator::new(sst.clone(), SeekTarget::First, options).unwrap());
+    assert_eq!(keys.len(), num_of_keys());
+    assert!(cache.get(&(0, 0)).is_none());
+
+    SsTableIterator::create_and_seek_to_first(sst).unwrap();
+    assert!(cache.get(&(0, 0)).is_some());
+}
+
+#[test]
+fn test_readahead_window() {
+    let mut state = ReadaheadState::default();
+    let mut windows = vec![];
+    let mut blk_idx = 0;
+    for _ in 0..7 {
+        let window = state.access(blk_idx, 8);
+        state.read(blk_idx, window);
+        windows.push(window);
+        blk_idx += window;
+    }
+    assert_eq!(windows, vec![1, 1, 2, 4, 8, 8, 8]);
+
+    // a read anywhere else starts over, the block just read included
+    assert_eq!(state.access(3, 8), 1);
+    state.read(3, 1);
+    assert_eq!(state.access(3, 8), 1);
+    // a read that took fewer blocks than it could moves the next sequential read back
+    state.read(3, 1);
+    assert_eq!(state.access(4, 8), 1);
+    state.read(4, 1);
+    assert_eq!(state.access(5, 8), 2);
+    state.read(5, 1);
+    assert_eq!(state.access(6, 8), 4);
+    assert_eq!(state.access(6, 1), 1);
+}
+
+#[test]
+fn test_sst_readahead_ramps_up_on_sequential_reads() {
+    use crate::lsm_storage::ReadOptions;
+
+    let (dir, _) = generate_sst();
+    let file = FileObject::open(&dir.path().join("1.sst")).unwrap();
+    let sst = Arc::new(SsTable::open(0, None, file).unwrap());
+    let num_blocks = sst.num_of_blocks();
+    assert!(num_blocks > 20);
+    let span = |first: usize, count: usize| {
+        (sst.block_end(first + count - 1).unwrap() - sst.block_offset(first).unwrap()) as u64
+    };
+    let options = ReadOptions {
+        max_readahead_blocks: 4,
+        ..Default::default()
+    };
+    let scan = |target: SeekTarget, upper: Bound<Bytes>| {
+        READ_LENGTHS.with(|lengths| lengths.borrow_mut().clear());
+        let target = match target {
+            SeekTarget::Key(key) => SeekTarget::Range(Bound::Included(key), upper),
+            _ => SeekTarget::Range(Bound::Unbounded, upper),
+        };
+        let mut iter = SsTableIterator::new(sst.clone(), target, options.clone()).unwrap();
+        while iter.is_valid() {
+            iter.next().unwrap();
+        }
+        (
+            READ_LENGTHS.with(|lengths| lengths.take()),
+            iter.readahead(),
+        )
+    };
+
+    // a block at a time, then 2, then 4, stopping at the block holding the upper bound
+    let metas = sst.block_metas().unwrap();
+    let upper = Bound::Excluded(metas[10].first_key.clone());
+    let (reads, readahead) = scan(SeekTarget::First, upper);
+    assert_eq!(
+        reads,
+        vec![span(0, 1), span(1, 1), span(2, 2), span(4, 4), span(8, 3)]
+    );
+    assert_eq!(readahead, 4);
+
+    // a scan starting where the last one stopped picks up its readahead from the table
+    let (reads, _) = scan(SeekTarget::Key(&metas[11].first_key), Bound::Unbounded);
+    assert_eq!(reads[..2], [span(11, 4), span(15, 4)]);
+
+    // a point read elsewhere resets it
+    sst.record_read(ReadKind::Get, 3);
+    let (reads, _) = scan(SeekTarget::Key(&metas[16].first_key), Bound::Unbounded);
+    assert_eq!(reads[..3], [span(16, 1), span(17, 1), span(18, 2)]);
+}
+
+#[test]
+fn test_file_create_replaces_existing_content() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("file");
+    // what a table written with this id before a crash left behind
+    std::fs::write(&path, vec![1; 200]).unwrap();
+    let file = FileObject::create(&path, vec![7; 100]).unwrap();
+    file.sync().unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), vec![7; 100]);
+    assert_eq!(FileObject::open(&path).unwrap().size(), 100);
+}
+
+#[test]
+fn test_file_read_out_of_bounds() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("file");
+    let file = FileObject::create(&path, vec![7; 100]).unwrap();
+    let corruption = |result: Result<Vec<u8>>| {
+        result
+            .unwrap_err()
+            .downcast::<LsmError>()
+            .expect("a corruption error")
+    };
+
+    assert_eq!(file.read(90, 10).unwrap(), vec![7; 10]);
+    assert!(file.read_to_end_from(100).unwrap().is_empty());
+
+    let straddling = corruption(file.read(90, 20));
+    assert_eq!(
+        straddling,
+        LsmError::Corruption {
+            file: path.clone(),
+            requested: (90, 20),
+            size: 100,
+        }
+    );
+    assert!(straddling.to_string().contains("file"));
+    assert_eq!(
+        corruption(file.read(200, 1)),
+        LsmError::Corruption {
+            file: path.clone(),
+            requested: (200, 1),
+            size: 100,
+        }
+    );
+    assert_eq!(
+        corruption(file.read(u64::MAX, 2)),
+        LsmError::Corruption {
+            file: path.clone(),
+            requested: (u64::MAX, 2),
+            size: 100,
+        }
+    );
+    assert_eq!(
+        corruption(file.read_to_end_from(101)),
+        LsmError::Corruption {
+            file: path,
+            requested: (101, 0),
+            size: 100,
+        }
+    );
+}
+
+#[test]
+fn test_read_block_with_corrupted_meta() {
+    let (dir, _) = generate_sst();
+    let path = dir.path().join("1.sst");
+    let mut data = std::fs::read(&path).unwrap();
+
+    // point the second block past the end of the file
+    let len = data.len();
+    let meta = meta_offset_of(&data);
+    let key_len = |pos: usize| u16::from_le_bytes(data[pos..pos + 2].try_into().unwrap()) as usize;
+    let last_key = meta + 6 + key_len(meta + 4);
+    let second = last_key + 2 + key_len(last_key);
+    data[second..second + 4].copy_from_slice(&0xffff_0000u32.to_le_bytes());
+    // with checksums that agree, so that the open lets it through
+    reseal(&mut data);
+    std::fs::write(&path, &data).unwrap();
+
+    let sst = SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap();
+    let expected = LsmError::Corruption {
+        file: path,
+        requested: (0, 0xffff_0000),
+        size: len as u64,
+    };
+    let err = sst.read_block(0).err().unwrap();
+    assert_eq!(err.downcast_ref::<LsmError>(), Some(&expected));
+    let err = sst.read_block_cached(0).err().unwrap();
+    assert_eq!(err.downcast_ref::<LsmError>(), Some(&expected));
+}
+
+#[test]
+fn test_sst_block_offsets_match_encoding() {
+    let (dir, sst) = generate_sst();
+    assert!(sst.num_of_blocks() > 1);
+    let data = std::fs::read(dir.path().join("1.sst")).unwrap();
+    let meta_offset = meta_offset_of(&data);
+    // the key filter, properties and last key trailers sit between the metas and the footer
+    let footer = data.len() - FOOTER_LEN;
+    let trailer = &data[footer - 24..footer];
+    assert_eq!(&trailer[20..], &LAST_KEY_MAGIC.to_le_bytes());
+    assert_eq!(&trailer[16..20], &PROPERTIES_MAGIC.to_le_bytes());
+    assert_eq!(&trailer[4..8], &KEY_FILTER_MAGIC.to_le_bytes());
+    let key_filter_offset = u32::from_le_bytes(trailer[..4].try_into().unwrap()) as usize;
+    assert!(key_filter_offset < meta_offset);
+    let metas = BlockMeta::decode_block_meta(&data[meta_offset..footer - 24]);
+    assert_eq!(metas, sst.block_metas().unwrap());
+    assert_eq!(metas[0].offset, 0);
+
+    // the key filter sits between the blocks and the metas
+    let ends = metas
+        .iter()
+        .skip(1)
+        .map(|meta| meta.offset)
+        .chain([key_filter_offset]);
+    for (meta, end) in metas.iter().zip(ends) {
+        assert!(meta.offset < end);
+        let block = Block::decode(&data[meta.offset..end]).unwrap();
+        assert_eq!(block.slice_at(0), &meta.first_key[..]);
+        assert_eq!(block.last(), Some(meta.last_key.to_vec()));
+        assert_eq!(block.encoded_len(), end - meta.offset);
+    }
+}
+
+#[test]
+fn test_sst_find_block_by_last_key() {
+    use bytes::BufMut;
+    use std::ops::Bound;
+
+    let (dir, sst) = generate_sst();
+    let metas = sst.block_metas().unwrap();
+    assert!(metas.len() > 2);
+    let number = |key: &[u8]| {
+        std::str::from_utf8(&key[4..])
+            .unwrap()
+            .parse::<usize>()
+            .unwrap()
+    };
+    let probe = |number: usize| format!("key_{:03}", number).into_bytes();
+    let open = |name: &str| {
+        let file = FileObject::open(&dir.path().join(name)).unwrap();
+        SsTable::open(0, None, file).unwrap()
+    };
+    let find_block_idx = |sst: &SsTable, key: &[u8]| {
+        FILES_READ.with(|files| files.borrow_mut().clear());
+        let idx = sst.find_block_idx(key);
+        (idx, FILES_READ.with(|files| files.borrow().len()))
+    };
+
+    let sst = open("1.sst");
+    for (idx, meta) in metas.iter().enumerate() {
+        let (first, last) = (number(&meta.first_key), number(&meta.last_key));
+        assert!(first < last);
+        // the middle of the block, on a key and between two, then past its last key
+        let middle = (first + last) / 10 * 5;
+        assert_eq!(find_block_idx(&sst, &probe(middle)), (idx, 0));
+        assert_eq!(find_block_idx(&sst, &probe(middle + 1)), (idx, 0));
+        let next = (idx + 1).min(metas.len() - 1);
+        assert_eq!(find_block_idx(&sst, &probe(last + 1)), (next, 0));
+    }
+
+    assert!(sst.overlaps(Bound::Unbounded, Bound::Unbounded));
+    assert!(sst.overlaps(Bound::Included(b"key_495"), Bound::Unbounded));
+    assert!(!sst.overlaps(Bound::Excluded(b"key_495"), Bound::Unbounded));
+    assert!(!sst.overlaps(Bound::Included(b"key_496"), Bound::Unbounded));
+    assert!(sst.overlaps(Bound::Unbounded, Bound::Included(b"key_000")));
+    assert!(!sst.overlaps(Bound::Unbounded, Bound::Excluded(b"key_000")));
+    assert!(sst.overlaps(Bound::Included(b"key_001"), Bound::Excluded(b"key_004")));
+
+    // the same table, written before the metas held the last key of their block
+    let data = std::fs::read(dir.path().join("1.sst")).unwrap();
+    let meta_offset = meta_offset_of(&data);
+    let mut legacy = data[..meta_offset].to_vec();
+    for meta in &metas {
+        legacy.put_u32_le(meta.offset as u32);
+        legacy.put_u16_le(meta.first_key.len() as u16);
+        legacy.extend_from_slice(&meta.first_key);
+    }
+    // the key filter and properties trailers, without the last key magic
+    let footer = data.len() - FOOTER_LEN;
+    legacy.extend_from_slice(&data[footer - 24..footer - 4]);
+    let meta_checksum = crc32fast::hash(&legacy[meta_offset..]);
+    encode_footer(meta_offset as u32, meta_checksum, &mut legacy);
+    std::fs::write(dir.path().join("2.sst"), legacy).unwrap();
+    let legacy = open("2.sst");
+    let legacy_metas = legacy.block_metas().unwrap();
+    assert!(legacy_metas.iter().all(|meta| meta.last_key.is_empty()));
+    for (idx, meta) in metas.iter().enumerate().skip(1) {
+        // the block before the first key past the probe is read to tell where the probe lies
+        assert_eq!(
+            find_block_idx(&legacy, &probe(number(&meta.first_key) - 1)),
+            (idx, 1)
+        );
+        let middle = (number(&meta.first_key) + number(&meta.last_key)) / 10 * 5;
+        let reads = usize::from(idx + 1 < metas.len());
+        assert_eq!(find_block_idx(&legacy, &probe(middle + 1)), (idx, reads));
+    }
+    assert!(legacy.overlaps(Bound::Included(b"key_496"), Bound::Unbounded));
+}
+
+#[test]
+fn test_sst_lookup_and_seek_agree_at_block_boundaries() {
+    let (_dir, sst) = generate_sst();
+    let sst = Arc::new(sst);
+    let metas = sst.block_metas().unwrap();
+    let seek = |sst: &Arc<SsTable>, key: &[u8]| {
+        let iter = SsTableIterator::create_and_seek_to_key(sst.clone(), key).unwrap();
+        iter.is_valid()
+            .then(|| (iter.key().to_vec(), iter.value().to_vec()))
+    };
+    for (idx, meta) in metas.iter().enumerate() {
+        // the first key of the block
+        assert_eq!(sst.find_block_idx(&meta.first_key), idx);
+        let found = seek(&sst, &meta.first_key).unwrap();
+        assert_eq!(found.0, meta.first_key);
+        // past the last key of the block, before the first key of the next one
+        let mut between = meta.last_key.to_vec();
+        between.push(0);
+        assert_eq!(sst.find_block_idx(&between), (idx + 1).min(metas.len() - 1));
+        let next = metas.get(idx + 1).map(|next| next.first_key.to_vec());
+        assert_eq!(seek(&sst, &between).map(|found| found.0), next);
+    }
+    assert_eq!(sst.find_block_idx(b"a"), 0);
+    assert_eq!(seek(&sst, b"a").unwrap().0, key_of(0));
+    // past the last key of the table
+    assert_eq!(sst.find_block_idx(b"z"), metas.len() - 1);
+    assert_eq!(seek(&sst, b"z"), None);
+
+    // a key repeated across block boundaries, as the versions of a key are, is found from its
+    // first entry, with the index in one piece and sliced at every block
+    for slice_len in [DEFAULT_INDEX_SLICE_LEN, 1] {
+        let mut builder = SsTableBuilder::new(64).with_index_slice_len(slice_len);
+        builder.add(b"a", b"value");
+        for version in 0..10 {
+            builder.add(b"k", format!("version_{}", version).as_bytes());
+        }
+        builder.add(b"z", b"value");
+        let dir = tempdir().unwrap();
+        let sst = Arc::new(builder.build_for_test(dir.path().join("1.sst")).unwrap());
+        let metas = sst.block_metas().unwrap();
+        let first = metas
+            .iter()
+            .position(|meta| meta.last_key.as_ref() >= &b"k"[..])
+            .unwrap();
+        assert_eq!(metas[first + 1].first_key, &b"k"[..]);
+        assert_eq!(sst.find_block_idx(b"k"), first);
+        let found = seek(&sst, b"k").unwrap();
+        assert_eq!((found.0, found.1), (b"k".to_vec(), b"version_0".to_vec()));
+    }
+}
+
+#[test]
+fn test_sst_lz4_round_trip() {
+    let dir = tempdir().unwrap();
+    let build = |compression, name| {
+        let mut builder = SsTableBuilder::new(128).with_compression(compression);
+        for idx in 0..num_of_keys() {
+            builder.add(&key_of(idx), &value_of(idx));
+        }
+        builder.build_for_test(dir.path().join(name)).unwrap();
+        std::fs::read(dir.path().join(name)).unwrap()
+    };
+    let plain = build(CompressionType::None, "1.sst");
+    let data = build(CompressionType::Lz4, "2.sst");
+    assert!(data.len() < plain.len());
+    assert_eq!(
+        &data[data.len() - FOOTER_LEN - 8..data.len() - FOOTER_LEN - 4],
+        &COMPRESSION_MAGIC.to_le_bytes()
+    );
+
+    let file = FileObject::open(&dir.path().join("2.sst")).unwrap();
+    let sst = SsTable::open_for_test(file).unwrap();
+    assert!(sst.num_of_blocks() > 1);
+    // the metas point at the compressed blocks, which run up to the key filter
+    let metas = sst.block_metas().unwrap();
+    for (idx, meta) in metas.iter().enumerate() {
+        let end = sst.block_end(idx).unwrap();
+        let block = Block::decode_compressed(&data[meta.offset..end]).unwrap();
+        assert_eq!(block.slice_at(0), &meta.first_key[..]);
+    }
+    let mut iter = SsTableIterator::create_and_seek_to_first(Arc::new(sst)).unwrap();
+    for idx in 0..num_of_keys() {
+        assert_eq!(iter.key(), &key_of(idx)[..]);
+        assert_eq!(iter.value(), &value_of(idx)[..]);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_sst_prefix_filter() {
+    let extractor = PrefixExtractor::fixed(5);
+    let mut builder = SsTableBuilder::new(128).with_prefix_extractor(extractor.clone());
+    for prefix in (0..50).map(|idx| idx * 2) {
+        for idx in 0..3 {
+            builder.add(
+                format!("p{:03}_{:03}", prefix, idx).as_bytes(),
+                &value_of(idx),
+            );
+        }
+    }
+    // too short to have a prefix
+    builder.add(b"q", b"value");
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    builder.build_for_test(&path).unwrap();
+
+    let sst = SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap();
+    let mut iter = SsTableIterator::create_and_seek_to_first(Arc::new(sst)).unwrap();
+    let mut entries = 0;
+    while iter.is_valid() {
+        entries += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(entries, 151);
+
+    let sst = SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap();
+    for prefix in 0..100 {
+        let present = sst.may_contain_prefix(&extractor, format!("p{:03}_", prefix).as_bytes());
+        if prefix % 2 == 0 {
+            assert!(present, "p{:03}_", prefix);
+        }
+    }
+    let false_positives = (0..1000)
+        .filter(|idx| sst.may_contain_prefix(&extractor, format!("x{:03}_", idx).as_bytes()))
+        .count();
+    assert!(false_positives < 50, "{}", false_positives);
+
+    // filters built by another extractor are not used
+    let other = PrefixExtractor::fixed(4);
+    assert!(sst.may_contain_prefix(&other, b"x000"));
+}
+
+#[test]
+fn test_sst_sliced_index() {
+    let dir = tempdir().unwrap();
+    let extractor = PrefixExtractor::fixed(6);
+    let build = |slice_len: usize, name: &str| {
+        let mut builder = SsTableBuilder::new(128)
+            .with_index_slice_len(slice_len)
+            .with_prefix_extractor(extractor.clone());
+        for idx in 0..10_000 {
+            builder.add(
+                format!("key_{:05}", idx * 2).as_bytes(),
+                format!("value_{:05}", idx).as_bytes(),
+            );
+        }
+        let path = dir.path().join(name);
+        builder.build_for_test(&path).unwrap();
+        SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap()
+    };
+    let full = build(usize::MAX, "full.sst");
+    index_block::META_SLICES_READ.with(|count| count.set(0));
+    let sliced = build(DEFAULT_INDEX_SLICE_LEN, "sliced.sst");
+    // opening reads the index block alone
+    assert_eq!(index_block::META_SLICES_READ.with(|count| count.get()), 0);
+    let slices = match sliced.index() {
+        TableIndex::Sliced { block, .. } => block.num_blocks() / block.slice_len() + 1,
+        TableIndex::Full(_) => panic!("{} blocks in one slice", sliced.num_of_blocks()),
+    };
+    assert!(slices > 10, "{}", slices);
+    assert!(matches!(full.index(), TableIndex::Full(_)));
+    assert_eq!(sliced.num_of_blocks(), full.num_of_blocks());
+    assert_eq!(sliced.index().first_key(), full.index().first_key());
+    assert_eq!(sliced.index().last_key(), full.index().last_key());
+    // the prefix filter comes after the index block
+    assert!(sliced.may_contain_prefix(&extractor, b"key_01"));
+    assert!((0..100)
+        .any(|idx| !sliced.may_contain_prefix(&extractor, format!("x{:05}", idx).as_bytes())));
+
+    // present keys, keys between them, and keys before and past all of them
+    let probes = (0..20_001).map(|idx| format!("key_{:05}", idx));
+    let probes = probes.chain(["a".to_string(), "key_".to_string(), "z".to_string()]);
+    for probe in probes {
+        index_block::META_SLICES_READ.with(|count| count.set(0));
+        let idx = sliced.find_block_idx(probe.as_bytes());
+        assert!(index_block::META_SLICES_READ.with(|count| count.get()) <= 1);
+        assert_eq!(idx, full.find_block_idx(probe.as_bytes()), "{}", probe);
+    }
+    let mut iter = SsTableIterator::create_and_seek_to_key(Arc::new(sliced), b"key_12345").unwrap();
+    assert_eq!(iter.key(), &b"key_12346"[..]);
+    iter.next().unwrap();
+    assert_eq!(iter.value(), &b"value_06174"[..]);
+
+    let sliced = build(DEFAULT_INDEX_SLICE_LEN, "sliced.sst");
+    sliced.verify_index().unwrap();
+    assert_eq!(sliced.block_metas().unwrap(), full.block_metas().unwrap());
+}
+
+#[test]
+fn test_fenced_index_search() {
+    let blocks = 50_000;
+    // every meta in the one index
+    let mut builder = SsTableBuilder::new(64).with_index_slice_len(blocks);
+    for idx in 0..blocks {
+        // one entry per block
+        builder.add(
+            format!("key_{:06}", idx * 2).as_bytes(),
+            b"value_of_the_key",
+        );
+    }
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    builder.build_for_test(&path).unwrap();
+    let sst = SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap();
+    assert_eq!(sst.num_of_blocks(), blocks);
+
+    let metas = sst.block_metas().unwrap();
+    let index = match sst.index() {
+        TableIndex::Full(index) => index,
+        TableIndex::Sliced { .. } => unreachable!("the table has a single slice"),
+    };
+    let max_comparisons = (usize::BITS - blocks.leading_zeros()) as usize + 1;
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    for _ in 0..10_000 {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        let probe = format!("key_{:06}", seed % (blocks as u64 * 2 + 10));
+
+        index::COMPARISONS.with(|count| count.set(0));
+        let found = index.search(probe.as_bytes());
+        let comparisons = index::COMPARISONS.with(|count| count.get());
+        assert!(comparisons <= max_comparisons, "{}", comparisons);
+        assert_eq!(
+            found,
+            metas.binary_search_by(|meta| meta.first_key.as_ref().cmp(probe.as_bytes())),
+            "{}",
+            probe
+        );
+    }
+}
+
+#[test]
+fn test_reader_exports_csv() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let mut builder = SsTableBuilder::new(32);
+    builder.add(b"apple", b"red");
+    builder.add(b"back\\slash", b"\x00\xff");
+    builder.add(b"gone", b"");
+    builder.add(b"quote\"d", b"a,b");
+    builder.build_for_test(&path).unwrap();
+
+    let reader = reader::SstReader::open(&path).unwrap();
+    assert!(reader.table().num_of_blocks() >= 2);
+    let kinds: Vec<_> = reader.iter().map(|entry| entry.unwrap().kind).collect();
+    assert_eq!(kinds[2], reader::EntryKind::Delete);
+
+    let mut csv = vec![];
+    reader
+        .export_csv(&mut csv, reader::EscapeMode::Backslash)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(csv).unwrap(),
+        "key,value,kind\n\
+         apple,red,put\n\
+         back\\x5cslash,\\x00\\xff,put\n\
+         gone,,delete\n\
+         \"quote\"\"d\",\"a,b\",put\n"
+    );
+
+    let mut csv = vec![];
+    reader
+        .export_csv(&mut csv, reader::EscapeMode::Hex)
+        .unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    assert_eq!(csv.lines().nth(1), Some("6170706c65,726564,put"));
+    assert_eq!(csv.lines().count(), 5);
+
+    // a file that is not a table is refused
+    std::fs::write(dir.path().join("2.sst"), u32::MAX.to_le_bytes()).unwrap();
+    assert!(reader::SstReader::open(dir.path().join("2.sst")).is_err());
+}
+
+#[test]
+fn test_sst_properties() {
+    let dir = tempdir().unwrap();
+    let mut builder = SsTableBuilder::new(128);
+    for idx in 0..100 {
+        let value = if idx % 4 == 0 { vec![] } else { value_of(idx) };
+        builder.add(&key_of(idx), &value);
+    }
+    let path = dir.path().join("1.sst");
+    let built = builder.build_for_test(&path).unwrap();
+    let expected = TableProperties {
+        num_entries: 100,
+        num_tombstones: 25,
+    };
+    assert_eq!(built.properties(), Some(expected));
+
+    let sst = SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap();
+    assert_eq!(sst.properties(), Some(expected));
+    assert_eq!(sst.properties().unwrap().num_live_entries(), 75);
+}
+
+#[test]
+fn test_sst_compressed_cache_tiers() {
+    use compressed_cache::{BlockCacheStats, CacheAdmission, CompressedBlockCache};
+
+    let (dir, reference) = generate_sst();
+    let open = |admission| {
+        let cache = Arc::new(BlockCache::new(128));
+        let tier = Arc::new(CompressedBlockCache::new(1 << 20, admission));
+        let file = FileObject::open(&dir.path().join("1.sst")).unwrap();
+        let sst = SsTable::open(0, Some(cache.clone()), file)
+            .unwrap()
+            .with_compressed_cache(Some(tier.clone()));
+        (sst, cache, tier)
+    };
+    let stats = |hits, compressed_hits, disk_reads| BlockCacheStats {
+        hits,
+        compressed_hits,
+        disk_reads,
+    };
+    let first_entry = |block: Arc<Block>| {
+        let (key, value) = block.entry(0).unwrap();
+        (key.to_vec(), value.to_vec())
+    };
+    let expected = first_entry(reference.read_block(1).unwrap());
+
+    let (sst, cache, tier) = open(CacheAdmission::Both);
+    assert_eq!(first_entry(sst.read_block_cached(1).unwrap()), expected);
+    assert_eq!(tier.stats(), stats(0, 0, 1));
+    assert!(cache.contains_key(&(0, 1)));
+    assert_eq!(first_entry(sst.read_block_cached(1).unwrap()), expected);
+    assert_eq!(tier.stats(), stats(1, 0, 1));
+    // evicted from the decoded tier only
+    cache.invalidate(&(0, 1));
+    assert_eq!(first_entry(sst.read_block_cached(1).unwrap()), expected);
+    assert_eq!(tier.stats(), stats(1, 1, 1));
+    assert!(cache.contains_key(&(0, 1)));
+    assert_eq!(tier.entry_count(), 1);
+    assert!((1..=128).contains(&tier.weighted_size()));
+
+    let (sst, cache, tier) = open(CacheAdmission::OnSecondRead);
+    assert_eq!(first_entry(sst.read_block_cached(1).unwrap()), expected);
+    assert!(!cache.contains_key(&(0, 1)));
+    assert_eq!(first_entry(sst.read_block_cached(1).unwrap()), expected);
+    assert!(cache.contains_key(&(0, 1)));
+    assert_eq!(first_entry(sst.read_block_cached(1).unwrap()), expected);
+    assert_eq!(tier.stats(), stats(1, 1, 1));
+
+    // a read that does not fill the cache leaves both tiers alone
+    let options = crate::lsm_storage::ReadOptions {
+        fill_cache: false,
+        ..Default::default()
+    };
+    assert_eq!(
+        first_entry(sst.read_block_with(2, &options).unwrap()),
+        first_entry(reference.read_block(2).unwrap())
+    );
+    assert_eq!(tier.stats(), stats(1, 1, 2));
+    assert_eq!(tier.entry_count(), 1);
+    sst.invalidate_cached_blocks();
+    assert_eq!((cache.entry_count(), tier.entry_count()), (0, 0));
+}
+
+#[test]
+fn test_sst_estimated_size_is_data_size() {
+    let dir = tempdir().unwrap();
+    for (num_keys, entry_checksums) in [(1, false), (37, false), (37, true), (100, true)] {
+        let mut builder = SsTableBuilder::new(128);
+        if entry_checksums {
+            builder = builder.with_entry_checksums();
+        }
+        assert_eq!(builder.estimated_size(), 0);
+        for idx in 0..num_keys {
+            builder.add(&key_of(idx), &value_of(idx));
+        }
+        // one block overflowed by its only entry
+        builder.add(b"zzz", &[7; 300]);
+        let estimated = builder.estimated_size();
+        let sst = builder.build_for_test(dir.path().join("1.sst")).unwrap();
+        assert_eq!(estimated, sst.data_end);
+    }
+}
+
+#[test]
+fn test_sst_key_filter_false_positives() {
+    let dir = tempdir().unwrap();
+    let false_positives = |builder: fn(SsTableBuilder) -> SsTableBuilder| {
+        let mut builder = builder(SsTableBuilder::new(4096));
+        for idx in 0..1000 {
+            builder.add(format!("key_{:05}", idx * 2).as_bytes(), b"value");
+        }
+        let sst = builder.build_for_test(dir.path().join("1.sst")).unwrap();
+        assert!((0..1000).all(|idx| sst.may_contain(format!("key_{:05}", idx * 2).as_bytes())));
+        (0..10000)
+            .filter(|idx| sst.may_contain(format!("key_{:05}", idx * 2 + 1).as_bytes()))
+            .count()
+    };
+    let default = false_positives(|builder| builder);
+    assert!(default < 300, "{} false positives", default);
+    let loose = false_positives(|builder| builder.with_bloom_fpr(0.2));
+    assert!(loose > default && loose < 3000, "{} false positives", loose);
+
+    // sized by bits per key, ten of which are about the default
+    let ten_bits = false_positives(|builder| builder.with_bloom_bits_per_key(10));
+    assert!(ten_bits < 300, "{} false positives", ten_bits);
+    let tight = false_positives(|builder| builder.with_bloom_bits_per_key(20));
+    assert!(tight < ten_bits / 10, "{} false positives", tight);
+    let one_bit = false_positives(|builder| builder.with_bloom_bits_per_key(1));
+    assert!(one_bit > loose, "{} false positives", one_bit);
+}
+
+#[test]
+fn test_block_cache_wrapper() {
+    use crate::lsm_storage::CacheStats;
+
+    let (dir, reference) = generate_sst();
+    let cache = Arc::new(BlockCache::new(128));
+    let file = FileObject::open(&dir.path().join("1.sst")).unwrap();
+    let sst = SsTable::open(0, Some(cache.clone()), file).unwrap();
+    let stats = |hits, misses, entry_count, capacity| CacheStats {
+        hits,
+        misses,
+        entry_count,
+        capacity,
+    };
+
+    sst.read_block_cached(0).unwrap();
+    sst.read_block_cached(0).unwrap();
+    sst.read_block_cached(1).unwrap();
+    assert_eq!(cache.stats(), stats(1, 2, 2, 128));
+
+    // the error of the read comes back, and nothing is cached
+    let err = cache
+        .get_or_try_insert_with((7, 0), || Err(LsmError::Cancelled.into()))
+        .err()
+        .unwrap();
+    assert_eq!(err.downcast_ref(), Some(&LsmError::Cancelled));
+    assert!(!cache.contains_key(&(7, 0)));
+    let block = cache
+        .get_or_try_insert_with((7, 0), || reference.read_block(0))
+        .unwrap();
+    assert_eq!(block.entry(0), reference.read_block(0).unwrap().entry(0));
+    assert!(cache.get(&(7, 0)).is_some());
+    assert_eq!(cache.stats(), stats(2, 4, 3, 128));
+
+    cache.invalidate_table(0, sst.num_of_blocks());
+    assert!(!cache.contains_key(&(0, 0)));
+    assert!(!cache.contains_key(&(0, 1)));
+    assert!(cache.contains_key(&(7, 0)));
+
+    sst.read_block_cached(0).unwrap();
+    sst.read_block_cached(1).unwrap();
+    cache.resize(1);
+    assert_eq!(cache.stats(), stats(2, 6, 1, 1));
+}
+
+#[test]
+fn test_sst_meta_checksum() {
+    let (dir, sst) = generate_sst();
+    let num_blocks = sst.num_of_blocks();
+    drop(sst);
+    let path = dir.path().join("1.sst");
+    let data = std::fs::read(&path).unwrap();
+    let meta_offset = meta_offset_of(&data);
+    let footer = data.len() - FOOTER_LEN;
+    assert_eq!(
+        &data[footer + 4..footer + 8],
+        &crc32fast::hash(&data[meta_offset..footer]).to_le_bytes()
+    );
+
+    // a single byte off anywhere from the metas to the footer, the metas and trailers alike
+    for pos in [
+        meta_offset,
+        meta_offset + 2,
+        (meta_offset + footer) / 2,
+        footer - 1,
+    ] {
+        let mut corrupt = data.clone();
+        corrupt[pos] ^= 0x01;
+        std::fs::write(&path, &corrupt).unwrap();
+        let err = SsTable::open_for_test(FileObject::open(&path).unwrap())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("sst 0 has a corrupt meta section"), "{}", err);
+        let expected = format!(
+            "expected {:#010x}",
+            crc32fast::hash(&data[meta_offset..footer])
+        );
+        assert!(err.contains(&expected), "{}", err);
+    }
+    std::fs::write(&path, &data).unwrap();
+    let sst = SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap();
+    assert_eq!(sst.num_of_blocks(), num_blocks);
+}
+
+#[test]
+fn test_sst_footer() {
+    let (dir, _) = generate_sst();
+    let path = dir.path().join("1.sst");
+    let data = std::fs::read(&path).unwrap();
+    let footer = &data[data.len() - FOOTER_LEN..];
+    assert_eq!(&footer[8..10], &FORMAT_VERSION.to_le_bytes());
+    assert_eq!(&footer[10..14], &SSTABLE_MAGIC.to_le_bytes());
+    let open = |data: &[u8]| {
+        std::fs::write(&path, data).unwrap();
+        SsTable::open_for_test(FileObject::open(&path).unwrap())
+    };
+
+    // a single byte off, in the magic or anywhere the checksum covers
+    let mut corrupt = data.clone();
+    corrupt[data.len() - 5] ^= 0x01;
+    let err = open(&corrupt).err().unwrap().to_string();
+    assert!(err.contains("not a mini-lsm table"), "{}", err);
+    let mut corrupt = data.clone();
+    corrupt[data.len() - FOOTER_LEN] ^= 0x01;
+    let err = open(&corrupt).err().unwrap().to_string();
+    assert!(err.contains("corrupt footer"), "{}", err);
+
+    // a table from a later version, with a footer that is otherwise sound
+    let mut newer = data[..data.len() - 6].to_vec();
+    newer.put_u16_le(FORMAT_VERSION + 1);
+    newer.put_u32_le(SSTABLE_MAGIC);
+    let checksum = crc32fast::hash(&newer[newer.len() - 14..]) as u16;
+    newer.put_u16_le(checksum);
+    let err = open(&newer).err().unwrap().to_string();
+    assert!(err.contains("format version 3"), "{}", err);
+
+    // a version 1 table has no meta checksum in its shorter footer
+    let mut v1 = data[..data.len() - FOOTER_LEN].to_vec();
+    v1.put_u32_le(meta_offset_of(&data) as u32);
+    v1.put_u16_le(1);
+    v1.put_u32_le(SSTABLE_MAGIC);
+    let checksum = crc32fast::hash(&v1[v1.len() - 10..]) as u16;
+    v1.put_u16_le(checksum);
+    let metas = open(&data).unwrap().block_metas().unwrap();
+    assert_eq!(open(&v1).unwrap().block_metas().unwrap(), metas);
+
+    // nor does any file too short to hold a footer
+    assert!(open(b"sst").is_err());
+    assert!(open(&data).is_ok());
+}
@@ -1 +1,5 @@
+pub mod background_tests;
 pub mod day4_tests;
+pub mod read_tests;
+pub mod recovery_tests;
+pub mod write_tests;
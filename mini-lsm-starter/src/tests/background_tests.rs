@@ -0,0 +1,913 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use tempfile::tempdir;
+
+use crate::compaction::NUM_LEVELS;
+use crate::iterators::StorageIterator;
+use crate::prelude::{
+    CompactionConfig, CompactionPicker, CompactionTask, LeveledPicker, LsmError,
+    LsmStateDescription, LsmStorage, LsmStorageOptions, MutableOptions, RetryPolicy, SstLayout,
+    TieringOptions, WorkerStatus,
+};
+use crate::table::{
+    FileObject, SsTable, SsTableBuilder, SsTableIterator, PEAK_BLOCKS_HELD, READ_LATENCY,
+    WRITE_FAULTS,
+};
+use crate::testing::{apply, apply_to_model, OpMix, WorkloadGen, WorkloadSpec};
+
+fn wait_for_jobs(storage: &LsmStorage, jobs: u64) -> WorkerStatus {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let status = storage.background_health().remove(0);
+        if status.jobs_completed >= jobs || Instant::now() > deadline {
+            return status;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_worker_survives_panicking_job() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(Bytes::from("1"), Bytes::from("233")).unwrap();
+
+    let health = storage.background_health();
+    let names = health
+        .iter()
+        .map(|status| &status.name[..])
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["mini-lsm-compaction", "mini-lsm-janitor"]);
+    assert!(health.iter().all(|status| status.alive));
+
+    *storage.job_hook.lock() = Some(Box::new(|| panic!("injected failure")));
+    storage.schedule_compaction().unwrap();
+    let status = wait_for_jobs(&storage, 1);
+    assert_eq!(status.jobs_completed, 1);
+    assert_eq!(status.jobs_failed, 1);
+    assert!(status.alive);
+    assert!(status
+        .last_error
+        .as_deref()
+        .unwrap()
+        .contains("injected failure"));
+
+    storage.schedule_compaction().unwrap();
+    let status = wait_for_jobs(&storage, 2);
+    assert_eq!(status.jobs_completed, 2);
+    assert_eq!(status.jobs_failed, 1);
+    assert!(status.alive);
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+}
+
+#[test]
+fn test_background_health_reports_every_thread() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        hotset_interval: Some(Duration::from_millis(10)),
+        warm_on_open: true,
+        small_sst_threshold: Some(1 << 20),
+        small_sst_merge_interval: Some(Duration::from_millis(10)),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    storage.wait_for_warm();
+    let status_of = |name: &str| {
+        storage
+            .background_health()
+            .into_iter()
+            .find(|status| status.name == name)
+            .unwrap()
+    };
+    let names = storage
+        .background_health()
+        .into_iter()
+        .map(|status| status.name)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        [
+            "mini-lsm-compaction",
+            "mini-lsm-warm",
+            "mini-lsm-hotset",
+            "mini-lsm-small-sst",
+            "mini-lsm-janitor"
+        ]
+    );
+    // the warmer is done after a single job, the others keep running theirs
+    let warm = status_of("mini-lsm-warm");
+    assert_eq!((warm.alive, warm.jobs_completed), (false, 1));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    for name in ["mini-lsm-hotset", "mini-lsm-small-sst", "mini-lsm-janitor"] {
+        while status_of(name).jobs_completed == 0 {
+            assert!(Instant::now() < deadline, "{} ran no job", name);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let status = status_of(name);
+        assert!(status.alive);
+        assert_eq!(status.jobs_failed, 0);
+    }
+
+    storage.stop().unwrap();
+    while storage
+        .background_health()
+        .iter()
+        .any(|status| status.alive)
+    {
+        assert!(Instant::now() < deadline, "a worker outlived stop");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_worker_retries_transient_errors() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        background_retry: RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    let put = |storage: &LsmStorage, keys: std::ops::Range<usize>| {
+        for idx in keys {
+            let key = format!("key_{:03}", idx);
+            storage.put(key.into(), Bytes::from("value")).unwrap();
+        }
+    };
+    let faults = |faults: usize| -> Box<dyn FnOnce() + Send> {
+        Box::new(move || WRITE_FAULTS.with(|count| count.set(faults)))
+    };
+
+    // the first two attempts at the flush fail, the third one goes through
+    put(&storage, 0..100);
+    *storage.job_hook.lock() = Some(faults(2));
+    storage.schedule_compaction().unwrap();
+    let status = wait_for_jobs(&storage, 1);
+    assert_eq!(status.jobs_failed, 0);
+    assert_eq!(storage.metrics().background_retries(), 2);
+    assert_eq!(storage.metrics().flushes(), 1);
+
+    // out of attempts, the job fails
+    put(&storage, 100..200);
+    *storage.job_hook.lock() = Some(faults(3));
+    storage.schedule_compaction().unwrap();
+    let status = wait_for_jobs(&storage, 2);
+    assert_eq!(status.jobs_failed, 1);
+    assert!(status.last_error.unwrap().contains("injected write fault"));
+    assert_eq!(storage.metrics().background_retries(), 4);
+    assert_eq!(storage.metrics().flushes(), 1);
+    storage.kill();
+
+    // the flushed keys are durable, and the ones of the failed job are still in the log
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    assert_eq!(storage.open_report().wal_records_replayed, 100);
+    assert_eq!(storage.sst_ids_by_level()[0].len(), 1);
+    for idx in 0..200 {
+        let key = format!("key_{:03}", idx);
+        assert_eq!(
+            storage.get(key.as_bytes()).unwrap(),
+            Some(Bytes::from("value"))
+        );
+    }
+    drop(storage);
+
+    // an error the policy takes for permanent fails the job right away
+    let options = LsmStorageOptions {
+        background_retry: RetryPolicy {
+            classify: Some(Arc::new(|_| false)),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    *storage.job_hook.lock() = Some(faults(1));
+    storage.schedule_compaction().unwrap();
+    let status = wait_for_jobs(&storage, 1);
+    assert_eq!(status.jobs_failed, 1);
+    assert_eq!(storage.metrics().background_retries(), 0);
+}
+
+#[test]
+fn test_deletes_alone_trigger_flush() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    for i in 0..1100 {
+        storage.delete(format!("{:01000}", i).as_bytes()).unwrap();
+    }
+    let status = wait_for_jobs(&storage, 1);
+    assert_eq!(status.jobs_completed, 1);
+    assert_eq!(status.jobs_failed, 0);
+}
+
+#[test]
+fn test_stop_cancels_compaction() {
+    let dir = tempdir().unwrap();
+    let write_sst = |path: std::path::PathBuf, keys: std::ops::Range<usize>| {
+        let mut builder = SsTableBuilder::new(128);
+        for idx in keys {
+            builder.add(
+                format!("key_{:03}", idx).as_bytes(),
+                format!("value_{:010}", idx).as_bytes(),
+            );
+        }
+        builder.build_for_test(path).unwrap();
+    };
+    std::fs::create_dir(dir.path().join("L0")).unwrap();
+    std::fs::create_dir(dir.path().join("L1")).unwrap();
+    write_sst(dir.path().join("L0").join("1.sst"), 0..300);
+    write_sst(dir.path().join("L0").join("2.sst"), 200..500);
+    write_sst(dir.path().join("L1").join("3.sst"), 400..600);
+    let options = LsmStorageOptions {
+        sst_layout: SstLayout::LevelDirs,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    let levels = storage.sst_ids_by_level();
+    let files = || {
+        let mut files = vec![];
+        for level in ["L0", "L1", "L2"] {
+            if let Ok(entries) = std::fs::read_dir(dir.path().join(level)) {
+                files.extend(entries.map(|entry| entry.unwrap().path()));
+            }
+        }
+        files.extend(
+            std::fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.is_file()),
+        );
+        files.sort();
+        files
+    };
+    let before = files();
+
+    let compaction = {
+        let storage = storage.clone();
+        std::thread::spawn(move || {
+            READ_LATENCY.with(|latency| latency.set(Duration::from_millis(2)));
+            storage.compact(0)
+        })
+    };
+    std::thread::sleep(Duration::from_millis(30));
+    let stopping = Instant::now();
+    storage.stop().unwrap();
+    assert!(stopping.elapsed() < Duration::from_secs(1));
+    assert!(!storage.background_health()[0].alive);
+
+    let err = compaction.join().unwrap().unwrap_err();
+    assert_eq!(err.downcast_ref::<LsmError>(), Some(&LsmError::Cancelled));
+    assert_eq!(storage.sst_ids_by_level(), levels);
+    assert_eq!(files(), before);
+}
+
+#[test]
+fn test_soft_max_files_boosts_compaction() {
+    let dir = tempdir().unwrap();
+    let write_sst = |path: std::path::PathBuf, keys: std::ops::Range<usize>| {
+        let mut builder = SsTableBuilder::new(128);
+        for idx in keys {
+            builder.add(
+                format!("key_{:03}", idx).as_bytes(),
+                format!("value_{:010}", idx).as_bytes(),
+            );
+        }
+        builder.build_for_test(path).unwrap();
+    };
+    std::fs::create_dir(dir.path().join("L0")).unwrap();
+    std::fs::create_dir(dir.path().join("L1")).unwrap();
+    // two L0 tables clear of five small L1 ones
+    write_sst(dir.path().join("L0").join("10.sst"), 900..910);
+    write_sst(dir.path().join("L0").join("11.sst"), 905..915);
+    for id in 1..=5 {
+        write_sst(
+            dir.path().join("L1").join(format!("{}.sst", id)),
+            id * 10..id * 10 + 5,
+        );
+    }
+    let open = |soft_max_files| {
+        let options = LsmStorageOptions {
+            sst_layout: SstLayout::LevelDirs,
+            soft_max_files,
+            ..Default::default()
+        };
+        LsmStorage::open_with_options(&dir, options).unwrap()
+    };
+
+    let storage = open(None);
+    assert_eq!(storage.metrics().live_file_count(), 7);
+    assert_eq!(storage.metrics().file_count_boost(), 1.0);
+    let plan = storage.plan_compaction(None).unwrap();
+    assert_eq!(plan.level, 0);
+    assert_eq!(plan.inputs.len(), 2);
+    drop(storage);
+
+    // past the cap, the merge removing the most files goes first
+    let storage = open(Some(4));
+    assert_eq!(storage.metrics().live_file_count(), 7);
+    assert_eq!(storage.metrics().file_count_boost(), 1.75);
+    let plan = storage.plan_compaction(None).unwrap();
+    assert_eq!(plan.level, 1);
+    assert_eq!(plan.inputs.len(), 5);
+
+    // the gauges follow flushes
+    storage
+        .put(Bytes::from("key_999"), Bytes::from("value"))
+        .unwrap();
+    storage.sync().unwrap();
+    assert_eq!(storage.metrics().live_file_count(), 8);
+    assert_eq!(storage.metrics().file_count_boost(), 2.0);
+}
+
+#[test]
+fn test_tiering_keeps_hot_keys_up() {
+    let dir = tempdir().unwrap();
+    let key = |idx: usize| Bytes::from(format!("key_{:03}", idx));
+    let value = |idx: usize| Bytes::from(format!("value_{:010}", idx));
+    let write_sst = |path: std::path::PathBuf, keys: std::ops::Range<usize>| {
+        let mut builder = SsTableBuilder::new(128);
+        for idx in keys {
+            builder.add(&key(idx), &value(idx));
+        }
+        builder.build_for_test(path).unwrap();
+    };
+    std::fs::create_dir(dir.path().join("L0")).unwrap();
+    std::fs::create_dir(dir.path().join("L1")).unwrap();
+    write_sst(dir.path().join("L0").join("1.sst"), 0..60);
+    write_sst(dir.path().join("L0").join("2.sst"), 40..100);
+    write_sst(dir.path().join("L1").join("3.sst"), 200..210);
+    let options = LsmStorageOptions {
+        sst_layout: SstLayout::LevelDirs,
+        tiering: Some(TieringOptions {
+            hot_threshold: 20,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+
+    // a few keys take most of the reads
+    let hot = [3, 42, 77];
+    let all = (0..100).map(key).collect::<Vec<_>>();
+    let all = all.iter().map(|key| &key[..]).collect::<Vec<_>>();
+    for round in 0..30 {
+        let keys = hot.iter().map(|&idx| key(idx)).collect::<Vec<_>>();
+        let keys = keys.iter().map(|key| &key[..]).collect::<Vec<_>>();
+        let found = storage.get_many(&keys).unwrap();
+        assert_eq!(
+            found,
+            hot.iter().map(|&idx| Some(value(idx))).collect::<Vec<_>>()
+        );
+        if round % 10 == 0 {
+            storage.get_many(&all).unwrap();
+        }
+    }
+
+    storage.compact(0).unwrap();
+    assert_eq!(storage.metrics().hot_entries_kept(), 3);
+    assert_eq!(storage.metrics().cold_entries_sunk(), 97);
+
+    let entries_of = |path: std::path::PathBuf| {
+        let file = FileObject::open(&path).unwrap();
+        let sst = std::sync::Arc::new(SsTable::open(0, None, file).unwrap());
+        let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+        let mut entries = vec![];
+        while iter.is_valid() {
+            entries.push((iter.key().clone(), iter.value().clone()));
+            iter.next().unwrap();
+        }
+        entries
+    };
+    // the hot output stays in L0, the cold one moves down to L1
+    assert_eq!(
+        entries_of(dir.path().join("L0").join("5.sst")),
+        hot.iter()
+            .map(|&idx| (key(idx), value(idx)))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        entries_of(dir.path().join("L1").join("4.sst")),
+        (0..100)
+            .filter(|idx| !hot.contains(idx))
+            .map(|idx| (key(idx), value(idx)))
+            .collect::<Vec<_>>()
+    );
+}
+
+fn write_l0_tables(dir: &std::path::Path, tables: usize, keys: usize) {
+    for id in 1..=tables {
+        let mut builder = SsTableBuilder::new(128);
+        for idx in 0..keys {
+            builder.add(
+                format!("key_{:05}", idx).as_bytes(),
+                format!("value_{}_{:010}", id, idx).as_bytes(),
+            );
+        }
+        builder
+            .build_for_test(dir.join(format!("{}.sst", id)))
+            .unwrap();
+    }
+}
+
+#[test]
+fn test_tiering_rejects_tasks_leaving_out_newer_l0_tables() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        background_compaction: false,
+        tiering: Some(TieringOptions {
+            hot_threshold: 1,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    storage.put(Bytes::from("k"), Bytes::from("v1")).unwrap();
+    storage.sync().unwrap();
+    storage.put(Bytes::from("k"), Bytes::from("v2")).unwrap();
+    storage.sync().unwrap();
+    for _ in 0..10 {
+        assert_eq!(storage.get(b"k").unwrap(), Some(Bytes::from("v2")));
+    }
+
+    // the hot output of the older table would land in L0 after the newer one
+    let task = |ids: Vec<usize>| CompactionTask {
+        inputs: BTreeMap::from([(0, ids)]),
+        output_level: 1,
+    };
+    let err = storage.compact_task(&task(vec![0])).unwrap_err();
+    assert!(
+        matches!(err.downcast_ref(), Some(LsmError::InvalidArgument(_))),
+        "{:#}",
+        err
+    );
+    assert_eq!(storage.get(b"k").unwrap(), Some(Bytes::from("v2")));
+
+    storage.compact_task(&task(vec![0, 1])).unwrap();
+    assert_eq!(storage.sst_ids_by_level()[0].len(), 1);
+    assert_eq!(storage.get(b"k").unwrap(), Some(Bytes::from("v2")));
+}
+
+#[test]
+fn test_set_options_changes_compaction_trigger() {
+    let dir = tempdir().unwrap();
+    write_l0_tables(dir.path(), 3, 10);
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(storage.current_options().l0_compaction_trigger, 2);
+    assert_eq!(storage.plan_compaction(None).unwrap().inputs.len(), 3);
+
+    storage
+        .set_options(MutableOptions {
+            l0_compaction_trigger: Some(4),
+            ..Default::default()
+        })
+        .unwrap();
+    assert_eq!(storage.current_options().l0_compaction_trigger, 4);
+    assert!(storage.plan_compaction(None).unwrap().is_empty());
+    // the worker decides against compacting too
+    storage.schedule_compaction().unwrap();
+    let status = wait_for_jobs(&storage, 1);
+    assert_eq!((status.jobs_completed, status.jobs_failed), (1, 0));
+    assert_eq!(storage.metrics().compactions(), 0);
+
+    storage
+        .set_options(
+            MutableOptions::default()
+                .set("l0_compaction_trigger", "3")
+                .unwrap(),
+        )
+        .unwrap();
+    assert_eq!(storage.plan_compaction(None).unwrap().inputs.len(), 3);
+}
+
+/// Merges the two oldest L0 tables into L1, and keeps the tasks it picked.
+#[derive(Default)]
+struct TwoOldestPicker {
+    picked: Mutex<Vec<CompactionTask>>,
+}
+
+impl CompactionPicker for TwoOldestPicker {
+    fn pick(&self, state: &LsmStateDescription) -> anyhow::Result<Option<CompactionTask>> {
+        if state.l0_sstables.len() < 2 {
+            return Ok(None);
+        }
+        let ids = state.l0_sstables[..2].iter().map(|sst| sst.sst_id());
+        let task = CompactionTask {
+            inputs: BTreeMap::from([(0, ids.collect())]),
+            output_level: 1,
+        };
+        self.picked.lock().push(task.clone());
+        Ok(Some(task))
+    }
+}
+
+#[test]
+fn test_custom_compaction_picker() {
+    let dir = tempdir().unwrap();
+    write_l0_tables(dir.path(), 3, 10);
+    let picker = Arc::new(TwoOldestPicker::default());
+    let options = LsmStorageOptions {
+        compaction: CompactionConfig::Custom(picker.clone()),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+
+    storage.schedule_compaction().unwrap();
+    let status = wait_for_jobs(&storage, 1);
+    assert_eq!((status.jobs_completed, status.jobs_failed), (1, 0));
+    let expected = CompactionTask {
+        inputs: BTreeMap::from([(0, vec![1, 2])]),
+        output_level: 1,
+    };
+    assert_eq!(*picker.picked.lock(), vec![expected]);
+    let levels = storage.sst_ids_by_level();
+    assert_eq!(levels[0], vec![3]);
+    assert_eq!(levels[1].len(), 1);
+    assert_eq!(storage.metrics().compactions(), 1);
+    assert_eq!(
+        storage.get(b"key_00004").unwrap(),
+        Some(Bytes::from(format!("value_3_{:010}", 4)))
+    );
+
+    // a single L0 table is left alone
+    storage.schedule_compaction().unwrap();
+    let status = wait_for_jobs(&storage, 2);
+    assert_eq!((status.jobs_completed, status.jobs_failed), (2, 0));
+    assert_eq!(picker.picked.lock().len(), 1);
+    assert_eq!(storage.sst_ids_by_level(), levels);
+
+    // the storage checks the tasks: the newest table alone would move under L1, which holds
+    // older versions of its keys
+    let err = storage
+        .compact_task(&CompactionTask {
+            inputs: BTreeMap::from([(0, vec![3])]),
+            output_level: 2,
+        })
+        .unwrap_err();
+    assert!(
+        matches!(err.downcast_ref(), Some(LsmError::InvalidArgument(_))),
+        "{}",
+        err
+    );
+    storage
+        .compact_task(&CompactionTask {
+            inputs: BTreeMap::from([(0, vec![3]), (1, levels[1].clone())]),
+            output_level: 2,
+        })
+        .unwrap();
+    let levels = storage.sst_ids_by_level();
+    assert!(levels[0].is_empty() && levels[1].is_empty());
+    assert_eq!(levels[2].len(), 1);
+    assert_eq!(
+        storage.get(b"key_00004").unwrap(),
+        Some(Bytes::from(format!("value_3_{:010}", 4)))
+    );
+}
+
+#[test]
+fn test_compaction_streams_into_target_sized_tables() {
+    let dir = tempdir().unwrap();
+    // over a megabyte per table, every one larger than a full memtable
+    write_l0_tables(dir.path(), 3, 40000);
+    let target = 256 << 10;
+    let options = LsmStorageOptions {
+        target_sst_size: target,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+
+    PEAK_BLOCKS_HELD.with(|peak| peak.set(0));
+    storage.compact(0).unwrap();
+    // every block went to disk as soon as it was finished
+    assert_eq!(PEAK_BLOCKS_HELD.with(|peak| peak.get()), 0);
+
+    let levels = storage.sst_ids_by_level();
+    assert!(levels[0].is_empty());
+    assert!(levels[1].len() > 1, "{:?}", levels);
+    let tables = storage.describe_state().levels[0].clone();
+    let mut last_key = Bytes::new();
+    for sst in &tables {
+        assert!(sst.file_size() < (target + target / 4) as u64);
+        let (first, last) = sst.key_range().unwrap().unwrap();
+        assert!(first > last_key, "tables out of order or overlapping");
+        last_key = last;
+    }
+    assert_eq!(
+        tables.iter().map(|sst| sst.sst_id()).collect::<Vec<_>>(),
+        levels[1]
+    );
+
+    let mut iter = storage.scan(..).unwrap();
+    let mut count = 0;
+    while iter.is_valid() {
+        assert_eq!(iter.key(), format!("key_{:05}", count).as_bytes());
+        assert_eq!(iter.value(), format!("value_3_{:010}", count).as_bytes());
+        count += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(count, 40000);
+}
+
+#[test]
+fn test_compact_moves_each_level_into_the_next() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    // every level exists from the start, empty
+    assert_eq!(storage.sst_ids_by_level(), vec![vec![]; NUM_LEVELS]);
+    for (tag, keys) in [("first", 0..60), ("second", 30..90)] {
+        for idx in keys {
+            let key = format!("key_{:03}", idx);
+            storage
+                .put(key.into(), format!("{}_{}", tag, idx).into())
+                .unwrap();
+        }
+        storage.sync().unwrap();
+    }
+    let flushed = storage.sst_ids_by_level()[0].clone();
+    assert_eq!(flushed.len(), 2);
+    let expect_all = |storage: &LsmStorage| {
+        for idx in 0..90 {
+            let tag = if idx < 30 { "first" } else { "second" };
+            assert_eq!(
+                storage.get(format!("key_{:03}", idx).as_bytes()).unwrap(),
+                Some(Bytes::from(format!("{}_{}", tag, idx)))
+            );
+        }
+    };
+
+    storage.compact(0).unwrap();
+    let levels = storage.sst_ids_by_level();
+    assert!(levels[0].is_empty());
+    assert_eq!(levels[1].len(), 1);
+    assert!(!flushed.contains(&levels[1][0]));
+    assert!(levels[2..].iter().all(Vec::is_empty));
+    expect_all(&storage);
+
+    // level x reads level x and writes level x + 1
+    storage.compact(1).unwrap();
+    let moved = storage.sst_ids_by_level();
+    assert!(moved[1].is_empty());
+    assert_eq!(moved[2].len(), 1);
+    expect_all(&storage);
+
+    // an empty level has nothing to compact, and there is no level past the deepest one
+    storage.compact(NUM_LEVELS - 1).unwrap();
+    assert_eq!(storage.sst_ids_by_level(), moved);
+    assert!(storage.compact(NUM_LEVELS).is_err());
+}
+
+#[test]
+fn test_compaction_deletes_its_inputs_once_unread() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    for (tag, keys) in [("first", 0..60), ("second", 30..90)] {
+        for idx in keys {
+            let key = format!("key_{:03}", idx);
+            storage
+                .put(key.into(), format!("{}_{}", tag, idx).into())
+                .unwrap();
+        }
+        storage.sync().unwrap();
+    }
+    let flushed = storage.sst_ids_by_level()[0]
+        .iter()
+        .map(|id| dir.path().join(format!("{}.sst", id)))
+        .collect::<Vec<_>>();
+    assert_eq!(flushed.len(), 2);
+
+    // an iterator opened before the compaction reads on from the inputs
+    let mut iter = storage.scan(..).unwrap();
+    storage.compact(0).unwrap();
+    storage.purge_obsolete_files().unwrap();
+    assert!(flushed.iter().all(|path| path.exists()));
+    let mut count = 0;
+    while iter.is_valid() {
+        let tag = if count < 30 { "first" } else { "second" };
+        assert_eq!(iter.key(), format!("key_{:03}", count).as_bytes());
+        assert_eq!(iter.value(), format!("{}_{}", tag, count).as_bytes());
+        count += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(count, 90);
+
+    drop(iter);
+    storage.purge_obsolete_files().unwrap();
+    assert!(flushed.iter().all(|path| !path.exists()));
+    let output = storage.sst_ids_by_level()[1][0];
+    assert!(dir.path().join(format!("{}.sst", output)).exists());
+    assert_eq!(
+        storage.get(b"key_045").unwrap(),
+        Some(Bytes::from("second_45"))
+    );
+}
+
+#[test]
+fn test_flushes_during_compaction_stay_in_l0() {
+    let dir = tempdir().unwrap();
+    let write_sst = |path: std::path::PathBuf, keys: std::ops::Range<usize>| {
+        let mut builder = SsTableBuilder::new(128);
+        for idx in keys {
+            builder.add(
+                format!("key_{:03}", idx).as_bytes(),
+                format!("value_{:010}", idx).as_bytes(),
+            );
+        }
+        builder.build_for_test(path).unwrap();
+    };
+    std::fs::create_dir(dir.path().join("L0")).unwrap();
+    write_sst(dir.path().join("L0").join("1.sst"), 0..300);
+    write_sst(dir.path().join("L0").join("2.sst"), 200..500);
+    let options = LsmStorageOptions {
+        sst_layout: SstLayout::LevelDirs,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+
+    let compaction = {
+        let storage = storage.clone();
+        std::thread::spawn(move || {
+            READ_LATENCY.with(|latency| latency.set(Duration::from_millis(2)));
+            storage.compact(0)
+        })
+    };
+    std::thread::sleep(Duration::from_millis(20));
+    for round in 0..3 {
+        for idx in 0..20 {
+            let key = format!("flushed_{}_{:02}", round, idx);
+            storage.put(key.into(), Bytes::from("value")).unwrap();
+        }
+        storage.sync().unwrap();
+    }
+    // all three flushes landed while the merge was still reading
+    let flushed = storage.sst_ids_by_level()[0][2..].to_vec();
+    assert_eq!(flushed.len(), 3);
+    assert!(!compaction.is_finished());
+    compaction.join().unwrap().unwrap();
+
+    let check = |storage: &LsmStorage| {
+        let levels = storage.sst_ids_by_level();
+        assert_eq!(levels[0], flushed);
+        assert_eq!(levels[1].len(), 1);
+        for idx in 0..500 {
+            assert!(storage
+                .get(format!("key_{:03}", idx).as_bytes())
+                .unwrap()
+                .is_some());
+        }
+        for round in 0..3 {
+            for idx in 0..20 {
+                let key = format!("flushed_{}_{:02}", round, idx);
+                assert_eq!(
+                    storage.get(key.as_bytes()).unwrap(),
+                    Some(Bytes::from("value"))
+                );
+            }
+        }
+    };
+    check(&storage);
+    drop(storage);
+    check(&LsmStorage::open_with_options(&dir, options).unwrap());
+}
+
+/// Whether the tables of every level below L0 are sorted and keep to key ranges of their own.
+fn levels_disjoint(storage: &LsmStorage) -> bool {
+    storage.describe_state().levels.iter().all(|tables| {
+        let ranges = tables
+            .iter()
+            .map(|sst| sst.key_range().unwrap().unwrap())
+            .collect::<Vec<_>>();
+        ranges.windows(2).all(|pair| pair[0].1 < pair[1].0)
+    })
+}
+
+#[test]
+fn test_leveled_compaction_keeps_levels_disjoint() {
+    for seed in 0..4 {
+        let dir = tempdir().unwrap();
+        let options = LsmStorageOptions {
+            target_sst_size: 4096,
+            ..Default::default()
+        };
+        let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+        assert_eq!(storage.sst_ids_by_level().len(), NUM_LEVELS);
+        let spec = WorkloadSpec {
+            key_count: 10000,
+            key_len: 8,
+            op_mix: OpMix {
+                put: 1,
+                delete: 0,
+                get: 0,
+                scan: 0,
+            },
+            ..Default::default()
+        };
+        let ops = WorkloadGen::new(seed, spec).take(1000).collect::<Vec<_>>();
+        let mut model = BTreeMap::new();
+        for (round, chunk) in ops.chunks(100).enumerate() {
+            apply(&storage, chunk.to_vec()).unwrap();
+            apply_to_model(&mut model, chunk.to_vec());
+            storage.sync().unwrap();
+            while let Some(task) = LeveledPicker.pick(&storage.describe_state()).unwrap() {
+                storage.compact_task(&task).unwrap();
+                assert!(levels_disjoint(&storage), "seed {} after {:?}", seed, task);
+            }
+            // and a table from somewhere in the middle
+            storage.compact_level_to_next(1 + round % 3).unwrap();
+            assert!(levels_disjoint(&storage), "seed {}", seed);
+        }
+
+        let levels = storage.sst_ids_by_level();
+        assert!(levels[2..].iter().any(|tables| !tables.is_empty()));
+        let mut iter = storage.scan(..).unwrap();
+        for (key, value) in &model {
+            assert_eq!((iter.key(), iter.value()), (key, value));
+            iter.next().unwrap();
+        }
+        assert!(!iter.is_valid());
+    }
+}
+
+#[test]
+fn test_set_options_validates() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    let before = storage.current_options();
+
+    let err = MutableOptions::default()
+        .set("comparator", "reverse")
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<LsmError>(),
+        Some(&LsmError::ImmutableOption("comparator".to_string()))
+    );
+    for (name, value) in [("no_such_option", "1"), ("max_batch_bytes", "lots")] {
+        let err = MutableOptions::default().set(name, value).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LsmError>(),
+            Some(LsmError::InvalidArgument(_))
+        ));
+    }
+
+    // one invalid value and nothing changes
+    let delta = MutableOptions::default()
+        .set("max_batch_bytes", "100")
+        .unwrap()
+        .set("soft_max_files", "0")
+        .unwrap();
+    assert!(storage.set_options(delta).is_err());
+    assert_eq!(
+        storage.current_options().max_batch_bytes,
+        before.max_batch_bytes
+    );
+
+    let delta = MutableOptions::default()
+        .set("soft_max_files", "none")
+        .unwrap()
+        .set("max_batch_bytes", "100")
+        .unwrap();
+    storage.set_options(delta).unwrap();
+    assert_eq!(storage.current_options().max_batch_bytes, 100);
+    assert_eq!(storage.current_options().soft_max_files, None);
+}
+
+#[test]
+fn test_set_options_shrinks_block_cache() {
+    let dir = tempdir().unwrap();
+    write_l0_tables(dir.path(), 1, 1000);
+    let options = LsmStorageOptions {
+        block_cache_capacity: 256,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    let scan = || {
+        let mut iter = storage.scan(..).unwrap();
+        while iter.is_valid() {
+            iter.next().unwrap();
+        }
+    };
+    let cache_converges_to = |capacity: u64| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while storage.block_cache().entry_count() > capacity {
+            assert!(Instant::now() < deadline, "the cache did not shrink");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    };
+    scan();
+    assert!(storage.block_cache().entry_count() > 64);
+
+    storage
+        .set_options(MutableOptions {
+            block_cache_capacity: Some(16),
+            ..Default::default()
+        })
+        .unwrap();
+    cache_converges_to(16);
+    // the blocks read since are evicted in the background
+    scan();
+    cache_converges_to(16);
+    assert_eq!(storage.current_options().block_cache_capacity, 16);
+}
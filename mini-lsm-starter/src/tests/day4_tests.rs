@@ -3,7 +3,7 @@ use std::ops::Bound;
 use bytes::Bytes;
 use tempfile::tempdir;
 
-use crate::iterators::StorageIterator;
+use crate::prelude::{LsmStorage, StorageIterator};
 
 fn __(x: &[u8]) -> Bytes {
     Bytes::copy_from_slice(x)
@@ -34,7 +34,6 @@ fn check_iter_result(iter: impl StorageIterator, expected: Vec<(Bytes, Bytes)>)
 
 #[test]
 fn test_storage_get() {
-    use crate::lsm_storage::LsmStorage;
     let dir = tempdir().unwrap();
     let storage = LsmStorage::open(&dir).unwrap();
     storage.put(__(b"1"), __(b"233")).unwrap();
@@ -49,7 +48,6 @@ fn test_storage_get() {
 
 #[test]
 fn test_storage_scan_memtable_1() {
-    use crate::lsm_storage::LsmStorage;
     let dir = tempdir().unwrap();
     let storage = LsmStorage::open(&dir).unwrap();
     storage.put(__(b"1"), __(b"233")).unwrap();
@@ -57,21 +55,19 @@ fn test_storage_scan_memtable_1() {
     storage.put(__(b"3"), __(b"23333")).unwrap();
     storage.delete(b"2").unwrap();
     check_iter_result(
-        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        storage.scan(..).unwrap(),
         vec![
             (Bytes::from("1"), Bytes::from("233")),
             (Bytes::from("3"), Bytes::from("23333")),
         ],
     );
     check_iter_result(
-        storage
-            .scan(Bound::Included(b"1"), Bound::Included(b"2"))
-            .unwrap(),
+        storage.scan(b"1"..=b"2").unwrap(),
         vec![(Bytes::from("1"), Bytes::from("233"))],
     );
     check_iter_result(
         storage
-            .scan(Bound::Excluded(b"1"), Bound::Excluded(b"3"))
+            .scan((Bound::Excluded(&b"1"[..]), Bound::Excluded(&b"3"[..])))
             .unwrap(),
         vec![],
     );
@@ -79,7 +75,6 @@ fn test_storage_scan_memtable_1() {
 
 #[test]
 fn test_storage_scan_memtable_2() {
-    use crate::lsm_storage::LsmStorage;
     let dir = tempdir().unwrap();
     let storage = LsmStorage::open(&dir).unwrap();
     storage.put(__(b"1"), __(b"233")).unwrap();
@@ -87,21 +82,19 @@ fn test_storage_scan_memtable_2() {
     storage.put(__(b"3"), __(b"23333")).unwrap();
     storage.delete(b"1").unwrap();
     check_iter_result(
-        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        storage.scan(..).unwrap(),
         vec![
             (Bytes::from("2"), Bytes::from("2333")),
             (Bytes::from("3"), Bytes::from("23333")),
         ],
     );
     check_iter_result(
-        storage
-            .scan(Bound::Included(b"1"), Bound::Included(b"2"))
-            .unwrap(),
+        storage.scan(b"1"..=b"2").unwrap(),
         vec![(Bytes::from("2"), Bytes::from("2333"))],
     );
     check_iter_result(
         storage
-            .scan(Bound::Excluded(b"1"), Bound::Excluded(b"3"))
+            .scan((Bound::Excluded(&b"1"[..]), Bound::Excluded(&b"3"[..])))
             .unwrap(),
         vec![(Bytes::from("2"), Bytes::from("2333"))],
     );
@@ -109,7 +102,6 @@ fn test_storage_scan_memtable_2() {
 
 #[test]
 fn test_storage_get_after_sync() {
-    use crate::lsm_storage::LsmStorage;
     let dir = tempdir().unwrap();
     let storage = LsmStorage::open(&dir).unwrap();
     storage.put(__(b"1"), __(b"233")).unwrap();
@@ -125,7 +117,6 @@ fn test_storage_get_after_sync() {
 
 #[test]
 fn test_storage_scan_memtable_1_after_sync() {
-    use crate::lsm_storage::LsmStorage;
     let dir = tempdir().unwrap();
     let storage = LsmStorage::open(&dir).unwrap();
     storage.put(__(b"1"), __(b"233")).unwrap();
@@ -134,21 +125,19 @@ fn test_storage_scan_memtable_1_after_sync() {
     storage.put(__(b"3"), __(b"23333")).unwrap();
     storage.delete(b"2").unwrap();
     check_iter_result(
-        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        storage.scan(..).unwrap(),
         vec![
             (Bytes::from("1"), Bytes::from("233")),
             (Bytes::from("3"), Bytes::from("23333")),
         ],
     );
     check_iter_result(
-        storage
-            .scan(Bound::Included(b"1"), Bound::Included(b"2"))
-            .unwrap(),
+        storage.scan(b"1"..=b"2").unwrap(),
         vec![(Bytes::from("1"), Bytes::from("233"))],
     );
     check_iter_result(
         storage
-            .scan(Bound::Excluded(b"1"), Bound::Excluded(b"3"))
+            .scan((Bound::Excluded(&b"1"[..]), Bound::Excluded(&b"3"[..])))
             .unwrap(),
         vec![],
     );
@@ -156,7 +145,6 @@ fn test_storage_scan_memtable_1_after_sync() {
 
 #[test]
 fn test_storage_scan_memtable_2_after_sync() {
-    use crate::lsm_storage::LsmStorage;
     let dir = tempdir().unwrap();
     let storage = LsmStorage::open(&dir).unwrap();
     storage.put(__(b"1"), __(b"233")).unwrap();
@@ -166,22 +154,37 @@ fn test_storage_scan_memtable_2_after_sync() {
     storage.sync().unwrap();
     storage.delete(b"1").unwrap();
     check_iter_result(
-        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        storage.scan(..).unwrap(),
         vec![
             (Bytes::from("2"), Bytes::from("2333")),
             (Bytes::from("3"), Bytes::from("23333")),
         ],
     );
     check_iter_result(
-        storage
-            .scan(Bound::Included(b"1"), Bound::Included(b"2"))
-            .unwrap(),
+        storage.scan(b"1"..=b"2").unwrap(),
         vec![(Bytes::from("2"), Bytes::from("2333"))],
     );
     check_iter_result(
         storage
-            .scan(Bound::Excluded(b"1"), Bound::Excluded(b"3"))
+            .scan((Bound::Excluded(&b"1"[..]), Bound::Excluded(&b"3"[..])))
             .unwrap(),
         vec![(Bytes::from("2"), Bytes::from("2333"))],
     );
 }
+
+#[test]
+fn test_storage_scan_bytes() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(__(b"1"), __(b"233")).unwrap();
+    storage.put(__(b"2"), __(b"2333")).unwrap();
+    storage.sync().unwrap();
+    storage.put(__(b"3"), __(b"23333")).unwrap();
+    storage.delete(b"2").unwrap();
+    let expected = vec![
+        (Bytes::from("1"), Bytes::from("233")),
+        (Bytes::from("3"), Bytes::from("23333")),
+    ];
+    check_iter_result(storage.scan(b"1"..=b"3").unwrap(), expected.clone());
+    check_iter_result(storage.scan(__(b"1")..=__(b"3")).unwrap(), expected);
+}
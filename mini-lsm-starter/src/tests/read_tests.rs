@@ -0,0 +1,1634 @@
+use std::ops::Bound;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::hotset;
+use crate::key::{max_seq_key, InternalKey, ValueKind};
+use crate::lsm_iterator::LAST_NEXT_SKIPPED;
+use crate::mem_table::MEMTABLE_SCANS;
+use crate::prelude::{
+    CancellationToken, EntryOp, KeyRange, LsmError, LsmStorage, LsmStorageOptions, PrefixExtractor,
+    RawScanIter, ReadOptions, RecoveryMode, ResumeToken, SnapshotAge, StorageIterator,
+    ValueLocation, WriteBatch,
+};
+use crate::retention::FileId;
+use crate::table::{
+    FileObject, SsTable, SsTableBuilder, CACHE_INSERTS, FILES_READ, KEY_FILTER_PROBES,
+    READ_LATENCY, READ_LENGTHS, SCAN_FILL_BATCH,
+};
+
+fn key_of(idx: usize) -> Bytes {
+    Bytes::from(format!("key_{:03}", idx))
+}
+
+fn value_of(tag: &str, idx: usize) -> Bytes {
+    Bytes::from(format!("{}_{:010}", tag, idx))
+}
+
+fn write_sst(path: &std::path::Path, keys: impl IntoIterator<Item = usize>, tag: &str) {
+    let mut builder = SsTableBuilder::new(128);
+    for idx in keys {
+        builder.add(&key_of(idx), &value_of(tag, idx));
+    }
+    builder.build_for_test(path).unwrap();
+}
+
+fn is_deadline_exceeded(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<LsmError>() == Some(&LsmError::DeadlineExceeded)
+}
+
+#[test]
+fn test_scan_resumes_after_deadline() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), 0..200, "value");
+    let storage = LsmStorage::open(&dir).unwrap();
+
+    READ_LATENCY.with(|latency| latency.set(Duration::from_millis(5)));
+    let options = ReadOptions {
+        fill_cache: false,
+        ..Default::default()
+    };
+    let mut iter = storage.scan_opt(.., options).unwrap();
+    iter.set_deadline(Some(Instant::now() + Duration::from_millis(30)));
+
+    let mut keys = vec![];
+    let mut timed_out = false;
+    while iter.is_valid() {
+        keys.push(iter.key().clone());
+        if let Err(err) = iter.next() {
+            assert!(is_deadline_exceeded(&err), "{:?}", err);
+            timed_out = true;
+            break;
+        }
+    }
+    assert!(timed_out);
+    assert!(keys.len() < 200);
+
+    // the failed call did not move the iterator, so nothing is skipped or repeated
+    iter.set_deadline(None);
+    iter.next().unwrap();
+    while iter.is_valid() {
+        keys.push(iter.key().clone());
+        iter.next().unwrap();
+    }
+    READ_LATENCY.with(|latency| latency.set(Duration::ZERO));
+    assert_eq!(keys, (0..200).map(key_of).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_get_checks_deadline_only_on_disk() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), 0..20, "value");
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(Bytes::from("a"), Bytes::from("1")).unwrap();
+
+    let expired = ReadOptions {
+        deadline: Some(Instant::now()),
+        ..Default::default()
+    };
+    let err = storage.get_opt(&key_of(0), expired.clone()).unwrap_err();
+    assert!(is_deadline_exceeded(&err), "{:?}", err);
+    assert_eq!(
+        storage.get_opt(b"a", expired).unwrap(),
+        Some(Bytes::from("1"))
+    );
+    assert_eq!(storage.get(&key_of(0)).unwrap(), Some(value_of("value", 0)));
+}
+
+#[test]
+fn test_get_absent_keys() {
+    let dir = tempdir().unwrap();
+    // no key filters, so that every lookup within the range of a table reads its block
+    let mut builder = SsTableBuilder::new(128).without_key_filter();
+    for idx in (10..50).step_by(2) {
+        builder.add(&key_of(idx), &value_of("old", idx));
+    }
+    builder.build_for_test(dir.path().join("1.sst")).unwrap();
+    let mut builder = SsTableBuilder::new(128).without_key_filter();
+    for idx in (20..30).chain([40]) {
+        let value = if idx == 40 {
+            Bytes::new()
+        } else {
+            value_of("new", idx)
+        };
+        builder.add(&key_of(idx), &value);
+    }
+    builder.build_for_test(dir.path().join("2.sst")).unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+
+    // before the first key, after the last one, and between the keys of the older table
+    assert_eq!(storage.get(&key_of(5)).unwrap(), None);
+    assert_eq!(storage.get(&key_of(60)).unwrap(), None);
+    assert_eq!(storage.get(&key_of(11)).unwrap(), None);
+    assert_eq!(storage.get(&key_of(49)).unwrap(), None);
+    // within the range of the newer table, but only in the older one, or in neither
+    assert_eq!(storage.get(&key_of(32)).unwrap(), Some(value_of("old", 32)));
+    assert_eq!(storage.get(&key_of(33)).unwrap(), None);
+    assert_eq!(storage.get(&key_of(21)).unwrap(), Some(value_of("new", 21)));
+    // the tombstone in the newer table hides the value in the older one
+    assert_eq!(storage.get(&key_of(40)).unwrap(), None);
+    assert_eq!(storage.get(&key_of(42)).unwrap(), Some(value_of("old", 42)));
+}
+
+#[test]
+fn test_get_many() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), 0..100, "older");
+    write_sst(&dir.path().join("2.sst"), (0..100).step_by(2), "newer");
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(key_of(3), Bytes::from("mem")).unwrap();
+    storage.delete(&key_of(4)).unwrap();
+    storage.delete(&key_of(5)).unwrap();
+
+    let keys = [
+        key_of(99),
+        key_of(3),
+        key_of(4),
+        key_of(5),
+        Bytes::from("missing"),
+        key_of(10),
+        key_of(0),
+        key_of(99),
+    ];
+    let keys = keys.iter().map(|key| key.as_ref()).collect::<Vec<_>>();
+    assert_eq!(
+        storage.get_many(&keys).unwrap(),
+        vec![
+            Some(value_of("older", 99)),
+            Some(Bytes::from("mem")),
+            None,
+            None,
+            None,
+            Some(value_of("newer", 10)),
+            Some(value_of("newer", 0)),
+            Some(value_of("older", 99)),
+        ]
+    );
+    assert!(storage.get_many(&[]).unwrap().is_empty());
+}
+
+#[test]
+fn test_get_honors_tombstones_in_newer_l0_tables() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        background_compaction: false,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    storage.put(Bytes::from("k"), Bytes::from("v")).unwrap();
+    storage.put(Bytes::from("other"), Bytes::from("v")).unwrap();
+    storage.sync().unwrap();
+    storage.delete(b"k").unwrap();
+    storage.sync().unwrap();
+    assert_eq!(storage.sst_ids_by_level()[0].len(), 2);
+
+    assert_eq!(storage.get(b"k").unwrap(), None);
+    assert_eq!(
+        storage.get_many(&[b"k", b"other"]).unwrap(),
+        vec![None, Some(Bytes::from("v"))]
+    );
+    drop(storage);
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert_eq!(storage.get(b"k").unwrap(), None);
+}
+
+#[test]
+fn test_snapshot_reads_hold_still() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), 0..10, "table");
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(key_of(1), value_of("mem", 1)).unwrap();
+    storage.delete(&key_of(2)).unwrap();
+    let snapshot = storage.get_snapshot();
+    let scan = |iter: &mut dyn StorageIterator| {
+        let mut entries = vec![];
+        while iter.is_valid() {
+            entries.push((Bytes::copy_from_slice(iter.key()), iter.value().to_vec()));
+            iter.next().unwrap();
+        }
+        entries
+    };
+    let before = scan(&mut snapshot.scan(..).unwrap());
+    assert_eq!(before.len(), 9);
+
+    // writes, a flush and a compaction after the snapshot go unseen
+    storage.put(key_of(0), value_of("new", 0)).unwrap();
+    storage.put(key_of(2), value_of("new", 2)).unwrap();
+    storage.delete(&key_of(3)).unwrap();
+    storage.put(key_of(10), value_of("new", 10)).unwrap();
+    storage.sync().unwrap();
+    storage.compact(0).unwrap();
+    storage.purge_obsolete_files().unwrap();
+    assert!(dir.path().join("1.sst").exists());
+    assert_eq!(
+        snapshot.get(&key_of(0)).unwrap(),
+        Some(value_of("table", 0))
+    );
+    assert_eq!(snapshot.get(&key_of(1)).unwrap(), Some(value_of("mem", 1)));
+    assert_eq!(snapshot.get(&key_of(2)).unwrap(), None);
+    assert_eq!(
+        snapshot.get(&key_of(3)).unwrap(),
+        Some(value_of("table", 3))
+    );
+    assert_eq!(snapshot.get(&key_of(10)).unwrap(), None);
+    let lower = key_of(1);
+    assert_eq!(scan(&mut snapshot.scan(&lower..).unwrap()), before[1..]);
+
+    drop(snapshot);
+    storage.purge_obsolete_files().unwrap();
+    assert!(!dir.path().join("1.sst").exists());
+    assert_eq!(storage.get(&key_of(0)).unwrap(), Some(value_of("new", 0)));
+    assert_eq!(storage.get(&key_of(2)).unwrap(), Some(value_of("new", 2)));
+    assert_eq!(storage.get(&key_of(3)).unwrap(), None);
+    assert_eq!(storage.get(&key_of(10)).unwrap(), Some(value_of("new", 10)));
+}
+
+#[test]
+fn test_snapshot_get_many_holds_still() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), 0..10, "table");
+    let storage = LsmStorage::open(&dir).unwrap();
+    for idx in 10..20 {
+        storage.put(key_of(idx), value_of("mem", idx)).unwrap();
+    }
+    let snapshot = storage.get_snapshot();
+    let keys = (0..20).map(key_of).collect::<Vec<_>>();
+    let keys = keys.iter().map(|key| &key[..]).collect::<Vec<_>>();
+    let before = snapshot.get_many(&keys).unwrap();
+    assert_eq!(before, storage.get_many(&keys).unwrap());
+
+    // half of the keys, in the table and the memtable alike, change after the snapshot
+    for idx in (0..20).step_by(2) {
+        match idx % 4 {
+            0 => storage.put(key_of(idx), value_of("new", idx)).unwrap(),
+            _ => storage.delete(&key_of(idx)).unwrap(),
+        };
+    }
+    storage.sync().unwrap();
+    storage.compact(0).unwrap();
+    assert_ne!(storage.get_many(&keys).unwrap(), before);
+    assert_eq!(snapshot.get_many(&keys).unwrap(), before);
+    for (idx, value) in before.iter().enumerate() {
+        let tag = if idx < 10 { "table" } else { "mem" };
+        assert_eq!(value, &Some(value_of(tag, idx)));
+    }
+}
+
+#[test]
+fn test_snapshots_copy_small_memtables() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    let snapshots = (0..10)
+        .map(|idx| {
+            storage.put(key_of(idx), value_of("mem", idx)).unwrap();
+            storage.get_snapshot()
+        })
+        .collect::<Vec<_>>();
+    for (taken, snapshot) in snapshots.iter().enumerate() {
+        for idx in 0..10 {
+            let expected = (idx <= taken).then(|| value_of("mem", idx));
+            assert_eq!(snapshot.get(&key_of(idx)).unwrap(), expected);
+        }
+    }
+    // the snapshots left the memtable in place, so it flushes as a single table
+    storage.sync().unwrap();
+    assert_eq!(storage.sst_ids_by_level()[0].len(), 1);
+
+    // a large memtable is frozen instead
+    let large = Bytes::from(vec![b'x'; 40 << 10]);
+    storage.put(key_of(10), large.clone()).unwrap();
+    storage.put(key_of(11), large.clone()).unwrap();
+    let snapshot = storage.get_snapshot();
+    storage.put(key_of(12), large.clone()).unwrap();
+    assert_eq!(snapshot.get(&key_of(11)).unwrap(), Some(large));
+    assert_eq!(snapshot.get(&key_of(12)).unwrap(), None);
+    storage.sync().unwrap();
+    assert_eq!(storage.sst_ids_by_level()[0].len(), 3);
+}
+
+#[test]
+fn test_get_from_lower_levels() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    for (tag, keys) in [("first", 0..40), ("second", 20..60), ("third", 40..80)] {
+        for idx in keys.step_by(2) {
+            storage.put(key_of(idx), value_of(tag, idx)).unwrap();
+        }
+        storage.sync().unwrap();
+    }
+    storage.delete(&key_of(50)).unwrap();
+    storage.sync().unwrap();
+    storage.compact(0).unwrap();
+    let levels = storage.sst_ids_by_level();
+    assert!(levels[0].is_empty());
+    assert_eq!(levels[1].len(), 1);
+
+    // a second table in L1, and a newer version of a key of the first one in L0
+    for idx in 100..110 {
+        storage.put(key_of(idx), value_of("fourth", idx)).unwrap();
+    }
+    storage.sync().unwrap();
+    storage.compact(0).unwrap();
+    assert_eq!(storage.sst_ids_by_level()[1].len(), 2);
+    storage.put(key_of(10), value_of("fifth", 10)).unwrap();
+    storage.sync().unwrap();
+
+    let expected = |idx: usize| match idx {
+        10 => Some(value_of("fifth", 10)),
+        50 => None,
+        idx if (100..110).contains(&idx) => Some(value_of("fourth", idx)),
+        idx if idx % 2 == 0 && idx < 20 => Some(value_of("first", idx)),
+        idx if idx % 2 == 0 && idx < 40 => Some(value_of("second", idx)),
+        idx if idx % 2 == 0 && idx < 80 => Some(value_of("third", idx)),
+        _ => None,
+    };
+    // present keys, keys between them, past the last table and between the tables of L1
+    for idx in (0..90).chain(100..120) {
+        assert_eq!(storage.get(&key_of(idx)).unwrap(), expected(idx), "{}", idx);
+    }
+    let keys = (0..120).map(key_of).collect::<Vec<_>>();
+    let keys = keys.iter().map(|key| key.as_ref()).collect::<Vec<_>>();
+    assert_eq!(
+        storage.get_many(&keys).unwrap(),
+        (0..120).map(expected).collect::<Vec<_>>()
+    );
+
+    let mut iter = storage.scan(..).unwrap();
+    let mut scanned = vec![];
+    while iter.is_valid() {
+        scanned.push((iter.key().clone(), iter.value().clone()));
+        iter.next().unwrap();
+    }
+    let present = (0..120).filter_map(|idx| expected(idx).map(|value| (key_of(idx), value)));
+    assert_eq!(scanned, present.collect::<Vec<_>>());
+}
+
+#[test]
+fn test_scan_lower_levels() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    for (tag, keys) in [("first", 0..60), ("second", 30..90)] {
+        for idx in keys {
+            storage.put(key_of(idx), value_of(tag, idx)).unwrap();
+        }
+        storage.sync().unwrap();
+    }
+    storage.compact(0).unwrap();
+    // a second table in L1, past the first one
+    for idx in 100..130 {
+        storage.put(key_of(idx), value_of("third", idx)).unwrap();
+    }
+    storage.sync().unwrap();
+    storage.compact(0).unwrap();
+    let levels = storage.sst_ids_by_level();
+    assert!(levels[0].is_empty());
+    assert_eq!(levels[1].len(), 2);
+    // and newer versions in L0 and the memtable
+    storage.put(key_of(5), value_of("fourth", 5)).unwrap();
+    storage.sync().unwrap();
+    storage.delete(&key_of(110)).unwrap();
+
+    let expected = |idx: usize| match idx {
+        5 => Some(value_of("fourth", 5)),
+        110 => None,
+        idx if idx < 30 => Some(value_of("first", idx)),
+        idx if idx < 90 => Some(value_of("second", idx)),
+        idx if (100..130).contains(&idx) => Some(value_of("third", idx)),
+        _ => None,
+    };
+    let scan = |lower: Bound<usize>, upper: Bound<usize>| {
+        let (lower, upper) = (lower.map(key_of), upper.map(key_of));
+        let mut iter = storage
+            .scan((
+                lower.as_ref().map(|key| &key[..]),
+                upper.as_ref().map(|key| &key[..]),
+            ))
+            .unwrap();
+        let mut scanned = vec![];
+        while iter.is_valid() {
+            scanned.push((iter.key().clone(), iter.value().clone()));
+            iter.next().unwrap();
+        }
+        scanned
+    };
+    let present = |range: std::ops::Range<usize>| {
+        range
+            .filter_map(|idx| expected(idx).map(|value| (key_of(idx), value)))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(scan(Bound::Unbounded, Bound::Unbounded), present(0..130));
+    // from the middle of the first table of L1 into the second one, and within each
+    assert_eq!(
+        scan(Bound::Included(40), Bound::Excluded(115)),
+        present(40..115)
+    );
+    assert_eq!(
+        scan(Bound::Excluded(3), Bound::Included(20)),
+        present(4..21)
+    );
+    assert_eq!(
+        scan(Bound::Included(105), Bound::Unbounded),
+        present(105..130)
+    );
+    // between the tables of L1
+    assert_eq!(scan(Bound::Included(92), Bound::Excluded(100)), vec![]);
+}
+
+#[test]
+fn test_scan_with_degenerate_bounds() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), 0..100, "value");
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(key_of(50), value_of("fresh", 50)).unwrap();
+
+    let key = key_of(50);
+    let (lo, hi) = (key_of(20), key_of(30));
+    let inverted = [
+        (Bound::Included(&hi[..]), Bound::Included(&lo[..])),
+        (Bound::Included(&hi[..]), Bound::Excluded(&lo[..])),
+        (Bound::Excluded(&hi[..]), Bound::Included(&lo[..])),
+        (Bound::Excluded(&hi[..]), Bound::Excluded(&lo[..])),
+    ];
+    for (lower, upper) in inverted {
+        let err = storage.scan((lower, upper)).err().unwrap();
+        assert!(
+            matches!(
+                err.downcast_ref::<LsmError>(),
+                Some(LsmError::InvalidArgument(_))
+            ),
+            "{:?}",
+            err
+        );
+
+        let options = ReadOptions {
+            empty_on_inverted_bounds: true,
+            ..Default::default()
+        };
+        assert!(!storage
+            .scan_opt((lower, upper), options)
+            .unwrap()
+            .is_valid());
+    }
+
+    // an expired deadline fails any block read, so these never touch the SST
+    let expired = ReadOptions {
+        deadline: Some(Instant::now()),
+        ..Default::default()
+    };
+    let empty = [
+        (Bound::Included(&key[..]), Bound::Excluded(&key[..])),
+        (Bound::Excluded(&key[..]), Bound::Included(&key[..])),
+        (Bound::Excluded(&key[..]), Bound::Excluded(&key[..])),
+    ];
+    for (lower, upper) in empty {
+        let mut iter = storage.scan_opt((lower, upper), expired.clone()).unwrap();
+        assert!(!iter.is_valid());
+        iter.next().unwrap();
+        assert!(!iter.is_valid());
+    }
+    let err = storage
+        .scan_opt(&key[..]..=&key[..], expired)
+        .err()
+        .unwrap();
+    assert!(is_deadline_exceeded(&err), "{:?}", err);
+
+    let mut iter = storage.scan(&key[..]..=&key[..]).unwrap();
+    assert_eq!(iter.key(), &key);
+    assert_eq!(iter.value(), &value_of("fresh", 50));
+    iter.next().unwrap();
+    assert!(!iter.is_valid());
+}
+
+/// The keys a scan yields, or that it failed with an invalid argument.
+fn scanned(iter: anyhow::Result<impl StorageIterator>) -> Result<Vec<Bytes>, bool> {
+    let mut iter = iter.map_err(|err| {
+        matches!(
+            err.downcast_ref::<LsmError>(),
+            Some(LsmError::InvalidArgument(_))
+        )
+    })?;
+    let mut keys = vec![];
+    while iter.is_valid() {
+        keys.push(iter.key().clone());
+        iter.next().unwrap();
+    }
+    Ok(keys)
+}
+
+#[test]
+#[allow(deprecated)]
+fn test_bound_pair_scans_match_key_range_scans() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), 0..100, "value");
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(key_of(50), value_of("fresh", 50)).unwrap();
+    storage.delete(&key_of(20)).unwrap();
+    let snapshot = storage.get_snapshot();
+
+    let (lo, hi) = (key_of(10), key_of(60));
+    let (lo, hi) = (&lo[..], &hi[..]);
+    let bounds = [
+        (Bound::Unbounded, Bound::Unbounded),
+        (Bound::Included(lo), Bound::Excluded(hi)),
+        (Bound::Excluded(lo), Bound::Included(hi)),
+        (Bound::Included(lo), Bound::Unbounded),
+        (Bound::Unbounded, Bound::Included(lo)),
+        (Bound::Included(lo), Bound::Excluded(lo)),
+        (Bound::Included(hi), Bound::Included(lo)),
+    ];
+    for (lower, upper) in bounds {
+        let range = KeyRange::from((lower, upper));
+        let expected = scanned(storage.scan(range.clone()));
+        let owned = (
+            lower.map(Bytes::copy_from_slice),
+            upper.map(Bytes::copy_from_slice),
+        );
+        let shims = [
+            scanned(storage.scan_bounds(lower, upper)),
+            scanned(storage.scan_bytes(owned.0, owned.1)),
+            scanned(storage.scan_bounds_opt(lower, upper, ReadOptions::default())),
+            scanned(storage.scan_opt(range.clone(), ReadOptions::default())),
+        ];
+        for keys in shims {
+            assert_eq!(keys, expected, "{:?}", range);
+        }
+        let raw = |iter: anyhow::Result<RawScanIter>| {
+            iter.map(|iter| iter.map(Result::unwrap).collect::<Vec<_>>())
+                .map_err(|err| err.to_string())
+        };
+        assert_eq!(
+            raw(storage.scan_raw_bounds(lower, upper)),
+            raw(storage.scan_raw(range.clone())),
+            "{:?}",
+            range
+        );
+        assert_eq!(
+            scanned(snapshot.scan_bounds(lower, upper)),
+            scanned(snapshot.scan(range.clone())),
+            "{:?}",
+            range
+        );
+        assert_eq!(
+            scanned(snapshot.scan(range.clone())),
+            expected,
+            "{:?}",
+            range
+        );
+    }
+    assert_eq!(
+        scanned(storage.scan(..)),
+        scanned(storage.scan(KeyRange::all()))
+    );
+    assert_eq!(
+        scanned(storage.scan(key_of(10)..key_of(60))),
+        scanned(storage.scan((Bound::Included(lo), Bound::Excluded(hi))))
+    );
+    assert_eq!(
+        scanned(storage.scan(..=hi)),
+        scanned(storage.scan((Bound::Unbounded, Bound::Included(hi))))
+    );
+    // the deleted key is left out, and the inverted range is rejected
+    assert_eq!(scanned(storage.scan(lo..hi)).unwrap().len(), 49);
+    assert_eq!(
+        scanned(storage.scan((Bound::Included(hi), Bound::Included(lo)))),
+        Err(true)
+    );
+}
+
+#[test]
+fn test_scan_prefix_skips_tables() {
+    let dir = tempdir().unwrap();
+    for table in 1..=8 {
+        let mut builder = SsTableBuilder::new(128).with_prefix_extractor(PrefixExtractor::fixed(4));
+        for idx in 0..50 {
+            let key = format!("p{:03}_{:03}", table, idx);
+            builder.add(key.as_bytes(), &value_of("value", idx));
+        }
+        builder
+            .build_for_test(dir.path().join(format!("{}.sst", table)))
+            .unwrap();
+    }
+    let scan = |storage: &LsmStorage, prefix: &[u8]| {
+        FILES_READ.with(|files| files.borrow_mut().clear());
+        let mut iter = storage.scan_prefix(prefix).unwrap();
+        let mut keys = vec![];
+        while iter.is_valid() {
+            keys.push(iter.key().clone());
+            iter.next().unwrap();
+        }
+        let mut files = FILES_READ.with(|files| files.take());
+        files.sort();
+        files.dedup();
+        (keys, files)
+    };
+    let expected = (0..50)
+        .map(|idx| Bytes::from(format!("p005_{:03}", idx)))
+        .collect::<Vec<_>>();
+
+    let open = |extractor| {
+        let options = LsmStorageOptions {
+            prefix_extractor: extractor,
+            ..Default::default()
+        };
+        LsmStorage::open_with_options(&dir, options).unwrap()
+    };
+    let storage = open(Some(PrefixExtractor::fixed(4)));
+    let (keys, files) = scan(&storage, b"p005");
+    assert_eq!(keys, expected);
+    assert_eq!(files, vec![dir.path().join("5.sst")]);
+    let (keys, files) = scan(&storage, b"p005_01");
+    assert_eq!(keys.len(), 10);
+    // the blocks may be cached by now, but no other table is read
+    assert!(files.iter().all(|file| *file == dir.path().join("5.sst")));
+    let (keys, files) = scan(&storage, b"p009");
+    assert!(keys.is_empty());
+    assert!(files.is_empty());
+    drop(storage);
+
+    // the filters were built by another extractor, so only the key ranges of the tables leave
+    // them out
+    let storage = open(Some(PrefixExtractor::fixed(3)));
+    let (keys, files) = scan(&storage, b"p005");
+    assert_eq!(keys, expected);
+    assert_eq!(files, vec![dir.path().join("5.sst")]);
+}
+
+#[test]
+fn test_scan_snapshot_age() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    for idx in 0..10 {
+        storage.put(key_of(idx), value_of("value", idx)).unwrap();
+    }
+    let options = ReadOptions {
+        max_staleness: Some(SnapshotAge {
+            sequences_behind: u64::MAX,
+            compactions_since: 1,
+        }),
+        ..Default::default()
+    };
+    let mut iter = storage.scan(..).unwrap();
+    let mut bounded = storage.scan_opt(.., options).unwrap();
+    assert_eq!(iter.snapshot_age(), SnapshotAge::default());
+
+    for idx in 10..15 {
+        storage.put(key_of(idx), value_of("value", idx)).unwrap();
+    }
+    storage.delete(&key_of(0)).unwrap();
+    storage.sync().unwrap();
+    assert_eq!(
+        iter.snapshot_age(),
+        SnapshotAge {
+            sequences_behind: 6,
+            compactions_since: 1,
+        }
+    );
+    bounded.next().unwrap();
+
+    // an empty memtable is not flushed
+    storage.sync().unwrap();
+    assert_eq!(iter.snapshot_age().compactions_since, 1);
+    storage.put(key_of(15), value_of("value", 15)).unwrap();
+    storage.sync().unwrap();
+    assert_eq!(iter.snapshot_age().compactions_since, 2);
+    let err = bounded.next().err().unwrap();
+    assert_eq!(
+        err.downcast_ref::<LsmError>(),
+        Some(&LsmError::SnapshotTooOld {
+            sequences_behind: 7,
+            compactions_since: 2,
+        })
+    );
+    // without a limit the scan goes on
+    iter.next().unwrap();
+    assert!(iter.is_valid());
+}
+
+#[test]
+fn test_scan_skip_unreadable() {
+    let dir = tempdir().unwrap();
+    for table in 1..=3 {
+        write_sst(
+            &dir.path().join(format!("{}.sst", table)),
+            (table - 1) * 50..table * 50,
+            "value",
+        );
+    }
+    let storage = LsmStorage::open(&dir).unwrap();
+    // the tables stay open, but every read of them fails from now on
+    let break_table = |table: usize| {
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(dir.path().join(format!("{}.sst", table)))
+            .unwrap();
+        file.set_len(0).unwrap();
+    };
+    let collect = |options: ReadOptions, lower: usize, upper: usize| -> anyhow::Result<_> {
+        let mut iter = storage.scan_opt(key_of(lower)..=key_of(upper), options)?;
+        let mut keys = vec![];
+        while iter.is_valid() {
+            keys.push(iter.key().clone());
+            iter.next()?;
+        }
+        Ok((keys, iter.stats().clone()))
+    };
+
+    // the scan never reaches the table, so it cannot fail it
+    break_table(3);
+    let (keys, stats) = collect(ReadOptions::default(), 0, 40).unwrap();
+    assert_eq!(keys, (0..=40).map(key_of).collect::<Vec<_>>());
+    assert!(stats.skipped_tables.is_empty());
+
+    break_table(2);
+    assert!(collect(ReadOptions::default(), 40, 60).is_err());
+    let options = ReadOptions {
+        skip_unreadable: true,
+        ..Default::default()
+    };
+    let (keys, stats) = collect(options, 40, 60).unwrap();
+    assert_eq!(keys, (40..50).map(key_of).collect::<Vec<_>>());
+    assert_eq!(stats.skipped_tables, vec![2]);
+    assert_eq!(storage.metrics().unreadable_tables_skipped(), 1);
+}
+
+#[test]
+fn test_scan_cancelled() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    for idx in 0..10 {
+        storage.put(key_of(idx), value_of("value", idx)).unwrap();
+    }
+
+    let cancel = CancellationToken::new();
+    let options = ReadOptions {
+        cancel: Some(cancel.clone()),
+        ..Default::default()
+    };
+    let mut iter = storage.scan_opt(.., options).unwrap();
+    for idx in 0..3 {
+        assert_eq!(iter.key(), &key_of(idx));
+        iter.next().unwrap();
+    }
+    cancel.cancel();
+    let err = iter.next().unwrap_err();
+    assert_eq!(err.downcast_ref::<LsmError>(), Some(&LsmError::Cancelled));
+}
+
+#[test]
+fn test_scan_yields_across_deletions() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    // short keys, so that the deletions all fit in the memtable
+    let key = |idx: usize| Bytes::from(format!("k{:06}", idx));
+    let live: Vec<_> = (0..5).chain(100_005..100_010).map(key).collect();
+    for key in &live {
+        storage.put(key.clone(), Bytes::from("value")).unwrap();
+    }
+    for idx in 5..100_005 {
+        storage.delete(&key(idx)).unwrap();
+    }
+
+    let budget = 1_000;
+    let options = || ReadOptions {
+        max_tombstones_per_next: Some(budget),
+        ..Default::default()
+    };
+    let mut iter = storage.scan_opt(.., options()).unwrap();
+    let mut keys = vec![];
+    let mut yields = 0;
+    let mut token = None;
+    while iter.is_valid() {
+        if !iter.value().is_empty() {
+            keys.push(iter.key().clone());
+        }
+        match iter.next() {
+            Ok(()) => {}
+            Err(err) => {
+                assert_eq!(
+                    err.downcast_ref::<LsmError>(),
+                    Some(&LsmError::Yielded {
+                        tombstones_skipped: budget
+                    })
+                );
+                yields += 1;
+                token.get_or_insert_with(|| iter.resume_token());
+            }
+        }
+        assert!(LAST_NEXT_SKIPPED.with(|last| last.get()) <= budget);
+    }
+    assert_eq!(keys, live);
+    assert_eq!(yields, 100_000 / budget - 1);
+    assert_eq!(iter.stats().tombstones_skipped, 100_000);
+
+    // the token of a scan that yielded moved past the last live key; creating a scan skips the
+    // deletions it starts on all at once
+    let token = token.unwrap();
+    assert_eq!(token.last_key(), Some(&key(4)));
+    let iter = storage.scan_resume(&token, options()).unwrap();
+    assert_eq!(iter.key(), &key(100_005));
+
+    // without a budget one call skips them all
+    let mut iter = storage.scan(..).unwrap();
+    for _ in 0..5 {
+        iter.next().unwrap();
+    }
+    assert_eq!(iter.key(), &key(100_005));
+    assert_eq!(LAST_NEXT_SKIPPED.with(|last| last.get()), 100_000);
+}
+
+#[test]
+fn test_entry_checksum_catches_corrupted_block() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        entry_checksums: true,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    for idx in 0..10 {
+        storage.put(key_of(idx), value_of("value", idx)).unwrap();
+    }
+    storage.sync().unwrap();
+    drop(storage);
+
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert_eq!(
+        storage.get_many(&[&key_of(3)]).unwrap(),
+        vec![Some(value_of("value", 3))]
+    );
+    let mut iter = storage.scan(..).unwrap();
+    for idx in 0..10 {
+        assert_eq!(iter.key(), &key_of(idx));
+        assert_eq!(iter.value(), &value_of("value", idx)[..]);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+
+    // flip a byte of the first value in the cached block
+    let sst_id = storage.sst_ids_by_level().concat()[0];
+    let cache = storage.block_cache();
+    let block = cache.get(&(sst_id, 0)).unwrap();
+    cache.insert(
+        (sst_id, 0),
+        std::sync::Arc::new(block.with_corrupted_value(0)),
+    );
+
+    let expected = LsmError::EntryChecksumMismatch { key: key_of(0) };
+    let err = storage.get_many(&[&key_of(0)]).unwrap_err();
+    assert_eq!(err.downcast_ref::<LsmError>(), Some(&expected));
+    let err = storage.scan(..).map(|_| ()).unwrap_err();
+    assert_eq!(err.downcast_ref::<LsmError>(), Some(&expected));
+    // the other entries of the block still check out
+    assert_eq!(
+        storage.get_many(&[&key_of(1)]).unwrap(),
+        vec![Some(value_of("value", 1))]
+    );
+}
+
+#[test]
+fn test_scan_resume_pages() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    let key = |idx: usize| Bytes::from(format!("key_{:05}", idx));
+    for idx in 0..10_000 {
+        storage.put(key(idx), value_of("value", idx)).unwrap();
+    }
+
+    let mut iter = storage.scan(&key(10)..&key(9_990)).unwrap();
+    let mut keys = vec![];
+    let mut pages = 0;
+    loop {
+        let mut page = 0;
+        while iter.is_valid() && page < 100 {
+            keys.push(iter.key().clone());
+            iter.next().unwrap();
+            page += 1;
+        }
+        if page == 0 {
+            break;
+        }
+        pages += 1;
+        // overwriting the keys between the pages moves no key
+        storage.put(key(pages * 97), Bytes::from("new")).unwrap();
+        let token = ResumeToken::decode(&iter.resume_token().encode()).unwrap();
+        assert_eq!(token.last_key(), keys.last());
+        iter = storage.scan_resume(&token, ReadOptions::default()).unwrap();
+    }
+    assert_eq!(pages, 100);
+    assert_eq!(keys, (10..9_990).map(key).collect::<Vec<_>>());
+
+    // a token that has not moved starts over
+    let iter = storage.scan(..).unwrap();
+    let token = iter.resume_token();
+    assert_eq!(token.last_key(), None);
+    let iter = storage.scan_resume(&token, ReadOptions::default()).unwrap();
+    assert_eq!(iter.key(), &key(0));
+    assert!(ResumeToken::decode(b"garbage").is_err());
+    let encoded = token.encode();
+    assert!(ResumeToken::decode(&encoded[..encoded.len() - 1]).is_err());
+}
+
+#[test]
+fn test_scan_resume_strict() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    for idx in 0..10 {
+        storage.put(key_of(idx), value_of("value", idx)).unwrap();
+    }
+    let strict = || ReadOptions {
+        max_staleness: Some(SnapshotAge::default()),
+        ..Default::default()
+    };
+    let is_too_old = |err: anyhow::Error| {
+        matches!(
+            err.downcast_ref::<LsmError>(),
+            Some(LsmError::SnapshotTooOld { .. })
+        )
+    };
+
+    let mut iter = storage.scan(..).unwrap();
+    iter.next().unwrap();
+    let token = iter.resume_token();
+    drop(iter);
+    // nothing changed, so the snapshot of the first page still holds
+    let iter = storage.scan_resume(&token, strict()).unwrap();
+    assert_eq!(iter.key(), &key_of(1));
+
+    storage.put(key_of(0), Bytes::from("new")).unwrap();
+    assert!(is_too_old(
+        storage.scan_resume(&token, strict()).err().unwrap()
+    ));
+    let iter = storage.scan_resume(&token, ReadOptions::default()).unwrap();
+    assert_eq!(iter.key(), &key_of(1));
+    drop(iter);
+    drop(storage);
+
+    // the snapshot is gone with the storage that took it
+    let token = ResumeToken::decode(&token.encode()).unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert!(is_too_old(
+        storage.scan_resume(&token, strict()).err().unwrap()
+    ));
+    let iter = storage.scan_resume(&token, ReadOptions::default()).unwrap();
+    assert_eq!(iter.key(), &key_of(1));
+    // the resumed scan counts from a snapshot of its own
+    let token = iter.resume_token();
+    assert!(storage.scan_resume(&token, strict()).is_ok());
+}
+
+#[test]
+fn test_value_handle_reads_lazily() {
+    let dir = tempdir().unwrap();
+    let large = Bytes::from((0..3000).map(|idx| idx as u8).collect::<Vec<_>>());
+    let storage = LsmStorage::open(&dir).unwrap();
+    for idx in 0..20 {
+        storage.put(key_of(idx), value_of("value", idx)).unwrap();
+    }
+    storage.put(key_of(10), large.clone()).unwrap();
+    storage.sync().unwrap();
+    drop(storage);
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    let sst_id = storage.sst_ids_by_level().concat()[0];
+    let handle = storage.get_handle(&key_of(10)).unwrap().unwrap();
+    let block_idx = match handle.location() {
+        ValueLocation::Sst {
+            sst_id: id,
+            block_idx,
+            ..
+        } => {
+            assert_eq!(id, sst_id);
+            block_idx
+        }
+        location => panic!("unexpected location {:?}", location),
+    };
+    assert!(storage.pinned_files().contains(&FileId::Sst(sst_id)));
+
+    // the length comes without reading the block
+    let cache = storage.block_cache();
+    cache.invalidate_all();
+    assert_eq!(handle.len(), large.len());
+    assert!(!cache.contains_key(&(sst_id, block_idx)));
+
+    assert_eq!(
+        handle.read_range(1000..1100).unwrap(),
+        large.slice(1000..1100)
+    );
+    assert!(cache.contains_key(&(sst_id, block_idx)));
+    assert_eq!(handle.read_all().unwrap(), large);
+    let err = handle.read_range(2990..3010).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<LsmError>(),
+        Some(LsmError::InvalidArgument(_))
+    ));
+    drop(handle);
+    assert!(storage.pinned_files().is_empty());
+
+    // the memtables shadow the SSTs
+    storage.put(key_of(3), Bytes::from("fresh")).unwrap();
+    let handle = storage.get_handle(&key_of(3)).unwrap().unwrap();
+    assert_eq!(handle.location(), ValueLocation::Memtable);
+    assert_eq!(handle.read_range(1..3).unwrap(), Bytes::from("re"));
+    storage.delete(&key_of(4)).unwrap();
+    assert!(storage.get_handle(&key_of(4)).unwrap().is_none());
+    assert!(storage.get_handle(b"missing").unwrap().is_none());
+}
+
+#[test]
+fn test_emptiness_estimates() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert!(storage.is_empty_estimate());
+    assert_eq!(storage.approximate_len(), 0);
+
+    for idx in 0..10 {
+        storage.put(key_of(idx), value_of("value", idx)).unwrap();
+    }
+    assert!(!storage.is_empty_estimate());
+    assert_eq!(storage.approximate_len(), 10);
+    storage.sync().unwrap();
+    drop(storage);
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert!(!storage.is_empty_estimate());
+    assert_eq!(storage.approximate_len(), 10);
+    for idx in 0..10 {
+        storage.delete(&key_of(idx)).unwrap();
+    }
+    storage.sync().unwrap();
+    drop(storage);
+
+    // the deleted keys still count while the older table holds them
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert!(!storage.is_empty_estimate());
+    assert_eq!(storage.approximate_len(), 10);
+    let ssts = storage.sst_ids_by_level().concat();
+    assert_eq!(ssts.len(), 2);
+    drop(storage);
+
+    // a full compaction leaves the tombstones at most
+    std::fs::remove_file(dir.path().join(format!("{}.sst", ssts[0]))).unwrap();
+    let options = LsmStorageOptions {
+        recovery: RecoveryMode::BestEffort,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert_eq!(storage.sst_ids_by_level().concat(), vec![ssts[1]]);
+    assert!(storage.is_empty_estimate());
+    assert_eq!(storage.approximate_len(), 0);
+}
+
+#[test]
+fn test_scan_raw_reports_deletes() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), 0..10, "old");
+    let mut builder = SsTableBuilder::new(128);
+    for (idx, value) in [
+        (2, Bytes::new()),
+        (3, Bytes::new()),
+        (4, value_of("mid", 4)),
+    ] {
+        builder.add(&key_of(idx), &value);
+    }
+    builder.add(&key_of(10), &value_of("mid", 10));
+    builder.build_for_test(dir.path().join("2.sst")).unwrap();
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.delete(&key_of(4)).unwrap();
+    storage.put(key_of(3), value_of("new", 3)).unwrap();
+    storage.delete(&key_of(5)).unwrap();
+    storage.put(key_of(11), value_of("new", 11)).unwrap();
+
+    let expected: Vec<(Bytes, EntryOp)> = (0..12)
+        .map(|idx| {
+            let op = match idx {
+                2 | 4 | 5 => EntryOp::Delete,
+                3 | 11 => EntryOp::Put(value_of("new", idx)),
+                10 => EntryOp::Put(value_of("mid", idx)),
+                _ => EntryOp::Put(value_of("old", idx)),
+            };
+            (key_of(idx), op)
+        })
+        .collect();
+    let raw: Vec<_> = storage
+        .scan_raw(..)
+        .unwrap()
+        .map(|entry| entry.map(|entry| (entry.key, entry.op)))
+        .collect::<anyhow::Result<_>>()
+        .unwrap();
+    assert_eq!(raw, expected);
+    let seqs: Vec<_> = storage
+        .scan_raw(&key_of(2)..&key_of(4))
+        .unwrap()
+        .map(|entry| entry.unwrap().seq)
+        .collect();
+    assert_eq!(seqs, vec![4, 4]);
+    // the newest version of a key sorts first, whatever it does
+    let internal: Vec<_> = storage
+        .scan_raw(&key_of(2)..&key_of(4))
+        .unwrap()
+        .map(|entry| entry.unwrap().internal_key())
+        .collect();
+    assert_eq!(
+        internal,
+        vec![
+            InternalKey::new(key_of(2), 4, ValueKind::Delete),
+            InternalKey::new(key_of(3), 4, ValueKind::Put),
+        ]
+    );
+    assert!(max_seq_key(key_of(2)) < internal[0]);
+    assert!(internal[0] < InternalKey::new(key_of(2), 3, ValueKind::Put));
+
+    let mut iter = storage.scan(..).unwrap();
+    let mut keys = vec![];
+    while iter.is_valid() {
+        keys.push(iter.key().clone());
+        iter.next().unwrap();
+    }
+    let live: Vec<_> = expected
+        .into_iter()
+        .filter(|(_, op)| op != &EntryOp::Delete)
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, live);
+}
+
+#[test]
+fn test_compressed_block_cache_tier() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), 0..100, "value");
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(storage.block_cache_stats(), None);
+    drop(storage);
+
+    let options = LsmStorageOptions {
+        compressed_block_cache_bytes: Some(1 << 20),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    let scan = || {
+        let mut iter = storage.scan(..).unwrap();
+        while iter.is_valid() {
+            iter.next().unwrap();
+        }
+    };
+    scan();
+    let stats = storage.block_cache_stats().unwrap();
+    assert!(stats.disk_reads > 1);
+    assert_eq!((stats.hits, stats.compressed_hits), (0, 0));
+
+    storage.block_cache().invalidate_all();
+    scan();
+    let again = storage.block_cache_stats().unwrap();
+    assert_eq!(again.disk_reads, stats.disk_reads);
+    assert_eq!(again.compressed_hits, stats.disk_reads);
+    scan();
+    assert_eq!(storage.block_cache_stats().unwrap().hits, stats.disk_reads);
+}
+
+#[test]
+fn test_get_skips_tables_by_key_filter() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    for idx in 10..50 {
+        storage.put(key_of(idx), value_of("value", idx)).unwrap();
+    }
+    storage.sync().unwrap();
+    drop(storage);
+    let storage = LsmStorage::open(&dir).unwrap();
+    for idx in 30..40 {
+        storage.delete(&key_of(idx)).unwrap();
+    }
+    storage.sync().unwrap();
+    drop(storage);
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    let ssts = storage.sst_ids_by_level().concat();
+    assert_eq!(ssts.len(), 2);
+    let get = |key: &[u8]| {
+        KEY_FILTER_PROBES.with(|probes| probes.borrow_mut().clear());
+        FILES_READ.with(|files| files.borrow_mut().clear());
+        let value = storage.get_many(&[key]).unwrap().pop().unwrap();
+        let probes = KEY_FILTER_PROBES.with(|probes| probes.take());
+        let reads = FILES_READ.with(|files| files.take().len());
+        (value, probes, reads)
+    };
+
+    // before the first key of both tables, so neither filter is asked
+    assert_eq!(get(&key_of(5)), (None, vec![], 0));
+    // before the first key of the newer table only
+    assert_eq!(
+        get(&key_of(20)),
+        (Some(value_of("value", 20)), vec![ssts[0]], 1)
+    );
+    assert_eq!(get(&key_of(35)), (None, vec![ssts[1]], 1));
+    // past the last key of the newer table only, in the block the get of 20 cached
+    assert_eq!(
+        get(&key_of(45)),
+        (Some(value_of("value", 45)), vec![ssts[0]], 0)
+    );
+    // within both tables, and in neither of them
+    assert_eq!(get(b"key_0305"), (None, vec![ssts[1], ssts[0]], 0));
+}
+
+#[test]
+fn test_scan_skips_tables_outside_the_bounds() {
+    let dir = tempdir().unwrap();
+    for keys in [0..10, 10..20, 20..30] {
+        let storage = LsmStorage::open(&dir).unwrap();
+        for idx in keys {
+            storage.put(key_of(idx), value_of("value", idx)).unwrap();
+        }
+        storage.sync().unwrap();
+    }
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    let ssts = storage.sst_ids_by_level().concat();
+    assert_eq!(ssts.len(), 3);
+    // the keys, and the tables read from
+    let scan = |lower: Bound<usize>, upper: Bound<usize>| {
+        FILES_READ.with(|files| files.borrow_mut().clear());
+        let (lower, upper) = (lower.map(key_of), upper.map(key_of));
+        // leave the cache empty, so that every table opened is read from disk
+        let options = ReadOptions {
+            fill_cache: false,
+            ..Default::default()
+        };
+        let mut iter = storage
+            .scan_opt(
+                (
+                    lower.as_ref().map(|key| key.as_ref()),
+                    upper.as_ref().map(|key| key.as_ref()),
+                ),
+                options,
+            )
+            .unwrap();
+        let mut keys = vec![];
+        while iter.is_valid() {
+            keys.push(iter.key().clone());
+            iter.next().unwrap();
+        }
+        let mut read: Vec<_> = FILES_READ.with(|files| files.take());
+        read.dedup();
+        let read: Vec<_> = ssts
+            .iter()
+            .copied()
+            .filter(|id| read.contains(&dir.path().join(format!("{}.sst", id))))
+            .collect();
+        (keys, read)
+    };
+    let keys = |range: std::ops::Range<usize>| range.map(key_of).collect::<Vec<_>>();
+
+    // the bounds take in the last key of the first table and the first key of the last one
+    assert_eq!(
+        scan(Bound::Included(9), Bound::Included(20)),
+        (keys(9..21), ssts.clone())
+    );
+    // the same keys excluded leave those tables out
+    assert_eq!(
+        scan(Bound::Excluded(9), Bound::Excluded(20)),
+        (keys(10..20), vec![ssts[1]])
+    );
+    assert_eq!(
+        scan(Bound::Unbounded, Bound::Excluded(10)),
+        (keys(0..10), vec![ssts[0]])
+    );
+    assert_eq!(
+        scan(Bound::Excluded(29), Bound::Unbounded),
+        (vec![], vec![])
+    );
+    assert_eq!(
+        scan(Bound::Included(30), Bound::Included(40)),
+        (vec![], vec![])
+    );
+}
+
+#[test]
+fn test_scan_fills_cache_in_batches() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    write_sst(&path, 0..1000, "value");
+    // read ahead or not, the scan reads every block of the table
+    let blocks = SsTable::open(0, None, FileObject::open(&path).unwrap())
+        .unwrap()
+        .num_of_blocks();
+    let scan = |storage: &LsmStorage| {
+        CACHE_INSERTS.with(|inserts| inserts.set(0));
+        let mut iter = storage.scan(..).unwrap();
+        while iter.is_valid() {
+            iter.next().unwrap();
+        }
+        let stopped = iter.stats().cache_fill_stopped;
+        drop(iter);
+        (CACHE_INSERTS.with(|inserts| inserts.get()), stopped)
+    };
+    let get_reads = |storage: &LsmStorage, keys: &[Bytes]| {
+        FILES_READ.with(|files| files.borrow_mut().clear());
+        let keys: Vec<_> = keys.iter().map(|key| key.as_ref()).collect();
+        let values = storage.get_many(&keys).unwrap();
+        assert!(values.iter().all(Option::is_some));
+        FILES_READ.with(|files| files.take().len())
+    };
+    let keys: Vec<_> = (0..1000).step_by(7).map(key_of).collect();
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    let (inserts, stopped) = scan(&storage);
+    assert!(blocks > 4 * SCAN_FILL_BATCH);
+    assert!(inserts <= blocks / SCAN_FILL_BATCH + 1);
+    assert!(!stopped);
+    // the scan left every block it read in the cache
+    assert_eq!(get_reads(&storage, &keys), 0);
+    drop(storage);
+
+    let options = LsmStorageOptions {
+        scan_fill_cache_limit: Some(1024),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    let (inserts, stopped) = scan(&storage);
+    assert!(stopped);
+    assert!(inserts <= 1);
+    // past the limit the blocks are read again, and point reads fill the cache as usual
+    assert!(get_reads(&storage, &keys) > blocks / 2);
+    assert_eq!(get_reads(&storage, &keys), 0);
+}
+
+#[test]
+fn test_scan_reads_ahead_while_gets_read_single_blocks() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), 0..1000, "value");
+    let storage = LsmStorage::open(&dir).unwrap();
+    let reads = |op: &mut dyn FnMut()| {
+        READ_LENGTHS.with(|lengths| lengths.borrow_mut().clear());
+        op();
+        READ_LENGTHS.with(|lengths| lengths.take())
+    };
+    // load the slices of the index, so that the gets below read nothing but their block
+    for idx in [0, 999] {
+        storage.get(&key_of(idx)).unwrap();
+    }
+
+    // the scan reads the first half of the table, the gets the other half
+    let upper = key_of(500);
+    let mut iter = storage.scan(..&upper).unwrap();
+    let (mut scan_reads, mut get_reads) = (vec![], vec![]);
+    let mut idx = 0;
+    while iter.is_valid() {
+        scan_reads.extend(reads(&mut || iter.next().unwrap()));
+        idx = (idx + 7919) % 500;
+        let get = reads(&mut || assert!(storage.get(&key_of(500 + idx)).unwrap().is_some()));
+        assert!(get.len() <= 1, "{:?}", get);
+        get_reads.extend(get);
+    }
+    assert_eq!(iter.stats().max_readahead_blocks, 16);
+
+    let longest_get = get_reads.iter().max().copied().unwrap();
+    assert!(scan_reads.iter().any(|&len| len > 8 * longest_get));
+    assert!(scan_reads.len() < get_reads.len());
+}
+
+#[test]
+fn test_warm_on_open_reads_hot_blocks_back() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), 0..1000, "value");
+    let hot: Vec<_> = (100..110).chain(700..705).map(key_of).collect();
+    let get_reads = |storage: &LsmStorage, key: &Bytes| {
+        FILES_READ.with(|files| files.borrow_mut().clear());
+        assert!(storage.get(key).unwrap().is_some());
+        FILES_READ.with(|files| files.take().len())
+    };
+
+    let options = LsmStorageOptions {
+        hotset_interval: Some(Duration::from_secs(3600)),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    for _ in 0..10 {
+        for key in &hot {
+            get_reads(&storage, key);
+        }
+    }
+    // the hot set records key ranges, which still find the blocks once the compaction has
+    // rewritten them into a table with another id and other block boundaries
+    storage.compact(0).unwrap();
+    storage.close().unwrap();
+    drop(storage);
+    let ranges = hotset::read(dir.path());
+    assert!(!ranges.is_empty() && ranges.len() <= hot.len());
+
+    let options = LsmStorageOptions {
+        warm_on_open: true,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    storage.wait_for_warm();
+    for key in &hot {
+        assert_eq!(get_reads(&storage, key), 0, "{:?}", key);
+    }
+    // the rest of the table stays cold
+    assert_eq!(get_reads(&storage, &key_of(400)), 1);
+    drop(storage);
+
+    // anchors no table holds any more are skipped
+    hotset::write(
+        dir.path(),
+        &[(Bytes::from_static(b"zz_0"), Bytes::from_static(b"zz_9"))],
+    )
+    .unwrap();
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    storage.wait_for_warm();
+    assert_eq!(get_reads(&storage, &hot[0]), 1);
+}
+
+#[test]
+fn test_level_io() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    for idx in 0..100 {
+        storage.put(key_of(idx), value_of("first", idx)).unwrap();
+    }
+    storage.sync().unwrap();
+    let flushed = storage.metrics().flush_bytes_written();
+    assert_eq!(storage.metrics().level_io(0).bytes_written, flushed);
+
+    // the compaction reads L0 and writes into L1
+    storage.compact(0).unwrap();
+    let summary = storage.metrics().last_compaction().unwrap();
+    assert_eq!(summary.level_bytes_read, flushed);
+    assert_eq!(summary.level_bytes_written, 0);
+    let l0 = storage.metrics().level_io(0);
+    let l1 = storage.metrics().level_io(1);
+    assert_eq!(l0.compaction_bytes_read, summary.bytes_read);
+    assert_eq!(l1.compaction_bytes_read, 0);
+    assert_eq!(l1.bytes_written, summary.bytes_written);
+    assert_eq!(l0.bytes_written, flushed);
+
+    for idx in 100..200 {
+        storage.put(key_of(idx), value_of("second", idx)).unwrap();
+    }
+    storage.sync().unwrap();
+    assert!(storage.sst_ids_by_level()[2..].iter().all(Vec::is_empty));
+
+    // a get reads from the level of the table holding the key
+    storage.get(&key_of(50)).unwrap().unwrap();
+    let (l0, l1) = (storage.metrics().level_io(0), storage.metrics().level_io(1));
+    assert_eq!(l0.get_bytes_read, 0);
+    assert!(l1.get_bytes_read > 0);
+    storage.get(&key_of(150)).unwrap().unwrap();
+    assert!(storage.metrics().level_io(0).get_bytes_read > 0);
+    assert_eq!(storage.metrics().level_io(1), l1);
+
+    // and a scan from the levels whose tables it goes through
+    let mut iter = storage.scan(..&key_of(50)).unwrap();
+    while iter.is_valid() {
+        iter.next().unwrap();
+    }
+    let (l0, l1) = (storage.metrics().level_io(0), storage.metrics().level_io(1));
+    assert_eq!(l0.scan_bytes_read, 0);
+    assert!(l1.scan_bytes_read > 0);
+    let mut iter = storage.scan(..).unwrap();
+    while iter.is_valid() {
+        iter.next().unwrap();
+    }
+    assert!(storage.metrics().level_io(0).scan_bytes_read > 0);
+    assert!(storage.metrics().level_io(1).scan_bytes_read > l1.scan_bytes_read);
+    assert_eq!(storage.metrics().level_io(2), Default::default());
+}
+
+#[test]
+fn test_negative_cache_answers_repeated_misses() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), 0..100, "value");
+    let options = LsmStorageOptions {
+        negative_cache_capacity: Some(16),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    let missing = key_of(500);
+
+    assert_eq!(storage.get(&missing).unwrap(), None);
+    KEY_FILTER_PROBES.with(|probes| probes.borrow_mut().clear());
+    for _ in 0..3 {
+        assert_eq!(storage.get(&missing).unwrap(), None);
+    }
+    // the repeated misses never reached the tables
+    assert!(KEY_FILTER_PROBES.with(|probes| probes.borrow().is_empty()));
+    let stats = storage.negative_cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (3, 1, 1));
+
+    storage.put(missing.clone(), value_of("new", 500)).unwrap();
+    assert_eq!(storage.get(&missing).unwrap(), Some(value_of("new", 500)));
+
+    // deleted keys are cached too, until a batch puts them back
+    storage.delete(&key_of(7)).unwrap();
+    assert_eq!(storage.get(&key_of(7)).unwrap(), None);
+    assert_eq!(storage.get(&key_of(7)).unwrap(), None);
+    let mut batch = WriteBatch::new();
+    batch.put(key_of(7), value_of("batch", 7));
+    storage.write(batch).unwrap();
+    assert_eq!(storage.get(&key_of(7)).unwrap(), Some(value_of("batch", 7)));
+    let stats = storage.negative_cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (4, 4, 0));
+
+    let storage_without = {
+        drop(storage);
+        LsmStorage::open(&dir).unwrap()
+    };
+    assert!(storage_without.negative_cache_stats().is_none());
+}
+
+#[test]
+fn test_negative_cache_never_hides_a_put() {
+    let dir = tempdir().unwrap();
+    // no key filter, so that a get of a missing key within the table reads a block
+    let mut builder = SsTableBuilder::new(128).without_key_filter();
+    for idx in (0..100).step_by(2) {
+        builder.add(&key_of(idx), &value_of("value", idx));
+    }
+    builder.build_for_test(dir.path().join("1.sst")).unwrap();
+    let options = LsmStorageOptions {
+        negative_cache_capacity: Some(64),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+
+    // the put lands while the get reads the table, which does not have the key either
+    let reader = {
+        let storage = storage.clone();
+        std::thread::spawn(move || {
+            READ_LATENCY.with(|latency| latency.set(Duration::from_millis(50)));
+            storage.get(&key_of(51)).unwrap()
+        })
+    };
+    std::thread::sleep(Duration::from_millis(10));
+    storage.put(key_of(51), value_of("new", 51)).unwrap();
+    assert_eq!(reader.join().unwrap(), None);
+    assert_eq!(storage.get(&key_of(51)).unwrap(), Some(value_of("new", 51)));
+    assert_eq!(storage.negative_cache_stats().unwrap().entries, 0);
+}
+
+#[test]
+fn test_reads_outside_the_data_touch_nothing() {
+    let dir = tempdir().unwrap();
+    // the results, the memtable iterators created, the key filters probed and the files read
+    let scan = |storage: &LsmStorage, lower: Bound<&[u8]>, upper: Bound<&[u8]>| {
+        MEMTABLE_SCANS.with(|scans| scans.set(0));
+        FILES_READ.with(|files| files.borrow_mut().clear());
+        let mut iter = storage.scan((lower, upper)).unwrap();
+        let mut keys = vec![];
+        while iter.is_valid() {
+            keys.push(iter.key().clone());
+            iter.next().unwrap();
+        }
+        let scans = MEMTABLE_SCANS.with(|scans| scans.get());
+        (keys, scans, FILES_READ.with(|files| files.take().len()))
+    };
+    let get = |storage: &LsmStorage, key: &[u8]| {
+        KEY_FILTER_PROBES.with(|probes| probes.borrow_mut().clear());
+        FILES_READ.with(|files| files.borrow_mut().clear());
+        let value = storage.get(key).unwrap();
+        assert_eq!(storage.get_many(&[key]).unwrap(), vec![value.clone()]);
+        let probes = KEY_FILTER_PROBES.with(|probes| probes.take());
+        (value, probes, FILES_READ.with(|files| files.take().len()))
+    };
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(
+        scan(&storage, Bound::Unbounded, Bound::Unbounded),
+        (vec![], 0, 0)
+    );
+    assert_eq!(get(&storage, b"key_000"), (None, vec![], 0));
+
+    for idx in 10..20 {
+        storage.put(key_of(idx), value_of("value", idx)).unwrap();
+    }
+    storage.sync().unwrap();
+    let outside = (Bound::Included(&b"key_020"[..]), Bound::Unbounded);
+    assert_eq!(scan(&storage, outside.0, outside.1), (vec![], 0, 0));
+    let before = (Bound::Unbounded, Bound::Excluded(&b"key_010"[..]));
+    assert_eq!(scan(&storage, before.0, before.1), (vec![], 0, 0));
+    assert_eq!(get(&storage, &key_of(5)), (None, vec![], 0));
+    assert_eq!(get(&storage, &key_of(25)), (None, vec![], 0));
+    // within the range, the tables are read as before
+    let (keys, _, _) = scan(
+        &storage,
+        Bound::Included(&b"key_005"[..]),
+        Bound::Included(&b"key_011"[..]),
+    );
+    assert_eq!(keys, vec![key_of(10), key_of(11)]);
+    assert_eq!(get(&storage, &key_of(15)).0, Some(value_of("value", 15)));
+    drop(storage);
+
+    // the range is recovered with the tables
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(scan(&storage, outside.0, outside.1), (vec![], 0, 0));
+    assert_eq!(get(&storage, &key_of(25)), (None, vec![], 0));
+    // a key in the memtable is found wherever it is
+    storage.put(key_of(30), value_of("value", 30)).unwrap();
+    let (keys, scans, reads) = scan(&storage, outside.0, outside.1);
+    assert_eq!(keys, vec![key_of(30)]);
+    assert!(scans > 0);
+    assert_eq!(reads, 0);
+    assert_eq!(get(&storage, &key_of(25)), (None, vec![], 0));
+}
@@ -0,0 +1,1230 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::manifest::{
+    read_db_id, read_header, read_records, read_tables, ManifestRecord, OptionsFingerprint,
+    MANIFEST,
+};
+use crate::prelude::{
+    LsmError, LsmStorage, LsmStorageOptions, RecoveryMode, SstLayout, StorageIterator,
+    TrashOptions, VerifyLevel,
+};
+use crate::quarantine::QUARANTINE_DIR;
+use crate::table::{FileObject, SsTable, SsTableBuilder};
+
+fn write_sst(path: &Path, keys: &[&str]) {
+    let mut builder = SsTableBuilder::new(128);
+    for key in keys {
+        builder.add(key.as_bytes(), format!("value_{}", key).as_bytes());
+    }
+    builder.build_for_test(path).unwrap();
+}
+
+fn corrupt_footer(path: &Path) {
+    let mut data = std::fs::read(path).unwrap();
+    let len = data.len();
+    data[len - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+    std::fs::write(path, data).unwrap();
+}
+
+fn keys(storage: &LsmStorage) -> Vec<Bytes> {
+    let mut iter = storage.scan(..).unwrap();
+    let mut keys = vec![];
+    while iter.is_valid() {
+        keys.push(iter.key().clone());
+        iter.next().unwrap();
+    }
+    keys
+}
+
+fn best_effort() -> LsmStorageOptions {
+    LsmStorageOptions {
+        recovery: RecoveryMode::BestEffort,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_open_recovers_ssts() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), &["a", "b"]);
+    write_sst(&dir.path().join("2.sst"), &["b", "c"]);
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(keys(&storage), vec!["a", "b", "c"]);
+}
+
+#[test]
+fn test_strict_open_fails_on_corrupt_sst() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), &["a"]);
+    corrupt_footer(&dir.path().join("1.sst"));
+
+    let err = LsmStorage::open(&dir).err().unwrap();
+    assert!(format!("{:#}", err).contains("1.sst"));
+    assert!(dir.path().join("1.sst").exists());
+}
+
+#[test]
+fn test_best_effort_open_quarantines_corrupt_sst() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), &["a", "b"]);
+    write_sst(&dir.path().join("2.sst"), &["c", "d"]);
+    corrupt_footer(&dir.path().join("2.sst"));
+
+    let candidates = LsmStorage::recover_dry_run(&dir).unwrap();
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(candidates[0].file, dir.path().join("2.sst"));
+    assert_eq!(candidates[0].operation, "open sst");
+    assert!(dir.path().join("2.sst").exists());
+
+    let storage = LsmStorage::open_with_options(&dir, best_effort()).unwrap();
+    assert_eq!(keys(&storage), vec!["a", "b"]);
+    assert!(!dir.path().join("2.sst").exists());
+
+    let mut quarantined = std::fs::read_dir(dir.path().join(QUARANTINE_DIR))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    quarantined.sort();
+    assert_eq!(quarantined.len(), 2);
+    assert!(quarantined[0].starts_with("2.sst."));
+    assert_eq!(quarantined[1], format!("{}.json", quarantined[0]));
+
+    let sidecar =
+        std::fs::read_to_string(dir.path().join(QUARANTINE_DIR).join(&quarantined[1])).unwrap();
+    assert!(sidecar.contains("\"file\":\"2.sst\""));
+    assert!(sidecar.contains("\"operation\":\"open sst\""));
+    assert!(sidecar.contains("not a mini-lsm table"));
+
+    // The quarantined id is not reused by the next flush.
+    storage.put(Bytes::from("e"), Bytes::from("f")).unwrap();
+    storage.sync().unwrap();
+    assert!(dir.path().join("3.sst").exists());
+}
+
+#[test]
+fn test_quarantine_reports_offset_and_checksums() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    write_sst(&path, &["a", "b"]);
+    let mut data = std::fs::read(&path).unwrap();
+    let footer = data.len() - 16;
+    let meta_offset = u32::from_le_bytes(data[footer..footer + 4].try_into().unwrap());
+    data[meta_offset as usize] ^= 0x01;
+    std::fs::write(&path, data).unwrap();
+
+    let candidates = LsmStorage::recover_dry_run(&dir).unwrap();
+    assert_eq!(candidates.len(), 1);
+    let report = &candidates[0];
+    assert_eq!(report.offset, Some(meta_offset as u64));
+    assert!(report.expected_checksum.is_some());
+    assert!(report.actual_checksum.is_some());
+    assert_ne!(report.expected_checksum, report.actual_checksum);
+
+    LsmStorage::open_with_options(&dir, best_effort()).unwrap();
+    let sidecar = std::fs::read_dir(dir.path().join(QUARANTINE_DIR))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("json".as_ref()))
+        .unwrap();
+    let sidecar = std::fs::read_to_string(sidecar).unwrap();
+    assert!(
+        sidecar.contains(&format!("\"offset\":{}", meta_offset)),
+        "{}",
+        sidecar
+    );
+    assert!(
+        sidecar.contains(&format!(
+            "\"expected_checksum\":{}",
+            report.expected_checksum.unwrap()
+        )),
+        "{}",
+        sidecar
+    );
+}
+
+#[test]
+fn test_best_effort_open_quarantines_corrupt_wal() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        entry_checksums: true,
+        ..Default::default()
+    };
+    let wal = dir.path().join("memtable.wal");
+    {
+        let mut log = crate::wal::Wal::create(&wal)
+            .unwrap()
+            .with_entry_checksums();
+        for key in ["a", "b", "c"] {
+            log.append(&Bytes::from(key), &Bytes::from(format!("value_{}", key)))
+                .unwrap();
+        }
+    }
+
+    // the value of the second record, corrupted
+    let mut data = std::fs::read(&wal).unwrap();
+    let at = data
+        .windows(7)
+        .position(|bytes| bytes == b"value_b")
+        .unwrap();
+    data[at] ^= 0x01;
+    std::fs::write(&wal, data).unwrap();
+
+    let err = LsmStorage::open_with_options(&dir, options.clone())
+        .err()
+        .unwrap();
+    assert!(format!("{:#}", err).contains("memtable.wal"), "{:#}", err);
+
+    let storage = LsmStorage::open_with_options(
+        &dir,
+        LsmStorageOptions {
+            recovery: RecoveryMode::BestEffort,
+            ..options.clone()
+        },
+    )
+    .unwrap();
+    assert_eq!(keys(&storage), vec!["a"]);
+    let quarantined = std::fs::read_dir(dir.path().join(QUARANTINE_DIR))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(quarantined.len(), 2);
+    assert!(quarantined
+        .iter()
+        .all(|name| name.starts_with("memtable.wal.")));
+    storage
+        .put(Bytes::from("d"), Bytes::from("value_d"))
+        .unwrap();
+    drop(storage);
+
+    // the record replayed before the corruption was kept in a table
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert_eq!(keys(&storage), vec!["a", "d"]);
+}
+
+#[test]
+fn test_reopen_with_other_comparator_fails() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(Bytes::from("a"), Bytes::from("1")).unwrap();
+    storage.sync().unwrap();
+    drop(storage);
+
+    let options = LsmStorageOptions {
+        comparator: "reverse".to_string(),
+        ..Default::default()
+    };
+    let err = LsmStorage::open_with_options(&dir, options).err().unwrap();
+    let msg = format!("{:#}", err);
+    assert!(msg.contains("comparator mismatch"), "{}", msg);
+    assert!(
+        msg.contains("\"bytewise\"") && msg.contains("\"reverse\""),
+        "{}",
+        msg
+    );
+}
+
+#[test]
+fn test_reopen_adopts_benign_option_changes() {
+    let dir = tempdir().unwrap();
+    drop(LsmStorage::open(&dir).unwrap());
+
+    let options = LsmStorageOptions {
+        block_cache_capacity: 64,
+        ..Default::default()
+    };
+    drop(LsmStorage::open_with_options(&dir, options.clone()).unwrap());
+    assert_eq!(
+        read_header(dir.path()).unwrap(),
+        Some(OptionsFingerprint::of(&options))
+    );
+}
+
+#[test]
+fn test_reopen_with_smaller_limits_needs_format_change() {
+    let dir = tempdir().unwrap();
+    drop(LsmStorage::open(&dir).unwrap());
+
+    let mut options = LsmStorageOptions {
+        max_key_size: 16,
+        ..Default::default()
+    };
+    let err = LsmStorage::open_with_options(&dir, options.clone())
+        .err()
+        .unwrap();
+    assert!(format!("{:#}", err).contains("allow_format_change"));
+
+    options.allow_format_change = true;
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert!(storage
+        .put(Bytes::from(vec![b'k'; 17]), Bytes::from("1"))
+        .is_err());
+}
+
+#[test]
+fn test_retain_live_files() {
+    use crate::retention::FileId;
+
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), &["a"]);
+    write_sst(&dir.path().join("2.sst"), &["b"]);
+    let storage = LsmStorage::open(&dir).unwrap();
+
+    let guard = storage.retain_live_files();
+    assert_eq!(guard.files(), &[FileId::Sst(1), FileId::Sst(2)]);
+    let other = storage.retain_live_files();
+    assert_eq!(storage.pinned_files().len(), 2);
+
+    drop(guard);
+    assert_eq!(storage.pinned_files().len(), 2);
+    drop(other);
+    assert!(storage.pinned_files().is_empty());
+
+    // live files are never obsolete
+    assert!(storage.purge_obsolete_files().unwrap().is_empty());
+    assert_eq!(keys(&storage), vec!["a", "b"]);
+}
+
+#[test]
+fn test_compaction_plan_goes_stale() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), &["a", "b"]);
+    write_sst(&dir.path().join("2.sst"), &["b", "c"]);
+    let storage = LsmStorage::open(&dir).unwrap();
+
+    let mut plan = storage.plan_compaction(None).unwrap();
+    assert_eq!(plan.level, 0);
+    assert_eq!(plan.inputs.len(), 2);
+    assert_eq!(plan.inputs[0].sst_id, 2);
+
+    plan.inputs.pop();
+    let err = storage.compact_with_plan(&plan).unwrap_err();
+    assert!(err.to_string().contains("stale"));
+}
+
+#[test]
+fn test_open_merges_small_l0_tables() {
+    let dir = tempdir().unwrap();
+    let mut expected = std::collections::BTreeMap::new();
+    for table in 1..=10 {
+        // each table overwrites half of the previous one and deletes one of its keys
+        let mut builder = SsTableBuilder::new(128);
+        for key in table * 30..table * 30 + 60 {
+            let value = if key == table * 30 + 1 {
+                String::new()
+            } else {
+                format!("{:05}_{:010}", table, key)
+            };
+            let key = format!("key_{:03}", key);
+            builder.add(key.as_bytes(), value.as_bytes());
+            expected.insert(Bytes::from(key), Bytes::from(value));
+        }
+        let path = dir.path().join(format!("{}.sst", table));
+        let sst = builder.build_for_test(path).unwrap();
+        assert!((1024..4096).contains(&sst.file_size()));
+    }
+    let scan = |storage: &LsmStorage| {
+        let mut iter = storage.scan(..).unwrap();
+        let mut entries = vec![];
+        while iter.is_valid() {
+            entries.push((iter.key().clone(), iter.value().clone()));
+            iter.next().unwrap();
+        }
+        entries
+    };
+    expected.retain(|_, value| !value.is_empty());
+    let expected = expected.into_iter().collect::<Vec<_>>();
+
+    let options = LsmStorageOptions {
+        small_sst_threshold: Some(4096),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    assert_eq!(storage.metrics().small_sst_merges(), 1);
+    assert_eq!(
+        storage.retain_live_files().files(),
+        &[crate::retention::FileId::Sst(11)]
+    );
+    assert_eq!(scan(&storage), expected);
+    drop(storage);
+
+    // nothing left to merge, and the merged table keeps the data across reopens
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert_eq!(storage.metrics().small_sst_merges(), 0);
+    assert_eq!(scan(&storage), expected);
+    let ssts = std::fs::read_dir(dir.path())
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("sst".as_ref()))
+        .count();
+    assert_eq!(ssts, 1);
+}
+
+#[test]
+fn test_small_tables_merge_in_the_background() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        small_sst_threshold: Some(1 << 20),
+        small_sst_merge_interval: Some(Duration::from_millis(20)),
+        background_compaction: false,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    for table in 0..5 {
+        storage
+            .put(Bytes::from("shared"), Bytes::from(table.to_string()))
+            .unwrap();
+        storage
+            .put(Bytes::from(format!("key_{}", table)), Bytes::from("value"))
+            .unwrap();
+        storage.sync().unwrap();
+    }
+
+    // the merged table takes an id none of the flushed tables had
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while storage.sst_ids_by_level()[0].len() > 1 {
+        assert!(
+            Instant::now() < deadline,
+            "{:?}",
+            storage.sst_ids_by_level()
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let merged = storage.sst_ids_by_level()[0][0];
+    assert!(merged > 4);
+    assert_eq!(storage.metrics().small_sst_merges(), 1);
+    assert_eq!(&storage.get(b"shared").unwrap().unwrap()[..], b"4");
+    assert_eq!(
+        read_tables(dir.path()).unwrap(),
+        vec![(0, merged)],
+        "the manifest records only the merged table"
+    );
+
+    // a later flush still shadows the merged table
+    storage
+        .put(Bytes::from("shared"), Bytes::from("5"))
+        .unwrap();
+    storage.sync().unwrap();
+    assert!(storage.sst_ids_by_level()[0][1] > merged);
+    assert_eq!(&storage.get(b"shared").unwrap().unwrap()[..], b"5");
+    storage.close().unwrap();
+}
+
+#[test]
+fn test_wal_replay_rotates_memtables() {
+    let dir = tempdir().unwrap();
+    let value = |idx: usize| Bytes::from(format!("{:0>1000}", idx));
+    {
+        // more than a memtable's worth of writes that never got flushed
+        let mut wal = crate::wal::Wal::create(dir.path().join("memtable.wal")).unwrap();
+        for idx in 0..1500 {
+            let key = Bytes::from(format!("key_{:05}", idx));
+            wal.append(&key, &value(idx)).unwrap();
+        }
+        wal.append(&Bytes::from("key_00007"), &Bytes::new())
+            .unwrap();
+    }
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(storage.metrics().wal_records_replayed(), 1501);
+    assert!(storage.num_imm_memtables() >= 1);
+    for idx in (0..1500).step_by(97) {
+        let key = format!("key_{:05}", idx);
+        assert_eq!(storage.get(key.as_bytes()).unwrap(), Some(value(idx)));
+    }
+    assert_eq!(storage.get(b"key_00007").unwrap(), None);
+    assert_eq!(keys(&storage).len(), 1499);
+}
+
+#[test]
+fn test_close_marks_a_clean_shutdown() {
+    let dir = tempdir().unwrap();
+    let put = |storage: &LsmStorage, tag: &str| {
+        for idx in 0..10 {
+            let key = format!("key_{:02}", idx);
+            storage.put(key.into(), tag.to_string().into()).unwrap();
+        }
+    };
+    let expect = |storage: &LsmStorage, tag: &str| {
+        for idx in 0..10 {
+            let key = format!("key_{:02}", idx);
+            assert_eq!(
+                storage.get(key.as_bytes()).unwrap(),
+                Some(Bytes::from(tag.to_string()))
+            );
+        }
+    };
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    put(&storage, "first");
+    storage.close().unwrap();
+    let err = storage
+        .put(Bytes::from("key"), Bytes::from("late"))
+        .unwrap_err();
+    assert_eq!(err.downcast_ref::<LsmError>(), Some(&LsmError::Closed));
+    expect(&storage, "first");
+    storage.close().unwrap();
+    drop(storage);
+
+    // the memtable was flushed, so there is no log to replay
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert!(storage.open_report().clean_shutdown);
+    assert_eq!(storage.open_report().wal_records_replayed, 0);
+    expect(&storage, "first");
+
+    // killed without `close`: the marker is gone and the log is replayed
+    put(&storage, "second");
+    storage.kill();
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert!(!storage.open_report().clean_shutdown);
+    assert_eq!(storage.open_report().wal_records_replayed, 10);
+    expect(&storage, "second");
+    storage.kill();
+
+    // closed without the flush, the log is still replayed, whole
+    let options = LsmStorageOptions {
+        flush_on_close: false,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    assert!(!storage.open_report().clean_shutdown);
+    put(&storage, "third");
+    storage.close().unwrap();
+    drop(storage);
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert!(storage.open_report().clean_shutdown);
+    assert_eq!(storage.open_report().wal_records_replayed, 20);
+    expect(&storage, "third");
+}
+
+#[test]
+fn test_dropping_the_last_handle_closes() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    for idx in 0..100 {
+        let key = format!("key_{:03}", idx);
+        storage
+            .put(Bytes::from(key), Bytes::from(format!("value_{}", idx)))
+            .unwrap();
+    }
+    let worker = storage.worker_thread.clone();
+    assert!(worker.lock().is_some());
+
+    // another handle keeps the storage open
+    let other = storage.clone();
+    drop(storage);
+    assert!(worker.lock().is_some());
+    drop(other);
+    assert!(worker.lock().is_none());
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert!(storage.open_report().clean_shutdown);
+    assert_eq!(storage.open_report().wal_records_replayed, 0);
+    for idx in 0..100 {
+        let key = format!("key_{:03}", idx);
+        assert_eq!(
+            storage.get(key.as_bytes()).unwrap(),
+            Some(Bytes::from(format!("value_{}", idx)))
+        );
+    }
+
+    drop(storage);
+
+    // handles dropped at the same time still close the storage, once
+    for _ in 0..20 {
+        let storage = LsmStorage::open(&dir).unwrap();
+        assert!(storage.open_report().clean_shutdown);
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(4));
+        let threads = (0..4)
+            .map(|_| {
+                let (storage, barrier) = (storage.clone(), barrier.clone());
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    drop(storage);
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(storage);
+        threads
+            .into_iter()
+            .for_each(|thread| thread.join().unwrap());
+    }
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert!(storage.open_report().clean_shutdown);
+}
+
+#[test]
+fn test_retired_files_go_through_trash() {
+    let dir = tempdir().unwrap();
+    let mut retired_bytes = 0;
+    for table in 1..=6 {
+        let key = format!("key_{:03}", table);
+        write_sst(&dir.path().join(format!("{}.sst", table)), &[&key]);
+        retired_bytes += std::fs::metadata(dir.path().join(format!("{}.sst", table)))
+            .unwrap()
+            .len();
+    }
+    let trash = dir.path().join(crate::retention::TRASH_DIR);
+    let open = |grace_period| {
+        let options = LsmStorageOptions {
+            small_sst_threshold: Some(4096),
+            trash: TrashOptions {
+                grace_period,
+                files_per_sec: Some(50),
+                subdir: true,
+            },
+            ..Default::default()
+        };
+        LsmStorage::open_with_options(&dir, options).unwrap()
+    };
+
+    // the merge at open retires every table, the merged one takes a fresh id
+    let storage = open(Duration::from_secs(3600));
+    let stats = storage.trash_stats();
+    assert_eq!(
+        (stats.pending_files, stats.pending_bytes),
+        (6, retired_bytes)
+    );
+    assert_eq!(std::fs::read_dir(&trash).unwrap().count(), 6);
+    assert_eq!(keys(&storage).len(), 6);
+    drop(storage);
+
+    // what was pending before a crash is picked up again
+    let storage = open(Duration::from_millis(300));
+    assert_eq!(storage.trash_stats().pending_files, 6);
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(storage.trash_stats().pending_files, 6);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while storage.trash_stats().pending_files > 0 {
+        assert!(Instant::now() < deadline, "{:?}", storage.trash_stats());
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(storage.trash_stats().pending_bytes, 0);
+    assert_eq!(std::fs::read_dir(&trash).unwrap().count(), 0);
+    assert_eq!(keys(&storage).len(), 6);
+}
+
+#[test]
+fn test_purge_trash_now() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), &["a"]);
+    write_sst(&dir.path().join("2.sst"), &["b"]);
+    let options = LsmStorageOptions {
+        small_sst_threshold: Some(4096),
+        trash: TrashOptions {
+            grace_period: Duration::from_secs(3600),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert_eq!(storage.trash_stats().pending_files, 2);
+    assert!(storage.purge_obsolete_files().unwrap().is_empty());
+    assert!(dir.path().join("1.sst").exists());
+
+    let mut purged = storage.purge_trash_now().unwrap();
+    purged.sort();
+    assert_eq!(
+        purged,
+        vec![
+            crate::retention::FileId::Sst(1),
+            crate::retention::FileId::Sst(2)
+        ]
+    );
+    assert!(!dir.path().join("1.sst").exists());
+    assert_eq!(storage.trash_stats().pending_files, 0);
+}
+
+#[test]
+fn test_sst_layout_records_levels() {
+    let dir = tempdir().unwrap();
+    // a database from before the layouts, and a table a compaction wrote into L1
+    write_sst(&dir.path().join("1.sst"), &["a", "b"]);
+    write_sst(&dir.path().join("2.sst"), &["b", "c"]);
+    std::fs::create_dir(dir.path().join("L1")).unwrap();
+    write_sst(&dir.path().join("L1").join("5.sst"), &["x", "y"]);
+
+    let open = |layout| {
+        let options = LsmStorageOptions {
+            sst_layout: layout,
+            ..Default::default()
+        };
+        LsmStorage::open_with_options(&dir, options).unwrap()
+    };
+    let assert_layout = |storage: &LsmStorage, path_of: &dyn Fn(usize, usize) -> String| {
+        let mut expected = vec![];
+        for (level, ids) in storage.sst_ids_by_level().into_iter().enumerate() {
+            expected.extend(ids.into_iter().map(|id| path_of(level, id)));
+        }
+        let mut found = vec![];
+        for entry in walk(dir.path()) {
+            let path = entry.strip_prefix(dir.path()).unwrap();
+            if path.extension().map_or(false, |ext| ext == "sst") {
+                found.push(path.to_str().unwrap().to_string());
+            }
+        }
+        expected.sort();
+        found.sort();
+        assert_eq!(found, expected);
+    };
+
+    let storage = open(SstLayout::LevelDirs);
+    assert_eq!(storage.sst_ids_by_level()[..2], [vec![1, 2], vec![5]]);
+    assert_eq!(keys(&storage)[..3], ["a", "b", "c"]);
+    storage
+        .put(Bytes::from_static(b"d"), Bytes::from_static(b"value_d"))
+        .unwrap();
+    storage.sync().unwrap();
+    drop(storage);
+
+    let storage = open(SstLayout::LevelDirs);
+    assert_eq!(storage.sst_ids_by_level()[..2], [vec![1, 2, 6], vec![5]]);
+    assert_layout(&storage, &|level, id| format!("L{}/{}.sst", level, id));
+    drop(storage);
+
+    let storage = open(SstLayout::LevelSuffix);
+    assert_eq!(storage.sst_ids_by_level()[..2], [vec![1, 2, 6], vec![5]]);
+    assert_layout(&storage, &|level, id| format!("{}_L{}.sst", id, level));
+    drop(storage);
+
+    // the flat layout has no room for the levels, which the manifest keeps
+    let storage = open(SstLayout::Flat);
+    assert_eq!(storage.sst_ids_by_level()[..2], [vec![1, 2, 6], vec![5]]);
+    assert_layout(&storage, &|_, id| format!("{}.sst", id));
+}
+
+/// The files under `dir`, recursively.
+fn walk(dir: &Path) -> Vec<std::path::PathBuf> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(walk(&path));
+        } else {
+            files.push(path);
+        }
+    }
+    files
+}
+
+#[test]
+fn test_db_id_survives_reopen() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    let db_id = storage.db_id();
+    let report = storage.open_report().clone();
+    assert_eq!(report.db_id, db_id);
+    assert!(report.created);
+    assert!(report.to_string().contains(&db_id.to_string()));
+    storage.put(Bytes::from("a"), Bytes::from("1")).unwrap();
+    let close = storage.close_report();
+    assert_eq!(close.db_id, db_id);
+    assert_eq!(close.bytes_ingested, 2);
+    storage.kill();
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(storage.db_id(), db_id);
+    let report = storage.open_report();
+    assert!(!report.created);
+    assert_eq!(report.wal_records_replayed, 1);
+    assert_eq!(
+        report.fingerprint,
+        OptionsFingerprint::of(&Default::default())
+    );
+    drop(storage);
+
+    let other = tempdir().unwrap();
+    assert_ne!(LsmStorage::open(&other).unwrap().db_id(), db_id);
+
+    // a manifest written before databases had ids gets one, kept from then on
+    let header = OptionsFingerprint::of(&Default::default()).encode();
+    std::fs::write(other.path().join(MANIFEST), header).unwrap();
+    let adopted = LsmStorage::open(&other).unwrap().db_id();
+    assert_eq!(read_db_id(other.path()).unwrap(), Some(adopted));
+    assert_eq!(LsmStorage::open(&other).unwrap().db_id(), adopted);
+}
+
+/// Write a table of many blocks with entry checksums, and flip a byte of the first value of
+/// block `corrupt_block` on disk.
+fn write_sst_with_corrupt_block(path: &Path, corrupt_block: usize) -> usize {
+    let mut builder = SsTableBuilder::new(128).with_entry_checksums();
+    for idx in 0..200 {
+        let key = format!("key_{:03}", idx);
+        builder.add(key.as_bytes(), format!("value_{}", key).as_bytes());
+    }
+    builder.build_for_test(path).unwrap();
+    let table = SsTable::open(0, None, FileObject::open(path).unwrap()).unwrap();
+    let num_blocks = table.num_of_blocks();
+    // past the key and the two lengths
+    let pos = table.block_metas().unwrap()[corrupt_block].offset + 2 + "key_000".len() + 2;
+    drop(table);
+    let mut data = std::fs::read(path).unwrap();
+    data[pos] ^= 0xff;
+    std::fs::write(path, data).unwrap();
+    num_blocks
+}
+
+/// Picks the corrupted block among 8 samples of the table, though not among 4.
+const SEED: u64 = 15;
+
+fn verify_with(level: VerifyLevel, seed: u64) -> LsmStorageOptions {
+    LsmStorageOptions {
+        verify_on_open: level,
+        verify_seed: Some(seed),
+        ..best_effort()
+    }
+}
+
+#[test]
+// the block checksum catches the flipped byte first, with a panic until decoding can fail
+#[cfg_attr(feature = "checksum", ignore)]
+fn test_verify_on_open_samples_blocks() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), &["a", "b"]);
+    let num_blocks = write_sst_with_corrupt_block(&dir.path().join("2.sst"), 37);
+    assert_eq!(num_blocks, 50);
+
+    // the footers check out, and too few blocks are sampled to notice
+    for level in [
+        VerifyLevel::FootersOnly,
+        VerifyLevel::SampleBlocks { per_table: 4 },
+    ] {
+        let storage = LsmStorage::open_with_options(&dir, verify_with(level, SEED)).unwrap();
+        assert_eq!(storage.sst_ids_by_level().concat().len(), 2);
+        assert_eq!(
+            storage.open_report().verify_seed,
+            matches!(level, VerifyLevel::SampleBlocks { .. }).then_some(SEED)
+        );
+        drop(storage);
+    }
+
+    let level = VerifyLevel::SampleBlocks { per_table: 8 };
+    let options = LsmStorageOptions {
+        recovery: RecoveryMode::Strict,
+        ..verify_with(level, SEED)
+    };
+    let err = LsmStorage::open_with_options(&dir, options).err().unwrap();
+    assert!(format!("{:#}", err).contains("2.sst"));
+
+    let storage = LsmStorage::open_with_options(&dir, verify_with(level, SEED)).unwrap();
+    assert_eq!(storage.open_report().verify_seed, Some(SEED));
+    assert_eq!(storage.open_report().recovered_tables, 1);
+    assert_eq!(keys(&storage), vec!["a", "b"]);
+    assert!(!dir.path().join("2.sst").exists());
+    let sidecar = std::fs::read_dir(dir.path().join(QUARANTINE_DIR))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().map_or(false, |ext| ext == "json"))
+        .unwrap();
+    let sidecar = std::fs::read_to_string(sidecar).unwrap();
+    assert!(sidecar.contains("\"operation\":\"verify sst\""));
+}
+
+#[test]
+// the block checksum catches the flipped byte first, with a panic until decoding can fail
+#[cfg_attr(feature = "checksum", ignore)]
+fn test_verify_on_open_none_defers_to_reads() {
+    let dir = tempdir().unwrap();
+    write_sst_with_corrupt_block(&dir.path().join("1.sst"), 37);
+
+    let storage = LsmStorage::open_with_options(&dir, best_effort()).unwrap();
+    assert_eq!(storage.open_report().verify_seed, None);
+    assert_eq!(storage.open_report().recovered_tables, 1);
+    let mut iter = storage.scan(..).unwrap();
+    let err = loop {
+        if let Err(err) = iter.next() {
+            break err;
+        }
+        assert!(iter.is_valid(), "the scan missed the corrupted entry");
+    };
+    assert!(matches!(
+        err.downcast_ref::<LsmError>(),
+        Some(LsmError::EntryChecksumMismatch { .. })
+    ));
+    drop(iter);
+    drop(storage);
+
+    let storage = LsmStorage::open_with_options(&dir, verify_with(VerifyLevel::Full, 0)).unwrap();
+    assert_eq!(storage.open_report().recovered_tables, 0);
+}
+
+#[test]
+fn test_open_adopts_legacy_files() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), &["a", "b"]);
+    write_sst(&dir.path().join("2.sst"), &["b", "c"]);
+
+    let options = LsmStorageOptions {
+        adopt_legacy_files: false,
+        ..Default::default()
+    };
+    let err = LsmStorage::open_with_options(&dir, options).err().unwrap();
+    assert_eq!(
+        err.downcast_ref::<LsmError>(),
+        Some(&LsmError::LegacyDirectory { tables: 2 })
+    );
+    assert!(!dir.path().join(MANIFEST).exists());
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert!(!storage.open_report().created);
+    assert_eq!(keys(&storage), vec!["a", "b", "c"]);
+    assert_eq!(read_tables(dir.path()).unwrap(), vec![(0, 1), (0, 2)]);
+    assert_eq!(
+        read_header(dir.path()).unwrap(),
+        Some(OptionsFingerprint::of(&LsmStorageOptions::default()))
+    );
+}
+
+#[test]
+fn test_adopt_legacy() {
+    let dir = tempdir().unwrap();
+    write_sst(&dir.path().join("1.sst"), &["a", "b"]);
+    write_sst(&dir.path().join("2.sst"), &["c"]);
+    write_sst(&dir.path().join("3.sst"), &["d"]);
+    corrupt_footer(&dir.path().join("2.sst"));
+
+    let report = LsmStorage::adopt_legacy(&dir).unwrap();
+    assert_eq!(report.adopted, vec![1, 3]);
+    assert_eq!(report.unreadable.len(), 1);
+    assert_eq!(report.unreadable[0].file, dir.path().join("2.sst"));
+    assert!(!dir.path().join("2.sst").exists());
+    assert_eq!(read_tables(dir.path()).unwrap(), vec![(0, 1), (0, 3)]);
+    // only once
+    assert!(LsmStorage::adopt_legacy(&dir).is_err());
+
+    let options = LsmStorageOptions {
+        adopt_legacy_files: false,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert_eq!(keys(&storage), vec!["a", "b", "d"]);
+}
+
+#[test]
+fn test_manifest_replays_flushes_and_compactions() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        sst_layout: SstLayout::Flat,
+        ..Default::default()
+    };
+    let put = |storage: &LsmStorage, range: std::ops::Range<usize>, tag: &str| {
+        for idx in range {
+            let key = Bytes::from(format!("key_{:03}", idx));
+            storage
+                .put(key, Bytes::from(format!("{}_{}", tag, idx)))
+                .unwrap();
+        }
+        storage.sync().unwrap();
+    };
+
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    put(&storage, 0..100, "first");
+    put(&storage, 50..150, "second");
+    storage.compact(0).unwrap();
+    put(&storage, 100..200, "third");
+    let levels = storage.sst_ids_by_level();
+    assert!(levels[2..].iter().all(Vec::is_empty));
+    assert_eq!(levels[0].len(), 1);
+    let expected = storage
+        .scan(..)
+        .map(|mut iter| {
+            let mut entries = vec![];
+            while iter.is_valid() {
+                entries.push((iter.key().clone(), iter.value().clone()));
+                iter.next().unwrap();
+            }
+            entries
+        })
+        .unwrap();
+    assert_eq!(expected.len(), 200);
+    drop(storage);
+
+    // the compacted tables are still on disk, but the manifest has them removed
+    assert_eq!(
+        read_tables(dir.path()).unwrap(),
+        vec![(1, levels[1][0]), (0, levels[0][0])]
+    );
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    assert_eq!(storage.sst_ids_by_level(), levels);
+    assert_eq!(storage.open_report().recovered_tables, 2);
+    for (key, value) in &expected {
+        assert_eq!(storage.get(key).unwrap().as_ref(), Some(value));
+    }
+    drop(storage);
+
+    // a record a crash cut short is dropped, and the records after it start a line of their own
+    let mut manifest = std::fs::OpenOptions::new()
+        .append(true)
+        .open(dir.path().join(MANIFEST))
+        .unwrap();
+    std::io::Write::write_all(&mut manifest, b"sst level=1 id=9").unwrap();
+    drop(manifest);
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    assert_eq!(storage.open_report().recovered_tables, 2);
+    put(&storage, 300..310, "fifth");
+    drop(storage);
+    assert!(!read_records(dir.path())
+        .unwrap()
+        .contains(&ManifestRecord::AddTable { level: 1, id: 9 }));
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert_eq!(storage.open_report().recovered_tables, 3);
+    assert_eq!(keys(&storage).len(), 210);
+}
+
+#[test]
+fn test_manifest_is_the_source_of_live_tables() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        sst_layout: SstLayout::Flat,
+        trash: TrashOptions {
+            grace_period: Duration::from_secs(3600),
+            files_per_sec: None,
+            subdir: true,
+        },
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    storage.put(Bytes::from("k"), Bytes::from("old")).unwrap();
+    storage.sync().unwrap();
+    storage.put(Bytes::from("k"), Bytes::from("new")).unwrap();
+    storage.sync().unwrap();
+    let ids = storage.sst_ids_by_level().concat();
+    let newest = ids.iter().max().copied().unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    // a copy of the older table under a newer id, and a table a crash cut short, neither of
+    // which the manifest records
+    let sst = |id: usize| dir.path().join(format!("{}.sst", id));
+    std::fs::copy(sst(ids[0].min(ids[1])), sst(newest + 5)).unwrap();
+    std::fs::write(sst(newest + 6), b"partial").unwrap();
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    assert_eq!(&storage.get(b"k").unwrap().unwrap()[..], b"new");
+    let mut reopened = storage.sst_ids_by_level().concat();
+    reopened.sort();
+    let mut expected = ids.clone();
+    expected.sort();
+    assert_eq!(reopened, expected);
+    // which go to the trash as orphans
+    let mut trashed = std::fs::read_dir(dir.path().join(crate::retention::TRASH_DIR))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    trashed.sort();
+    assert_eq!(
+        trashed,
+        vec![format!("{}.sst", newest + 5), format!("{}.sst", newest + 6)]
+    );
+    drop(storage);
+
+    // a recorded table that is missing fails a strict open, and is dropped by a best-effort one
+    std::fs::remove_file(sst(ids[0])).unwrap();
+    let err = LsmStorage::open_with_options(&dir, options.clone())
+        .err()
+        .unwrap();
+    assert!(format!("{:#}", err).contains("missing"), "{:#}", err);
+    let storage = LsmStorage::open_with_options(
+        &dir,
+        LsmStorageOptions {
+            recovery: RecoveryMode::BestEffort,
+            ..options.clone()
+        },
+    )
+    .unwrap();
+    assert_eq!(storage.sst_ids_by_level().concat(), vec![ids[1]]);
+    drop(storage);
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert_eq!(storage.sst_ids_by_level().concat(), vec![ids[1]]);
+}
+
+/// Copy the files under `from` to `to`, recursively.
+fn copy_dir(from: &Path, to: &Path) {
+    std::fs::create_dir_all(to).unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let path = entry.unwrap().path();
+        let target = to.join(path.file_name().unwrap());
+        if path.is_dir() {
+            copy_dir(&path, &target);
+        } else {
+            std::fs::copy(&path, &target).unwrap();
+        }
+    }
+}
+
+/// What an open leaves, for opens of copies of the same directory to compare: the entries, the
+/// tables, the manifest and the files outside the trash and the quarantine, whose names carry
+/// times.
+type OpenedState = (
+    Vec<(Bytes, Bytes)>,
+    Vec<Vec<usize>>,
+    Vec<(usize, usize)>,
+    Option<OptionsFingerprint>,
+    Vec<(std::path::PathBuf, u64)>,
+    usize,
+);
+
+fn opened_state(storage: &LsmStorage, dir: &Path) -> OpenedState {
+    let mut iter = storage.scan(..).unwrap();
+    let mut entries = vec![];
+    while iter.is_valid() {
+        entries.push((iter.key().clone(), iter.value().clone()));
+        iter.next().unwrap();
+    }
+    let mut files = walk(dir)
+        .into_iter()
+        .map(|path| path.strip_prefix(dir).unwrap().to_path_buf())
+        .filter(|path| !path.starts_with(QUARANTINE_DIR) && !path.starts_with("trash"))
+        .map(|path| {
+            let len = std::fs::metadata(dir.join(&path)).unwrap().len();
+            (path, len)
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    let quarantined = match std::fs::read_dir(dir.join(QUARANTINE_DIR)) {
+        Ok(entries) => entries
+            .filter(|entry| entry.as_ref().unwrap().path().extension() != Some("json".as_ref()))
+            .count(),
+        Err(_) => 0,
+    };
+    (
+        entries,
+        storage.sst_ids_by_level(),
+        read_tables(dir).unwrap(),
+        read_header(dir).unwrap(),
+        files,
+        quarantined,
+    )
+}
+
+/// `state` with the ids of its tables left out. A merge takes the next free id, which depends
+/// on whether the crashed open had recorded the ids of the tables it quarantined.
+fn without_table_ids(mut state: OpenedState) -> OpenedState {
+    for id in state.1.iter_mut().flatten() {
+        *id = 0;
+    }
+    for (_, id) in &mut state.2 {
+        *id = 0;
+    }
+    for (path, _) in &mut state.4 {
+        if path.extension() == Some("sst".as_ref()) {
+            path.set_file_name("0.sst");
+        }
+    }
+    state.4.sort();
+    state
+}
+
+#[test]
+fn test_open_converges_after_crash_at_any_step() {
+    let options = LsmStorageOptions {
+        recovery: RecoveryMode::BestEffort,
+        small_sst_threshold: Some(1 << 20),
+        sst_layout: SstLayout::LevelDirs,
+        ..Default::default()
+    };
+    let template = |managed: bool| {
+        let dir = tempdir().unwrap();
+        for table in 1..=4 {
+            let keys = (table * 10..table * 10 + 20)
+                .map(|key| format!("key_{:03}", key))
+                .collect::<Vec<_>>();
+            let keys = keys.iter().map(|key| key.as_str()).collect::<Vec<_>>();
+            write_sst(&dir.path().join(format!("{}.sst", table)), &keys);
+        }
+        write_sst(&dir.path().join("5.sst"), &["key_999"]);
+        corrupt_footer(&dir.path().join("5.sst"));
+        // a table a crash kept from being renamed into place
+        std::fs::write(dir.path().join("6.sst.tmp"), b"partial").unwrap();
+        {
+            let mut wal = crate::wal::Wal::create(dir.path().join("memtable.wal")).unwrap();
+            for idx in 0..5 {
+                let key = Bytes::from(format!("key_{:03}", idx * 7));
+                wal.append(&key, &Bytes::from(format!("wal_{}", idx)))
+                    .unwrap();
+            }
+            wal.append(&Bytes::from("key_015"), &Bytes::new()).unwrap();
+            let batch = (0..3)
+                .map(|idx| (Bytes::from(format!("torn_{}", idx)), Bytes::from("torn")))
+                .collect::<Vec<_>>();
+            wal.append_batch(&batch).unwrap();
+        }
+        // which a crash cut short
+        let wal = dir.path().join("memtable.wal");
+        let len = std::fs::metadata(&wal).unwrap().len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&wal)
+            .unwrap()
+            .set_len(len - 4096)
+            .unwrap();
+        if managed {
+            // options from an earlier open, which this one records anew
+            let earlier = LsmStorageOptions {
+                block_cache_capacity: 1 << 10,
+                ..Default::default()
+            };
+            crate::manifest::write_header(
+                dir.path(),
+                &crate::manifest::DbId::generate(),
+                &OptionsFingerprint::of(&earlier),
+            )
+            .unwrap();
+            crate::manifest::append_records(
+                dir.path(),
+                (1..=5).map(|id| ManifestRecord::AddTable { level: 0, id }),
+            )
+            .unwrap();
+        }
+        dir
+    };
+
+    for managed in [false, true] {
+        let template = template(managed);
+        let expected = {
+            let dir = tempdir().unwrap();
+            copy_dir(template.path(), dir.path());
+            let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+            opened_state(&storage, dir.path())
+        };
+        // the four readable tables, merged into one with a fresh id
+        assert_eq!(expected.1.concat(), vec![6]);
+        assert_eq!(expected.1[0], vec![6]);
+        assert_eq!(expected.2, vec![(0, 6)]);
+        assert_eq!(expected.3, Some(OptionsFingerprint::of(&options)));
+        assert_eq!(expected.5, 1);
+        assert!(expected.0.iter().any(|(_, value)| &value[..] == b"wal_4"));
+        assert!(!expected.0.iter().any(|(_, value)| &value[..] == b"torn"));
+
+        let mut steps = 0;
+        loop {
+            let dir = tempdir().unwrap();
+            copy_dir(template.path(), dir.path());
+            crate::storage::OPEN_FAULT.with(|fault| fault.set(Some(steps)));
+            let crashed = LsmStorage::open_with_options(&dir, options.clone());
+            crate::storage::OPEN_FAULT.with(|fault| fault.set(None));
+            let crashed = match crashed {
+                Ok(_) => break,
+                Err(err) => err,
+            };
+            assert!(
+                crashed.to_string().starts_with("injected fault"),
+                "{}",
+                crashed
+            );
+
+            let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+            assert_eq!(
+                without_table_ids(opened_state(&storage, dir.path())),
+                without_table_ids(expected.clone()),
+                "crashed after step {}",
+                steps
+            );
+            steps += 1;
+        }
+        // every step of the open was a place to crash at
+        assert_eq!(steps, 7);
+    }
+}
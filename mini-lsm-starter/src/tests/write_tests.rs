@@ -0,0 +1,796 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::iterators::StorageIterator;
+use crate::lsm_storage::{MAX_KEY_SIZE, MAX_VALUE_SIZE};
+use crate::prelude::{
+    EntryOp, LsmError, LsmStorage, LsmStorageOptions, WriteBatch, WriteOptions, WriteToken,
+};
+use crate::table::SsTable;
+
+#[test]
+fn test_write_validation() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+
+    assert!(storage.delete(b"").is_err());
+    assert!(storage.put(Bytes::new(), Bytes::from("1")).is_err());
+    assert!(storage.put(Bytes::from("1"), Bytes::new()).is_err());
+
+    let long_key = vec![b'k'; MAX_KEY_SIZE + 1];
+    assert!(storage.delete(&long_key).is_err());
+    assert!(storage
+        .put(Bytes::from(long_key), Bytes::from("1"))
+        .is_err());
+    assert!(storage
+        .put(
+            Bytes::from("1"),
+            Bytes::from(vec![b'v'; MAX_VALUE_SIZE + 1])
+        )
+        .is_err());
+
+    // none of the rejected writes reached the memtable
+    assert!(storage.get(b"1").unwrap().is_none());
+
+    storage
+        .put(Bytes::from(vec![b'k'; MAX_KEY_SIZE]), Bytes::from("1"))
+        .unwrap();
+    storage.delete(&vec![b'k'; MAX_KEY_SIZE]).unwrap();
+    assert!(storage.get(&vec![b'k'; MAX_KEY_SIZE]).unwrap().is_none());
+}
+
+#[test]
+fn test_write_options_durability() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    let sync = WriteOptions {
+        sync: true,
+        ..Default::default()
+    };
+    let no_wal = WriteOptions {
+        disable_wal: true,
+        ..Default::default()
+    };
+
+    assert!(storage
+        .put_opt(
+            Bytes::from("a"),
+            Bytes::from("1"),
+            WriteOptions {
+                disable_wal: true,
+                sync: true,
+            }
+        )
+        .is_err());
+
+    storage
+        .put_opt(Bytes::from("precious"), Bytes::from("1"), sync)
+        .unwrap();
+    storage
+        .put_opt(Bytes::from("cache"), Bytes::from("2"), no_wal)
+        .unwrap();
+    storage.delete_opt(b"precious", no_wal).unwrap();
+    storage
+        .put(Bytes::from("unsynced"), Bytes::from("3"))
+        .unwrap();
+    assert!(storage.get(b"precious").unwrap().is_none());
+    assert_eq!(storage.get(b"cache").unwrap(), Some(Bytes::from("2")));
+
+    storage.simulate_power_loss().unwrap();
+    storage.kill();
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(storage.get(b"precious").unwrap(), Some(Bytes::from("1")));
+    assert!(storage.get(b"cache").unwrap().is_none());
+    assert!(storage.get(b"unsynced").unwrap().is_none());
+}
+
+#[test]
+fn test_flush_write_amplification() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    for idx in 0..100 {
+        storage
+            .put(
+                Bytes::from(format!("key_{:03}", idx)),
+                Bytes::from(format!("value_{:010}", idx)),
+            )
+            .unwrap();
+    }
+    storage.delete(b"key_000").unwrap();
+    // a rejected write takes nothing in
+    assert!(storage.put(Bytes::from("k"), Bytes::new()).is_err());
+
+    let metrics = storage.metrics();
+    assert_eq!(metrics.bytes_ingested(), 100 * (7 + 16) + 7);
+    assert_eq!(metrics.write_amplification(), 0.0);
+
+    storage.sync().unwrap();
+    let flushed = std::fs::metadata(dir.path().join("0.sst")).unwrap().len();
+    assert_eq!(metrics.flush_bytes_written(), flushed);
+    assert_eq!(
+        metrics.write_amplification(),
+        flushed as f64 / metrics.bytes_ingested() as f64
+    );
+    assert_eq!(metrics.last_compaction(), None);
+}
+
+#[test]
+fn test_small_memtables_flush_often() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default()
+        .with_memtable_size_limit(4096)
+        .with_block_size(8192)
+        .with_background_compaction(false);
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert!(!storage.background_health()[0].alive);
+    for idx in 0..200 {
+        storage
+            .put(
+                Bytes::from(format!("key_{:03}", idx)),
+                Bytes::from(format!("value_{:050}", idx)),
+            )
+            .unwrap();
+    }
+
+    // the writes flushed every full memtable themselves, and nothing compacted them
+    let flushes = storage.metrics().flushes();
+    assert!(flushes >= 3, "{} flushes", flushes);
+    let levels = storage.sst_ids_by_level();
+    assert_eq!(levels[0].len() as u64, flushes);
+    assert!(levels[1..].iter().all(Vec::is_empty));
+    assert_eq!(storage.metrics().compactions(), 0);
+    for idx in 0..200 {
+        assert_eq!(
+            storage.get(format!("key_{:03}", idx).as_bytes()).unwrap(),
+            Some(Bytes::from(format!("value_{:050}", idx)))
+        );
+    }
+
+    for options in [
+        LsmStorageOptions::default().with_block_size(1000),
+        LsmStorageOptions::default().with_memtable_size_limit(0),
+        LsmStorageOptions::default().with_max_imm_memtables(0),
+    ] {
+        let err = match LsmStorage::open_with_options(tempdir().unwrap(), options) {
+            Ok(_) => panic!("opened with invalid options"),
+            Err(err) => err,
+        };
+        assert!(matches!(
+            err.downcast_ref::<LsmError>(),
+            Some(LsmError::InvalidArgument(_))
+        ));
+    }
+}
+
+#[test]
+fn test_sync_installs_flushed_tables() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    // flush by hand, so that the tables are known
+    storage.stop().unwrap();
+    for idx in 0..1100 {
+        storage
+            .put(
+                Bytes::from(format!("key_{:05}", idx)),
+                Bytes::from(vec![b'v'; 1000]),
+            )
+            .unwrap();
+    }
+    storage.sync().unwrap();
+    assert_eq!(storage.sst_ids_by_level().concat(), vec![0]);
+    assert_eq!(storage.memtable_len(), 0);
+    assert_eq!(storage.num_imm_memtables(), 0);
+    assert_eq!(
+        storage.get(b"key_00000").unwrap(),
+        Some(Bytes::from(vec![b'v'; 1000]))
+    );
+
+    // the memtable a batch freezes is flushed along with the current one, and the next flush
+    // does not write over the first table
+    storage
+        .put(Bytes::from("key_00000"), Bytes::from("new"))
+        .unwrap();
+    for prefix in ["fill_a", "fill_b", "fill_c"] {
+        storage.write(batch_of(prefix, 250, 1000)).unwrap();
+    }
+    storage.write(batch_of("batch", 250, 1000)).unwrap();
+    assert_eq!(storage.num_imm_memtables(), 1);
+    storage.sync().unwrap();
+    assert_eq!(storage.sst_ids_by_level().concat(), vec![0, 1, 2]);
+    assert_eq!(storage.num_imm_memtables(), 0);
+    // nothing to flush
+    storage.sync().unwrap();
+    assert_eq!(storage.sst_ids_by_level().concat(), vec![0, 1, 2]);
+
+    let keys: [&[u8]; 3] = [b"key_00000", b"key_01099", b"batch_00249"];
+    let expected = vec![
+        Some(Bytes::from("new")),
+        Some(Bytes::from(vec![b'v'; 1000])),
+        Some(Bytes::from(vec![b'v'; 1000])),
+    ];
+    assert_eq!(storage.get_many(&keys).unwrap(), expected);
+    drop(storage);
+
+    // the WAL was truncated, so the data comes from the tables
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(storage.open_report().wal_records_replayed, 0);
+    assert_eq!(storage.get_many(&keys).unwrap(), expected);
+}
+
+#[test]
+fn test_flush_and_compaction_publish_their_tables() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        background_compaction: false,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    let key_range = |tables: &[Arc<SsTable>]| {
+        tables
+            .iter()
+            .map(|sst| sst.key_range().unwrap().unwrap())
+            .collect::<Vec<_>>()
+    };
+    let range = (Bytes::from("k"), Bytes::from("k"));
+
+    storage.put(Bytes::from("k"), Bytes::from("v")).unwrap();
+    storage.sync().unwrap();
+    let state = storage.describe_state();
+    assert_eq!(key_range(&state.l0_sstables), vec![range.clone()]);
+    assert_eq!(storage.get(b"k").unwrap(), Some(Bytes::from("v")));
+
+    storage.compact(0).unwrap();
+    let state = storage.describe_state();
+    assert!(state.l0_sstables.is_empty());
+    assert_eq!(key_range(&state.levels[0]), vec![range]);
+    assert_eq!(storage.get(b"k").unwrap(), Some(Bytes::from("v")));
+}
+
+#[test]
+fn test_sync_during_writes() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    let writer = storage.clone();
+    let handle = std::thread::spawn(move || {
+        for idx in 0..2000 {
+            writer
+                .put(
+                    Bytes::from(format!("key_{:05}", idx)),
+                    Bytes::from(format!("value_{}", idx)),
+                )
+                .unwrap();
+        }
+    });
+    while !handle.is_finished() {
+        storage.sync().unwrap();
+    }
+    handle.join().unwrap();
+    storage.sync().unwrap();
+    assert_eq!(storage.memtable_len(), 0);
+    assert_eq!(storage.num_imm_memtables(), 0);
+
+    // every write made it into exactly one table
+    let keys = (0..2000)
+        .map(|idx| format!("key_{:05}", idx))
+        .collect::<Vec<_>>();
+    let keys = keys.iter().map(|key| key.as_bytes()).collect::<Vec<_>>();
+    let found = storage.get_many(&keys).unwrap();
+    for (idx, value) in found.into_iter().enumerate() {
+        assert_eq!(value, Some(Bytes::from(format!("value_{}", idx))));
+    }
+    let mut ids = storage.sst_ids_by_level().concat();
+    let tables = ids.len();
+    ids.dedup();
+    assert_eq!(ids.len(), tables);
+}
+
+#[test]
+fn test_concurrent_puts_and_flushes_lose_nothing() {
+    const WRITERS: usize = 4;
+    const KEYS: usize = 500;
+    let key = |writer: usize, idx: usize| Bytes::from(format!("key_{}_{:04}", writer, idx));
+    let value = |writer: usize, idx: usize| Bytes::from(format!("value_{}_{}", writer, idx));
+
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    // how many keys of each writer were acknowledged
+    let acked = Arc::new(
+        (0..WRITERS)
+            .map(|_| AtomicUsize::new(0))
+            .collect::<Vec<_>>(),
+    );
+    let writers = (0..WRITERS)
+        .map(|writer| {
+            let storage = storage.clone();
+            let acked = acked.clone();
+            std::thread::spawn(move || {
+                for idx in 0..KEYS {
+                    storage.put(key(writer, idx), value(writer, idx)).unwrap();
+                    acked[writer].store(idx + 1, Ordering::SeqCst);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    let done = Arc::new(AtomicBool::new(false));
+    let flusher = {
+        let storage = storage.clone();
+        let done = done.clone();
+        std::thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                storage.sync().unwrap();
+            }
+        })
+    };
+
+    // the first and the latest acknowledged key of every writer stay readable throughout
+    while writers.iter().any(|writer| !writer.is_finished()) {
+        for (writer, acked) in acked.iter().enumerate() {
+            let last = acked.load(Ordering::SeqCst);
+            for idx in [0, last]
+                .into_iter()
+                .filter(|&idx| idx > 0)
+                .map(|idx| idx - 1)
+            {
+                let found = storage.get_many(&[&key(writer, idx)]).unwrap().pop();
+                assert_eq!(found, Some(Some(value(writer, idx))));
+            }
+        }
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    done.store(true, Ordering::SeqCst);
+    flusher.join().unwrap();
+
+    let check = |storage: &LsmStorage| {
+        for writer in 0..WRITERS {
+            let keys = (0..KEYS).map(|idx| key(writer, idx)).collect::<Vec<_>>();
+            let keys = keys.iter().map(|key| &key[..]).collect::<Vec<_>>();
+            let found = storage.get_many(&keys).unwrap();
+            for (idx, found) in found.into_iter().enumerate() {
+                assert_eq!(found, Some(value(writer, idx)), "key {}", idx);
+            }
+        }
+    };
+    check(&storage);
+    drop(storage);
+    check(&LsmStorage::open(&dir).unwrap());
+}
+
+#[test]
+fn test_group_commit_makes_every_write_durable() {
+    const WRITERS: usize = 8;
+    const KEYS: usize = 50;
+    let key = |writer: usize, idx: usize| Bytes::from(format!("key_{}_{:04}", writer, idx));
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default().with_group_commit(Duration::from_micros(100));
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    let writers = (0..WRITERS)
+        .map(|writer| {
+            let storage = storage.clone();
+            std::thread::spawn(move || {
+                for idx in 0..KEYS {
+                    if idx % 10 == 9 {
+                        let mut batch = WriteBatch::new();
+                        batch.put(key(writer, idx), Bytes::from("batch"));
+                        batch.delete(&key(writer, idx - 1));
+                        storage.write(batch).unwrap();
+                    } else {
+                        storage.put(key(writer, idx), Bytes::from("value")).unwrap();
+                    }
+                    if writer == 0 && idx == KEYS / 2 {
+                        storage.sync().unwrap();
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    // nothing was synced but by the group commit
+    storage.simulate_power_loss().unwrap();
+    storage.kill();
+
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    for writer in 0..WRITERS {
+        for idx in 0..KEYS {
+            let expected = match idx % 10 {
+                9 => Some(Bytes::from("batch")),
+                8 => None,
+                _ => Some(Bytes::from("value")),
+            };
+            assert_eq!(storage.get(&key(writer, idx)).unwrap(), expected);
+        }
+    }
+    storage.close().unwrap();
+    let err = storage
+        .put(Bytes::from("key"), Bytes::from("late"))
+        .unwrap_err();
+    assert_eq!(err.downcast_ref::<LsmError>(), Some(&LsmError::Closed));
+}
+
+#[test]
+fn test_read_your_writes_across_handles() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    let writer = storage.clone();
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let handle = std::thread::spawn(move || {
+        let mut last = WriteToken(0);
+        for idx in 0..200 {
+            let key = format!("key_{:03}", idx);
+            let token = if idx % 10 == 9 {
+                writer.delete(key.as_bytes()).unwrap()
+            } else {
+                writer
+                    .put(
+                        Bytes::from(key.clone()),
+                        Bytes::from(format!("value_{}", idx)),
+                    )
+                    .unwrap()
+            };
+            assert!(token > last);
+            last = token;
+            tx.send((key, idx, token)).unwrap();
+        }
+    });
+    for (key, idx, token) in rx {
+        let expected = (idx % 10 != 9).then(|| Bytes::from(format!("value_{}", idx)));
+        assert_eq!(storage.get_after(key.as_bytes(), token).unwrap(), expected);
+    }
+    handle.join().unwrap();
+
+    // a token no write has been given yet
+    let err = storage
+        .wait_for_visibility(WriteToken(u64::MAX), std::time::Duration::from_millis(20))
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<LsmError>(),
+        Some(&LsmError::VisibilityTimeout {
+            token: u64::MAX,
+            applied: 200
+        })
+    );
+}
+
+fn batch_of(prefix: &str, len: usize, value_len: usize) -> WriteBatch {
+    let mut batch = WriteBatch::new();
+    for idx in 0..len {
+        batch.put(
+            Bytes::from(format!("{}_{:05}", prefix, idx)),
+            Bytes::from(vec![b'v'; value_len]),
+        );
+    }
+    batch
+}
+
+#[test]
+fn test_batch_rotates_memtable_first() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    // keep the flush the rotation schedules from moving the memtables around
+    *storage.job_hook.lock() = Some(Box::new(|| panic!("no flush")));
+    for idx in 0..800 {
+        storage
+            .put(
+                Bytes::from(format!("fill_{:05}", idx)),
+                Bytes::from(vec![b'f'; 1000]),
+            )
+            .unwrap();
+    }
+    assert_eq!(storage.num_imm_memtables(), 0);
+
+    // just under the limit, and more than what is left of the memtable
+    let batch = batch_of("batch", 250, 1000);
+    assert!(batch.size() <= LsmStorageOptions::default().max_batch_bytes);
+    storage.write(batch).unwrap();
+    assert_eq!(storage.num_imm_memtables(), 1);
+    assert_eq!(storage.memtable_len(), 250);
+    assert_eq!(
+        storage.get(b"batch_00249").unwrap(),
+        Some(Bytes::from(vec![b'v'; 1000]))
+    );
+    assert!(storage.get(b"fill_00000").unwrap().is_some());
+
+    // a batch that fits goes into the same memtable
+    storage.write(batch_of("small", 10, 10)).unwrap();
+    assert_eq!(storage.num_imm_memtables(), 1);
+    assert_eq!(storage.memtable_len(), 260);
+}
+
+#[test]
+fn test_batch_limits() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+
+    let batch = batch_of("big", 300, 1000);
+    let size = batch.size();
+    let err = storage.write(batch).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<LsmError>(),
+        Some(&LsmError::BatchTooLarge {
+            size,
+            limit: LsmStorageOptions::default().max_batch_bytes,
+        })
+    );
+    assert!(storage.get(b"big_00000").unwrap().is_none());
+    assert_eq!(storage.memtable_len(), 0);
+    assert_eq!(storage.metrics().bytes_ingested(), 0);
+
+    // an invalid entry fails the whole batch
+    let mut batch = WriteBatch::new();
+    batch.put(Bytes::from("a"), Bytes::from("1"));
+    batch.put(Bytes::from("b"), Bytes::new());
+    assert!(storage.write(batch).is_err());
+    assert!(storage.get(b"a").unwrap().is_none());
+    drop(storage);
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(storage.open_report().wal_records_replayed, 0);
+}
+
+#[test]
+fn test_batch_of_puts_and_deletes_is_atomic() {
+    const ROUNDS: usize = 500;
+    // round `round` puts every other key and deletes the rest, the other half every round
+    fn fill(batch: &mut WriteBatch, round: usize) {
+        for idx in 0..10 {
+            let key = format!("key_{}", idx);
+            if idx % 2 == round % 2 {
+                batch.put(Bytes::from(key), Bytes::from(format!("round_{}", round)));
+            } else {
+                batch.delete(key.as_bytes());
+            }
+        }
+    }
+    fn expected(round: usize) -> Vec<Option<Bytes>> {
+        (0..10)
+            .map(|idx| (idx % 2 == round % 2).then(|| Bytes::from(format!("round_{}", round))))
+            .collect()
+    }
+
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    let mut batch = WriteBatch::new();
+    fill(&mut batch, 1);
+    batch.clear();
+    assert!(batch.is_empty());
+    assert_eq!(batch.size(), 0);
+    fill(&mut batch, 0);
+    assert_eq!(batch.len(), 10);
+    storage.write(batch).unwrap();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let storage = storage.clone();
+        let done = done.clone();
+        std::thread::spawn(move || {
+            let keys = (0..10)
+                .map(|idx| format!("key_{}", idx))
+                .collect::<Vec<_>>();
+            let keys = keys.iter().map(|key| key.as_bytes()).collect::<Vec<_>>();
+            while !done.load(Ordering::Acquire) {
+                let found = storage.get_many(&keys).unwrap();
+                let value = found.iter().flatten().next().unwrap();
+                let round = std::str::from_utf8(value).unwrap()[6..].parse().unwrap();
+                assert_eq!(found, expected(round));
+            }
+        })
+    };
+    for round in 1..ROUNDS {
+        let mut batch = WriteBatch::new();
+        fill(&mut batch, round);
+        storage.write(batch).unwrap();
+    }
+    done.store(true, Ordering::Release);
+    reader.join().unwrap();
+
+    let mut iter = storage.scan(..).unwrap();
+    let mut entries = vec![];
+    while iter.is_valid() {
+        entries.push((iter.key().to_vec(), iter.value().to_vec()));
+        iter.next().unwrap();
+    }
+    let value = format!("round_{}", ROUNDS - 1).into_bytes();
+    let odd = [1, 3, 5, 7, 9].map(|idx| (format!("key_{}", idx).into_bytes(), value.clone()));
+    assert_eq!(entries, odd);
+}
+
+#[test]
+fn test_batch_last_write_of_a_key_wins() {
+    let dir = tempdir().unwrap();
+    let wal = dir.path().join("memtable.wal");
+    let sync = WriteOptions {
+        sync: true,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(Bytes::from("b"), Bytes::from("0")).unwrap();
+    let batch = || {
+        let mut batch = WriteBatch::new();
+        // delete then put
+        batch.delete(b"a");
+        batch.put(Bytes::from("a"), Bytes::from("1"));
+        // put then delete
+        batch.put(Bytes::from("b"), Bytes::from("1"));
+        batch.delete(b"b");
+        // several puts
+        for value in ["1", "2", "3"] {
+            batch.put(Bytes::from("c"), Bytes::from(value));
+        }
+        batch
+    };
+    let expect = |storage: &LsmStorage| {
+        assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("1")));
+        assert_eq!(storage.get(b"b").unwrap(), None);
+        assert_eq!(storage.get(b"c").unwrap(), Some(Bytes::from("3")));
+    };
+    storage.write_opt(batch(), sync).unwrap();
+    expect(&storage);
+    // one entry per key
+    assert_eq!(storage.memtable_len(), 3);
+
+    // the log holds every write of the batch, and replay applies only the last of each key
+    storage.kill();
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(storage.open_report().wal_records_replayed, 8);
+    assert_eq!(storage.memtable_len(), 3);
+    expect(&storage);
+
+    // deduplicated, the batch logs less and leaves the same outcome
+    let mut deduped = batch();
+    let size = deduped.size();
+    deduped.dedup();
+    assert_eq!(deduped.len(), 3);
+    assert!(deduped.size() < size);
+    let len = std::fs::metadata(&wal).unwrap().len();
+    storage.write_opt(deduped, sync).unwrap();
+    let deduped_len = std::fs::metadata(&wal).unwrap().len() - len;
+    storage.write_opt(batch(), sync).unwrap();
+    assert!(deduped_len < std::fs::metadata(&wal).unwrap().len() - len - deduped_len);
+    expect(&storage);
+    storage.kill();
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(storage.open_report().wal_records_replayed, 8 + 3 + 7);
+    expect(&storage);
+}
+
+#[test]
+fn test_reads_never_see_the_earlier_writes_of_a_batch() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(Bytes::from("key"), Bytes::from("0")).unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let storage = storage.clone();
+        let done = done.clone();
+        std::thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                assert!(storage.get(b"key").unwrap().is_some());
+            }
+        })
+    };
+    for idx in 0..2000 {
+        let mut batch = WriteBatch::new();
+        batch.delete(b"key");
+        batch.put(Bytes::from("key"), Bytes::from(idx.to_string()));
+        storage.write(batch).unwrap();
+    }
+    done.store(true, Ordering::SeqCst);
+    reader.join().unwrap();
+}
+
+#[test]
+fn test_batch_recovery_is_all_or_nothing() {
+    let dir = tempdir().unwrap();
+    let wal = dir.path().join("memtable.wal");
+    let sync = WriteOptions {
+        sync: true,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage
+        .put_opt(Bytes::from("before"), Bytes::from("1"), sync)
+        .unwrap();
+    storage.write_opt(batch_of("whole", 3, 10), sync).unwrap();
+    let mut torn = batch_of("torn", 3, 10);
+    torn.delete(b"before");
+    storage.write_opt(torn, sync).unwrap();
+    storage.kill();
+
+    // crash before the last record of the batch reached the log
+    let len = std::fs::metadata(&wal).unwrap().len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&wal)
+        .unwrap()
+        .set_len(len - 4096)
+        .unwrap();
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(storage.open_report().wal_records_replayed, 4);
+    assert_eq!(storage.get(b"before").unwrap(), Some(Bytes::from("1")));
+    assert!(storage.get(b"whole_00002").unwrap().is_some());
+    for idx in 0..3 {
+        let key = format!("torn_{:05}", idx);
+        assert!(storage.get(key.as_bytes()).unwrap().is_none());
+    }
+
+    // what is written after recovery does not complete the torn batch
+    storage
+        .put_opt(Bytes::from("after"), Bytes::from("2"), sync)
+        .unwrap();
+    storage.kill();
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(storage.open_report().wal_records_replayed, 5);
+    assert_eq!(storage.get(b"before").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"after").unwrap(), Some(Bytes::from("2")));
+    assert!(storage.get(b"torn_00000").unwrap().is_none());
+}
+
+#[test]
+fn test_gc_horizon_keeps_the_tombstones_of_later_deletes() {
+    let dir = tempdir().unwrap();
+    let tombstones = |storage: &LsmStorage| {
+        storage
+            .scan_raw(..)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().op == EntryOp::Delete)
+            .count()
+    };
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(Bytes::from("a"), Bytes::from("1")).unwrap();
+    let deleted = storage.delete(b"a").unwrap();
+    storage.sync().unwrap();
+    let last = storage.put(Bytes::from("b"), Bytes::from("1")).unwrap();
+    storage.sync().unwrap();
+
+    // a consumer yet to observe the delete keeps its tombstone, whatever the ids of the tables
+    storage.set_gc_horizon(deleted);
+    storage.compact(0).unwrap();
+    assert_eq!(tombstones(&storage), 1);
+    drop(storage);
+
+    // the sequences carry on from the ones the tables hold
+    let storage = LsmStorage::open(&dir).unwrap();
+    let token = storage.put(Bytes::from("aa"), Bytes::from("1")).unwrap();
+    assert!(token > last, "{:?} after {:?}", token, last);
+    storage.sync().unwrap();
+    storage.set_gc_horizon(WriteToken(token.0 + 1));
+    storage.compact(0).unwrap();
+    assert_eq!(tombstones(&storage), 0);
+}
+
+#[test]
+fn test_tombstones_kept_above_older_levels() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        background_compaction: false,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    storage.put(Bytes::from("k"), Bytes::from("old")).unwrap();
+    storage.sync().unwrap();
+    storage.compact(0).unwrap();
+    storage.compact(1).unwrap();
+
+    // the tombstone lands in L1, above the value it deletes in L2
+    storage.delete(b"k").unwrap();
+    storage.sync().unwrap();
+    storage.compact(0).unwrap();
+    assert_eq!(storage.get(b"k").unwrap(), None);
+    assert!(!storage.scan(..).unwrap().is_valid());
+
+    // once both meet at the bottom, the tombstone goes along with the value
+    storage.compact(1).unwrap();
+    assert_eq!(storage.get(b"k").unwrap(), None);
+    let entries = storage.scan_raw(..).unwrap().count();
+    assert_eq!(entries, 0);
+}
@@ -0,0 +1,156 @@
+//! A reference to a value that reads it only when asked, see
+//! [`LsmStorage::get_handle`](crate::lsm_storage::LsmStorage::get_handle).
+
+use std::ops::Range;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use crate::block::strip_entry_checksum;
+use crate::error::LsmError;
+use crate::lsm_storage::ReadOptions;
+use crate::metrics::ReadKind;
+use crate::retention::RetentionGuard;
+use crate::table::SsTable;
+
+/// Where the value of a [`ValueHandle`] lives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueLocation {
+    /// In a memtable; the handle holds the value itself.
+    Memtable,
+    /// Entry `entry_idx` of block `block_idx` of SST `sst_id`.
+    Sst {
+        sst_id: usize,
+        block_idx: usize,
+        entry_idx: usize,
+    },
+}
+
+enum Source {
+    Memory(Bytes),
+    Sst {
+        table: Arc<SsTable>,
+        block_idx: usize,
+        entry_idx: usize,
+        /// Keeps the file on disk while the handle is alive, whatever compaction does.
+        _guard: RetentionGuard,
+    },
+}
+
+/// The value of a key as of the `get_handle` that returned it. Its length is known up front;
+/// the SST block holding it is only read by `read_all` and `read_range`.
+pub struct ValueHandle {
+    key: Bytes,
+    len: usize,
+    source: Source,
+    options: ReadOptions,
+}
+
+impl ValueHandle {
+    pub(crate) fn in_memory(key: Bytes, value: Bytes) -> Self {
+        Self {
+            key,
+            len: value.len(),
+            source: Source::Memory(value),
+            options: ReadOptions::default(),
+        }
+    }
+
+    pub(crate) fn in_sst(
+        key: Bytes,
+        len: usize,
+        table: Arc<SsTable>,
+        (block_idx, entry_idx): (usize, usize),
+        guard: RetentionGuard,
+        options: ReadOptions,
+    ) -> Self {
+        Self {
+            key,
+            len,
+            source: Source::Sst {
+                table,
+                block_idx,
+                entry_idx,
+                _guard: guard,
+            },
+            options,
+        }
+    }
+
+    pub fn key(&self) -> &Bytes {
+        &self.key
+    }
+
+    /// Length of the value in bytes, without reading it.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn location(&self) -> ValueLocation {
+        match &self.source {
+            Source::Memory(_) => ValueLocation::Memtable,
+            Source::Sst {
+                table,
+                block_idx,
+                entry_idx,
+                ..
+            } => ValueLocation::Sst {
+                sst_id: table.sst_id(),
+                block_idx: *block_idx,
+                entry_idx: *entry_idx,
+            },
+        }
+    }
+
+    pub fn read_all(&self) -> Result<Bytes> {
+        self.read_range(0..self.len)
+    }
+
+    /// The bytes of the value within `range`, reading the block that holds it if it is in an
+    /// SST. Fails with [`LsmError::InvalidArgument`] if the range is not within the value.
+    pub fn read_range(&self, range: Range<usize>) -> Result<Bytes> {
+        if range.start > range.end || range.end > self.len {
+            return Err(LsmError::InvalidArgument(format!(
+                "range {:?} is not within a value of {} bytes",
+                range, self.len
+            ))
+            .into());
+        }
+        let (table, block_idx, entry_idx) = match &self.source {
+            Source::Memory(value) => return Ok(value.slice(range)),
+            Source::Sst {
+                table,
+                block_idx,
+                entry_idx,
+                ..
+            } => (table, *block_idx, *entry_idx),
+        };
+        let block = table.read_block_with(block_idx, &self.options)?;
+        table.record_read(ReadKind::Get, block_idx);
+        let (key, stored) = block.entry(entry_idx).ok_or_else(|| {
+            anyhow::anyhow!(
+                "sst {} has no entry {} in block {}",
+                table.sst_id(),
+                entry_idx,
+                block_idx
+            )
+        })?;
+        anyhow::ensure!(
+            key == self.key,
+            "sst {} holds another key at entry {} of block {}",
+            table.sst_id(),
+            entry_idx,
+            block_idx
+        );
+        let value = match block.has_entry_checksums() {
+            true => strip_entry_checksum(&key, stored)?,
+            false => stored,
+        };
+        Ok(Bytes::copy_from_slice(&value[range]))
+    }
+}
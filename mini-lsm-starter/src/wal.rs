@@ -1,28 +1,45 @@
-use core::mem::MaybeUninit;
-use std::io::{Read, Write};
+//! The write-ahead log. [`Wal`] is the log the storage writes to, through a
+//! [`WalGroupCommit`] with [`LsmStorageOptions::group_commit`] on. [`WriteAheadLog`] is a
+//! RocksDB-style log of fixed-size blocks, for direct IO; nothing reads it back yet.
+//!
+//! [`LsmStorageOptions::group_commit`]: crate::lsm_storage::LsmStorageOptions::group_commit
+
+#![deny(unsafe_op_in_unsafe_fn)]
+
+mod group_commit;
+
+use std::io::Write;
 use std::os::unix::fs::FileExt;
 use std::os::unix::fs::OpenOptionsExt;
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use bytes::Buf;
+use bytes::BufMut;
 use bytes::Bytes;
 use bytes::BytesMut;
 use bytes_utils::SegmentedSlice;
 use crc32fast;
 use libc;
 
+use crate::block::{entry_checksum, strip_entry_checksum};
+use crate::format::WAL_RECORD;
 use crate::mem_table::MemTable;
 
+pub use group_commit::WalGroupCommit;
+
 // ioctl(file, BLKGETSIZE64, &file_size_in_bytes);
 const HEADER_SIZE: usize = 4 + 2 + 1;
 const BLOCK_SIZE: usize = 1 << 15;
-const ALIGNMENT_SIZE: usize = 4096;
-const U16SZ: usize = std::mem::size_of::<u16>();
+const ALIGNMENT_SIZE: usize = WAL_RECORD.alignment;
+/// Length of the lengths that start a record of a [`Wal`].
+const RECORD_HEADER_SIZE: usize = WAL_RECORD.header_len;
 
 // https://github.com/facebook/rocksdb/wiki/Write-Ahead-Log-File-Format
 
 #[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum Kind {
+    /// Padding at the end of a block too short for another header.
     Zero = 0,
     First,
     Middle,
@@ -30,161 +47,327 @@ enum Kind {
     Full,
 }
 
-#[repr(packed)]
+impl Kind {
+    #[allow(dead_code)]
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(Kind::Zero),
+            1 => Some(Kind::First),
+            2 => Some(Kind::Middle),
+            3 => Some(Kind::Last),
+            4 => Some(Kind::Full),
+            _ => None,
+        }
+    }
+}
+
+/// The header of a fragment: `| crc (u32) | size (u16) | kind (u8) |`, little endian.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 struct Header {
+    /// Checksum of the payload of the fragment.
     crc: u32,
     size: u16,
     kind: Kind,
 }
 
 impl Header {
-    pub fn as_slice(&self) -> &[u8; std::mem::size_of::<Self>()] {
-        unsafe { std::mem::transmute(self) }
+    fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0u8; HEADER_SIZE];
+        buf[..4].copy_from_slice(&self.crc.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.size.to_le_bytes());
+        buf[6] = self.kind as u8;
+        buf
     }
-}
 
-#[repr(packed)]
-struct Record {
-    crc: u32,
-    size: u16,
-    kind: Kind,
-    payload: [u8; BLOCK_SIZE - HEADER_SIZE],
+    /// Only the tests read fragments back so far.
+    #[allow(dead_code)]
+    fn decode(buf: &[u8; HEADER_SIZE]) -> Option<Self> {
+        Some(Self {
+            crc: u32::from_le_bytes(buf[..4].try_into().unwrap()),
+            size: u16::from_le_bytes(buf[4..6].try_into().unwrap()),
+            kind: Kind::from_u8(buf[6])?,
+        })
+    }
 }
 
+/// A block of the log, aligned for direct IO.
+#[repr(align(4096))]
+struct AlignedBlock([u8; BLOCK_SIZE]);
+
 pub struct WriteAheadLog {
     file: std::fs::File,
+    /// The block being filled, written out whole after every append.
+    block: Box<AlignedBlock>,
+    /// Bytes of `block` used so far.
+    block_len: usize,
+    /// Where `block` goes in the file.
+    block_offset: u64,
 }
 
 impl WriteAheadLog {
     pub fn create<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Self::open_with_flags(path, libc::O_DIRECT | libc::O_DSYNC)
+    }
+
+    fn open_with_flags<P: AsRef<std::path::Path>>(path: P, flags: i32) -> Result<Self> {
         let file = std::fs::OpenOptions::new()
-            .append(true)
+            .write(true)
             .create(true)
-            .custom_flags(libc::O_DIRECT | libc::O_DSYNC)
+            .custom_flags(flags)
             .open(&path)?;
-
-        Ok(Self { file })
+        // a log left by an earlier process goes on in a block of its own
+        let len = file.metadata()?.len();
+        let block_offset = (len + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64 * BLOCK_SIZE as u64;
+
+        Ok(Self {
+            file,
+            block: Box::new(AlignedBlock([0u8; BLOCK_SIZE])),
+            block_len: 0,
+            block_offset,
+        })
     }
 
+    /// Append a record, split into fragments wherever it crosses a block boundary.
     pub fn append(&mut self, key: Bytes, value: Bytes) -> Result<()> {
-        static mut BUF: [u8; BLOCK_SIZE] = [0u8; BLOCK_SIZE];
-
-        let mut buffers = [
-            &(key.len() as u16).to_le_bytes(),
-            key.as_ref(),
-            value.as_ref(),
-        ];
+        let key_len = (key.len() as u16).to_le_bytes();
+        let mut buffers: [&[u8]; 3] = [&key_len, key.as_ref(), value.as_ref()];
         let mut payload = SegmentedSlice::new(&mut buffers);
-        let payload_len = 2 + key.len() + value.len();
 
-        let mut buf_written = 0;
+        let mut first = true;
         while payload.has_remaining() {
-            // TODO: skip to the next block if space remaining <= HEADER_SIZE
-
-            let mut kind;
-            let to_write;
-            if payload.remaining() <= BLOCK_SIZE - buf_written - HEADER_SIZE {
-                if payload_len > payload.remaining() {
-                    kind = Kind::Full;
-                    to_write = payload.remaining();
-                } else {
-                    kind = Kind::Last;
-                    to_write = payload.remaining();
-                }
-            } else if payload_written == 0 {
-                kind = Kind::First;
-                to_write = payload.remaining() - (BLOCK_SIZE - buf_written - HEADER_SIZE);
-            } else {
-                kind = Kind::Middle;
-                to_write = BLOCK_SIZE - HEADER_SIZE;
+            if BLOCK_SIZE - self.block_len < HEADER_SIZE {
+                // the rest of the block is zeroed already, i.e. `Kind::Zero` padding
+                self.next_block()?;
             }
 
-            unsafe {
-                match kind {
-                    Kind::First => {
-                        let pay = &mut BUF
-                            [buf_written + HEADER_SIZE..buf_written + HEADER_SIZE + to_write];
-
-                        let header = Header {
-                            crc: crc32fast::hash(pay),
-                            size: to_write as _,
-                            kind,
-                        };
-
-                        BUF[buf_written..buf_written + HEADER_SIZE]
-                            .copy_from_slice(header.as_slice());
-                        payload.copy_to_slice(pay);
-                    }
-                    Kind::Middle => {
-
-                    }
-                    Kind::Last => {}
-                    _ => unreachable!(),
-                }
-
-                if BLOCK_SIZE - buf_written <= HEADER_SIZE {
-                    BUF[buf_written..].fill(0);
-                    buf_written = 0;
-                }
-                self.file.write_all(&BUF)?;
+            let start = self.block_len + HEADER_SIZE;
+            let to_write = payload.remaining().min(BLOCK_SIZE - start);
+            let last = to_write == payload.remaining();
+            let kind = match (first, last) {
+                (true, true) => Kind::Full,
+                (true, false) => Kind::First,
+                (false, false) => Kind::Middle,
+                (false, true) => Kind::Last,
+            };
+            let fragment = &mut self.block.0[start..start + to_write];
+            payload.copy_to_slice(fragment);
+            let header = Header {
+                crc: crc32fast::hash(fragment),
+                size: to_write as u16,
+                kind,
+            };
+            self.block.0[self.block_len..start].copy_from_slice(&header.encode());
+            self.block_len = start + to_write;
+            first = false;
+
+            if self.block_len == BLOCK_SIZE {
+                self.next_block()?;
             }
         }
 
+        if self.block_len > 0 {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    /// Write out the block so far, in place of its earlier writes.
+    fn write_block(&mut self) -> Result<()> {
+        self.file.write_all_at(&self.block.0, self.block_offset)?;
+        Ok(())
+    }
+
+    /// Write out the block and start filling the next one.
+    fn next_block(&mut self) -> Result<()> {
+        self.write_block()?;
+        self.block_offset += BLOCK_SIZE as u64;
+        self.block_len = 0;
+        self.block.0.fill(0);
         Ok(())
     }
 }
 
+/// A write read back from the log. An empty value is a deletion.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WalRecord {
+    pub key: Bytes,
+    pub value: Bytes,
+}
+
+/// What [`Wal::replay`] went through.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    /// Number of records handed to the sink.
+    pub records: u64,
+    /// Bytes of the log up to the end of the last record.
+    pub bytes: u64,
+}
+
 pub struct Wal {
     file: std::fs::File,
+    /// Length of the file at the last `sync`, i.e. what survives a power loss.
+    synced_len: u64,
+    /// Every value ends with the checksum of its record.
+    entry_checksums: bool,
 }
 
 impl Wal {
-    /// O_DIRECT | O_DSYNC is used for latency. Need batch/buffer for throughput
+    /// Records are padded to `ALIGNMENT_SIZE` but go through the page cache; call `sync` to make
+    /// them durable.
     pub fn create<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
         let file = std::fs::OpenOptions::new()
             .append(true)
             .create(true)
-            .custom_flags(libc::O_DIRECT | libc::O_DSYNC)
             .open(&path)?;
+        let synced_len = file.metadata()?.len();
 
-        Ok(Self { file })
+        Ok(Self {
+            file,
+            synced_len,
+            entry_checksums: false,
+        })
     }
 
     pub fn from<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
-        let file = std::fs::OpenOptions::new()
-            .read(true)
-            .custom_flags(libc::O_DIRECT)
-            .open(&path)?;
+        let file = std::fs::OpenOptions::new().read(true).open(&path)?;
+        let synced_len = file.metadata()?.len();
+
+        Ok(Self {
+            file,
+            synced_len,
+            entry_checksums: false,
+        })
+    }
 
-        Ok(Self { file })
+    /// End the value of every record with the checksum of its entry, the way the SSTs do, and
+    /// check it on replay.
+    pub fn with_entry_checksums(mut self) -> Self {
+        self.entry_checksums = true;
+        self
     }
 
     pub fn append(&mut self, key: &Bytes, value: &Bytes) -> Result<()> {
-        let key_len = &(key.len() as u16).to_le_bytes();
-        let val_len = &(value.len() as u16).to_le_bytes();
-        let complement = ALIGNMENT_SIZE - (U16SZ * 2 + key.len() + value.len()) % ALIGNMENT_SIZE;
+        let mut buf = vec![];
+        self.encode(key, value, &mut buf);
+        self.write_encoded(&buf)
+    }
 
-        let total = 4 + key.len() + value.len() + complement;
-        let mut buf = Vec::with_capacity(total);
+    /// Append `entries` behind a marker record counting them. Replay hands over either all of
+    /// them or, if the log ends before the last one, none. Keys are never empty, so the marker
+    /// has an empty key.
+    pub fn append_batch(&mut self, entries: &[(Bytes, Bytes)]) -> Result<()> {
+        let mut buf = vec![];
+        self.encode_batch(entries, &mut buf);
+        self.write_encoded(&buf)
+    }
+
+    /// Add the record of `key` and `value` to `buf`, laid out as [`WAL_RECORD`].
+    fn encode(&self, key: &Bytes, value: &Bytes, buf: &mut Vec<u8>) {
+        let sum = self
+            .entry_checksums
+            .then(|| entry_checksum(key, value).to_le_bytes());
+        let stored_len = value.len() + sum.map_or(0, |sum| sum.len());
+        let mut header = [0; RECORD_HEADER_SIZE];
+        WAL_RECORD.key_len.put(&mut header, key.len() as u64);
+        WAL_RECORD.value_len.put(&mut header, stored_len as u64);
+        let len = RECORD_HEADER_SIZE + key.len() + stored_len;
+        let complement = (ALIGNMENT_SIZE - len % ALIGNMENT_SIZE) % ALIGNMENT_SIZE;
+
+        let total = len + complement;
+        let end = buf.len() + total;
+        buf.reserve(total);
 
         // iovec still writes buffer by buffer which is align guaranteed
-        buf.extend_from_slice(key_len.as_ref());
-        buf.extend_from_slice(val_len.as_ref());
+        buf.extend_from_slice(&header);
         buf.extend_from_slice(key.as_ref());
         buf.extend_from_slice(value.as_ref());
-        buf.resize(total, 0);
+        if let Some(sum) = &sum {
+            buf.extend_from_slice(sum);
+        }
+        buf.resize(end, 0);
+    }
+
+    /// Add the records `append_batch` writes for `entries` to `buf`.
+    fn encode_batch(&self, entries: &[(Bytes, Bytes)], buf: &mut Vec<u8>) {
+        let mut count = BytesMut::new();
+        count.put_uint_le(entries.len() as u64, WAL_RECORD.batch_count_size);
+        self.encode(&Bytes::new(), &count.freeze(), buf);
+        for (key, value) in entries {
+            self.encode(key, value, buf);
+        }
+    }
 
-        self.file.write_all(&buf)?;
+    /// Append records `encode` put together, in one write.
+    fn write_encoded(&mut self, buf: &[u8]) -> Result<()> {
+        self.file.write_all(buf)?;
+        Ok(())
+    }
 
+    /// Wait for everything appended so far to reach the disk.
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+        self.synced_len = self.file.metadata()?.len();
+        Ok(())
+    }
+
+    /// Length of the log at the last `sync`.
+    pub fn synced_len(&self) -> u64 {
+        self.synced_len
+    }
+
+    /// Drop all records, once the memtable they belong to is flushed.
+    pub fn truncate(&mut self) -> Result<()> {
+        self.truncate_to(0)
+    }
+
+    /// Drop everything past the first `len` bytes, e.g. the records replay left out.
+    pub fn truncate_to(&mut self, len: u64) -> Result<()> {
+        self.file.set_len(len)?;
+        self.file.sync_all()?;
+        self.synced_len = len;
+        Ok(())
+    }
+
+    /// Lose whatever was appended since the last `sync`, as a power loss would.
+    #[cfg(test)]
+    pub(crate) fn simulate_power_loss(&mut self) -> Result<()> {
+        self.file.set_len(self.synced_len)?;
         Ok(())
     }
 
     pub fn to_memtable(&self) -> Result<MemTable> {
         let tbl = MemTable::create();
+        self.replay(&mut |record| {
+            tbl.put(record.key, record.value);
+            Ok(())
+        })?;
+        Ok(tbl)
+    }
+
+    /// Hand every record of the log to `sink`, in the order they were appended. Stops at the
+    /// first error `sink` returns.
+    pub fn replay(&self, sink: &mut dyn FnMut(WalRecord) -> Result<()>) -> Result<ReplayStats> {
+        self.replay_batches(&mut |records| records.into_iter().try_for_each(&mut *sink))
+    }
+
+    /// Like `replay`, handing over the records of a batch together, and a record written on its
+    /// own as a batch of one. A batch cut short by the end of the log is left out.
+    pub fn replay_batches(
+        &self,
+        sink: &mut dyn FnMut(Vec<WalRecord>) -> Result<()>,
+    ) -> Result<ReplayStats> {
+        let mut stats = ReplayStats::default();
+        // the records of the batch being read, and how many it has
+        let mut batch: Option<(usize, Vec<WalRecord>)> = None;
         let mut buf = [0u8; ALIGNMENT_SIZE as usize];
 
         let file_len = self.file.metadata()?.len();
-        assert_eq!(file_len % ALIGNMENT_SIZE as u64, 0);
+        ensure!(
+            file_len % ALIGNMENT_SIZE as u64 == 0,
+            "corrupted write-ahead log: {} bytes is not a whole number of {} byte records",
+            file_len,
+            ALIGNMENT_SIZE
+        );
 
         // read pair by pair
         enum Reading {
@@ -203,50 +386,70 @@ impl Wal {
         let mut buffer = BytesMut::new();
         while read < file_len {
             self.file.read_exact_at(&mut buf, read)?;
+            read += ALIGNMENT_SIZE as u64;
 
-            match state {
+            let complete = match state {
                 Reading::Start => {
-                    let header = 4usize;
                     let (key_len, val_len) = self.header_of(&buf);
-                    let total = header + key_len + val_len;
+                    let total = RECORD_HEADER_SIZE + key_len + val_len;
 
                     if total <= ALIGNMENT_SIZE {
                         buffer.extend_from_slice(&buf[..total]);
-                        let (key, value) = self.consume_buffer(&mut buffer);
-                        tbl.put(key, value);
-                        remaining = usize::MAX;
-                        state = Reading::Start;
+                        true
                     } else {
                         buffer.extend_from_slice(&buf);
                         remaining = total - ALIGNMENT_SIZE;
                         state = Reading::Cont;
+                        false
                     }
                 }
                 Reading::Cont => {
                     let off = remaining.min(ALIGNMENT_SIZE);
                     buffer.extend_from_slice(&buf[..off]);
                     remaining -= off;
+                    remaining == 0
+                }
+            };
 
-                    if remaining == 0 {
-                        let (key, value) = self.consume_buffer(&mut buffer);
-                        tbl.put(key, value);
-                        state = Reading::Start;
-                        remaining = usize::MAX;
-                    } else {
-                        state = Reading::Cont;
+            if complete {
+                let (key, mut value) = self.consume_buffer(&mut buffer);
+                if self.entry_checksums {
+                    let len = strip_entry_checksum(&key, &value)?.len();
+                    value.truncate(len);
+                }
+                state = Reading::Start;
+                remaining = usize::MAX;
+                if key.is_empty() {
+                    ensure!(
+                        batch.is_none() && value.len() == WAL_RECORD.batch_count_size,
+                        "corrupted batch marker in the write-ahead log"
+                    );
+                    let count = (&value[..]).get_uint_le(value.len()) as usize;
+                    batch = Some((count, Vec::with_capacity(count)));
+                } else {
+                    let record = WalRecord { key, value };
+                    match &mut batch {
+                        Some((_, records)) => records.push(record),
+                        None => batch = Some((1, vec![record])),
+                    }
+                }
+                if let Some((count, records)) = &batch {
+                    if records.len() == *count {
+                        let (_, records) = batch.take().unwrap();
+                        stats.records += records.len() as u64;
+                        stats.bytes = read;
+                        sink(records)?;
                     }
                 }
             }
-
-            read += ALIGNMENT_SIZE as u64;
         }
 
-        Ok(tbl)
+        Ok(stats)
     }
 
     fn consume_buffer(&self, buffer: &mut BytesMut) -> (Bytes, Bytes) {
         let key_len = self.header_of(&buffer).0;
-        let mut kv = buffer.split_off(4);
+        let mut kv = buffer.split_off(RECORD_HEADER_SIZE);
         let value = kv.split_off(key_len);
         let key = kv;
 
@@ -255,9 +458,9 @@ impl Wal {
     }
 
     fn header_of<T: AsRef<[u8]>>(&self, buf: &T) -> (usize, usize) {
-        debug_assert!(buf.as_ref().len() >= 4);
-        let key_len = u16::from_le_bytes(buf.as_ref()[..2].try_into().unwrap()) as usize;
-        let val_len = u16::from_le_bytes(buf.as_ref()[2..4].try_into().unwrap()) as usize;
+        let header = &buf.as_ref()[..RECORD_HEADER_SIZE];
+        let key_len = WAL_RECORD.key_len.get(header) as usize;
+        let val_len = WAL_RECORD.value_len.get(header) as usize;
         (key_len, val_len)
     }
 }
@@ -265,6 +468,8 @@ impl Wal {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::block::ENTRY_CHECKSUM_SIZE;
+    use crate::error::LsmError;
     use bytes::BufMut;
 
     #[test]
@@ -302,4 +507,203 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_entry_checksums() -> Result<()> {
+        let dir = tempfile::tempdir_in(".")?;
+        let path = dir.path().join("file");
+        let mut wal = Wal::create(&path)?.with_entry_checksums();
+        wal.append(&Bytes::from("a"), &Bytes::from("1"))?;
+        wal.append(&Bytes::from("b"), &Bytes::new())?;
+        drop(wal);
+
+        let tbl = Wal::from(&path)?.with_entry_checksums().to_memtable()?;
+        assert_eq!(tbl.get(b"a"), Some(Bytes::from("1")));
+        assert_eq!(tbl.get(b"b"), Some(Bytes::new()));
+
+        // flip the last byte of the checksum of "a"
+        let mut data = std::fs::read(&path)?;
+        let pos = data.windows(2).position(|w| w == b"a1").unwrap() + 1 + ENTRY_CHECKSUM_SIZE;
+        data[pos] ^= 1;
+        std::fs::write(&path, data)?;
+        let err = Wal::from(&path)?
+            .with_entry_checksums()
+            .to_memtable()
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.downcast_ref::<LsmError>(),
+            Some(&LsmError::EntryChecksumMismatch {
+                key: Bytes::from("a")
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_in_order() -> Result<()> {
+        let dir = tempfile::tempdir_in(".")?;
+        let path = dir.path().join("file");
+        let mut wal = Wal::create(&path)?;
+        let large = Bytes::from(vec![b'v'; ALIGNMENT_SIZE * 2]);
+        wal.append(&Bytes::from("a"), &Bytes::from("1"))?;
+        wal.append(&Bytes::from("b"), &large)?;
+        wal.append(&Bytes::from("a"), &Bytes::new())?;
+        drop(wal);
+
+        let mut records = vec![];
+        let stats = Wal::from(&path)?.replay(&mut |record| {
+            records.push(record);
+            Ok(())
+        })?;
+        assert_eq!(
+            records,
+            vec![
+                WalRecord {
+                    key: Bytes::from("a"),
+                    value: Bytes::from("1"),
+                },
+                WalRecord {
+                    key: Bytes::from("b"),
+                    value: large,
+                },
+                WalRecord {
+                    key: Bytes::from("a"),
+                    value: Bytes::new(),
+                },
+            ]
+        );
+        assert_eq!(stats.records, 3);
+        assert_eq!(stats.bytes, std::fs::metadata(&path)?.len());
+
+        // the sink can stop the replay
+        let mut seen = 0;
+        let result = Wal::from(&path)?.replay(&mut |_| {
+            seen += 1;
+            anyhow::bail!("stop")
+        });
+        assert!(result.is_err());
+        assert_eq!(seen, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_batches_are_all_or_nothing() -> Result<()> {
+        let dir = tempfile::tempdir_in(".")?;
+        let path = dir.path().join("file");
+        let mut wal = Wal::create(&path)?;
+        let entry = |key: &str| (Bytes::from(key.to_string()), Bytes::from("v"));
+        wal.append(&Bytes::from("a"), &Bytes::from("1"))?;
+        wal.append_batch(&[entry("b"), entry("c")])?;
+        let complete = std::fs::metadata(&path)?.len();
+        wal.append_batch(&[entry("d"), entry("e"), entry("f")])?;
+
+        let mut batches = vec![];
+        let stats = Wal::from(&path)?.replay_batches(&mut |records| {
+            batches.push(records.len());
+            Ok(())
+        })?;
+        assert_eq!(batches, vec![1, 2, 3]);
+        assert_eq!(stats.records, 6);
+
+        // lose the last record of the last batch
+        wal.truncate_to(std::fs::metadata(&path)?.len() - ALIGNMENT_SIZE as u64)?;
+        let mut keys = vec![];
+        let stats = Wal::from(&path)?.replay(&mut |record| {
+            keys.push(record.key);
+            Ok(())
+        })?;
+        assert_eq!(keys, vec!["a", "b", "c"]);
+        assert_eq!(stats.records, 3);
+        assert_eq!(stats.bytes, complete);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        for kind in [
+            Kind::Zero,
+            Kind::First,
+            Kind::Middle,
+            Kind::Last,
+            Kind::Full,
+        ] {
+            let header = Header {
+                crc: 0xdead_beef,
+                size: 0x1234,
+                kind,
+            };
+            let buf = header.encode();
+            assert_eq!(&buf[..6], &[0xef, 0xbe, 0xad, 0xde, 0x34, 0x12]);
+            assert_eq!(Header::decode(&buf), Some(header));
+        }
+        let mut buf = Header {
+            crc: 0,
+            size: 0,
+            kind: Kind::Full,
+        }
+        .encode();
+        buf[6] = 5;
+        assert_eq!(Header::decode(&buf), None);
+    }
+
+    /// The payloads of the records in `data`, joined back from their fragments.
+    fn records_of(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut records = vec![];
+        let mut record = vec![];
+        for block in data.chunks(BLOCK_SIZE) {
+            let mut pos = 0;
+            while pos + HEADER_SIZE <= block.len() {
+                let header =
+                    Header::decode(block[pos..pos + HEADER_SIZE].try_into().unwrap()).unwrap();
+                let payload = &block[pos + HEADER_SIZE..pos + HEADER_SIZE + header.size as usize];
+                if header.kind == Kind::Zero {
+                    break;
+                }
+                assert_eq!(crc32fast::hash(payload), header.crc);
+                record.extend_from_slice(payload);
+                if matches!(header.kind, Kind::Full | Kind::Last) {
+                    records.push(std::mem::take(&mut record));
+                }
+                pos += HEADER_SIZE + header.size as usize;
+            }
+        }
+        records
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_write_ahead_log_fragments() -> Result<()> {
+        let dir = tempfile::tempdir_in(".")?;
+        let path = dir.path().join("log");
+        // direct IO is not available everywhere tests run
+        let mut log = WriteAheadLog::open_with_flags(&path, 0)?;
+        let large = Bytes::from(vec![b'v'; BLOCK_SIZE * 2]);
+        log.append(Bytes::from("a"), Bytes::from("1"))?;
+        log.append(Bytes::from("b"), large.clone())?;
+        log.append(Bytes::from("c"), Bytes::from("3"))?;
+
+        let data = std::fs::read(&path)?;
+        assert_eq!(data.len() % BLOCK_SIZE, 0);
+        let record = |key: &str, value: &[u8]| {
+            let mut record = (key.len() as u16).to_le_bytes().to_vec();
+            record.extend_from_slice(key.as_bytes());
+            record.extend_from_slice(value);
+            record
+        };
+        assert_eq!(
+            records_of(&data),
+            vec![record("a", b"1"), record("b", &large), record("c", b"3")]
+        );
+
+        // a reopened log starts a block of its own
+        let mut log = WriteAheadLog::open_with_flags(&path, 0)?;
+        log.append(Bytes::from("d"), Bytes::from("4"))?;
+        let data = std::fs::read(&path)?;
+        assert_eq!(records_of(&data).last(), Some(&record("d", b"4")));
+        assert_eq!(records_of(&data).len(), 4);
+        Ok(())
+    }
 }
@@ -0,0 +1,290 @@
+//! Group commit: the appends of concurrent writers share one write and one sync of the log.
+
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use parking_lot::{Condvar, Mutex};
+
+use super::Wal;
+use crate::error::LsmError;
+
+/// What a writer queued: a record, or the records of a batch.
+struct PendingEntry {
+    records: Vec<(Bytes, Bytes)>,
+}
+
+struct Queue {
+    pending: Vec<PendingEntry>,
+    /// Entries queued so far. The `n`-th one waits for `written` to reach `n`.
+    queued: u64,
+    /// Entries the leader went through, durable ones or not.
+    written: u64,
+    /// Entries on disk, all of the first `durable` ones.
+    durable: u64,
+    /// Why a write or a sync failed. The log is in doubt from then on, so every later entry
+    /// fails too.
+    failed: Option<String>,
+    /// Entries of the last group, as many as the leader waits for in the next one.
+    last_group: usize,
+    shutdown: bool,
+}
+
+struct Shared {
+    wal: Arc<Mutex<Wal>>,
+    queue: Mutex<Queue>,
+    /// Wakes the leader as an entry is queued.
+    queued: Condvar,
+    /// Wakes the writers as a group is written.
+    written: Condvar,
+    commit_timeout: Duration,
+}
+
+/// Appends to a [`Wal`] that return once the record is on disk, as an `append` followed by a
+/// `sync` would. A leader thread takes the entries queued while it waits out the commit
+/// timeout, writes them with a single `write_all` and syncs the log once for all of them, so
+/// that concurrent writers pay for one sync instead of one each. It stops waiting once as many
+/// entries as in the last group are queued, so that a lone writer does not wait at all.
+///
+/// The [`Wal`] stays shared: the leader locks it for every group, so whoever else holds the
+/// lock, e.g. to truncate the log, holds the groups back meanwhile.
+pub struct WalGroupCommit {
+    shared: Arc<Shared>,
+    leader: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl WalGroupCommit {
+    /// Start the leader, which waits up to `commit_timeout` after the first entry of a group for
+    /// other writers to join it.
+    pub fn new(wal: Arc<Mutex<Wal>>, commit_timeout: Duration) -> Result<Self> {
+        let shared = Arc::new(Shared {
+            wal,
+            queue: Mutex::new(Queue {
+                pending: vec![],
+                queued: 0,
+                written: 0,
+                durable: 0,
+                failed: None,
+                last_group: 0,
+                shutdown: false,
+            }),
+            queued: Condvar::new(),
+            written: Condvar::new(),
+            commit_timeout,
+        });
+        let leader = {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name("mini-lsm-group-commit".to_string())
+                .spawn(move || shared.lead())?
+        };
+        Ok(Self {
+            shared,
+            leader: Mutex::new(Some(leader)),
+        })
+    }
+
+    /// Append a record and wait for it to reach the disk along with its group.
+    pub fn append(&self, key: &Bytes, value: &Bytes) -> Result<()> {
+        let ticket = self.enqueue(vec![(key.clone(), value.clone())])?;
+        self.wait(ticket)
+    }
+
+    /// Like [`Wal::append_batch`], waiting for the batch to reach the disk.
+    pub fn append_batch(&self, entries: &[(Bytes, Bytes)]) -> Result<()> {
+        let ticket = self.enqueue(entries.to_vec())?;
+        self.wait(ticket)
+    }
+
+    /// Queue `records`, a batch if there are several, for the next group. The entries are
+    /// written in the order they are queued. Pass the returned ticket to `wait`.
+    pub(crate) fn enqueue(&self, records: Vec<(Bytes, Bytes)>) -> Result<u64> {
+        let mut queue = self.shared.queue.lock();
+        if queue.shutdown {
+            return Err(LsmError::Closed.into());
+        }
+        if let Some(err) = &queue.failed {
+            return Err(anyhow!("the write-ahead log failed earlier: {}", err));
+        }
+        queue.pending.push(PendingEntry { records });
+        queue.queued += 1;
+        self.shared.queued.notify_one();
+        Ok(queue.queued)
+    }
+
+    /// Wait for the entry `enqueue` issued `ticket` for to reach the disk.
+    pub(crate) fn wait(&self, ticket: u64) -> Result<()> {
+        let mut queue = self.shared.queue.lock();
+        while queue.written < ticket {
+            self.shared.written.wait(&mut queue);
+        }
+        match &queue.failed {
+            Some(err) if queue.durable < ticket => {
+                Err(anyhow!("failed to write the write-ahead log: {}", err))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the entries queued so far and stop the leader. Appends fail with
+    /// [`LsmError::Closed`] from then on.
+    pub fn shutdown(&self) {
+        self.shared.queue.lock().shutdown = true;
+        self.shared.queued.notify_one();
+        if let Some(leader) = self.leader.lock().take() {
+            let _ = leader.join();
+        }
+    }
+}
+
+impl Drop for WalGroupCommit {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl Shared {
+    fn lead(&self) {
+        let mut queue = self.queue.lock();
+        loop {
+            while queue.pending.is_empty() && !queue.shutdown {
+                self.queued.wait(&mut queue);
+            }
+            if queue.pending.is_empty() {
+                return;
+            }
+            // give the writers racing in a moment to join the group, until it is as large as
+            // the last one
+            let deadline = Instant::now() + self.commit_timeout;
+            while !queue.shutdown
+                && queue.pending.len() < queue.last_group
+                && !self.queued.wait_until(&mut queue, deadline).timed_out()
+            {}
+
+            let group = std::mem::take(&mut queue.pending);
+            queue.last_group = group.len();
+            let end = queue.written + group.len() as u64;
+            let result = match &queue.failed {
+                Some(_) => Ok(()),
+                None => parking_lot::MutexGuard::unlocked(&mut queue, || self.write(&group)),
+            };
+            match result {
+                Ok(()) if queue.failed.is_none() => queue.durable = end,
+                Ok(()) => {}
+                Err(err) => queue.failed = Some(format!("{:#}", err)),
+            }
+            queue.written = end;
+            self.written.notify_all();
+        }
+    }
+
+    /// Write `group` with one `write_all` and sync it.
+    fn write(&self, group: &[PendingEntry]) -> Result<()> {
+        let mut wal = self.wal.lock();
+        let mut buf = vec![];
+        for entry in group {
+            match entry.records.as_slice() {
+                [] => {}
+                [(key, value)] => wal.encode(key, value, &mut buf),
+                records => wal.encode_batch(records, &mut buf),
+            }
+        }
+        wal.write_encoded(&buf)?;
+        wal.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrent_appends_share_groups() -> Result<()> {
+        let dir = tempfile::tempdir_in(".")?;
+        let path = dir.path().join("file");
+        let wal = Arc::new(Mutex::new(Wal::create(&path)?));
+        let group = Arc::new(WalGroupCommit::new(wal.clone(), Duration::from_millis(5))?);
+        let writers = (0..8)
+            .map(|writer| {
+                let group = group.clone();
+                std::thread::spawn(move || {
+                    for idx in 0..10 {
+                        let key = Bytes::from(format!("key_{}_{}", writer, idx));
+                        group.append(&key, &Bytes::from("value")).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        group.append_batch(&[
+            (Bytes::from("a"), Bytes::from("1")),
+            (Bytes::from("b"), Bytes::from("2")),
+        ])?;
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        group.shutdown();
+        let err = group
+            .append(&Bytes::from("c"), &Bytes::from("3"))
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<LsmError>(), Some(&LsmError::Closed));
+        // everything returned was synced
+        assert_eq!(wal.lock().synced_len(), std::fs::metadata(&path)?.len());
+
+        let tbl = Wal::from(&path)?.to_memtable()?;
+        assert_eq!(tbl.len(), 82);
+        assert_eq!(tbl.get(b"key_7_9"), Some(Bytes::from("value")));
+        assert_eq!(tbl.get(b"b"), Some(Bytes::from("2")));
+        Ok(())
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_group_commit`.
+    #[test]
+    #[ignore]
+    fn bench_group_commit_throughput() -> Result<()> {
+        const WRITERS: usize = 8;
+        const APPENDS: usize = 200;
+        let dir = tempfile::tempdir_in(".")?;
+        let value = Bytes::from(vec![b'v'; 100]);
+
+        // one writer, syncing every append
+        let mut wal = Wal::create(dir.path().join("single"))?;
+        let start = Instant::now();
+        for idx in 0..WRITERS * APPENDS {
+            wal.append(&Bytes::from(format!("key_{}", idx)), &value)?;
+            wal.sync()?;
+        }
+        let single = (WRITERS * APPENDS) as f64 / start.elapsed().as_secs_f64();
+
+        let wal = Arc::new(Mutex::new(Wal::create(dir.path().join("grouped"))?));
+        let group = Arc::new(WalGroupCommit::new(wal, Duration::from_micros(100))?);
+        let start = Instant::now();
+        let writers = (0..WRITERS)
+            .map(|writer| {
+                let group = group.clone();
+                let value = value.clone();
+                std::thread::spawn(move || {
+                    for idx in 0..APPENDS {
+                        let key = Bytes::from(format!("key_{}_{}", writer, idx));
+                        group.append(&key, &value).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let grouped = (WRITERS * APPENDS) as f64 / start.elapsed().as_secs_f64();
+
+        println!(
+            "single writer: {:.0} appends/s, {} writers grouped: {:.0} appends/s ({:.1}x)",
+            single,
+            WRITERS,
+            grouped,
+            grouped / single
+        );
+        assert!(grouped >= 3.0 * single);
+        Ok(())
+    }
+}